@@ -0,0 +1,86 @@
+/*
+ * C bindings for ForkFS session management.
+ *
+ * Build the crate with the `ffi` feature and link against the resulting
+ * libforkfs.so. Every function returns 0 on success and -1 on failure, in
+ * which case forkfs_last_error() describes what went wrong.
+ */
+
+#ifndef FORKFS_H
+#define FORKFS_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Returns a description of the last error that occurred on this thread, or
+ * NULL if none has. The string remains valid until the next failing call on
+ * this thread.
+ */
+const char *forkfs_last_error(void);
+
+/* Creates the session if necessary and activates it. */
+int forkfs_create(const char *session);
+
+/*
+ * Runs argv, a NULL-terminated array, in the session. This replaces the
+ * current process and therefore only returns on failure, so fork first to
+ * keep the calling program alive.
+ */
+int forkfs_run(const char *session, const char *const *argv);
+
+/* Unmounts the session if it is active. */
+int forkfs_stop(const char *session);
+
+/* Stops and deletes the session. */
+int forkfs_delete(const char *session);
+
+enum forkfs_change_kind {
+    /* The path doesn't exist on the real file system. */
+    FORKFS_ADDED,
+    /* The path exists on the real file system with different contents or metadata. */
+    FORKFS_MODIFIED,
+    /* The path exists on the real file system but not in the session. */
+    FORKFS_DELETED,
+    /*
+     * Only the path's metadata differs from the real one's. forkfs_diff()
+     * doesn't tell these apart and reports them as FORKFS_MODIFIED.
+     */
+    FORKFS_METADATA_ONLY,
+};
+
+struct forkfs_change {
+    /* The changed path as seen from inside the session. */
+    char *path;
+    enum forkfs_change_kind kind;
+};
+
+/*
+ * Lists the changes the session made to the real file system, sorted by path.
+ * On success, *changes points to an array of *len changes that must be
+ * released with forkfs_changes_free().
+ */
+int forkfs_diff(const char *session, struct forkfs_change **changes, size_t *len);
+
+/*
+ * Replays the session's changes onto the real file system and empties the
+ * session. Changes flagged as risky are refused unless they are at or below
+ * one of the paths in allowed, a NULL-terminated array or NULL, and files
+ * that were also changed outside the session are only overwritten if force is
+ * non-zero. On success, *changes points to an array of the *len changes that
+ * were applied, which must be released with forkfs_changes_free().
+ */
+int forkfs_apply(const char *session, const char *const *allowed, int force,
+                 struct forkfs_change **changes, size_t *len);
+
+/* Releases an array of changes returned by this library. */
+void forkfs_changes_free(struct forkfs_change *changes, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,144 @@
+"""Python bindings for ForkFS session management.
+
+These wrap the C bindings with ctypes rather than being a compiled extension module, so the same
+`libforkfs.so` serves C and Python programs alike and works with any Python version without
+needing its headers to build. Build ForkFS with `cargo build --release --features ffi` and either
+put `libforkfs.so` on the library search path or point `FORKFS_LIBRARY` at it.
+
+The tests in `test_forkfs.py` load the library the same way and need root:
+`cd python && FORKFS_LIBRARY=../target/debug/libforkfs.so python3 -m unittest test_forkfs`.
+"""
+
+import ctypes
+import enum
+import os
+from typing import NamedTuple
+
+
+class _Change(ctypes.Structure):
+    _fields_ = [("path", ctypes.c_char_p), ("kind", ctypes.c_int)]
+
+
+_lib = ctypes.CDLL(os.environ.get("FORKFS_LIBRARY", "libforkfs.so"))
+_lib.forkfs_last_error.restype = ctypes.c_char_p
+for _name in ("forkfs_create", "forkfs_stop", "forkfs_delete"):
+    getattr(_lib, _name).argtypes = [ctypes.c_char_p]
+_lib.forkfs_run.argtypes = [ctypes.c_char_p, ctypes.POINTER(ctypes.c_char_p)]
+_lib.forkfs_diff.argtypes = [
+    ctypes.c_char_p,
+    ctypes.POINTER(ctypes.POINTER(_Change)),
+    ctypes.POINTER(ctypes.c_size_t),
+]
+_lib.forkfs_apply.argtypes = [
+    ctypes.c_char_p,
+    ctypes.POINTER(ctypes.c_char_p),
+    ctypes.c_int,
+    ctypes.POINTER(ctypes.POINTER(_Change)),
+    ctypes.POINTER(ctypes.c_size_t),
+]
+_lib.forkfs_changes_free.argtypes = [ctypes.POINTER(_Change), ctypes.c_size_t]
+_lib.forkfs_changes_free.restype = None
+
+
+class ChangeKind(enum.IntEnum):
+    """How a path differs from the real file system, mirroring `enum forkfs_change_kind`."""
+
+    ADDED = 0
+    MODIFIED = 1
+    DELETED = 2
+    METADATA_ONLY = 3
+
+
+class Change(NamedTuple):
+    """A path the session changed, as seen from inside the session."""
+
+    path: str
+    kind: ChangeKind
+
+
+class Error(Exception):
+    """A failed ForkFS operation, carrying its full error report."""
+
+
+def _check(status):
+    if status != 0:
+        raise Error(_lib.forkfs_last_error().decode(errors="replace"))
+
+
+def _changes(call, *args):
+    """Calls a function returning an array of changes and copies them out before freeing it."""
+    changes = ctypes.POINTER(_Change)()
+    length = ctypes.c_size_t()
+    _check(call(*args, ctypes.byref(changes), ctypes.byref(length)))
+    try:
+        return [
+            Change(os.fsdecode(change.path), ChangeKind(change.kind))
+            for change in changes[: length.value]
+        ]
+    finally:
+        _lib.forkfs_changes_free(changes, length)
+
+
+class Session:
+    """A ForkFS session, identified by name, index, or prefix like on the command line."""
+
+    def __init__(self, name="default"):
+        self.name = name
+        self._name = os.fsencode(name)
+
+    def create(self):
+        """Create the session if necessary and activate it."""
+        _check(_lib.forkfs_create(self._name))
+        return self
+
+    def run(self, *command):
+        """Run a command in the session and return its exit code.
+
+        The command runs in a forked child because the sandboxed command replaces the process it
+        is started from.
+        """
+        argv = (ctypes.c_char_p * (len(command) + 1))(*map(os.fsencode, command), None)
+        read, write = os.pipe()
+        pid = os.fork()
+        if pid == 0:
+            os.close(read)
+            os.set_inheritable(write, False)
+            _lib.forkfs_run(self._name, argv)
+            os.write(write, _lib.forkfs_last_error() or b"")
+            os._exit(127)
+
+        os.close(write)
+        with os.fdopen(read, "rb") as errors:
+            error = errors.read()
+        status = os.waitpid(pid, 0)[1]
+        if error:
+            raise Error(error.decode(errors="replace"))
+        return os.waitstatus_to_exitcode(status)
+
+    def diff(self):
+        """Return the changes the session made to the real file system, sorted by path."""
+        return _changes(_lib.forkfs_diff, self._name)
+
+    def apply(self, allowed=(), force=False):
+        """Replay the session's changes onto the real file system and empty the session.
+
+        Risky changes are refused unless they are at or below one of the `allowed` paths, and files
+        also changed outside the session are only overwritten with `force`. Returns the changes that
+        were applied.
+        """
+        paths = (ctypes.c_char_p * (len(allowed) + 1))(*map(os.fsencode, allowed), None)
+        return _changes(_lib.forkfs_apply, self._name, paths, int(force))
+
+    def stop(self):
+        """Unmount the session if it is active."""
+        _check(_lib.forkfs_stop(self._name))
+
+    def delete(self):
+        """Stop and delete the session."""
+        _check(_lib.forkfs_delete(self._name))
+
+    def __enter__(self):
+        return self.create()
+
+    def __exit__(self, *_):
+        self.stop()
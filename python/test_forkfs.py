@@ -0,0 +1,35 @@
+"""Smoke tests for the Python bindings, which need root and a library built with `--features ffi`."""
+
+import os
+import unittest
+
+import forkfs
+
+
+@unittest.skipUnless(os.geteuid() == 0, "sessions can only be activated by root")
+class SessionTest(unittest.TestCase):
+    def test_changes_are_diffed_and_applied(self):
+        session = forkfs.Session(f"forkfs-python-test-{os.getpid()}")
+        target = f"/var/tmp/{session.name}"
+        self.addCleanup(lambda: os.path.exists(target) and os.remove(target))
+        self.addCleanup(session.delete)
+
+        with session:
+            status = session.run("sh", "-c", f"echo hello > {target}")
+            changes = session.diff()
+
+        self.assertEqual(status, 0)
+        self.assertEqual(changes, [forkfs.Change(target, forkfs.ChangeKind.ADDED)])
+        self.assertFalse(os.path.exists(target))
+        self.assertEqual(session.apply(), changes)
+        with open(target) as applied:
+            self.assertEqual(applied.read(), "hello\n")
+        self.assertEqual(session.diff(), [])
+
+    def test_failures_raise(self):
+        with self.assertRaises(forkfs.Error):
+            forkfs.Session("forkfs-python-test-missing").diff()
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,921 @@
+//! Replaying a session's changes onto the real file system.
+//!
+//! Applying happens in two phases so that a failure part way through leaves
+//! the real file system untouched wherever possible. Every added or replaced
+//! entry is first staged under a temporary name next to its destination, and
+//! only once everything was staged are deletions carried out and the staged
+//! entries renamed into place. Each individual replacement is therefore atomic
+//! even though the whole operation isn't.
+//!
+//! Every step is written to a journal in the session's directory before it is
+//! taken, and the real entries that are replaced or deleted are only moved
+//! aside until the apply is done. An interrupted apply can thus be continued
+//! with [`resume`] or undone with [`abort`], and no other apply of the session
+//! starts until either was.
+//!
+//! Staged entries and the directories holding them are synced to disk before
+//! anything is replaced, and the directories again once everything was, so
+//! that a crash can neither leave a real file replaced by a partially written
+//! one nor lose changes that were already emptied from the session. Fast
+//! applies skip the syncing, leaving it to the kernel to write the changes
+//! back in its own time.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::{CString, OsStr, OsString},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::{
+        fd::OwnedFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{OpenOptionsExt, lchown, symlink},
+        },
+    },
+    path::{Path, PathBuf},
+    process, str,
+};
+
+use error_stack::{Report, Result, ResultExt};
+use rustix::{
+    fs::{
+        AtFlags, CWD, FileType, Mode, OFlags, Stat, Timespec, Timestamps, chmodat, mkdirat,
+        mknodat, readlinkat, statat, utimensat,
+    },
+    io::Errno,
+};
+
+use crate::{
+    Change, Concern, Error, FaultPoint, IoErr, SessionOperand, diff,
+    diff::{has_overlay_xattr, is_whiteout},
+    encryption, fault, get_sessions_dir,
+    path_undo::TmpPath,
+    pin, review,
+    sessions::{open_diff, remove_tree, resolve_session},
+    stop_sessions, subtree, walk,
+    walk::Links,
+};
+
+/// Replays a session's changes onto the real file system and empties the
+/// session so that it continues from the updated real file system.
+///
+/// The session is stopped first. Changes flagged by [`review::review`] are
+/// refused unless they are at or below one of the `allowed` paths. Changes to
+/// files that were also modified outside the session, and the whole apply once
+/// a file the session pins changed, are refused with [`Error::Conflict`]
+/// unless `force` is set. Permissions, ownership,
+/// modification times, and hard links between the changed files are
+/// preserved. Unless `fast` is set, the changes are synced to disk before the
+/// session is emptied.
+///
+/// Returns the changes that were applied.
+pub fn apply(
+    session: impl AsRef<OsStr>,
+    allowed: &[PathBuf],
+    force: bool,
+    fast: bool,
+) -> Result<Vec<Change>, Error> {
+    apply_with(session.as_ref(), allowed, force, &BTreeMap::new(), fast)
+}
+
+/// How a file changed both inside and outside a session is applied.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Resolution {
+    /// Overwrite the real file with the session's version.
+    Session,
+    /// Keep the real file, dropping the session's version.
+    Live,
+    /// Replace the real file with these contents, taking on the metadata of
+    /// the session's version or, if the session deleted the file, of the
+    /// real one.
+    Contents(Vec<u8>),
+}
+
+/// Like [`apply`], but settles the conflicts with the real file system as
+/// given by `resolutions` instead of refusing or overwriting them all.
+///
+/// Each resolution is keyed by the absolute path of the changed file, and
+/// every file changed outside the session must be resolved. Returns the
+/// changes that were applied, leaving out those resolved as
+/// [`Resolution::Live`].
+pub fn apply_resolved(
+    session: impl AsRef<OsStr>,
+    allowed: &[PathBuf],
+    resolutions: &BTreeMap<PathBuf, Resolution>,
+    fast: bool,
+) -> Result<Vec<Change>, Error> {
+    apply_with(session.as_ref(), allowed, false, resolutions, fast)
+}
+
+fn apply_with(
+    session: &OsStr,
+    allowed: &[PathBuf],
+    force: bool,
+    resolutions: &BTreeMap<PathBuf, Resolution>,
+    fast: bool,
+) -> Result<Vec<Change>, Error> {
+    stop_sessions(SessionOperand::List(&[session]))?;
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    if encryption::is_encrypted(&mut dir) {
+        return Err(Error::InvalidArgument).attach_printable(
+            "Encrypted sessions can't be read once stopped and so can't be applied.",
+        );
+    }
+    if TmpPath::new(&mut dir, JOURNAL).exists() {
+        return Err(Error::Conflict).attach_printable(
+            "An earlier apply of this session was interrupted. Continue or abort it first.",
+        );
+    }
+
+    let findings = review::review(session, allowed)?;
+    if !findings.is_empty() {
+        let mut report = Report::new(Error::InvalidArgument);
+        for finding in findings {
+            report = report.attach_printable(format!(
+                "Refusing to apply {}: {}",
+                finding.path.display(),
+                describe(finding.concern)
+            ));
+        }
+        return Err(report).attach_printable("Allow the paths explicitly if these are intended.");
+    }
+
+    let mut changes = diff::changes(session)?;
+    let unresolved = |change: &&Change| change.diverged && !resolutions.contains_key(&change.path);
+    if !force && changes.iter().any(|change| unresolved(&change)) {
+        let mut report = Report::new(Error::Conflict);
+        for change in changes.iter().filter(unresolved) {
+            report = report.attach_printable(format!(
+                "{} was changed outside the session",
+                change.path.display()
+            ));
+        }
+        return Err(report).attach_printable("Force the apply to overwrite those changes.");
+    }
+    let unpinned = pin::changed(&mut dir)?;
+    if !force && !unpinned.is_empty() {
+        let mut report = Report::new(Error::Conflict);
+        for path in unpinned {
+            report = report.attach_printable(format!(
+                "{} was changed since the session pinned it",
+                path.display()
+            ));
+        }
+        return Err(report).attach_printable("Force the apply to ignore the pins.");
+    }
+
+    let upper = open_diff(session)?;
+    let mut plan = Plan::new(&mut dir, !fast)?;
+    let mut root = subtree::get(&mut dir)?;
+    if let Err(e) = stage(&upper, &mut root, resolutions, &mut plan)
+        .and_then(|()| fault::check(FaultPoint::MidCopy))
+    {
+        plan.discard();
+        drop(fs::remove_file(TmpPath::new(&mut dir, JOURNAL)));
+        return Err(e);
+    }
+    plan.commit()
+        .attach_printable("Continue or abort the apply once the cause was dealt with.")?;
+
+    finish(&upper, &mut dir)?;
+    changes.retain(|change| resolutions.get(&change.path) != Some(&Resolution::Live));
+    Ok(changes)
+}
+
+/// Carries an interrupted apply through, replacing whatever real entries it
+/// hadn't yet and emptying the session.
+///
+/// If the apply was interrupted before it replaced anything, it starts over
+/// from scratch as [`apply`] would with `allowed` and `force`.
+pub fn resume(
+    session: impl AsRef<OsStr>,
+    allowed: &[PathBuf],
+    force: bool,
+    fast: bool,
+) -> Result<(), Error> {
+    let session = session.as_ref();
+    stop_sessions(SessionOperand::List(&[session]))?;
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    let mut plan = Plan::load(&mut dir, !fast)?;
+    match plan.progress {
+        Progress::Staging => {
+            plan.discard();
+            fs::remove_file(TmpPath::new(&mut dir, JOURNAL))
+                .map_io_err_lazy(|| format!("Failed to delete the journal in {}", dir.display()))?;
+            return apply(session, allowed, force, fast).map(drop);
+        }
+        Progress::Committing => plan.commit()?,
+        Progress::Done => plan.clean_up()?,
+    }
+    finish(&open_diff(session)?, &mut dir)
+}
+
+/// Undoes an interrupted apply, putting back the real entries it replaced or
+/// deleted and leaving the session as it was.
+///
+/// Applies that were interrupted while cleaning up after themselves can't be
+/// undone anymore and fail with [`Error::Conflict`].
+pub fn abort(session: impl AsRef<OsStr>) -> Result<(), Error> {
+    let session = session.as_ref();
+    stop_sessions(SessionOperand::List(&[session]))?;
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    let plan = Plan::load(&mut dir, true)?;
+    match plan.progress {
+        Progress::Staging => plan.discard(),
+        Progress::Committing => plan.undo()?,
+        Progress::Done => {
+            return Err(Error::Conflict).attach_printable(
+                "The apply already replaced every file and can only be continued.",
+            );
+        }
+    }
+    fs::remove_file(TmpPath::new(&mut dir, JOURNAL))
+        .map_io_err_lazy(|| format!("Failed to delete the journal in {}", dir.display()))
+}
+
+/// Empties a session whose changes were applied and pins the files it pins
+/// again, and only then forgets the apply.
+fn finish(upper: &OwnedFd, dir: &mut PathBuf) -> Result<(), Error> {
+    remove_tree(upper, &mut TmpPath::new(dir, "diff"))?;
+    pin::refresh(dir)?;
+    fs::remove_file(TmpPath::new(dir, JOURNAL))
+        .map_io_err_lazy(|| format!("Failed to delete the journal in {}", dir.display()))
+}
+
+/// The file in a session's directory that records an apply in progress.
+const JOURNAL: &str = "apply-journal";
+
+/// How far an apply got according to its journal.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Progress {
+    /// Entries were being staged and nothing real was replaced yet.
+    Staging,
+    /// Real entries were being replaced.
+    Committing,
+    /// Every real entry was replaced, and only the entries moved aside were
+    /// left to delete.
+    Done,
+}
+
+/// A step of an apply, recorded in its journal before it is taken.
+#[derive(Debug)]
+enum Entry {
+    /// An entry staged at `staged` to replace `path`, moving any real entry
+    /// that isn't a directory to `backup` until the apply is done.
+    Rename {
+        staged: PathBuf,
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    /// A real entry to delete, moved to `backup` until the apply is done.
+    Removal { path: PathBuf, backup: PathBuf },
+    /// An existing real directory that takes on the metadata of the session's
+    /// version.
+    Directory {
+        path: PathBuf,
+        old: Metadata,
+        new: Metadata,
+    },
+    /// Everything was staged.
+    Commit,
+    /// Everything was replaced.
+    Done,
+}
+
+impl Entry {
+    /// Fields are NUL-terminated so that any path can be recorded.
+    fn encode(&self) -> Vec<u8> {
+        let mut record = Vec::new();
+        let mut field = |field: &[u8]| {
+            record.extend_from_slice(field);
+            record.push(0);
+        };
+        match self {
+            Self::Rename {
+                staged,
+                path,
+                backup,
+            } => {
+                field(b"rename");
+                for path in [staged, path, backup] {
+                    field(path.as_os_str().as_bytes());
+                }
+            }
+            Self::Removal { path, backup } => {
+                field(b"removal");
+                field(path.as_os_str().as_bytes());
+                field(backup.as_os_str().as_bytes());
+            }
+            Self::Directory { path, old, new } => {
+                field(b"directory");
+                field(path.as_os_str().as_bytes());
+                for metadata in [old, new] {
+                    for number in metadata.fields() {
+                        field(number.to_string().as_bytes());
+                    }
+                }
+            }
+            Self::Commit => field(b"commit"),
+            Self::Done => field(b"done"),
+        }
+        record
+    }
+
+    /// Reads back the entries of a journal. A record cut short by a crash is
+    /// dropped along with the step it would have announced.
+    fn decode(journal: &[u8]) -> Option<Vec<Self>> {
+        let mut fields = journal.split(|&b| b == 0).collect::<Vec<_>>();
+        // Whatever follows the last NUL is part of an unfinished field
+        fields.pop();
+        let mut fields = fields.into_iter();
+        let mut entries = Vec::new();
+        let path = |field: &[u8]| PathBuf::from(OsStr::from_bytes(field));
+        while let Some(tag) = fields.next() {
+            let mut take = |count| {
+                let taken = fields.by_ref().take(count).collect::<Vec<_>>();
+                (taken.len() == count).then_some(taken)
+            };
+            let entry = match tag {
+                b"rename" => {
+                    let Some(taken) = take(3) else { break };
+                    Self::Rename {
+                        staged: path(taken[0]),
+                        path: path(taken[1]),
+                        backup: path(taken[2]),
+                    }
+                }
+                b"removal" => {
+                    let Some(taken) = take(2) else { break };
+                    Self::Removal {
+                        path: path(taken[0]),
+                        backup: path(taken[1]),
+                    }
+                }
+                b"directory" => {
+                    let Some(taken) = take(1 + 2 * Metadata::FIELDS) else {
+                        break;
+                    };
+                    let (old, new) = taken[1..].split_at(Metadata::FIELDS);
+                    Self::Directory {
+                        path: path(taken[0]),
+                        old: Metadata::parse(old)?,
+                        new: Metadata::parse(new)?,
+                    }
+                }
+                b"commit" => Self::Commit,
+                b"done" => Self::Done,
+                _ => return None,
+            };
+            entries.push(entry);
+        }
+        Some(entries)
+    }
+}
+
+/// The metadata an apply gives the entries it replaces.
+#[derive(Copy, Clone, Debug)]
+struct Metadata {
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    atime: (i64, i64),
+    mtime: (i64, i64),
+}
+
+impl Metadata {
+    const FIELDS: usize = 7;
+
+    fn fields(&self) -> [i64; Self::FIELDS] {
+        [
+            self.uid.into(),
+            self.gid.into(),
+            self.mode.into(),
+            self.atime.0,
+            self.atime.1,
+            self.mtime.0,
+            self.mtime.1,
+        ]
+    }
+
+    fn parse(fields: &[&[u8]]) -> Option<Self> {
+        let numbers = fields
+            .iter()
+            .map(|field| str::from_utf8(field).ok()?.parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let &[uid, gid, mode, atime, atime_nsec, mtime, mtime_nsec] = numbers.as_slice() else {
+            return None;
+        };
+        Some(Self {
+            uid: uid.try_into().ok()?,
+            gid: gid.try_into().ok()?,
+            mode: mode.try_into().ok()?,
+            atime: (atime, atime_nsec),
+            mtime: (mtime, mtime_nsec),
+        })
+    }
+}
+
+impl From<&Stat> for Metadata {
+    fn from(stat: &Stat) -> Self {
+        Self {
+            uid: stat.st_uid,
+            gid: stat.st_gid,
+            mode: stat.st_mode,
+            atime: (
+                stat.st_atime.cast_signed(),
+                stat.st_atime_nsec.cast_signed(),
+            ),
+            mtime: (
+                stat.st_mtime.cast_signed(),
+                stat.st_mtime_nsec.cast_signed(),
+            ),
+        }
+    }
+}
+
+/// What is left to do once every change was staged.
+struct Plan {
+    /// Real entries to delete and where they are moved until then.
+    removals: Vec<(PathBuf, PathBuf)>,
+    /// Staged entries, the real paths they replace, and where the real
+    /// entries are moved until the apply is done.
+    renames: Vec<(PathBuf, PathBuf, PathBuf)>,
+    /// Existing real directories along with their metadata and that of the
+    /// upper directories they should take on, outermost first.
+    directories: Vec<(PathBuf, Metadata, Metadata)>,
+    /// The staged copies of the upper files that are linked to others.
+    links: Links,
+    /// Whether staged entries, the directories holding them, and the journal
+    /// are synced.
+    durable: bool,
+    journal: File,
+    progress: Progress,
+}
+
+impl Plan {
+    /// Starts the journal of a new apply of the session at `dir`.
+    fn new(dir: &mut PathBuf, durable: bool) -> Result<Self, Error> {
+        let path = TmpPath::new(dir, JOURNAL);
+        let journal = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)
+            .map_io_err_lazy(|| format!("Failed to create {}", path.display()))?;
+        Ok(Self::with_journal(journal, durable))
+    }
+
+    fn with_journal(journal: File, durable: bool) -> Self {
+        Self {
+            removals: Vec::new(),
+            renames: Vec::new(),
+            directories: Vec::new(),
+            links: Links::default(),
+            durable,
+            journal,
+            progress: Progress::Staging,
+        }
+    }
+
+    /// Reads back the journal of an interrupted apply of the session at `dir`.
+    fn load(dir: &mut PathBuf, durable: bool) -> Result<Self, Error> {
+        let path = TmpPath::new(dir, JOURNAL);
+        let contents = match fs::read(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(Error::InvalidArgument)
+                    .attach_printable("No apply of this session was interrupted.");
+            }
+            r => r.map_io_err_lazy(|| format!("Failed to read {}", path.display()))?,
+        };
+        let entries = Entry::decode(&contents)
+            .ok_or(Error::InvalidArgument)
+            .attach_printable_lazy(|| format!("The journal {} is corrupt", path.display()))?;
+        let journal = OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .map_io_err_lazy(|| format!("Failed to open {}", path.display()))?;
+        let mut plan = Self::with_journal(journal, durable);
+        for entry in entries {
+            match entry {
+                Entry::Rename {
+                    staged,
+                    path,
+                    backup,
+                } => plan.renames.push((staged, path, backup)),
+                Entry::Removal { path, backup } => plan.removals.push((path, backup)),
+                Entry::Directory { path, old, new } => plan.directories.push((path, old, new)),
+                Entry::Commit => plan.progress = Progress::Committing,
+                Entry::Done => plan.progress = Progress::Done,
+            }
+        }
+        Ok(plan)
+    }
+
+    fn record(&mut self, entry: &Entry) -> Result<(), Error> {
+        self.journal
+            .write_all(&entry.encode())
+            .and_then(|()| {
+                if self.durable && matches!(entry, Entry::Commit | Entry::Done) {
+                    self.journal.sync_data()
+                } else {
+                    Ok(())
+                }
+            })
+            .map_io_err("Failed to write to the apply's journal")
+    }
+
+    /// Plans for `path` to be deleted.
+    fn remove(&mut self, path: &Path) -> Result<(), Error> {
+        let backup = temporary_path(path, "backup");
+        self.record(&Entry::Removal {
+            path: path.to_path_buf(),
+            backup: backup.clone(),
+        })?;
+        self.removals.push((path.to_path_buf(), backup));
+        Ok(())
+    }
+
+    /// Plans for `path` to be replaced, returning where to stage its
+    /// replacement.
+    fn replace(&mut self, path: &Path) -> Result<PathBuf, Error> {
+        let staged = temporary_path(path, "apply");
+        let backup = temporary_path(path, "backup");
+        self.record(&Entry::Rename {
+            staged: staged.clone(),
+            path: path.to_path_buf(),
+            backup: backup.clone(),
+        })?;
+        self.renames
+            .push((staged.clone(), path.to_path_buf(), backup));
+        Ok(staged)
+    }
+
+    /// Plans for the real directory at `path` to take on `new`'s metadata.
+    fn update(&mut self, path: &Path, old: &Stat, new: &Stat) -> Result<(), Error> {
+        let (old, new) = (Metadata::from(old), Metadata::from(new));
+        self.record(&Entry::Directory {
+            path: path.to_path_buf(),
+            old,
+            new,
+        })?;
+        self.directories.push((path.to_path_buf(), old, new));
+        Ok(())
+    }
+
+    fn discard(self) {
+        for (staged, ..) in self.renames {
+            drop(remove(&staged));
+        }
+    }
+
+    fn parents(&self) -> BTreeSet<PathBuf> {
+        self.removals
+            .iter()
+            .map(|(path, _)| path)
+            .chain(self.renames.iter().map(|(_, path, _)| path))
+            .filter_map(|path| path.parent())
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// Moves the staged entries into place. Steps that were already taken
+    /// before an interruption are skipped.
+    fn commit(&mut self) -> Result<(), Error> {
+        let parents = self.parents();
+        if self.progress == Progress::Staging {
+            for parent in &parents {
+                self.sync(parent)?;
+            }
+            self.record(&Entry::Commit)?;
+            self.progress = Progress::Committing;
+        }
+
+        for (path, backup) in &self.removals {
+            if exists(path)? && !exists(backup)? {
+                fs::rename(path, backup)
+                    .map_io_err_lazy(|| format!("Failed to delete {}", path.display()))?;
+            }
+        }
+        fault::check(FaultPoint::MidCommit)?;
+        for (staged, path, backup) in &self.renames {
+            if !exists(staged)? {
+                continue;
+            }
+            // Links keep the real file in place until the staged one replaces it
+            if !exists(backup)? && exists(path)? {
+                fs::hard_link(path, backup)
+                    .map_io_err_lazy(|| format!("Failed to back up {}", path.display()))?;
+            }
+            fs::rename(staged, path)
+                .map_io_err_lazy(|| format!("Failed to replace {}", path.display()))?;
+        }
+        // Innermost first so that updating a directory doesn't touch its parent
+        for (path, _, new) in self.directories.iter().rev() {
+            set_metadata(path, FileType::Directory, new)?;
+        }
+        for parent in &parents {
+            self.sync(parent)?;
+        }
+        self.record(&Entry::Done)?;
+        self.progress = Progress::Done;
+        self.clean_up()
+    }
+
+    /// Deletes the real entries that were replaced or deleted.
+    fn clean_up(&self) -> Result<(), Error> {
+        for backup in self
+            .removals
+            .iter()
+            .map(|(_, backup)| backup)
+            .chain(self.renames.iter().map(|(.., backup)| backup))
+        {
+            remove(backup).map_io_err_lazy(|| format!("Failed to delete {}", backup.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Puts the real entries that were replaced or deleted back in place.
+    fn undo(self) -> Result<(), Error> {
+        for (staged, path, backup) in self.renames.iter().rev() {
+            let error = || format!("Failed to restore {}", path.display());
+            if exists(staged)? {
+                remove(staged).map_io_err_lazy(error)?;
+                // Either a link to the real file or a deleted real directory
+                if exists(backup)? && exists(path)? {
+                    remove(backup).map_io_err_lazy(error)?;
+                }
+                continue;
+            }
+            if !exists(backup)? {
+                remove(path).map_io_err_lazy(error)?;
+                continue;
+            }
+            // Only files can be renamed over one another
+            if is_directory(path)? || is_directory(backup)? {
+                remove(path).map_io_err_lazy(error)?;
+            }
+            fs::rename(backup, path).map_io_err_lazy(error)?;
+        }
+        for (path, backup) in self.removals.iter().rev() {
+            if exists(backup)? && !exists(path)? {
+                fs::rename(backup, path)
+                    .map_io_err_lazy(|| format!("Failed to restore {}", path.display()))?;
+            }
+        }
+        for (path, old, _) in self.directories.iter().rev() {
+            set_metadata(path, FileType::Directory, old)?;
+        }
+        for parent in &self.parents() {
+            self.sync(parent)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes a regular file or directory and its metadata to disk unless
+    /// the apply is a fast one.
+    fn sync(&self, path: &Path) -> Result<(), Error> {
+        if !self.durable {
+            return Ok(());
+        }
+        File::open(path)
+            .and_then(|file| file.sync_all())
+            .map_io_err_lazy(|| format!("Failed to sync {}", path.display()))
+    }
+}
+
+fn exists(path: &Path) -> Result<bool, Error> {
+    match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).map_io_err_lazy(|| format!("Failed to stat {}", path.display())),
+    }
+}
+
+fn is_directory(path: &Path) -> Result<bool, Error> {
+    fs::symlink_metadata(path)
+        .map(|metadata| metadata.is_dir())
+        .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))
+}
+
+/// Deletes an entry, recursively for directories, if it exists.
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn stage(
+    dir: &OwnedFd,
+    path: &mut PathBuf,
+    resolutions: &BTreeMap<PathBuf, Resolution>,
+    plan: &mut Plan,
+) -> Result<(), Error> {
+    for (name, file_type) in walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+        let stat = statat(dir, &name, AtFlags::SYMLINK_NOFOLLOW)
+            .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+        let host_stat = statat(CWD, &*path, AtFlags::SYMLINK_NOFOLLOW).ok();
+        let host = host_stat
+            .as_ref()
+            .map(|host| FileType::from_raw_mode(host.st_mode));
+
+        match resolutions.get(&*path) {
+            Some(Resolution::Live) => continue,
+            Some(Resolution::Contents(contents)) => {
+                if host == Some(FileType::Directory) {
+                    plan.remove(&path)?;
+                }
+                let stat = match (file_type, host_stat) {
+                    (FileType::RegularFile, _) | (_, None) => stat,
+                    (_, Some(host)) => host,
+                };
+                let staged = plan.replace(&path)?;
+                fs::write(&staged, contents)
+                    .map_io_err_lazy(|| format!("Failed to create {}", staged.display()))?;
+                copy_metadata(&staged, FileType::RegularFile, &stat)?;
+                plan.sync(&staged)?;
+                continue;
+            }
+            Some(Resolution::Session) | None => {}
+        }
+
+        if is_whiteout(file_type, &stat) {
+            if host.is_some() {
+                plan.remove(&path)?;
+            }
+            continue;
+        }
+        if file_type == FileType::Directory {
+            let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+                .map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?;
+            if has_overlay_xattr(&child, "redirect", &path)? {
+                return Err(Error::InvalidArgument).attach_printable(format!(
+                    "Applying renamed directories isn't supported: {}",
+                    path.display()
+                ));
+            }
+            if let Some(host) = host_stat
+                .as_ref()
+                .filter(|_| host == Some(FileType::Directory))
+                && !has_overlay_xattr(&child, "opaque", &path)?
+            {
+                plan.update(&path, host, &stat)?;
+                stage(&child, &mut path, resolutions, plan)?;
+                continue;
+            }
+        }
+
+        // Renaming can only replace files with files and never non-empty directories
+        if host.is_some_and(|host| host == FileType::Directory || file_type == FileType::Directory)
+        {
+            plan.remove(&path)?;
+        }
+        let staged = plan.replace(&path)?;
+        copy(dir, &name, file_type, &stat, &path, &staged, plan)?;
+    }
+    Ok(())
+}
+
+/// Copies an upper directory entry to `dest`, recursively for directories.
+fn copy(
+    dir: &OwnedFd,
+    name: &CString,
+    file_type: FileType,
+    stat: &Stat,
+    path: &Path,
+    dest: &Path,
+    plan: &mut Plan,
+) -> Result<(), Error> {
+    let mode = Mode::from_raw_mode(stat.st_mode);
+    match file_type {
+        FileType::RegularFile => {
+            if let Some(first) = plan.links.first_copy(stat, dest) {
+                match fs::hard_link(first, dest) {
+                    // Links can't span file systems, so those files are copied again
+                    Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+                    r => {
+                        return r.map_io_err_lazy(|| {
+                            format!("Failed to create link {}", dest.display())
+                        });
+                    }
+                }
+            }
+            let source = walk::open_beneath(dir, name, OFlags::RDONLY)
+                .map_io_err_lazy(|| format!("Failed to open {}", path.display()))?;
+            if has_overlay_xattr(&source, "metacopy", path)? {
+                return Err(Error::InvalidArgument).attach_printable(format!(
+                    "Applying metadata-only copies isn't supported: {}",
+                    path.display()
+                ));
+            }
+            let mut dest_file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(dest)
+                .map_io_err_lazy(|| format!("Failed to create {}", dest.display()))?;
+            io::copy(&mut File::from(source), &mut dest_file)
+                .map_io_err_lazy(|| format!("Failed to copy {}", path.display()))?;
+        }
+        FileType::Directory => {
+            mkdirat(CWD, dest, Mode::RWXU)
+                .map_io_err_lazy(|| format!("Failed to create directory {}", dest.display()))?;
+            let child = walk::open_beneath(dir, name, OFlags::RDONLY | OFlags::DIRECTORY)
+                .map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?;
+            for (name, file_type) in walk::entries(&child)
+                .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+            {
+                let file_name = OsStr::from_bytes(name.as_bytes());
+                let path = path.join(file_name);
+                let stat = statat(&child, &name, AtFlags::SYMLINK_NOFOLLOW)
+                    .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+                // Nothing shows through from below a directory that is new
+                if !is_whiteout(file_type, &stat) {
+                    copy(
+                        &child,
+                        &name,
+                        file_type,
+                        &stat,
+                        &path,
+                        &dest.join(file_name),
+                        plan,
+                    )?;
+                }
+            }
+        }
+        FileType::Symlink => {
+            let target = readlinkat(dir, name, Vec::new())
+                .map_io_err_lazy(|| format!("Failed to read link {}", path.display()))?;
+            symlink(OsStr::from_bytes(target.as_bytes()), dest)
+                .map_io_err_lazy(|| format!("Failed to create link {}", dest.display()))?;
+        }
+        _ => {
+            mknodat(CWD, dest, file_type, mode, stat.st_rdev)
+                .map_io_err_lazy(|| format!("Failed to create {}", dest.display()))?;
+        }
+    }
+    copy_metadata(dest, file_type, stat)?;
+    // Other entries only exist in their parent directory, which is synced
+    // once its entries were copied
+    if matches!(file_type, FileType::RegularFile | FileType::Directory) {
+        plan.sync(dest)?;
+    }
+    Ok(())
+}
+
+pub fn copy_metadata(path: &Path, file_type: FileType, stat: &Stat) -> Result<(), Error> {
+    set_metadata(path, file_type, &Metadata::from(stat))
+}
+
+fn set_metadata(path: &Path, file_type: FileType, metadata: &Metadata) -> Result<(), Error> {
+    // Changing the owner clears the setuid and setgid bits, so it goes first
+    lchown(path, Some(metadata.uid), Some(metadata.gid))
+        .map_io_err_lazy(|| format!("Failed to change the owner of {}", path.display()))?;
+    if file_type != FileType::Symlink {
+        chmodat(
+            CWD,
+            path,
+            Mode::from_raw_mode(metadata.mode),
+            AtFlags::empty(),
+        )
+        .map_io_err_lazy(|| format!("Failed to change the permissions of {}", path.display()))?;
+    }
+    let times = Timestamps {
+        last_access: Timespec {
+            tv_sec: metadata.atime.0,
+            tv_nsec: metadata.atime.1,
+        },
+        last_modification: Timespec {
+            tv_sec: metadata.mtime.0,
+            tv_nsec: metadata.mtime.1,
+        },
+    };
+    match utimensat(CWD, path, &times, AtFlags::SYMLINK_NOFOLLOW) {
+        // Some file systems can't set the times of symlinks
+        Err(Errno::OPNOTSUPP) if file_type == FileType::Symlink => Ok(()),
+        r => r.map_io_err_lazy(|| format!("Failed to change the times of {}", path.display())),
+    }
+}
+
+/// Where to keep a temporary entry of the given kind next to `path`.
+fn temporary_path(path: &Path, kind: &str) -> PathBuf {
+    let mut name = OsString::from(format!(".forkfs-{kind}-{}-", process::id()));
+    name.push(path.file_name().unwrap_or_default());
+    path.with_file_name(name)
+}
+
+pub const fn describe(concern: Concern) -> &'static str {
+    match concern {
+        Concern::SetId => "the file is setuid or setgid",
+        Concern::Device => "the file is a device",
+        Concern::UnexpectedOwner { .. } => "the file is owned by an unexpected user",
+        Concern::SensitivePath => "the path is security sensitive",
+    }
+}
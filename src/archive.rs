@@ -0,0 +1,930 @@
+//! Moving sessions between machines as tar archives.
+//!
+//! Archives hold the contents of a session's upper directory in the pax
+//! format. overlayfs' whiteouts are character devices and its opaque
+//! directories are marked with extended attributes, neither of which survive
+//! most tools, so they are encoded the way OCI image layers do instead: a
+//! deleted `name` becomes an empty `.wh.name` file and an opaque directory
+//! contains an empty `.wh..wh..opq` file. Files with several hard links are
+//! stored once, with their other names archived as links to the first.
+//!
+//! Unless asked to preserve them, setuid and setgid bits, file capabilities,
+//! and device nodes are left out so that sharing an archive can't smuggle in
+//! ways to escalate privileges. Sockets are always left out, and both are
+//! reported back rather than dropped silently.
+//!
+//! Archives meant to be shared can also have personal details, such as user
+//! names or tokens, replaced in the files they contain, and leave out large or
+//! binary files that would only weigh them down.
+
+use std::{
+    ffi::{CString, OsStr},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    mem,
+    os::{
+        fd::OwnedFd,
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::{OpenOptionsExt, lchown, symlink},
+        },
+    },
+    path::{Component, Path, PathBuf},
+    process,
+};
+
+use error_stack::{Report, Result, ResultExt};
+use rustix::{
+    fs::{
+        AtFlags, CWD, FileType, Mode, OFlags, Stat, Timespec, Timestamps, XattrFlags, chmodat,
+        fgetxattr, linkat, lsetxattr, major, makedev, minor, mknodat, readlinkat, statat,
+        utimensat,
+    },
+    io::{Errno, pread},
+    process::getuid,
+};
+
+use crate::{
+    Error, IoErr, Redaction,
+    diff::{has_overlay_xattr, is_whiteout},
+    get_sessions_dir, meta,
+    path_undo::TmpPath,
+    redact::Rules,
+    run::validate_permissions,
+    scheduler,
+    sessions::{delete_session, is_active_session, open_diff, validate_name},
+    walk,
+    walk::Links,
+};
+
+const BLOCK: usize = 512;
+/// The prefix OCI image layers use to mark deleted entries.
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+/// The entry OCI image layers use to mark opaque directories.
+const OPAQUE_MARKER: &[u8] = b".wh..wh..opq";
+/// The most data read into memory for a single pax header or long name.
+const MAX_METADATA_SIZE: u64 = 1 << 20;
+/// How much of a file is looked at to tell whether it is binary.
+const SNIFF_SIZE: usize = 8 << 10;
+
+/// An entry of a session's changes that [`export`] left out of its archive.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Omission {
+    /// The path as seen from inside the session.
+    pub path: PathBuf,
+    pub kind: OmissionKind,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OmissionKind {
+    /// Sockets only work while the program that created them listens on them,
+    /// so tar has no way to store them.
+    Socket,
+    /// Device nodes are only archived when special files are preserved.
+    Device,
+    /// The file is larger than [`Filter::max_file_size`].
+    TooLarge,
+    /// The file looks binary and [`Filter::exclude_binary`] is set, or it is
+    /// being written to a patch.
+    Binary,
+    /// Patches can only describe text files and symlinks, which leaves out
+    /// devices and FIFOs.
+    Unpatchable,
+}
+
+/// Regular files that [`export_filtered`] leaves out of its archive.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Filter {
+    /// Leave out files larger than this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Leave out files that contain NUL bytes or aren't UTF-8 near their
+    /// start, the way `git` tells binary files apart.
+    pub exclude_binary: bool,
+}
+
+/// Writes a session's changes to `out` as a tar archive, returning the
+/// entries that had to be left out.
+///
+/// Setuid and setgid bits, file capabilities, and device nodes are stripped
+/// unless `preserve_special` is set. The session is read at the maintenance
+/// IO priority from the config file.
+pub fn export(
+    session: impl AsRef<OsStr>,
+    out: impl Write,
+    preserve_special: bool,
+) -> Result<Vec<Omission>, Error> {
+    export_redacted(session, out, preserve_special, &[])
+}
+
+/// Like [`export`], but makes the `redactions` in the contents of the files
+/// they cover, which are read into memory to do so.
+pub fn export_redacted(
+    session: impl AsRef<OsStr>,
+    out: impl Write,
+    preserve_special: bool,
+    redactions: &[Redaction],
+) -> Result<Vec<Omission>, Error> {
+    export_filtered(
+        session,
+        out,
+        preserve_special,
+        redactions,
+        Filter::default(),
+    )
+}
+
+/// Like [`export_redacted`], but leaves out the regular files `filter` rules
+/// out, reporting them as omissions. Links to a file that was left out are
+/// left out along with it.
+pub fn export_filtered(
+    session: impl AsRef<OsStr>,
+    out: impl Write,
+    preserve_special: bool,
+    redactions: &[Redaction],
+    filter: Filter,
+) -> Result<Vec<Omission>, Error> {
+    let rules = Rules::compile(redactions)?;
+    let upper = open_diff(session.as_ref())?;
+    let mut writer = Writer {
+        out,
+        preserve_special,
+        filter,
+        rules,
+        links: Links::default(),
+        omissions: Vec::new(),
+    };
+    scheduler::background(|| writer.tree(&upper, &mut PathBuf::new()))?;
+    let omissions = mem::take(&mut writer.omissions);
+    writer.finish()?;
+    Ok(omissions)
+}
+
+struct Writer<W> {
+    out: W,
+    preserve_special: bool,
+    filter: Filter,
+    rules: Rules,
+    links: Links,
+    omissions: Vec<Omission>,
+}
+
+impl<W: Write> Writer<W> {
+    fn tree(&mut self, dir: &OwnedFd, path: &mut PathBuf) -> Result<(), Error> {
+        let mut entries = walk::entries(dir)
+            .map_io_err_lazy(|| format!("Failed to read directory /{}", path.display()))?;
+        // Sorted for reproducible archives
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        for (name, file_type) in entries {
+            let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+            let stat = statat(dir, &name, AtFlags::SYMLINK_NOFOLLOW)
+                .map_io_err_lazy(|| format!("Failed to stat /{}", path.display()))?;
+
+            if is_whiteout(file_type, &stat) {
+                let mut marker = WHITEOUT_PREFIX.to_vec();
+                marker.extend_from_slice(name.as_bytes());
+                self.marker(&path.with_file_name(OsStr::from_bytes(&marker)), &stat)?;
+                continue;
+            }
+            match file_type {
+                FileType::RegularFile => {
+                    if self
+                        .filter
+                        .max_file_size
+                        .is_some_and(|max| stat.st_size.cast_unsigned() > max)
+                    {
+                        self.omit(&path, OmissionKind::TooLarge);
+                        continue;
+                    }
+                    let file = walk::open_beneath(dir, &name, OFlags::RDONLY)
+                        .map_io_err_lazy(|| format!("Failed to open /{}", path.display()))?;
+                    if self.filter.exclude_binary && is_binary(&file, &path)? {
+                        self.omit(&path, OmissionKind::Binary);
+                        continue;
+                    }
+                    if let Some(first) = self.links.first_copy(&stat, &path) {
+                        let first = first.as_os_str().as_bytes().to_vec();
+                        self.entry(&path, b'1', &stat, 0, &first, &[])?;
+                        continue;
+                    }
+                    if has_overlay_xattr(&file, "metacopy", &path)? {
+                        return Err(Error::InvalidArgument).attach_printable(format!(
+                            "Exporting metadata-only copies isn't supported: /{}",
+                            path.display()
+                        ));
+                    }
+                    let records = self.records(&file, &path)?;
+                    let absolute = Path::new("/").join(&path);
+                    if self.rules.covers(&absolute) {
+                        let mut contents = Vec::new();
+                        File::from(file)
+                            .read_to_end(&mut contents)
+                            .map_io_err_lazy(|| format!("Failed to archive /{}", path.display()))?;
+                        let contents = self.rules.apply(&absolute, contents);
+                        let size = contents.len() as u64;
+                        self.entry(&path, b'0', &stat, size, &[], &records)?;
+                        self.out
+                            .write_all(&contents)
+                            .map_io_err("Failed to write archive")?;
+                        self.pad(size)?;
+                        continue;
+                    }
+                    let size = stat.st_size.cast_unsigned();
+                    self.entry(&path, b'0', &stat, size, &[], &records)?;
+                    // The archive is corrupted if the file shrinks under our feet
+                    let copied = io::copy(&mut File::from(file).take(size), &mut self.out)
+                        .map_io_err_lazy(|| format!("Failed to archive /{}", path.display()))?;
+                    io::copy(&mut io::repeat(0).take(size - copied), &mut self.out)
+                        .map_io_err("Failed to write archive")?;
+                    self.pad(size)?;
+                }
+                FileType::Directory => {
+                    let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+                        .map_io_err_lazy(|| {
+                            format!("Failed to open directory /{}", path.display())
+                        })?;
+                    if has_overlay_xattr(&child, "redirect", &path)? {
+                        return Err(Error::InvalidArgument).attach_printable(format!(
+                            "Exporting renamed directories isn't supported: /{}",
+                            path.display()
+                        ));
+                    }
+                    let records = self.records(&child, &path)?;
+                    self.entry(&path, b'5', &stat, 0, &[], &records)?;
+                    if has_overlay_xattr(&child, "opaque", &path)? {
+                        self.marker(&path.join(OsStr::from_bytes(OPAQUE_MARKER)), &stat)?;
+                    }
+                    self.tree(&child, &mut path)?;
+                }
+                FileType::Symlink => {
+                    let target = readlinkat(dir, &name, Vec::new())
+                        .map_io_err_lazy(|| format!("Failed to read link /{}", path.display()))?;
+                    self.entry(&path, b'2', &stat, 0, target.as_bytes(), &[])?;
+                }
+                FileType::Fifo => self.entry(&path, b'6', &stat, 0, &[], &[])?,
+                FileType::CharacterDevice | FileType::BlockDevice if self.preserve_special => {
+                    let kind = if file_type == FileType::CharacterDevice {
+                        b'3'
+                    } else {
+                        b'4'
+                    };
+                    self.entry(&path, kind, &stat, 0, &[], &[])?;
+                }
+                FileType::Socket => self.omit(&path, OmissionKind::Socket),
+                _ => self.omit(&path, OmissionKind::Device),
+            }
+        }
+        Ok(())
+    }
+
+    /// The extended attributes to archive, as pax records.
+    fn records(&self, fd: &OwnedFd, path: &Path) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        if !self.preserve_special {
+            return Ok(Vec::new());
+        }
+        let name = "security.capability";
+        let mut value = vec![0; 256];
+        match fgetxattr(fd, name, &mut value) {
+            Ok(len) => {
+                value.truncate(len);
+                Ok(vec![(format!("SCHILY.xattr.{name}"), value)])
+            }
+            Err(Errno::NODATA | Errno::OPNOTSUPP) => Ok(Vec::new()),
+            Err(e) => Err(e).map_io_err_lazy(|| {
+                format!("Failed to read extended attributes of /{}", path.display())
+            }),
+        }
+    }
+
+    fn omit(&mut self, path: &Path, kind: OmissionKind) {
+        self.omissions.push(Omission {
+            path: Path::new("/").join(path),
+            kind,
+        });
+    }
+
+    fn marker(&mut self, path: &Path, parent: &Stat) -> Result<(), Error> {
+        let mut stat = *parent;
+        stat.st_mode = 0o644;
+        self.entry(path, b'0', &stat, 0, &[], &[])
+    }
+
+    fn entry(
+        &mut self,
+        path: &Path,
+        kind: u8,
+        stat: &Stat,
+        size: u64,
+        link: &[u8],
+        records: &[(String, Vec<u8>)],
+    ) -> Result<(), Error> {
+        let mut name = path.as_os_str().as_bytes().to_vec();
+        if kind == b'5' {
+            name.push(b'/');
+        }
+
+        let mut pax = Vec::new();
+        let mut record = |key: &str, value: &[u8]| {
+            // The length prefix counts its own digits
+            let rest = key.len() + value.len() + 3;
+            let mut len = rest + rest.to_string().len();
+            while len != rest + len.to_string().len() {
+                len = rest + len.to_string().len();
+            }
+            pax.extend_from_slice(format!("{len} {key}=").as_bytes());
+            pax.extend_from_slice(value);
+            pax.push(b'\n');
+        };
+        if name.len() > 100 || !name.is_ascii() {
+            record("path", &name);
+        }
+        if link.len() > 100 || !link.is_ascii() {
+            record("linkpath", link);
+        }
+        if size > 0o777_7777_7777 {
+            record("size", size.to_string().as_bytes());
+        }
+        for (key, value) in records {
+            record(key, value);
+        }
+        if !pax.is_empty() {
+            let mut pax_name = b"PaxHeaders/".to_vec();
+            pax_name.extend_from_slice(&name[name.len().saturating_sub(80)..]);
+            let pax_len = pax.len() as u64;
+            self.header(&pax_name, b'x', stat, pax_len, &[])?;
+            self.out
+                .write_all(&pax)
+                .map_io_err("Failed to write archive")?;
+            self.pad(pax_len)?;
+        }
+        self.header(&name, kind, stat, size, link)
+    }
+
+    fn header(
+        &mut self,
+        name: &[u8],
+        kind: u8,
+        stat: &Stat,
+        size: u64,
+        link: &[u8],
+    ) -> Result<(), Error> {
+        let mut mode = Mode::from_raw_mode(stat.st_mode);
+        if !self.preserve_special {
+            mode.remove(Mode::SUID | Mode::SGID);
+        }
+
+        let mut header = [0; BLOCK];
+        let field = |header: &mut [u8; BLOCK], offset: usize, len: usize, value: &[u8]| {
+            let value = &value[..value.len().min(len)];
+            header[offset..offset + value.len()].copy_from_slice(value);
+        };
+        let octal = |header: &mut [u8; BLOCK], offset: usize, len: usize, value: u64| {
+            let value = format!("{value:0width$o}", width = len - 1);
+            // Values that don't fit are carried by a pax record instead
+            let value = value.as_bytes();
+            field(
+                header,
+                offset,
+                len - 1,
+                &value[value.len().saturating_sub(len - 1)..],
+            );
+        };
+        field(&mut header, 0, 100, name);
+        octal(&mut header, 100, 8, u64::from(mode.bits()));
+        octal(&mut header, 108, 8, u64::from(stat.st_uid));
+        octal(&mut header, 116, 8, u64::from(stat.st_gid));
+        octal(&mut header, 124, 12, size);
+        octal(&mut header, 136, 12, stat.st_mtime);
+        header[156] = kind;
+        field(&mut header, 157, 100, link);
+        field(&mut header, 257, 8, b"ustar\x0000");
+        octal(&mut header, 329, 8, u64::from(major(stat.st_rdev)));
+        octal(&mut header, 337, 8, u64::from(minor(stat.st_rdev)));
+
+        header[148..156].fill(b' ');
+        let checksum = header.iter().map(|&b| u64::from(b)).sum();
+        octal(&mut header, 148, 7, checksum);
+        header[154] = 0;
+        self.out
+            .write_all(&header)
+            .map_io_err("Failed to write archive")
+    }
+
+    fn pad(&mut self, len: u64) -> Result<(), Error> {
+        let padding = len.next_multiple_of(BLOCK as u64) - len;
+        io::copy(&mut io::repeat(0).take(padding), &mut self.out)
+            .map(drop)
+            .map_io_err("Failed to write archive")
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        self.out
+            .write_all(&[0; BLOCK * 2])
+            .and_then(|()| self.out.flush())
+            .map_io_err("Failed to write archive")
+    }
+}
+
+/// Recreates a session from an archive written by [`export`], leaving it
+/// inactive until it is first used.
+///
+/// An existing session of the same name is replaced unless it is active. The
+/// archive is fully extracted before anything is replaced, so an invalid
+/// archive leaves the existing session untouched.
+pub fn import(session: impl AsRef<OsStr>, input: impl Read) -> Result<(), Error> {
+    let session = session.as_ref();
+    validate_permissions(getuid())?;
+    validate_name(session)?;
+
+    let mut dir = get_sessions_dir()?;
+    dir.push(session);
+    if is_active_session(&mut dir, false)? {
+        return Err(Error::InvalidArgument).attach_printable(format!(
+            "Session {} is active, stop it before replacing it.",
+            session.display()
+        ));
+    }
+
+    // Hidden from the session listing until it is complete
+    let mut staging =
+        dir.with_file_name(format!(".{}.import-{}", session.display(), process::id()));
+    for path in ["diff", "work", "merged"] {
+        let dir = TmpPath::new(&mut staging, path);
+        fs::create_dir_all(&dir)
+            .map_io_err_lazy(|| format!("Failed to create directory {}", dir.display()))?;
+    }
+    let mut reader = Reader {
+        input,
+        root: staging.join("diff"),
+        directories: Vec::new(),
+    };
+    let result = reader.extract().and_then(|()| {
+        meta::record_creation(&mut staging)?;
+        if dir.exists() {
+            delete_session(&dir)?;
+        }
+        fs::rename(&staging, &dir)
+            .map_io_err_lazy(|| format!("Failed to create session {}", session.display()))
+    });
+    if result.is_err() {
+        drop(delete_session(&staging));
+    }
+    result
+}
+
+#[derive(Default)]
+struct Header {
+    kind: u8,
+    path: Vec<u8>,
+    link: Vec<u8>,
+    size: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    device: (u32, u32),
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+struct Reader<R> {
+    input: R,
+    /// The upper directory being populated.
+    root: PathBuf,
+    /// Extracted directories whose metadata is only restored once all their
+    /// entries exist.
+    directories: Vec<(PathBuf, Header)>,
+}
+
+impl<R: Read> Reader<R> {
+    fn extract(&mut self) -> Result<(), Error> {
+        let mut overrides = Header::default();
+        let mut index = 0_usize;
+        loop {
+            let mut block = [0; BLOCK];
+            self.read(&mut block)?;
+            if block.iter().all(|&b| b == 0) {
+                break;
+            }
+            index += 1;
+            let mut header = parse_header(&block)
+                .attach_printable_lazy(|| format!("Header {index} of the archive is invalid."))?;
+
+            match header.kind {
+                b'x' => {
+                    let data = self.data(&header)?;
+                    parse_pax(&data, &mut overrides).attach_printable_lazy(|| {
+                        format!("Header {index} of the archive is invalid.")
+                    })?;
+                    continue;
+                }
+                b'L' => {
+                    overrides.path = text(&self.data(&header)?);
+                    continue;
+                }
+                b'K' => {
+                    overrides.link = text(&self.data(&header)?);
+                    continue;
+                }
+                b'g' => {
+                    self.data(&header)?;
+                    continue;
+                }
+                _ => {}
+            }
+            let overrides = mem::take(&mut overrides);
+            if !overrides.path.is_empty() {
+                header.path = overrides.path;
+            }
+            if !overrides.link.is_empty() {
+                header.link = overrides.link;
+            }
+            if overrides.size != 0 {
+                header.size = overrides.size;
+            }
+            header.xattrs = overrides.xattrs;
+            self.entry(header)?;
+        }
+
+        // Innermost first so that restoring a directory doesn't touch its parent
+        for (path, header) in mem::take(&mut self.directories).iter().rev() {
+            restore_metadata(path, header)?;
+        }
+        Ok(())
+    }
+
+    fn entry(&mut self, header: Header) -> Result<(), Error> {
+        let path = sanitize(&header.path)?;
+        let display = Path::new("/").join(&path);
+        let Some(name) = path.file_name() else {
+            // The root directory itself
+            return self.data(&header).map(drop);
+        };
+        let parent = self.prepare_parent(&path)?;
+
+        if let Some(deleted) = name.as_bytes().strip_prefix(WHITEOUT_PREFIX) {
+            self.data(&header)?;
+            if name.as_bytes() == OPAQUE_MARKER {
+                return lsetxattr(&parent, "trusted.overlay.opaque", b"y", XattrFlags::empty())
+                    .map_io_err_lazy(|| format!("Failed to mark {} as opaque", display.display()));
+            }
+            let deleted = OsStr::from_bytes(deleted);
+            // aufs' special entries like `.wh..wh.plnk` have no overlayfs equivalent
+            if deleted.is_empty()
+                || deleted == "."
+                || deleted == ".."
+                || deleted.as_bytes().starts_with(WHITEOUT_PREFIX)
+            {
+                return Err(Error::InvalidArgument).attach_printable(format!(
+                    "Unsupported whiteout in the archive: {}",
+                    display.display()
+                ));
+            }
+            let whiteout = parent.join(deleted);
+            return mknodat(
+                CWD,
+                &whiteout,
+                FileType::CharacterDevice,
+                Mode::empty(),
+                makedev(0, 0),
+            )
+            .map_io_err_lazy(|| {
+                format!(
+                    "Failed to create whiteout for {}",
+                    display.with_file_name(deleted).display()
+                )
+            });
+        }
+
+        let dest = self.root.join(&path);
+        let create_error = || format!("Failed to create {}", display.display());
+        match header.kind {
+            b'0' | b'\0' | b'7' => {
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(&dest)
+                    .map_io_err_lazy(create_error)?;
+                let copied = io::copy(&mut (&mut self.input).take(header.size), &mut file)
+                    .map_io_err_lazy(create_error)?;
+                if copied != header.size {
+                    return Err(truncated());
+                }
+                self.skip_padding(header.size)?;
+            }
+            b'1' => {
+                self.data(&header)?;
+                let target = sanitize(&header.link)?;
+                let name = CString::new(target.into_os_string().into_vec())
+                    .change_context(Error::InvalidArgument)
+                    .attach_printable_lazy(|| {
+                        format!("Invalid link target for {}", display.display())
+                    })?;
+                // Resolved beneath the session without following symlinks, which
+                // earlier entries could have planted to point at host files
+                let root = walk::open_root(&self.root).map_io_err_lazy(create_error)?;
+                let target = match walk::open_beneath(&root, &name, OFlags::PATH) {
+                    Err(Errno::LOOP | Errno::XDEV) => {
+                        return Err(Error::InvalidArgument).attach_printable(format!(
+                            "The archive links {} to a path outside the session: {}",
+                            display.display(),
+                            String::from_utf8_lossy(&header.link)
+                        ));
+                    }
+                    r => r.map_io_err_lazy(create_error)?,
+                };
+                linkat(&target, c"", CWD, &dest, AtFlags::EMPTY_PATH)
+                    .map_io_err_lazy(create_error)?;
+                // Hard links share their target's metadata
+                return Ok(());
+            }
+            b'2' => {
+                self.data(&header)?;
+                symlink(OsStr::from_bytes(&header.link), &dest).map_io_err_lazy(create_error)?;
+            }
+            b'3' | b'4' | b'6' => {
+                self.data(&header)?;
+                let file_type = match header.kind {
+                    b'3' => FileType::CharacterDevice,
+                    b'4' => FileType::BlockDevice,
+                    _ => FileType::Fifo,
+                };
+                let (major, minor) = header.device;
+                mknodat(
+                    CWD,
+                    &dest,
+                    file_type,
+                    Mode::RUSR | Mode::WUSR,
+                    makedev(major, minor),
+                )
+                .map_io_err_lazy(create_error)?;
+            }
+            b'5' => {
+                self.data(&header)?;
+                match fs::symlink_metadata(&dest) {
+                    Ok(metadata) if metadata.is_dir() => {}
+                    _ => fs::create_dir(&dest).map_io_err_lazy(create_error)?,
+                }
+                self.directories.push((dest, header));
+                return Ok(());
+            }
+            kind => {
+                return Err(Error::InvalidArgument).attach_printable(format!(
+                    "Unsupported entry type {:?} in the archive: {}",
+                    char::from(kind),
+                    display.display()
+                ));
+            }
+        }
+        restore_metadata(&dest, &header)
+    }
+
+    /// Creates the missing parent directories of an entry, refusing to go
+    /// through anything but directories so entries can't escape the session.
+    fn prepare_parent(&self, path: &Path) -> Result<PathBuf, Error> {
+        let mut parent = self.root.clone();
+        for component in path.parent().into_iter().flat_map(Path::components) {
+            parent.push(component);
+            match fs::symlink_metadata(&parent) {
+                Ok(metadata) if metadata.is_dir() => {}
+                Ok(_) => {
+                    return Err(Error::InvalidArgument).attach_printable(format!(
+                        "The archive places entries below a non-directory: /{}",
+                        parent.strip_prefix(&self.root).unwrap_or(&parent).display()
+                    ));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => fs::create_dir(&parent)
+                    .map_io_err_lazy(|| {
+                        format!("Failed to create directory {}", parent.display())
+                    })?,
+                Err(e) => {
+                    return Err(e)
+                        .map_io_err_lazy(|| format!("Failed to stat {}", parent.display()));
+                }
+            }
+        }
+        Ok(parent)
+    }
+
+    /// Reads the entry's data, which is only expected to be non-empty for
+    /// the metadata entries that are held in memory.
+    fn data(&mut self, header: &Header) -> Result<Vec<u8>, Error> {
+        if header.size > MAX_METADATA_SIZE {
+            return Err(Error::InvalidArgument).attach_printable(format!(
+                "Entry {} of the archive is unreasonably large.",
+                String::from_utf8_lossy(&header.path)
+            ));
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let mut data = vec![0; header.size as usize];
+        self.read(&mut data)?;
+        self.skip_padding(header.size)?;
+        Ok(data)
+    }
+
+    fn skip_padding(&mut self, len: u64) -> Result<(), Error> {
+        let padding = len.next_multiple_of(BLOCK as u64) - len;
+        let skipped = io::copy(&mut (&mut self.input).take(padding), &mut io::sink())
+            .map_io_err("Failed to read archive")?;
+        if skipped == padding {
+            Ok(())
+        } else {
+            Err(truncated())
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        match self.input.read_exact(buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(truncated()),
+            r => r.map_io_err("Failed to read archive"),
+        }
+    }
+}
+
+/// Whether a file looks binary from its first few kilobytes, which are read
+/// without moving the file's offset.
+pub fn is_binary(file: &OwnedFd, path: &Path) -> Result<bool, Error> {
+    let mut head = vec![0; SNIFF_SIZE];
+    let mut len = 0;
+    while len < head.len() {
+        match pread(file, &mut head[len..], len as u64)
+            .map_io_err_lazy(|| format!("Failed to read /{}", path.display()))?
+        {
+            0 => break,
+            read => len += read,
+        }
+    }
+    let head = &head[..len];
+    Ok(head.contains(&0)
+        // A character may be cut off at the end of what was read
+        || str::from_utf8(head).is_err_and(|e| e.error_len().is_some()))
+}
+
+fn truncated() -> Report<Error> {
+    Report::new(Error::InvalidArgument).attach_printable("The archive is truncated.")
+}
+
+/// Turns an archived path into one relative to the upper directory, refusing
+/// paths that would leave it.
+fn sanitize(path: &[u8]) -> Result<PathBuf, Error> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(OsStr::from_bytes(path)).components() {
+        match component {
+            Component::Normal(component) => relative.push(component),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(Error::InvalidArgument).attach_printable(format!(
+                    "The archive contains a path outside the session: {}",
+                    String::from_utf8_lossy(path)
+                ));
+            }
+        }
+    }
+    Ok(relative)
+}
+
+fn parse_header(block: &[u8; BLOCK]) -> Result<Header, Error> {
+    if &block[257..262] != b"ustar" {
+        return Err(Error::InvalidArgument).attach_printable("This is not a tar archive.");
+    }
+    let expected = number(&block[148..156])?;
+    let mut unsigned = 0_u64;
+    let mut signed = 0_i64;
+    for (i, &b) in block.iter().enumerate() {
+        let b = if (148..156).contains(&i) { b' ' } else { b };
+        unsigned += u64::from(b);
+        signed += i64::from(b.cast_signed());
+    }
+    if expected != unsigned && expected.cast_signed() != signed {
+        return Err(Error::InvalidArgument).attach_printable("The header checksum doesn't match.");
+    }
+
+    let mut path = text(&block[0..100]);
+    let prefix = text(&block[345..500]);
+    // GNU archives use the prefix field for other purposes
+    if &block[257..265] == b"ustar\x0000" && !prefix.is_empty() {
+        path = [prefix, b"/".to_vec(), path].concat();
+    }
+    let narrow = |field: &[u8]| {
+        number(field).and_then(|n| {
+            u32::try_from(n)
+                .change_context(Error::InvalidArgument)
+                .attach_printable("A header field is out of range.")
+        })
+    };
+    Ok(Header {
+        kind: block[156],
+        path,
+        link: text(&block[157..257]),
+        size: number(&block[124..136])?,
+        mode: narrow(&block[100..108])?,
+        uid: narrow(&block[108..116])?,
+        gid: narrow(&block[116..124])?,
+        mtime: number(&block[136..148])?.cast_signed(),
+        device: (narrow(&block[329..337])?, narrow(&block[337..345])?),
+        xattrs: Vec::new(),
+    })
+}
+
+/// The contents of a NUL-terminated header field.
+fn text(field: &[u8]) -> Vec<u8> {
+    field[..field.iter().position(|&b| b == 0).unwrap_or(field.len())].to_vec()
+}
+
+/// Parses an octal header field, or a base-256 one as written by GNU tar for
+/// large values.
+fn number(field: &[u8]) -> Result<u64, Error> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        return Ok(field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |n, &b| n << 8 | u64::from(b)));
+    }
+    let digits = field
+        .iter()
+        .copied()
+        .skip_while(|&b| b == b' ')
+        .take_while(|&b| b != 0 && b != b' ')
+        .collect::<Vec<_>>();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u64::from_str_radix(digits, 8).ok())
+        .ok_or(Error::InvalidArgument)
+        .attach_printable("A header field isn't a number.")
+}
+
+/// Parses pax records into the fields of the next entry they override.
+fn parse_pax(mut data: &[u8], overrides: &mut Header) -> Result<(), Error> {
+    let invalid =
+        || Report::new(Error::InvalidArgument).attach_printable("A pax record is malformed.");
+    while !data.is_empty() {
+        let space = data.iter().position(|&b| b == b' ').ok_or_else(invalid)?;
+        let len = str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space && len <= data.len() && data[len - 1] == b'\n')
+            .ok_or_else(invalid)?;
+        let record = &data[space + 1..len - 1];
+        data = &data[len..];
+
+        let equals = record.iter().position(|&b| b == b'=').ok_or_else(invalid)?;
+        let (key, value) = (&record[..equals], &record[equals + 1..]);
+        match key {
+            b"path" => overrides.path = value.to_vec(),
+            b"linkpath" => overrides.link = value.to_vec(),
+            b"size" => {
+                overrides.size = str::from_utf8(value)
+                    .ok()
+                    .and_then(|size| size.parse().ok())
+                    .ok_or_else(invalid)?;
+            }
+            _ => {
+                if let Some(name) = key.strip_prefix(b"SCHILY.xattr.") {
+                    overrides.xattrs.push((name.to_vec(), value.to_vec()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn restore_metadata(path: &Path, header: &Header) -> Result<(), Error> {
+    let display = || path.display();
+    lchown(path, Some(header.uid), Some(header.gid))
+        .map_io_err_lazy(|| format!("Failed to change the owner of {}", display()))?;
+    if header.kind != b'2' {
+        chmodat(
+            CWD,
+            path,
+            Mode::from_raw_mode(header.mode & 0o7777),
+            AtFlags::empty(),
+        )
+        .map_io_err_lazy(|| format!("Failed to change the permissions of {}", display()))?;
+    }
+    for (name, value) in &header.xattrs {
+        // overlayfs' own attributes are encoded as whiteout entries instead
+        if name.windows(9).any(|w| w == b".overlay.") {
+            return Err(Error::InvalidArgument).attach_printable(format!(
+                "The archive sets overlayfs attributes on {}",
+                display()
+            ));
+        }
+        lsetxattr(path, OsStr::from_bytes(name), value, XattrFlags::empty()).map_io_err_lazy(
+            || format!("Failed to set the extended attributes of {}", display()),
+        )?;
+    }
+    let time = Timespec {
+        tv_sec: header.mtime,
+        tv_nsec: 0,
+    };
+    let times = Timestamps {
+        last_access: time,
+        last_modification: time,
+    };
+    match utimensat(CWD, path, &times, AtFlags::SYMLINK_NOFOLLOW) {
+        // Some file systems can't set the times of symlinks
+        Err(Errno::OPNOTSUPP) if header.kind == b'2' => Ok(()),
+        r => r.map_io_err_lazy(|| format!("Failed to change the times of {}", display())),
+    }
+}
@@ -0,0 +1,105 @@
+//! The ways a session's overlay can be mounted.
+//!
+//! Kernel overlayfs is fast and always preferred, but it refuses some lower
+//! and upper file systems, such as another overlay or many network file
+//! systems, and older or locked down kernels may not offer it at all.
+//! `fuse-overlayfs` implements the same layering in user space, so sessions
+//! work anywhere FUSE does at the cost of slower file access.
+
+use std::{
+    env,
+    ffi::{CStr, OsStr},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{MountFlags, mount},
+    io::Errno,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, IoErr, setup::is_executable};
+
+const FUSE_OVERLAYFS: &str = "fuse-overlayfs";
+/// What session overlays are mounted from, followed by the session's name, so
+/// that processes inside a session can tell which one they're in.
+pub const SOURCE_PREFIX: &[u8] = b"forkfs:";
+
+/// How sessions are mounted.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// Kernel overlayfs, falling back to `fuse-overlayfs` if the kernel
+    /// rejects the session's layers and `fuse-overlayfs` is installed.
+    #[default]
+    Auto,
+    /// Kernel overlayfs only.
+    Kernel,
+    /// `fuse-overlayfs` only, which must be in `PATH`.
+    Fuse,
+}
+
+/// Mounts the overlay described by `options`, overlayfs mount options naming
+/// the lower, upper, and work directories, on `merged` with `source` as its
+/// source.
+pub fn mount_overlay(
+    backend: Backend,
+    options: &CStr,
+    merged: &Path,
+    source: &CStr,
+) -> Result<(), Error> {
+    let kernel = || mount(source, merged, c"overlay", MountFlags::empty(), options);
+    match backend {
+        Backend::Kernel => kernel().map_io_err_lazy(|| mount_error(merged)),
+        Backend::Fuse => fuse(options, merged, source),
+        Backend::Auto => match kernel() {
+            // The errors overlayfs reports for unsupported layers or kernels
+            Err(Errno::INVAL | Errno::NODEV | Errno::OPNOTSUPP) if fuse_overlayfs().is_some() => {
+                fuse(options, merged, source)
+            }
+            r => r.map_io_err_lazy(|| mount_error(merged)),
+        },
+    }
+}
+
+fn fuse(options: &CStr, merged: &Path, source: &CStr) -> Result<(), Error> {
+    let Some(program) = fuse_overlayfs() else {
+        return Err(Error::SetupRequired).attach_printable(format!(
+            "The fuse backend requires {FUSE_OVERLAYFS}, which wasn't found in PATH."
+        ));
+    };
+    // fuse-overlayfs forks into the background once the mount is up
+    let output = Command::new(&program)
+        .arg("-o")
+        .arg(OsStr::from_bytes(options.to_bytes()))
+        .arg("-o")
+        .arg(OsStr::from_bytes(&[b"fsname=", source.to_bytes()].concat()))
+        .arg(merged)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .map_io_err_lazy(|| format!("Failed to run {}", program.display()))?;
+    if !output.status.success() {
+        return Err(Error::Io).attach_printable(format!(
+            "{}: {} ({})",
+            mount_error(merged),
+            String::from_utf8_lossy(&output.stderr).trim(),
+            output.status
+        ));
+    }
+    Ok(())
+}
+
+fn fuse_overlayfs() -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(FUSE_OVERLAYFS))
+        .find(|path| is_executable(path))
+}
+
+fn mount_error(merged: &Path) -> String {
+    format!("Failed to mount directory {}", merged.display())
+}
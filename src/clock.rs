@@ -0,0 +1,65 @@
+//! The source of the current time for session bookkeeping such as when a
+//! session was last used.
+//!
+//! Swapping in a [`FakeClock`] lets tests simulate sessions aging without
+//! sleeping.
+
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
+
+/// A source of the current time.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug)]
+pub struct FakeClock {
+    now: Cell<SystemTime>,
+}
+
+impl FakeClock {
+    #[must_use]
+    pub const fn new(now: SystemTime) -> Self {
+        Self {
+            now: Cell::new(now),
+        }
+    }
+
+    /// Moves the clock forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+
+    /// Moves the clock to `now`.
+    pub fn set(&self, now: SystemTime) {
+        self.now.set(now);
+    }
+}
+
+impl Clock for FakeClock {
+    fn now(&self) -> SystemTime {
+        self.now.get()
+    }
+}
+
+thread_local! {
+    static CLOCK: RefCell<Option<Rc<dyn Clock>>> = const { RefCell::new(None) };
+}
+
+/// Makes subsequent operations on this thread read the time from `clock`, or
+/// from the system if `None`.
+pub fn set_clock(clock: Option<Rc<dyn Clock>>) {
+    CLOCK.set(clock);
+}
+
+pub fn now() -> SystemTime {
+    CLOCK.with_borrow(|clock| {
+        clock
+            .as_ref()
+            .map_or_else(SystemTime::now, |clock| clock.now())
+    })
+}
@@ -0,0 +1,213 @@
+//! Branching a new session off an existing one.
+
+use std::{
+    ffi::{CString, OsStr},
+    fs::{self, File, OpenOptions},
+    io,
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::{
+            ffi::OsStrExt,
+            fs::{OpenOptionsExt, symlink},
+        },
+    },
+    path::{Path, PathBuf},
+    process,
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{
+        AtFlags, CWD, FileType, Mode, OFlags, RenameFlags, Stat, XattrFlags, fgetxattr, flistxattr,
+        fstat, ioctl_ficlone, lsetxattr, mkdirat, mknodat, readlinkat, renameat_with, statat,
+    },
+    io::Errno,
+    process::getuid,
+};
+
+use crate::{
+    Error, IoErr,
+    apply::copy_metadata,
+    encryption, get_sessions_dir, lower, meta, overlay_options,
+    path_undo::TmpPath,
+    pin,
+    run::validate_permissions,
+    sessions::{delete_session, open_diff, resolve_session, validate_name},
+    subtree, system_dirs, walk,
+    walk::Links,
+};
+
+/// Creates the session `name` with a copy of another session's changes. The
+/// new session is inactive until it is first used.
+///
+/// Files are cloned with reflinks on file systems that support them so that
+/// both sessions share their data until either one changes it. The session
+/// may be active while it is cloned, but files being written to at the same
+/// time may be copied half written. Encrypted sessions can't be cloned.
+pub fn clone(session: impl AsRef<OsStr>, name: impl AsRef<OsStr>) -> Result<(), Error> {
+    let (session, name) = (session.as_ref(), name.as_ref());
+    validate_permissions(getuid())?;
+    validate_name(name)?;
+
+    let upper = open_diff(session)?;
+    let mut source = get_sessions_dir()?;
+    source.push(resolve_session(session)?);
+    if encryption::is_encrypted(&mut source) {
+        return Err(Error::InvalidArgument).attach_printable("Encrypted sessions can't be cloned.");
+    }
+    let dir = source.with_file_name(name);
+    if dir.exists() {
+        return Err(Error::Conflict)
+            .attach_printable(format!("Session {} already exists", name.display()));
+    }
+
+    // Hidden from the session listing until it is complete
+    let mut staging = dir.with_file_name(format!(".{}.clone-{}", name.display(), process::id()));
+    for path in ["diff", "work", "merged"] {
+        let dir = TmpPath::new(&mut staging, path);
+        fs::create_dir_all(&dir)
+            .map_io_err_lazy(|| format!("Failed to create directory {}", dir.display()))?;
+    }
+    let result = copy_upper(&upper, &staging.join("diff")).and_then(|()| {
+        lower::inherit(&mut source, &mut staging)?;
+        subtree::inherit(&mut source, &mut staging)?;
+        system_dirs::inherit(&mut source, &mut staging)?;
+        overlay_options::inherit(&mut source, &mut staging)?;
+        pin::inherit(&mut source, &mut staging)?;
+        meta::record_creation(&mut staging)?;
+        match renameat_with(CWD, &staging, CWD, &dir, RenameFlags::NOREPLACE) {
+            Err(Errno::EXIST) => Err(Error::Conflict)
+                .attach_printable(format!("Session {} already exists", name.display())),
+            r => r.map_io_err_lazy(|| format!("Failed to create session {}", name.display())),
+        }
+    });
+    if result.is_err() {
+        drop(delete_session(&staging));
+    }
+    result
+}
+
+/// Copies an upper directory into the empty directory `dest` as is,
+/// whiteouts, hard links, and overlayfs' extended attributes included.
+pub fn copy_upper(upper: &OwnedFd, dest: &Path) -> Result<(), Error> {
+    copy_tree(upper, &mut PathBuf::new(), dest, &mut Links::default())?;
+    let stat = fstat(upper).map_io_err("Failed to stat the session's changes")?;
+    copy_metadata(dest, FileType::Directory, &stat)?;
+    copy_xattrs(upper, dest, Path::new(""))
+}
+
+fn copy_tree(
+    dir: &impl AsFd,
+    path: &mut PathBuf,
+    dest: &Path,
+    links: &mut Links,
+) -> Result<(), Error> {
+    for (name, file_type) in walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory /{}", path.display()))?
+    {
+        let file_name = OsStr::from_bytes(name.as_bytes());
+        let mut path = TmpPath::new(path, file_name);
+        let dest = dest.join(file_name);
+        let stat = statat(dir, &name, AtFlags::SYMLINK_NOFOLLOW)
+            .map_io_err_lazy(|| format!("Failed to stat /{}", path.display()))?;
+        copy_entry(dir, &name, file_type, &stat, &mut path, &dest, links)?;
+    }
+    Ok(())
+}
+
+fn copy_entry(
+    dir: &impl AsFd,
+    name: &CString,
+    file_type: FileType,
+    stat: &Stat,
+    path: &mut PathBuf,
+    dest: &Path,
+    links: &mut Links,
+) -> Result<(), Error> {
+    match file_type {
+        FileType::RegularFile => {
+            if let Some(first) = links.first_copy(stat, dest) {
+                return fs::hard_link(first, dest)
+                    .map_io_err_lazy(|| format!("Failed to create link {}", dest.display()));
+            }
+            let source = File::from(
+                walk::open_beneath(dir, name, OFlags::RDONLY)
+                    .map_io_err_lazy(|| format!("Failed to open /{}", path.display()))?,
+            );
+            let mut dest_file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(dest)
+                .map_io_err_lazy(|| format!("Failed to create {}", dest.display()))?;
+            match ioctl_ficlone(&dest_file, &source) {
+                // Not every file system supports reflinks, and none do across
+                // file systems
+                Err(Errno::OPNOTSUPP | Errno::XDEV | Errno::INVAL) => {
+                    io::copy(&mut &source, &mut dest_file).map(drop)
+                }
+                r => r.map_err(io::Error::from),
+            }
+            .map_io_err_lazy(|| format!("Failed to copy /{}", path.display()))?;
+            copy_metadata(dest, file_type, stat)?;
+            copy_xattrs(&source, dest, path)
+        }
+        FileType::Directory => {
+            mkdirat(CWD, dest, Mode::RWXU)
+                .map_io_err_lazy(|| format!("Failed to create directory {}", dest.display()))?;
+            let child = walk::open_beneath(dir, name, OFlags::RDONLY | OFlags::DIRECTORY)
+                .map_io_err_lazy(|| format!("Failed to open directory /{}", path.display()))?;
+            copy_tree(&child, path, dest, links)?;
+            copy_metadata(dest, file_type, stat)?;
+            copy_xattrs(&child, dest, path)
+        }
+        FileType::Symlink => {
+            let target = readlinkat(dir, name, Vec::new())
+                .map_io_err_lazy(|| format!("Failed to read link /{}", path.display()))?;
+            symlink(OsStr::from_bytes(target.as_bytes()), dest)
+                .map_io_err_lazy(|| format!("Failed to create link {}", dest.display()))?;
+            copy_metadata(dest, file_type, stat)
+        }
+        // Whiteouts are character devices and are recreated like any other
+        _ => {
+            mknodat(
+                CWD,
+                dest,
+                file_type,
+                Mode::from_raw_mode(stat.st_mode),
+                stat.st_rdev,
+            )
+            .map_io_err_lazy(|| format!("Failed to create {}", dest.display()))?;
+            copy_metadata(dest, file_type, stat)
+        }
+    }
+}
+
+/// Copies every extended attribute, which includes the markers overlayfs
+/// uses for opaque and renamed directories.
+fn copy_xattrs(source: &impl AsFd, dest: &Path, path: &Path) -> Result<(), Error> {
+    let error = || {
+        format!(
+            "Failed to copy the extended attributes of /{}",
+            path.display()
+        )
+    };
+    let mut names = Vec::new();
+    match flistxattr(source, &mut names) {
+        Err(Errno::OPNOTSUPP) | Ok(0) => return Ok(()),
+        r => names.resize(r.map_io_err_lazy(error)?, 0),
+    }
+    let len = flistxattr(source, &mut names).map_io_err_lazy(error)?;
+    let names = names[..len]
+        .iter()
+        .map(|&c| u8::from_ne_bytes(c.to_ne_bytes()))
+        .collect::<Vec<_>>();
+
+    for name in names.split(|&c| c == 0).filter(|name| !name.is_empty()) {
+        let name = OsStr::from_bytes(name);
+        let mut value = vec![0; fgetxattr(source, name, &mut []).map_io_err_lazy(error)?];
+        let len = fgetxattr(source, name, &mut value).map_io_err_lazy(error)?;
+        lsetxattr(dest, name, &value[..len], XattrFlags::empty()).map_io_err_lazy(error)?;
+    }
+    Ok(())
+}
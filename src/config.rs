@@ -0,0 +1,644 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    iter,
+    num::NonZeroUsize,
+    ops::Range,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use error_stack::{Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use toml_edit::{ImDocument, Item, Value};
+
+use crate::{
+    Backend, EncryptionKey, Error, IoErr, IoPriority, Network, OverlayOption, Preset, SystemDir,
+    Viewer,
+};
+
+static STATIC_PATHS: AtomicBool = AtomicBool::new(false);
+static SESSIONS_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keeps the config file, sessions, and forkfs' own bookkeeping in fixed
+/// system locations under `/etc/forkfs` and `/var/lib/forkfs` instead of the
+/// user's XDG directories.
+///
+/// This makes the paths independent of `$HOME` and of whichever user forkfs
+/// runs as, which suits a single binary shared by everyone on a server. It
+/// must be called before any other function is.
+pub fn use_static_paths() {
+    STATIC_PATHS.store(true, Ordering::Relaxed);
+}
+
+/// Whether [`use_static_paths`] is in effect.
+#[must_use]
+pub fn static_paths() -> bool {
+    STATIC_PATHS.load(Ordering::Relaxed)
+}
+
+/// Stores sessions in `dir` regardless of the config file's `sessions-dir`.
+///
+/// Like [`use_static_paths`], it must be called before any other function is.
+/// Sessions may already have been stored in the first directory, so calling it
+/// again with a different one fails with [`Error::InvalidArgument`].
+pub fn use_sessions_dir(dir: PathBuf) -> Result<(), Error> {
+    let Err(dir) = SESSIONS_DIR.set(dir) else {
+        return Ok(());
+    };
+    let stored = SESSIONS_DIR.wait();
+    if *stored == dir {
+        return Ok(());
+    }
+    Err(Error::InvalidArgument).attach_printable(format!(
+        "Sessions are already stored in {} rather than {}.",
+        stored.display(),
+        dir.display()
+    ))
+}
+
+/// The directory passed to [`use_sessions_dir`], if any.
+#[must_use]
+pub fn sessions_dir_override() -> Option<&'static Path> {
+    SESSIONS_DIR.get().map(PathBuf::as_path)
+}
+
+const SYSTEM_PATH: &str = "/etc/forkfs/config.toml";
+
+/// User preferences stored in `$XDG_CONFIG_HOME/forkfs/config.toml`, layered
+/// on top of the system-wide ones in `/etc/forkfs/config.toml`.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Where sessions are stored instead of the data directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sessions_dir: Option<PathBuf>,
+    /// The session `forkfs run` uses when none is given instead of `default`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// `run` options applied to every run underneath the selected profiles,
+    /// written as a `[defaults]` table.
+    #[serde(default, skip_serializing_if = "Profile::is_empty")]
+    pub defaults: Profile,
+    /// Named bundles of `run` options, written as `[profile.<name>]` tables.
+    #[serde(
+        default,
+        rename = "profile",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub profiles: BTreeMap<String, Profile>,
+    /// How long `forkfs gc` keeps untagged snapshots around.
+    #[serde(default, skip_serializing_if = "Retention::is_unlimited")]
+    pub retention: Retention,
+    /// How forkfs' own heavy IO, such as deleting sessions, is scheduled.
+    #[serde(default, skip_serializing_if = "Maintenance::is_default")]
+    pub maintenance: Maintenance,
+    /// Replacements made in the files `sessions export` archives, written as
+    /// `[[redact]]` tables.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub redact: Vec<Redaction>,
+}
+
+/// A replacement made in the contents of archived files so that archives can
+/// be shared without the personal details in them.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Redaction {
+    /// Glob patterns for the files to redact, as seen from inside the session,
+    /// such as `/home/*/.config/**`. Every file is redacted if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<String>,
+    /// The regular expression whose matches are replaced.
+    pub pattern: String,
+    /// What matches are replaced with, where `$1` or `${name}` stand for the
+    /// match's capture groups.
+    #[serde(default = "default_replacement")]
+    pub replacement: String,
+}
+
+fn default_replacement() -> String {
+    "[REDACTED]".to_string()
+}
+
+/// Limits on the untagged snapshots each session keeps, enforced by
+/// `forkfs gc`. Tagged snapshots are kept until they are deleted along with
+/// their session.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Retention {
+    /// The number of most recent untagged snapshots to keep.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshots: Option<usize>,
+    /// The number of days to keep untagged snapshots for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot_days: Option<u64>,
+}
+
+/// A bundle of `run` options selected with `forkfs run --profile <name>`.
+///
+/// Unset options fall back to the command line or the built-in defaults.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    /// Build system caches to pass through to the real file system.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub presets: Vec<Preset>,
+    /// Other directories to pass through to the real file system.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub passthroughs: Vec<PathBuf>,
+    /// The number of bytes the session may grow by before the sandboxed
+    /// processes are killed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_write: Option<u64>,
+    /// The number of bytes that must be available to start a run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_free_space: Option<u64>,
+    /// The key to encrypt the session's changes with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<PathBuf>,
+    /// The block device or directory to layer new sessions on instead of the
+    /// live root.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lower_device: Option<PathBuf>,
+    /// The only directory new sessions overlay, leaving the rest of the file
+    /// system read-only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<PathBuf>,
+    /// System directories new sessions don't bind, capturing writes to them
+    /// in the overlay instead.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_bind: Vec<SystemDir>,
+    /// overlayfs features new sessions are mounted with.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub overlay_options: Vec<OverlayOption>,
+    /// Whether new sessions keep their changes in memory instead of on disk.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volatile_storage: Option<bool>,
+    /// Whether the sandboxed processes get their own process IDs, hiding the
+    /// host's processes from them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_pids: Option<bool>,
+    /// Which network the sandboxed processes can reach.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+    /// The file mode creation mask the sandboxed processes start with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub umask: Option<u32>,
+    /// The user, by name or id, that files added by a run are handed over to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// How to mount sessions that aren't active yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<Backend>,
+}
+
+/// A problem found in the config file by [`Config::check`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Diagnostic {
+    /// The 1-based line the problem is on.
+    pub line: usize,
+    /// The 1-based column the problem starts at.
+    pub column: usize,
+    pub message: String,
+}
+
+impl Profile {
+    /// Whether the profile leaves every option unset.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Layers `other` on top of this profile: its options take precedence
+    /// while lists are combined.
+    pub fn merge(&mut self, other: Self) {
+        let Self {
+            presets,
+            passthroughs,
+            max_write,
+            min_free_space,
+            key_file,
+            lower_device,
+            root,
+            no_bind,
+            overlay_options,
+            volatile_storage,
+            private_pids,
+            network,
+            umask,
+            owner,
+            backend,
+        } = other;
+        for preset in presets {
+            if !self.presets.contains(&preset) {
+                self.presets.push(preset);
+            }
+        }
+        for passthrough in passthroughs {
+            if !self.passthroughs.contains(&passthrough) {
+                self.passthroughs.push(passthrough);
+            }
+        }
+        for dir in no_bind {
+            if !self.no_bind.contains(&dir) {
+                self.no_bind.push(dir);
+            }
+        }
+        for option in overlay_options {
+            if !self.overlay_options.contains(&option) {
+                self.overlay_options.push(option);
+            }
+        }
+        self.max_write = max_write.or(self.max_write);
+        self.min_free_space = min_free_space.or(self.min_free_space);
+        self.key_file = key_file.or_else(|| self.key_file.take());
+        self.lower_device = lower_device.or_else(|| self.lower_device.take());
+        self.root = root.or_else(|| self.root.take());
+        self.volatile_storage = volatile_storage.or(self.volatile_storage);
+        self.private_pids = private_pids.or(self.private_pids);
+        self.network = network.or(self.network);
+        self.umask = umask.or(self.umask);
+        self.owner = owner.or_else(|| self.owner.take());
+        self.backend = backend.or(self.backend);
+    }
+}
+
+/// Limits on the disk bandwidth taken up by deleting, shredding, exporting,
+/// and garbage-collecting sessions.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Maintenance {
+    /// The number of sessions to work on at the same time, defaulting to the
+    /// number of CPUs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<NonZeroUsize>,
+    /// The IO priority to do the work at.
+    #[serde(default)]
+    pub io_priority: IoPriority,
+}
+
+impl Maintenance {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl Retention {
+    /// Whether every snapshot is kept.
+    #[must_use]
+    pub const fn is_unlimited(&self) -> bool {
+        self.snapshots.is_none() && self.snapshot_days.is_none()
+    }
+}
+
+impl Config {
+    /// The location of the config file, if a config directory is known.
+    #[must_use]
+    pub fn path() -> Option<PathBuf> {
+        if static_paths() {
+            return Some(PathBuf::from(SYSTEM_PATH));
+        }
+        let mut path = dirs::config_dir()?;
+        path.push("forkfs");
+        path.push("config.toml");
+        Some(path)
+    }
+
+    /// The directory sessions are stored in.
+    #[must_use]
+    pub fn sessions_dir(&self) -> PathBuf {
+        if let Some(dir) = sessions_dir_override() {
+            return dir.to_path_buf();
+        }
+        self.sessions_dir.clone().unwrap_or_else(|| {
+            if static_paths() {
+                return PathBuf::from("/var/lib/forkfs/sessions");
+            }
+            default_sessions_dir()
+        })
+    }
+
+    /// Looks up a profile by name.
+    pub fn profile(&self, name: &str) -> Result<&Profile, Error> {
+        self.profiles
+            .get(name)
+            .ok_or(Error::InvalidArgument)
+            .attach_printable_lazy(|| format!("No profile named {name:?} in the config file"))
+    }
+
+    /// The locations of the config files that are read, from lowest to highest
+    /// precedence: the system-wide one followed by [`Self::path`].
+    #[must_use]
+    pub fn paths() -> Vec<PathBuf> {
+        let mut paths = vec![PathBuf::from(SYSTEM_PATH)];
+        if let Some(path) = Self::path()
+            && path != paths[0]
+        {
+            paths.push(path);
+        }
+        paths
+    }
+
+    /// Reads the config files, returning the defaults if none exist.
+    ///
+    /// The user's config file takes precedence over the system-wide one:
+    /// its settings replace the system's, profiles of the same name included,
+    /// and its `[defaults]` are [merged](Profile::merge) on top.
+    pub fn load() -> Result<Self, Error> {
+        let mut config = Self::default();
+        for path in Self::paths() {
+            let contents = match fs::read_to_string(&path) {
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                r => r.map_io_err_lazy(|| format!("Failed to read config {}", path.display()))?,
+            };
+            config.merge(
+                toml::from_str(&contents)
+                    .change_context(Error::InvalidArgument)
+                    .attach_printable_lazy(|| format!("Invalid config {}", path.display()))?,
+            );
+        }
+        Ok(config)
+    }
+
+    fn merge(&mut self, other: Self) {
+        let Self {
+            sessions_dir,
+            session,
+            defaults,
+            profiles,
+            retention:
+                Retention {
+                    snapshots,
+                    snapshot_days,
+                },
+            maintenance: Maintenance { jobs, io_priority },
+            redact,
+        } = other;
+        self.sessions_dir = sessions_dir.or_else(|| self.sessions_dir.take());
+        self.session = session.or_else(|| self.session.take());
+        self.defaults.merge(defaults);
+        self.profiles.extend(profiles);
+        self.retention.snapshots = snapshots.or(self.retention.snapshots);
+        self.retention.snapshot_days = snapshot_days.or(self.retention.snapshot_days);
+        self.maintenance.jobs = jobs.or(self.maintenance.jobs);
+        if io_priority != IoPriority::default() {
+            self.maintenance.io_priority = io_priority;
+        }
+        self.redact.extend(redact);
+    }
+
+    /// Validates the config file, returning its problems in the order they
+    /// appear. A missing config file has none.
+    ///
+    /// Unlike [`Self::load`], this goes beyond syntax errors by checking that
+    /// paths exist and that options don't contradict each other.
+    pub fn check() -> Result<Vec<Diagnostic>, Error> {
+        let Some(path) = Self::path() else {
+            return Ok(Vec::new());
+        };
+        match fs::read_to_string(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            r => Ok(check(&r.map_io_err_lazy(|| {
+                format!("Failed to read config {}", path.display())
+            })?)),
+        }
+    }
+
+    /// Writes the config file, creating its directory if necessary.
+    pub fn save(&self) -> Result<(), Error> {
+        let Some(path) = Self::path() else {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Could not determine the config directory.");
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_io_err_lazy(|| format!("Failed to create directory {}", dir.display()))?;
+        }
+        let config = toml::to_string(self)
+            .change_context(Error::InvalidArgument)
+            .attach_printable("Failed to serialize config")?;
+        fs::write(&path, config)
+            .map_io_err_lazy(|| format!("Failed to write config {}", path.display()))
+    }
+}
+
+/// Sessions are data and live in `$XDG_DATA_HOME/forkfs` where cache cleaners
+/// won't wipe them, unless an older forkfs already started storing them in
+/// `$XDG_CACHE_HOME/forkfs`.
+fn default_sessions_dir() -> PathBuf {
+    if let Some(mut legacy) = dirs::cache_dir() {
+        legacy.push("forkfs");
+        if legacy.is_dir() {
+            return legacy;
+        }
+    }
+    let mut sessions_dir = dirs::data_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
+    sessions_dir.push("forkfs");
+    sessions_dir
+}
+
+fn check(config: &str) -> Vec<Diagnostic> {
+    let at = |span: Option<Range<usize>>, message: String| {
+        let before = &config[..span.map_or(0, |span| span.start)];
+        Diagnostic {
+            line: before.matches('\n').count() + 1,
+            column: before
+                .rsplit('\n')
+                .next()
+                .unwrap_or_default()
+                .chars()
+                .count()
+                + 1,
+            message,
+        }
+    };
+
+    let parsed = match toml::from_str::<Config>(config) {
+        Ok(parsed) => parsed,
+        Err(e) => return vec![at(e.span(), e.message().to_owned())],
+    };
+    let Ok(document) = ImDocument::parse(config) else {
+        return Vec::new();
+    };
+
+    let mut diagnostics = Vec::new();
+    let sessions_dir = parsed.sessions_dir();
+    if !sessions_dir.is_absolute() {
+        diagnostics.push(at(
+            document.get("sessions-dir").and_then(Item::span),
+            format!(
+                "The sessions directory {} must be an absolute path.",
+                sessions_dir.display()
+            ),
+        ));
+    }
+
+    let profiles = parsed
+        .profiles
+        .iter()
+        .map(|(name, profile)| (Some(name), profile));
+    for (name, profile) in iter::once((None, &parsed.defaults)).chain(profiles) {
+        let (table, label) = name.map_or_else(
+            || (document.get("defaults"), "the [defaults] table".to_string()),
+            |name| {
+                (
+                    document
+                        .get("profile")
+                        .and_then(|profiles| profiles.get(name)),
+                    format!("profile {name:?}"),
+                )
+            },
+        );
+        let mut subject = label.clone();
+        subject[..1].make_ascii_uppercase();
+        let span = |key: &str| table.and_then(|table| table.get(key)).and_then(Item::span);
+
+        for (i, dir) in profile.passthroughs.iter().enumerate() {
+            let problem = if !dir.is_absolute() {
+                "must be an absolute path"
+            } else if dir == Path::new("/") {
+                "would pass the entire file system through"
+            } else if !dir.is_dir() {
+                "is not an existing directory"
+            } else if sessions_dir.starts_with(dir) || dir.starts_with(&sessions_dir) {
+                "overlaps with the sessions directory"
+            } else if profile.passthroughs[..i].contains(dir) {
+                "is listed more than once"
+            } else if profile
+                .presets
+                .iter()
+                .any(|preset| preset.passthroughs().contains(dir))
+            {
+                "is already passed through by one of the profile's presets"
+            } else {
+                continue;
+            };
+            let span = table
+                .and_then(|table| table.get("passthroughs"))
+                .and_then(Item::as_array)
+                .and_then(|passthroughs| passthroughs.get(i))
+                .and_then(Value::span);
+            diagnostics.push(at(
+                span,
+                format!("Passthrough {} in {label} {problem}.", dir.display()),
+            ));
+        }
+
+        if let Some(key_file) = &profile.key_file {
+            let problem = match fs::metadata(key_file) {
+                Err(e) => Some(format!("can't be read: {e}")),
+                Ok(metadata) if metadata.len() != EncryptionKey::LEN as u64 => Some(format!(
+                    "must contain exactly {} bytes, found {}",
+                    EncryptionKey::LEN,
+                    metadata.len()
+                )),
+                Ok(_) => None,
+            };
+            if let Some(problem) = problem {
+                diagnostics.push(at(
+                    span("key-file"),
+                    format!("Key file {} in {label} {problem}.", key_file.display()),
+                ));
+            }
+        }
+
+        if let Some(device) = &profile.lower_device {
+            let problem = match fs::metadata(device) {
+                Err(e) => Some(format!("can't be read: {e}")),
+                Ok(metadata) if !metadata.file_type().is_block_device() && !metadata.is_dir() => {
+                    Some("must be a block device or a directory".to_string())
+                }
+                Ok(_) => None,
+            };
+            if let Some(problem) = problem {
+                diagnostics.push(at(
+                    span("lower-device"),
+                    format!("Lower device {} in {label} {problem}.", device.display()),
+                ));
+            }
+        }
+
+        if let Some(root) = &profile.root {
+            let problem = match fs::metadata(root) {
+                Err(e) => Some(format!("can't be read: {e}")),
+                Ok(metadata) if !metadata.is_dir() => Some("must be a directory".to_string()),
+                Ok(_) => None,
+            };
+            if let Some(problem) = problem {
+                diagnostics.push(at(
+                    span("root"),
+                    format!("Root {} in {label} {problem}.", root.display()),
+                ));
+            }
+        }
+
+        if profile.volatile_storage == Some(true) && profile.key_file.is_some() {
+            diagnostics.push(at(
+                span("volatile-storage"),
+                format!("{subject} keeps sessions in memory, which can't be encrypted."),
+            ));
+        }
+
+        if profile.max_write == Some(0) {
+            diagnostics.push(at(
+                span("max-write"),
+                format!("{subject} kills every command with a max-write of 0."),
+            ));
+        }
+
+        if let Some(umask) = profile.umask.filter(|&umask| umask > 0o777) {
+            diagnostics.push(at(
+                span("umask"),
+                format!("Umask {umask:o} in {label} has bits beyond 0o777."),
+            ));
+        }
+
+        if let Some(owner) = &profile.owner
+            && Viewer::lookup(owner).is_err()
+        {
+            diagnostics.push(at(
+                span("owner"),
+                format!("Owner {owner:?} in {label} isn't a known user or id."),
+            ));
+        }
+    }
+
+    for (i, redaction) in parsed.redact.iter().enumerate() {
+        let table = document
+            .get("redact")
+            .and_then(Item::as_array_of_tables)
+            .and_then(|tables| tables.get(i));
+        let span = |key: &str| table.and_then(|table| table.get(key)).and_then(Item::span);
+        if let Err(e) = regex::bytes::Regex::new(&redaction.pattern) {
+            // Syntax errors draw the pattern over several lines before the reason
+            let e = e.to_string();
+            diagnostics.push(at(
+                span("pattern"),
+                format!(
+                    "Redaction pattern {:?} is invalid: {}.",
+                    redaction.pattern,
+                    e.lines()
+                        .last()
+                        .unwrap_or_default()
+                        .trim_start_matches("error: ")
+                ),
+            ));
+        }
+        for glob in &redaction.paths {
+            if let Err(e) = glob::Pattern::new(glob) {
+                diagnostics.push(at(
+                    span("paths"),
+                    format!("Redaction path {glob:?} is invalid: {}.", e.msg),
+                ));
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    diagnostics
+}
@@ -0,0 +1,241 @@
+//! The versions of files changed both inside and outside a session.
+//!
+//! A conflicted file has three versions: the lower one the session started
+//! from, the session's own, and the live one on the real file system. They are
+//! read in full so that they can be shown side by side and merged before the
+//! session is applied with [`apply_resolved`](crate::apply_resolved), or
+//! summed up in a [report](report) for tools to triage.
+
+use std::{
+    ffi::{CString, OsStr, OsString},
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    os::{
+        fd::OwnedFd,
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{FileType, OFlags, fstat, readlinkat},
+    io::Errno,
+    process::getuid,
+};
+
+use crate::{
+    Error, IoErr,
+    diff::{changes, is_whiteout},
+    get_sessions_dir, lower,
+    output::v1,
+    run::validate_permissions,
+    sessions::{is_active_session, open_diff, resolve_session},
+    sha256, subtree, walk,
+};
+
+/// Files larger than this are described by their size instead of being read.
+const MAX_SIZE: u64 = 1 << 24;
+
+/// One of the versions of a conflicted file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Version {
+    /// The version wasn't kept, which is the case for the lower version of
+    /// sessions layered on the live system.
+    Unknown,
+    Missing,
+    File(Vec<u8>),
+    /// A regular file too large to be read in full, with its size in bytes.
+    Large(u64),
+    Symlink(PathBuf),
+    Directory,
+    /// A device, FIFO, or socket.
+    Special,
+}
+
+/// A file changed both inside and outside a session.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Conflict {
+    /// The changed path as seen from inside the session.
+    pub path: PathBuf,
+    /// The version the session started from.
+    pub lower: Version,
+    /// The session's version.
+    pub session: Version,
+    /// The version on the real file system.
+    pub live: Version,
+}
+
+/// Returns the files a session changed that were also changed outside of it,
+/// sorted by path, along with their versions.
+pub fn conflicts(session: impl AsRef<OsStr>) -> Result<Vec<Conflict>, Error> {
+    let session = session.as_ref();
+    validate_permissions(getuid())?;
+    let upper = open_diff(session)?;
+    let changes = changes(session)?;
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    let subtree = subtree::get(&mut dir)?;
+
+    // Lower devices are only mounted while their session is active
+    let mount = lower::recorded(&mut dir)?.is_some() && !is_active_session(&mut dir, true)?;
+    let root = if mount {
+        lower::mount_device(&mut dir)?
+    } else {
+        lower::root(&mut dir)?
+    };
+    let result: Result<Vec<_>, Error> = changes
+        .into_iter()
+        .filter(|change| change.diverged)
+        .map(|change| {
+            // The live system is the lower layer, so its earlier version is gone
+            let lower = if root == Path::new("/") {
+                Version::Unknown
+            } else {
+                path_version(&root.join(change.path.strip_prefix("/").unwrap()))?
+            };
+            Ok(Conflict {
+                lower,
+                session: upper_version(&upper, &subtree, &change.path)?,
+                live: path_version(&change.path)?,
+                path: change.path,
+            })
+        })
+        .collect();
+    if mount {
+        lower::unmount_device(&mut dir)?;
+    }
+    result
+}
+
+/// Sums up a session's [`conflicts`] without their contents: why each file
+/// conflicts, hashes of both versions, and how it could be resolved.
+pub fn report(session: impl AsRef<OsStr>) -> Result<v1::ConflictReport, Error> {
+    let session = session.as_ref();
+    let conflicts = conflicts(session)?
+        .into_iter()
+        .map(|conflict| {
+            let Conflict {
+                path,
+                lower,
+                session,
+                live,
+            } = conflict;
+            let reason = match (&session, &live) {
+                _ if session == live => "same-change",
+                (Version::Missing, _) => "deleted-in-session",
+                (_, Version::Missing) => "deleted-outside",
+                _ if kind(&session) == kind(&live) => "modified-in-both",
+                _ => "type-changed",
+            };
+            // Large files can't be compared, so they never match
+            let same = |a: &Version, b: &Version| a == b && !matches!(a, Version::Large(_));
+            let suggested_resolution = if same(&session, &live) || same(&lower, &session) {
+                "live"
+            } else if same(&lower, &live) {
+                "session"
+            } else if matches!(
+                (&lower, &session, &live),
+                (Version::File(_), Version::File(_), Version::File(_))
+            ) {
+                "merge"
+            } else {
+                "manual"
+            };
+            v1::Conflict {
+                path: path.to_string_lossy().into_owned(),
+                reason: reason.to_string(),
+                session: summarize(&session),
+                live: summarize(&live),
+                suggested_resolution: suggested_resolution.to_string(),
+            }
+        })
+        .collect();
+    Ok(v1::ConflictReport {
+        version: v1::VERSION,
+        session: session.to_string_lossy().into_owned(),
+        conflicts,
+    })
+}
+
+const fn kind(version: &Version) -> &'static str {
+    match version {
+        Version::Unknown => "unknown",
+        Version::Missing => "missing",
+        Version::File(_) | Version::Large(_) => "file",
+        Version::Symlink(_) => "symlink",
+        Version::Directory => "directory",
+        Version::Special => "special",
+    }
+}
+
+fn summarize(version: &Version) -> v1::ConflictVersion {
+    let sha256 = match version {
+        Version::File(contents) => Some(sha256::digest(contents)),
+        Version::Symlink(target) => Some(sha256::digest(target.as_os_str().as_bytes())),
+        _ => None,
+    };
+    v1::ConflictVersion {
+        kind: kind(version).to_string(),
+        sha256: sha256.map(|digest| sha256::hex(&digest)),
+    }
+}
+
+fn path_version(path: &Path) -> Result<Version, Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Version::Missing),
+        r => r.map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?,
+    };
+    let file_type = metadata.file_type();
+    Ok(if file_type.is_dir() {
+        Version::Directory
+    } else if file_type.is_symlink() {
+        Version::Symlink(
+            fs::read_link(path)
+                .map_io_err_lazy(|| format!("Failed to read link {}", path.display()))?,
+        )
+    } else if !file_type.is_file() {
+        Version::Special
+    } else if metadata.len() > MAX_SIZE {
+        Version::Large(metadata.len())
+    } else {
+        Version::File(
+            fs::read(path).map_io_err_lazy(|| format!("Failed to read {}", path.display()))?,
+        )
+    })
+}
+
+/// Reads a path from the upper directory, where deleted files are whiteouts
+/// and the entries below a deleted directory are missing.
+fn upper_version(upper: &OwnedFd, subtree: &Path, path: &Path) -> Result<Version, Error> {
+    let name = CString::new(subtree::relative(subtree, path)?.as_os_str().as_bytes())
+        .change_context(Error::InvalidArgument)
+        .attach_printable_lazy(|| format!("Invalid path {}", path.display()))?;
+    let error = || format!("Failed to read the session's version of {}", path.display());
+    let fd = match walk::open_beneath(upper, &name, OFlags::PATH) {
+        Err(Errno::NOENT) => return Ok(Version::Missing),
+        r => r.map_io_err_lazy(error)?,
+    };
+    let stat = fstat(&fd).map_io_err_lazy(error)?;
+    let file_type = FileType::from_raw_mode(stat.st_mode);
+    Ok(match file_type {
+        _ if is_whiteout(file_type, &stat) => Version::Missing,
+        FileType::Directory => Version::Directory,
+        FileType::Symlink => Version::Symlink(PathBuf::from(OsString::from_vec(
+            readlinkat(&fd, c"", Vec::new())
+                .map_io_err_lazy(error)?
+                .into_bytes(),
+        ))),
+        FileType::RegularFile if stat.st_size.cast_unsigned() > MAX_SIZE => {
+            Version::Large(stat.st_size.cast_unsigned())
+        }
+        FileType::RegularFile => {
+            let mut contents = Vec::new();
+            File::from(walk::open_beneath(upper, &name, OFlags::RDONLY).map_io_err_lazy(error)?)
+                .read_to_end(&mut contents)
+                .map_io_err_lazy(error)?;
+            Version::File(contents)
+        }
+        _ => Version::Special,
+    })
+}
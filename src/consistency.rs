@@ -0,0 +1,223 @@
+//! Consistency checks for sessions whose directories were edited by hand.
+//!
+//! overlayfs trusts the contents of a session's upper and work directories.
+//! Entries it doesn't expect there are silently ignored at best, and make the
+//! mount misbehave at worst, so it is worth checking a session after poking
+//! around its `diff` directory.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, Metadata},
+    os::{
+        fd::OwnedFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{FileTypeExt, MetadataExt},
+        },
+    },
+    path::{Path, PathBuf},
+};
+
+use error_stack::Result;
+use rustix::{
+    fs::{AtFlags, FileType, OFlags, lgetxattr, llistxattr, statat},
+    io::Errno,
+};
+
+use crate::{
+    Error, IoErr, get_sessions_dir,
+    path_undo::TmpPath,
+    sessions::{is_active_session, open_diff, resolve_session},
+    subtree, walk,
+};
+
+/// overlayfs' markers that only make sense on directories.
+const DIRECTORY_MARKERS: &[&str] = &["impure", "opaque", "whiteouts", "xwhiteouts"];
+/// overlayfs' markers that only make sense on regular files.
+const FILE_MARKERS: &[&str] = &["metacopy", "whiteout", "xwhiteout"];
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Issue {
+    /// The entry is named like an aufs or OCI image whiteout, which overlayfs
+    /// treats as a regular file rather than a deletion.
+    ForeignWhiteout,
+    /// An overlayfs extended attribute is set on a kind of file it doesn't
+    /// apply to.
+    MisplacedXattr { name: String },
+    /// An overlayfs extended attribute has a value overlayfs doesn't
+    /// understand.
+    InvalidXattr { name: String },
+    /// The work directory holds the leftovers of an interrupted operation.
+    WorkLeftover,
+    /// The entry was created in the mount point while the session was
+    /// inactive and is hidden whenever it is active.
+    HiddenByMount,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Problem {
+    /// The affected path, as seen from inside the session for entries of the
+    /// upper directory and as is otherwise.
+    pub path: PathBuf,
+    pub issue: Issue,
+}
+
+/// Checks that a session's directories only contain what overlayfs expects,
+/// returning the problems sorted by path.
+///
+/// The work directory and mount point are only checked while the session is
+/// inactive since they are in use otherwise.
+pub fn check(session: impl AsRef<OsStr>) -> Result<Vec<Problem>, Error> {
+    let session = session.as_ref();
+    let upper = open_diff(session)?;
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+
+    let mut problems = Vec::new();
+    check_tree(
+        &upper,
+        &mut dir.join("diff"),
+        &mut subtree::get(&mut dir)?,
+        &mut problems,
+    )?;
+    if !is_active_session(&mut dir, true)? {
+        // overlayfs keeps a whiteout around in its scratch space for reuse
+        let expected = |dir: &str, name: &OsStr, metadata: &Metadata| match dir {
+            "work" => name == "work" || name == "index",
+            "work/work" => metadata.file_type().is_char_device() && metadata.rdev() == 0,
+            _ => false,
+        };
+        for (path, issue) in [
+            ("work", Issue::WorkLeftover),
+            ("work/work", Issue::WorkLeftover),
+            ("merged", Issue::HiddenByMount),
+        ] {
+            let dir = dir.join(path);
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries {
+                let entry = entry
+                    .map_io_err_lazy(|| format!("Failed to read directory {}", dir.display()))?;
+                let metadata = entry
+                    .metadata()
+                    .map_io_err_lazy(|| format!("Failed to stat {}", entry.path().display()))?;
+                if !expected(path, &entry.file_name(), &metadata) {
+                    problems.push(Problem {
+                        path: entry.path(),
+                        issue: issue.clone(),
+                    });
+                }
+            }
+        }
+    }
+    problems.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(problems)
+}
+
+fn check_tree(
+    dir: &OwnedFd,
+    real: &mut PathBuf,
+    path: &mut PathBuf,
+    problems: &mut Vec<Problem>,
+) -> Result<(), Error> {
+    for (name, file_type) in walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let name_ref = OsStr::from_bytes(name.as_bytes());
+        let mut path = TmpPath::new(path, name_ref);
+        let mut real = TmpPath::new(real, name_ref);
+        let mut report = |issue| {
+            problems.push(Problem {
+                path: path.to_path_buf(),
+                issue,
+            });
+        };
+
+        if name.as_bytes().starts_with(b".wh.") {
+            report(Issue::ForeignWhiteout);
+        }
+        for marker in overlay_xattrs(&real)? {
+            let (_, short) = marker.split_once(".overlay.").unwrap_or_default();
+            let misplaced = if DIRECTORY_MARKERS.contains(&short) {
+                file_type != FileType::Directory
+            } else if FILE_MARKERS.contains(&short) {
+                file_type != FileType::RegularFile
+            } else {
+                short == "redirect"
+                    && !matches!(file_type, FileType::Directory | FileType::RegularFile)
+            };
+            if misplaced {
+                report(Issue::MisplacedXattr { name: marker });
+                continue;
+            }
+
+            let invalid = match short {
+                "opaque" => !matches!(xattr_value(&real, &marker)?.as_slice(), b"y" | b"x"),
+                "redirect" => xattr_value(&real, &marker)?.is_empty(),
+                "whiteout" | "xwhiteout" => {
+                    statat(dir, &name, AtFlags::SYMLINK_NOFOLLOW)
+                        .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?
+                        .st_size
+                        != 0
+                }
+                _ => false,
+            };
+            if invalid {
+                report(Issue::InvalidXattr { name: marker });
+            }
+        }
+
+        if file_type == FileType::Directory {
+            let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+                .map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?;
+            check_tree(&child, &mut real, &mut path, problems)?;
+        }
+    }
+    Ok(())
+}
+
+/// The names of the overlayfs extended attributes set on a file, in the
+/// trusted namespace or the user namespace for unprivileged mounts.
+fn overlay_xattrs(path: &Path) -> Result<Vec<String>, Error> {
+    let error = || {
+        format!(
+            "Failed to list the extended attributes of {}",
+            path.display()
+        )
+    };
+    let mut list = vec![0; 256];
+    let len = loop {
+        match llistxattr(path, &mut list) {
+            Err(Errno::RANGE) => list.resize(list.len() * 2, 0),
+            Err(Errno::OPNOTSUPP) => return Ok(Vec::new()),
+            r => break r.map_io_err_lazy(error)?,
+        }
+    };
+    list.truncate(len);
+
+    let list = list.iter().map(|c| c.to_ne_bytes()[0]).collect::<Vec<u8>>();
+    Ok(list
+        .split(|&c| c == 0)
+        .filter(|name| name.starts_with(b"trusted.overlay.") || name.starts_with(b"user.overlay."))
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .collect())
+}
+
+fn xattr_value(path: &Path, name: &str) -> Result<Vec<u8>, Error> {
+    let error = || {
+        format!(
+            "Failed to read the extended attributes of {}",
+            path.display()
+        )
+    };
+    let mut value = vec![0; 256];
+    let len = loop {
+        match lgetxattr(path, name, &mut value) {
+            Err(Errno::RANGE) => value.resize(value.len() * 2, 0),
+            r => break r.map_io_err_lazy(error)?,
+        }
+    };
+    value.truncate(len);
+    Ok(value)
+}
@@ -0,0 +1,289 @@
+//! Line-by-line comparisons of changed files.
+//!
+//! Text files are compared by line and only the lines that changed are shown,
+//! grouped into hunks like `diff -U0` prints them. Binary files would render
+//! as garbage, so they are described by their size and hash instead and, on
+//! request, by a hexdump of the 16-byte rows that differ.
+
+use std::{borrow::Cow, fmt::Write};
+
+use crate::{
+    ChangeKind,
+    formats::{self, Line},
+    sha256,
+};
+
+/// The most lines a single file's comparison is cut down to.
+const MAX_LINES: usize = 40;
+/// Lines longer than this many characters are elided.
+const MAX_LINE: usize = 200;
+/// Text files whose changed regions would need a larger table than this to
+/// line up are shown as entirely replaced.
+const MAX_CELLS: usize = 1 << 22;
+/// Bytes per hexdump row.
+const ROW: usize = 16;
+/// The most rows of each version shown per differing region.
+const MAX_ROWS: usize = 4;
+
+/// Compares the two versions of a file. `old` is `None` for added files.
+pub fn compare(old: Option<&[u8]>, new: &[u8], hexdump: bool) -> Vec<Line> {
+    let mut lines = match (old.map(text), text(new)) {
+        (None, Some(new)) => text_lines("", new),
+        (Some(Some(old)), Some(new)) => text_lines(old, new),
+        _ => binary(old, new, hexdump),
+    };
+    formats::truncate(&mut lines, MAX_LINES);
+    lines
+}
+
+/// Describes files too large to read in full by their size alone.
+pub fn sizes(old: Option<u64>, new: u64) -> Line {
+    Line {
+        kind: ChangeKind::Modified,
+        text: old.map_or_else(
+            || format!("~ too large to compare: {new} bytes"),
+            |old| format!("~ too large to compare: {old} -> {new} bytes"),
+        ),
+    }
+}
+
+fn text(contents: &[u8]) -> Option<&str> {
+    if contents.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(contents).ok()
+}
+
+fn line(kind: ChangeKind, prefix: char, text: &str) -> Line {
+    let text = match text.char_indices().nth(MAX_LINE) {
+        Some((end, _)) => Cow::Owned(format!("{}…", &text[..end])),
+        None => Cow::Borrowed(text),
+    };
+    Line {
+        kind,
+        text: format!("{prefix} {text}"),
+    }
+}
+
+// Text
+
+/// One step of an edit script turning the old lines into the new ones.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub enum Edit {
+    Keep,
+    Delete,
+    Insert,
+}
+
+fn text_lines(old: &str, new: &str) -> Vec<Line> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let script = edits(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (prefix, prefix);
+    let mut edits = script.iter().peekable();
+    while let Some(&edit) = edits.next() {
+        if edit == Edit::Keep {
+            i += 1;
+            j += 1;
+            continue;
+        }
+        let (start_old, start_new) = (i, j);
+        let mut hunk = Vec::new();
+        for edit in std::iter::once(edit).chain(std::iter::from_fn(|| {
+            edits.next_if(|&&edit| edit != Edit::Keep).copied()
+        })) {
+            if edit == Edit::Delete {
+                hunk.push(line(ChangeKind::Deleted, '-', old[i]));
+                i += 1;
+            } else {
+                hunk.push(line(ChangeKind::Added, '+', new[j]));
+                j += 1;
+            }
+        }
+        lines.push(Line {
+            kind: ChangeKind::Modified,
+            text: format!(
+                "@@ -{} +{} @@",
+                range(start_old, i - start_old),
+                range(start_new, j - start_new)
+            ),
+        });
+        lines.append(&mut hunk);
+    }
+    lines
+}
+
+/// Formats a hunk's range of lines the way unified diffs do, where an empty
+/// range names the line before it.
+pub fn range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{len}", start + 1),
+    }
+}
+
+/// Finds the shortest edit script through the longest common subsequence,
+/// giving up on lining anything up if the table would be too large.
+pub fn edits(old: &[&str], new: &[&str]) -> Vec<Edit> {
+    let (n, m) = (old.len(), new.len());
+    if n.saturating_mul(m) > MAX_CELLS {
+        let mut script = vec![Edit::Delete; n];
+        script.resize(n + m, Edit::Insert);
+        return script;
+    }
+
+    // common[i][j] is the length of the longest common subsequence of
+    // old[i..] and new[j..]
+    let width = m + 1;
+    let mut common = vec![0u32; (n + 1) * width];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i * width + j] = if old[i] == new[j] {
+                common[(i + 1) * width + j + 1] + 1
+            } else {
+                common[(i + 1) * width + j].max(common[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut script = Vec::with_capacity(n + m);
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if old.get(i).is_some_and(|line| new.get(j) == Some(line)) {
+            script.push(Edit::Keep);
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[(i + 1) * width + j] >= common[i * width + j + 1]) {
+            script.push(Edit::Delete);
+            i += 1;
+        } else {
+            script.push(Edit::Insert);
+            j += 1;
+        }
+    }
+    script
+}
+
+// Binary
+
+fn binary(old: Option<&[u8]>, new: &[u8], hexdump: bool) -> Vec<Line> {
+    let hash = |data: &[u8]| sha256::hex(&sha256::digest(data)[..6]);
+    let Some(old) = old else {
+        let mut lines = vec![Line {
+            kind: ChangeKind::Added,
+            text: format!("+ binary: {} bytes, sha256 {}…", new.len(), hash(new)),
+        }];
+        if hexdump {
+            for row in (0..new.len().div_ceil(ROW)).take(MAX_ROWS) {
+                lines.push(dump(ChangeKind::Added, '+', new, row));
+            }
+        }
+        return lines;
+    };
+
+    let rows = old.len().max(new.len()).div_ceil(ROW);
+    let differs = |row: usize| chunk(old, row) != chunk(new, row);
+    let mut regions = Vec::new();
+    let mut row = 0;
+    while row < rows {
+        if differs(row) {
+            let start = row;
+            while row < rows && differs(row) {
+                row += 1;
+            }
+            regions.push(start..row);
+        }
+        row += 1;
+    }
+    let bytes = old.iter().zip(new).filter(|(a, b)| a != b).count() + old.len().abs_diff(new.len());
+
+    let mut lines = vec![
+        Line {
+            kind: ChangeKind::Modified,
+            text: format!(
+                "~ binary: {} -> {} bytes, sha256 {}… -> {}…",
+                old.len(),
+                new.len(),
+                hash(old),
+                hash(new)
+            ),
+        },
+        Line {
+            kind: ChangeKind::Modified,
+            text: format!(
+                "~ {bytes} byte{} changed in {} region{}",
+                if bytes == 1 { "" } else { "s" },
+                regions.len(),
+                if regions.len() == 1 { "" } else { "s" }
+            ),
+        },
+    ];
+    if hexdump {
+        for region in regions {
+            lines.push(Line {
+                kind: ChangeKind::Modified,
+                text: format!("@@ {:#010x} @@", region.start * ROW),
+            });
+            let shown = region.start..region.end.min(region.start + MAX_ROWS);
+            for row in shown.clone().filter(|&row| !chunk(old, row).is_empty()) {
+                lines.push(dump(ChangeKind::Deleted, '-', old, row));
+            }
+            for row in shown.clone().filter(|&row| !chunk(new, row).is_empty()) {
+                lines.push(dump(ChangeKind::Added, '+', new, row));
+            }
+            if shown.end < region.end {
+                let more = region.end - shown.end;
+                lines.push(Line {
+                    kind: ChangeKind::Modified,
+                    text: format!("… {more} more row{}", if more == 1 { "" } else { "s" }),
+                });
+            }
+        }
+    }
+    lines
+}
+
+fn chunk(data: &[u8], row: usize) -> &[u8] {
+    let start = (row * ROW).min(data.len());
+    &data[start..(start + ROW).min(data.len())]
+}
+
+/// Formats a row like `hexdump -C` does.
+fn dump(kind: ChangeKind, prefix: char, data: &[u8], row: usize) -> Line {
+    let bytes = chunk(data, row);
+    let mut text = format!("{prefix} {:08x} ", row * ROW);
+    for i in 0..ROW {
+        if i % 8 == 0 {
+            text.push(' ');
+        }
+        match bytes.get(i) {
+            Some(byte) => {
+                let _ = write!(text, "{byte:02x} ");
+            }
+            None => text.push_str("   "),
+        }
+    }
+    text.push_str(" |");
+    text.extend(bytes.iter().map(|&b| {
+        if b.is_ascii_graphic() || b == b' ' {
+            char::from(b)
+        } else {
+            '.'
+        }
+    }));
+    text.push('|');
+    Line { kind, text }
+}
@@ -0,0 +1,394 @@
+//! Commands left running in the background of a session.
+//!
+//! Detached commands outlive the forkfs invocation that started them, so they
+//! are recorded in the session's `processes.toml` to be found again later.
+//! Processes are identified by their pid along with their start time, which
+//! guards against the pid having been reused by an unrelated process since.
+//!
+//! Commands that are restarted on failure run under a shell loop inside the
+//! sandbox which counts restarts by writing a byte per restart to an inherited
+//! file descriptor. That file lives outside the sandbox next to the command's
+//! log, so its size is the restart count and its modification time is when the
+//! command was last started.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{fs::MetadataExt, process::CommandExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{FlockOperation, flock},
+    io::Errno,
+    process::{Pid, Signal, Uid, getuid, kill_process_group, setsid},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, IoErr, clock, get_sessions_dir,
+    path_undo::TmpPath,
+    run::{Namespace, confine, validate_permissions},
+    sessions::resolve_session,
+};
+
+const RECORD: &str = "processes.toml";
+pub const LOGS: &str = "logs";
+/// Restarts the command until it succeeds, with the command line as arguments.
+const SUPERVISOR: &str = "until \"$@\" 3>&-; do printf . >&3; sleep 1; done";
+
+/// When a detached command is started again after it exits.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    #[default]
+    Never,
+    /// After exiting unsuccessfully or being killed by a signal, waiting a
+    /// second between attempts.
+    OnFailure,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DetachedProcess {
+    pub pid: u32,
+    /// When the process started, in clock ticks since boot.
+    pub start_time: u64,
+    /// The cgroup the process was started in, empty if unknown.
+    pub cgroup: String,
+    /// The command line, lossily converted to UTF-8.
+    pub command: Vec<String>,
+    /// When the command was last started, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub started: u64,
+    #[serde(default)]
+    pub restart: Restart,
+    /// How many times the command was restarted.
+    #[serde(skip)]
+    pub restarts: u64,
+}
+
+impl DetachedProcess {
+    /// Whether the process is still running. Commands that are restarted on
+    /// failure keep running between restarts.
+    #[must_use]
+    pub fn is_running(&self) -> bool {
+        start_time(self.pid) == Some(self.start_time)
+    }
+
+    /// How long the command has been running since it was last started.
+    #[must_use]
+    pub fn uptime(&self) -> Option<Duration> {
+        if !self.is_running() {
+            return None;
+        }
+        clock::now()
+            .duration_since(UNIX_EPOCH + Duration::from_secs(self.started))
+            .ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Record {
+    #[serde(default, rename = "process")]
+    processes: Vec<DetachedProcess>,
+}
+
+/// Starts a command in an active session without waiting for it.
+///
+/// The command gets its own process session so that it is detached from the
+/// terminal, and is handed to init once forkfs exits, which is what a double
+/// fork would achieve. Its output goes to the session's `logs` directory.
+pub fn spawn(
+    session: &mut PathBuf,
+    args: &[impl AsRef<OsStr>],
+    prev_uid: Uid,
+    namespace: Namespace,
+    restart: Restart,
+) -> Result<(), Error> {
+    let _lock = lock(session)?;
+    let logs = TmpPath::new(session, LOGS);
+    fs::create_dir_all(&logs)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", logs.display()))?;
+    // The log is named after the process, which doesn't exist yet
+    let pending = logs.join(format!(".{}.log", std::process::id()));
+    let log = File::create(&pending)
+        .map_io_err_lazy(|| format!("Failed to create {}", pending.display()))?;
+    let log_err = log
+        .try_clone()
+        .map_io_err_lazy(|| format!("Failed to open {}", pending.display()))?;
+
+    let pending_restarts = pending.with_extension("restarts");
+    let restarts = match restart {
+        Restart::Never => None,
+        Restart::OnFailure => Some(
+            File::create(&pending_restarts)
+                .map_io_err_lazy(|| format!("Failed to create {}", pending_restarts.display()))?,
+        ),
+    };
+
+    let mut command = if restarts.is_some() {
+        let mut command = Command::new("/bin/sh");
+        command.arg("-c").arg(SUPERVISOR).arg("forkfs").args(args);
+        command
+    } else {
+        let mut command = Command::new(args[0].as_ref());
+        command.args(&args[1..]);
+        command
+    };
+    command.stdin(Stdio::null()).stdout(log).stderr(log_err);
+    let counter = restarts.as_ref().map(AsRawFd::as_raw_fd);
+    // SAFETY: setsid, dup2, and fcntl are async-signal-safe.
+    unsafe {
+        command.pre_exec(move || {
+            setsid()?;
+            // The supervisor expects its counter as fd 3, without close-on-exec
+            let result = match counter {
+                None => 0,
+                Some(3) => libc::fcntl(3, libc::F_SETFD, 0),
+                Some(fd) => libc::dup2(fd, 3),
+            };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    // Entered last so that any processes left waiting on the command in the
+    // namespace's stead are detached too
+    confine(&mut command, prev_uid, namespace);
+    let child = command.spawn().map_io_err_lazy(|| {
+        format!(
+            "Failed to spawn {:?}",
+            args.iter().map(AsRef::as_ref).collect::<Vec<_>>()
+        )
+    });
+    drop(restarts);
+    let child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            drop(fs::remove_file(&pending));
+            drop(fs::remove_file(&pending_restarts));
+            return Err(e);
+        }
+    };
+
+    let pid = child.id();
+    let log = logs.join(format!("{pid}.log"));
+    fs::rename(&pending, &log).map_io_err_lazy(|| format!("Failed to create {}", log.display()))?;
+    if restart == Restart::OnFailure {
+        let restarts = log.with_extension("restarts");
+        fs::rename(&pending_restarts, &restarts)
+            .map_io_err_lazy(|| format!("Failed to create {}", restarts.display()))?;
+    }
+    drop(logs);
+    // Our unreaped child can't disappear from /proc until we exit, though it
+    // may already have exited
+    let start_time = stat(pid)
+        .map(|(_, start_time)| start_time)
+        .ok_or(Error::Io)
+        .attach_printable_lazy(|| format!("Failed to read the start time of process {pid}"))?;
+    let cgroup = fs::read_to_string(format!("/proc/{pid}/cgroup"))
+        .ok()
+        .and_then(|cgroups| {
+            cgroups
+                .lines()
+                .find_map(|line| line.strip_prefix("0::"))
+                .map(str::to_owned)
+        })
+        .unwrap_or_default();
+
+    let mut record = load(session)?;
+    record.processes.retain(DetachedProcess::is_running);
+    record.processes.push(DetachedProcess {
+        pid,
+        start_time,
+        cgroup,
+        command: args
+            .iter()
+            .map(|arg| arg.as_ref().to_string_lossy().into_owned())
+            .collect(),
+        started: clock::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+        restart,
+        restarts: 0,
+    });
+    save(session, &record)
+}
+
+/// Lists a session's detached processes, oldest first.
+///
+/// Processes that exited are included until the next detached command is
+/// started in the session.
+pub fn processes(session: impl AsRef<OsStr>) -> Result<Vec<DetachedProcess>, Error> {
+    let mut session = session_dir(session.as_ref())?;
+    let mut processes = load(&mut session)?.processes;
+    session.push(LOGS);
+    for process in &mut processes {
+        if process.restart == Restart::Never {
+            continue;
+        }
+        let counter = TmpPath::new(&mut session, format!("{}.restarts", process.pid));
+        match fs::metadata(&counter) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            r => {
+                let metadata =
+                    r.map_io_err_lazy(|| format!("Failed to stat {}", counter.display()))?;
+                process.restarts = metadata.len();
+                process.started = metadata.mtime().cast_unsigned();
+            }
+        }
+    }
+    Ok(processes)
+}
+
+/// Copies the output of a detached process to `out` as it is produced, until
+/// the process exits. The output of a process that already exited is copied
+/// in full.
+///
+/// Without a `pid`, the most recently started process is picked.
+pub fn attach(
+    session: impl AsRef<OsStr>,
+    pid: Option<u32>,
+    mut out: impl Write,
+) -> Result<(), Error> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+    let session = session.as_ref();
+    let process = find(session, pid, false)?;
+    let mut log = session_dir(session)?;
+    log.push(LOGS);
+    log.push(format!("{}.log", process.pid));
+    let mut log =
+        File::open(&log).map_io_err_lazy(|| format!("Failed to open {}", log.display()))?;
+
+    loop {
+        // Checked before reading so that the last output isn't missed
+        let running = process.is_running();
+        io::copy(&mut log, &mut out)
+            .and_then(|_| out.flush())
+            .map_io_err_lazy(|| format!("Failed to copy the output of process {}", process.pid))?;
+        if !running {
+            return Ok(());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Asks a detached process to terminate, or kills it if `kill` is set.
+///
+/// The signal is sent to the process group, so the processes it started, and
+/// the command itself if it is restarted on failure, are stopped along with
+/// it.
+///
+/// Without a `pid`, the most recently started process is picked.
+pub fn stop(session: impl AsRef<OsStr>, pid: Option<u32>, kill: bool) -> Result<(), Error> {
+    validate_permissions(getuid())?;
+    let process = find(session.as_ref(), pid, true)?;
+    let signal = if kill { Signal::Kill } else { Signal::Term };
+    // Detached processes lead their own group, whose id can't be reused by
+    // another process for as long as any of its members are alive
+    let result = Pid::from_raw(process.pid.cast_signed())
+        .ok_or(Errno::SRCH)
+        .and_then(|group| kill_process_group(group, signal));
+    match result {
+        Err(Errno::SRCH) => Err(Error::InvalidArgument)
+            .attach_printable(format!("Process {} already exited", process.pid)),
+        r => r.map_io_err_lazy(|| format!("Failed to signal process {}", process.pid)),
+    }
+}
+
+fn find(session: &OsStr, pid: Option<u32>, running: bool) -> Result<DetachedProcess, Error> {
+    let mut processes = processes(session)?;
+    if running {
+        processes.retain(DetachedProcess::is_running);
+    }
+    let kind = if running { "running " } else { "" };
+    let Some(pid) = pid else {
+        return processes
+            .pop()
+            .ok_or(Error::InvalidArgument)
+            .attach_printable_lazy(|| {
+                format!(
+                    "Session {} has no {kind}detached processes",
+                    session.display()
+                )
+            });
+    };
+    processes
+        .into_iter()
+        .find(|process| process.pid == pid)
+        .ok_or(Error::InvalidArgument)
+        .attach_printable_lazy(|| {
+            format!(
+                "Process {pid} isn't a {kind}detached process of session {}",
+                session.display()
+            )
+        })
+}
+
+fn session_dir(session: &OsStr) -> Result<PathBuf, Error> {
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    if dir.exists() {
+        Ok(dir)
+    } else {
+        Err(Error::SessionNotFound)
+            .attach_printable(format!("Session {} does not exist", session.display()))
+    }
+}
+
+/// The start time of a process that hasn't exited yet, from field 22 of
+/// `/proc/<pid>/stat`.
+fn start_time(pid: u32) -> Option<u64> {
+    stat(pid).and_then(|(exited, start_time)| (!exited).then_some(start_time))
+}
+
+/// Reads a process' start time along with whether it exited without being
+/// reaped yet.
+fn stat(pid: u32) -> Option<(bool, u64)> {
+    let stat = fs::read(format!("/proc/{pid}/stat")).ok()?;
+    // The command name in field 2 may contain spaces and parentheses
+    let fields = &stat[stat.iter().rposition(|&b| b == b')')? + 2..];
+    let mut fields = fields.split(|&b| b == b' ');
+    let exited = fields.next()? == b"Z";
+    Some((exited, str::from_utf8(fields.nth(18)?).ok()?.parse().ok()?))
+}
+
+/// Serializes updates to the process record, which may come from several
+/// forkfs invocations at once.
+fn lock(session: &Path) -> Result<File, Error> {
+    let dir =
+        File::open(session).map_io_err_lazy(|| format!("Failed to open {}", session.display()))?;
+    flock(&dir, FlockOperation::LockExclusive)
+        .map_io_err_lazy(|| format!("Failed to lock {}", session.display()))?;
+    Ok(dir)
+}
+
+fn load(session: &mut PathBuf) -> Result<Record, Error> {
+    let path = TmpPath::new(session, RECORD);
+    let record = match fs::read_to_string(&path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Record::default()),
+        r => r.map_io_err_lazy(|| format!("Failed to read {}", path.display()))?,
+    };
+    toml::from_str(&record)
+        .change_context(Error::InvalidArgument)
+        .attach_printable_lazy(|| format!("Invalid process record {}", path.display()))
+}
+
+fn save(session: &mut PathBuf, record: &Record) -> Result<(), Error> {
+    let path = TmpPath::new(session, RECORD);
+    let contents = toml::to_string(record)
+        .change_context(Error::InvalidArgument)
+        .attach_printable("Failed to serialize the process record")?;
+    fs::write(&path, contents).map_io_err_lazy(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,708 @@
+//! Structural comparison of a session with the real file system.
+//!
+//! A session's upper directory only contains the entries that differ from the
+//! real file system, encoded the way overlayfs expects:
+//!
+//! - Deleted entries are whiteouts, character devices numbered 0:0.
+//! - Directories that were deleted and then recreated are marked opaque with an
+//!   `overlay.opaque` xattr so that nothing shows through from below.
+//! - Directories that were renamed carry an `overlay.redirect` xattr pointing
+//!   at their original location, if the kernel has `redirect_dir` enabled.
+//! - Everything else was either created in the session or copied up from the
+//!   real file system before being modified.
+//!
+//! Copying up or deleting a file sets its change time in the upper directory,
+//! so a real file with a later change time was modified outside the session
+//! after the session last touched it.
+
+#[cfg(feature = "cli")]
+use std::io::Write;
+use std::{
+    collections::BTreeMap,
+    ffi::{CString, OsStr, OsString},
+    fs::{self, File},
+    io::{self, Read},
+    iter,
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+#[cfg(feature = "cli")]
+use rustix::fs::fstat;
+use rustix::{
+    fs::{
+        AtFlags, CWD, FileType, OFlags, Stat, fgetxattr, flistxattr, major, minor, readlinkat,
+        statat,
+    },
+    io::Errno,
+};
+
+use crate::{Error, IoErr, path_undo::TmpPath, sessions::open_diff, subtree, walk};
+#[cfg(feature = "cli")]
+use crate::{Style, archive, content, formats};
+
+/// Files larger than this aren't read to describe what changed inside them.
+#[cfg(feature = "cli")]
+const MAX_SIZE: u64 = 64 << 20;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ChangeKind {
+    /// The path doesn't exist on the real file system.
+    Added,
+    /// The path exists on the real file system with different contents or
+    /// metadata.
+    Modified,
+    /// The path exists on the real file system but not in the session.
+    Deleted,
+    /// The path exists on the real file system with the same contents but
+    /// different metadata, such as its mode, owner, or extended attributes.
+    /// Only reported when asked for with [`Query::metadata_only`].
+    MetadataOnly,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Change {
+    /// The changed path as seen from inside the session.
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+    /// The real file changed after the session last modified or deleted it,
+    /// so the session's version doesn't account for those changes.
+    pub diverged: bool,
+}
+
+/// How many changes [`iter()`] reads from the session at a time unless told
+/// otherwise.
+const PAGE_SIZE: usize = 256;
+
+/// Returns the changes a session made to the real file system, sorted by
+/// path.
+///
+/// Deleting a directory is reported as a single change rather than one per
+/// entry it contained, while every entry of an added directory is reported.
+pub fn changes(session: impl AsRef<OsStr>) -> Result<Vec<Change>, Error> {
+    query(session, Query::default()).map(|page| page.changes)
+}
+
+/// Streams the changes `query` selects in the same order as [`changes()`],
+/// reading them from the session a page of `query.limit` changes at a time
+/// and starting after `query.cursor`.
+///
+/// Like paging through them with [`query()`], changes the session makes while
+/// it is iterated over may or may not be returned.
+pub fn iter(
+    session: impl AsRef<OsStr>,
+    query: Query,
+) -> Result<impl Iterator<Item = Result<Change, Error>>, Error> {
+    let session = session.as_ref().to_owned();
+    let first = query.cursor.clone();
+    let page = move |cursor| {
+        self::query(
+            &session,
+            Query {
+                limit: Some(query.limit.unwrap_or(PAGE_SIZE)),
+                cursor,
+                ..query.clone()
+            },
+        )
+    };
+    let Page { changes, mut next } = page(first)?;
+    let mut changes = changes.into_iter();
+    Ok(iter::from_fn(move || {
+        loop {
+            if let Some(change) = changes.next() {
+                return Some(Ok(change));
+            }
+            match page(Some(next.take()?)) {
+                Ok(page) => {
+                    changes = page.changes.into_iter();
+                    next = page.next;
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }))
+}
+
+/// Narrows down the changes [`query()`] returns and splits them into pages.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Query {
+    /// Only return changes to this absolute path and the entries below it.
+    pub prefix: Option<PathBuf>,
+    /// Only return changes of these kinds, or of any kind if empty.
+    pub kinds: Vec<ChangeKind>,
+    /// Return at most this many changes, or all of them if `None`.
+    pub limit: Option<usize>,
+    /// Continue after the page this cursor was returned with.
+    pub cursor: Option<Cursor>,
+    /// Tell changes to the metadata of paths apart as
+    /// [`ChangeKind::MetadataOnly`] rather than reporting them as
+    /// [`ChangeKind::Modified`]. This reads modified files that are as large as
+    /// the real ones until they first differ, so it is off by default.
+    pub metadata_only: bool,
+}
+
+/// Where a page of changes ended.
+///
+/// Cursors point between paths rather than at a change, so they remain valid
+/// while the session keeps changing: the next page starts with the first
+/// change sorted after the previous page's last one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Cursor(PathBuf);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Page {
+    /// The changes on this page, sorted by path.
+    pub changes: Vec<Change>,
+    /// Where the next page starts, `None` if this is the last one.
+    pub next: Option<Cursor>,
+}
+
+/// Returns one page of the changes a session made to the real file system,
+/// in the same order as [`changes()`].
+///
+/// Directories that can't contain any change on the page aren't walked, so
+/// paging through an enormous session only costs about as much as reading
+/// the pages.
+pub fn query(session: impl AsRef<OsStr>, query: Query) -> Result<Page, Error> {
+    let session = session.as_ref();
+    let Query {
+        prefix,
+        kinds,
+        limit,
+        cursor,
+        metadata_only,
+    } = query;
+    if let Some(prefix) = prefix.as_ref().filter(|prefix| !prefix.is_absolute()) {
+        return Err(Error::InvalidArgument).attach_printable(format!(
+            "Prefix {} must be an absolute path.",
+            prefix.display()
+        ));
+    }
+    if limit == Some(0) {
+        return Err(Error::InvalidArgument)
+            .attach_printable("Pages must hold at least one change.");
+    }
+
+    let fd = open_diff(session)?;
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut sink = Sink {
+        prefix,
+        kinds,
+        after: cursor.map(|Cursor(after)| after),
+        limit,
+        metadata_only,
+        changes: Vec::new(),
+    };
+    diff_tree(&fd, &mut subtree::of(session)?, false, &mut sink)?;
+
+    let mut changes = sink.changes;
+    let next = if changes.len() > limit {
+        changes.truncate(limit);
+        changes.last().map(|change| Cursor(change.path.clone()))
+    } else {
+        None
+    };
+    Ok(Page { changes, next })
+}
+
+/// What [`print()`] shows about the contents of added and modified files.
+#[cfg(feature = "cli")]
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Detail {
+    /// Summarize what changed inside files in a format forkfs understands,
+    /// such as JSON or sqlite.
+    pub structural: bool,
+    /// Show the changed lines of text files and the size and hash of binaries.
+    /// Structural summaries take precedence for files that have one.
+    pub content: bool,
+    /// Follow the size and hash of binaries with a hexdump of the regions that
+    /// differ.
+    pub hexdump: bool,
+    /// Leave out added and modified files that are now larger than this many
+    /// bytes.
+    pub max_file_size: Option<u64>,
+    /// Leave out added and modified files that now look binary, as told apart
+    /// by [`export_filtered`](crate::export_filtered_session).
+    pub exclude_binary: bool,
+}
+
+/// Prints the changes a session made to the real file system, one per line
+/// and prefixed with `A`, `M`, or `D` for added, modified, and deleted paths.
+///
+/// Depending on `detail`, added and modified files are followed by what
+/// changed inside them or left out altogether.
+#[cfg(feature = "cli")]
+pub fn print(session: impl AsRef<OsStr>, style: Style, detail: Detail) -> Result<(), Error> {
+    let session = session.as_ref();
+    let changes = changes(session)?;
+    let upper = if detail.structural
+        || detail.content
+        || detail.max_file_size.is_some()
+        || detail.exclude_binary
+    {
+        Some((open_diff(session)?, subtree::of(session)?))
+    } else {
+        None
+    };
+    let mut stdout = std::io::stdout().lock();
+    let colors = style.colors(&stdout);
+    for Change {
+        path,
+        kind,
+        diverged,
+        ..
+    } in changes
+    {
+        let upper = upper.as_ref().filter(|_| kind != ChangeKind::Deleted);
+        if upper.is_some_and(|(upper, subtree)| is_filtered(upper, subtree, &path, detail)) {
+            continue;
+        }
+        let lines = upper
+            .filter(|_| detail.structural || detail.content)
+            .and_then(|(upper, subtree)| read_versions(upper, subtree, &path, kind))
+            .and_then(|versions| describe(&path, versions, detail));
+        let path = path.to_string_lossy();
+        let path = format_args!(
+            "{}{}",
+            style.text(&path),
+            if diverged {
+                " (changed outside the session)"
+            } else {
+                ""
+            }
+        );
+        let letter = match kind {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified | ChangeKind::MetadataOnly => 'M',
+            ChangeKind::Deleted => 'D',
+        };
+        write_change(&mut stdout, colors, kind, format_args!("{letter} {path}"))
+            .map_io_err("Failed to write to stdout")?;
+        for formats::Line { kind, text } in lines.into_iter().flatten() {
+            write_change(
+                &mut stdout,
+                colors,
+                kind,
+                format_args!("    {}", style.text(&text)),
+            )
+            .map_io_err("Failed to write to stdout")?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "cli")]
+fn write_change(
+    out: &mut impl Write,
+    colors: bool,
+    kind: ChangeKind,
+    text: impl std::fmt::Display,
+) -> std::io::Result<()> {
+    match kind {
+        ChangeKind::Added => writeln!(out, "{}", Style::added(colors, text)),
+        ChangeKind::Modified | ChangeKind::MetadataOnly => {
+            writeln!(out, "{}", Style::modified(colors, text))
+        }
+        ChangeKind::Deleted => writeln!(out, "{}", Style::deleted(colors, text)),
+    }
+}
+
+/// Whether the session's version of a regular file is one `detail` leaves out.
+#[cfg(feature = "cli")]
+fn is_filtered(upper: &OwnedFd, subtree: &Path, path: &Path, detail: Detail) -> bool {
+    let Some(name) = path
+        .strip_prefix(subtree)
+        .ok()
+        .and_then(|name| CString::new(name.as_os_str().as_bytes()).ok())
+    else {
+        return false;
+    };
+    let Ok(stat) = walk::open_beneath(upper, &name, OFlags::PATH).and_then(|file| fstat(&file))
+    else {
+        return false;
+    };
+    if FileType::from_raw_mode(stat.st_mode) != FileType::RegularFile {
+        return false;
+    }
+    detail
+        .max_file_size
+        .is_some_and(|max| stat.st_size.cast_unsigned() > max)
+        || (detail.exclude_binary
+            && walk::open_beneath(upper, &name, OFlags::RDONLY)
+                .is_ok_and(|file| archive::is_binary(&file, path).unwrap_or_default()))
+}
+
+/// Both versions of a changed regular file. `old` is `None` for added files.
+#[cfg(feature = "cli")]
+enum Versions {
+    Read { old: Option<Vec<u8>>, new: Vec<u8> },
+    TooLarge { old: Option<u64>, new: u64 },
+}
+
+#[cfg(feature = "cli")]
+fn describe(path: &Path, versions: Versions, detail: Detail) -> Option<Vec<formats::Line>> {
+    match versions {
+        Versions::Read { old, new } => detail
+            .structural
+            .then(|| formats::summarize(path, old.as_deref(), &new))
+            .flatten()
+            .or_else(|| {
+                detail
+                    .content
+                    .then(|| content::compare(old.as_deref(), &new, detail.hexdump))
+            }),
+        Versions::TooLarge { old, new } => detail.content.then(|| vec![content::sizes(old, new)]),
+    }
+}
+
+/// Reads both versions of a changed file, giving up on anything that isn't a
+/// readable regular file on both sides.
+#[cfg(feature = "cli")]
+fn read_versions(
+    upper: &OwnedFd,
+    subtree: &Path,
+    path: &Path,
+    kind: ChangeKind,
+) -> Option<Versions> {
+    let name = CString::new(path.strip_prefix(subtree).ok()?.as_os_str().as_bytes()).ok()?;
+    let new = walk::open_beneath(upper, &name, OFlags::PATH).ok()?;
+    let stat = fstat(&new).ok()?;
+    if FileType::from_raw_mode(stat.st_mode) != FileType::RegularFile {
+        return None;
+    }
+    let new_size = u64::try_from(stat.st_size).ok()?;
+    let old_size = if kind == ChangeKind::Modified {
+        let metadata = fs::symlink_metadata(path).ok()?;
+        if !metadata.is_file() {
+            return None;
+        }
+        Some(metadata.len())
+    } else {
+        None
+    };
+    if new_size.max(old_size.unwrap_or_default()) > MAX_SIZE {
+        return Some(Versions::TooLarge {
+            old: old_size,
+            new: new_size,
+        });
+    }
+
+    let mut contents = Vec::new();
+    fs::File::from(walk::open_beneath(upper, &name, OFlags::RDONLY).ok()?)
+        .read_to_end(&mut contents)
+        .ok()?;
+    let old = if old_size.is_some() {
+        Some(fs::read(path).ok()?)
+    } else {
+        None
+    };
+    Some(Versions::Read { old, new: contents })
+}
+
+/// Whether an upper directory entry marks the deletion of a lower one.
+pub fn is_whiteout(file_type: FileType, stat: &Stat) -> bool {
+    file_type == FileType::CharacterDevice && major(stat.st_rdev) == 0 && minor(stat.st_rdev) == 0
+}
+
+/// Walks an upper directory. Below an opaque directory, nothing of the real
+/// file system shows through so every directory behaves as if it were opaque.
+/// Collects the changes a [`Query`] asks for as the upper directory is
+/// walked in path order.
+struct Sink {
+    prefix: Option<PathBuf>,
+    kinds: Vec<ChangeKind>,
+    after: Option<PathBuf>,
+    limit: usize,
+    metadata_only: bool,
+    changes: Vec<Change>,
+}
+
+impl Sink {
+    /// Whether `path` or any entry below it could be on the page.
+    fn wants(&self, path: &Path) -> bool {
+        self.prefix
+            .as_ref()
+            .is_none_or(|prefix| path.starts_with(prefix) || prefix.starts_with(path))
+            && self
+                .after
+                .as_ref()
+                .is_none_or(|after| path > after.as_path() || after.starts_with(path))
+    }
+
+    fn push(&mut self, path: &Path, kind: ChangeKind, diverged: bool) {
+        if self
+            .prefix
+            .as_ref()
+            .is_none_or(|prefix| path.starts_with(prefix))
+            && self
+                .after
+                .as_ref()
+                .is_none_or(|after| path > after.as_path())
+            && (self.kinds.is_empty() || self.kinds.contains(&kind))
+        {
+            self.changes.push(Change {
+                path: path.to_path_buf(),
+                kind,
+                diverged,
+            });
+        }
+    }
+
+    /// One change past the limit tells whether there is another page.
+    const fn is_full(&self) -> bool {
+        self.changes.len() > self.limit
+    }
+}
+
+/// An entry of a directory in the upper directory, or a real entry an opaque
+/// directory hides.
+enum Entry {
+    Upper(CString, FileType),
+    Hidden(OsString),
+}
+
+impl Entry {
+    fn name(&self) -> &[u8] {
+        match self {
+            Self::Upper(name, _) => name.as_bytes(),
+            Self::Hidden(name) => name.as_bytes(),
+        }
+    }
+}
+
+/// Walks a directory's entries sorted by name, which visits paths in sorted
+/// order since parents sort before their children.
+fn diff_tree(
+    dir: &OwnedFd,
+    path: &mut PathBuf,
+    opaque: bool,
+    sink: &mut Sink,
+) -> Result<(), Error> {
+    let mut entries = walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+        .into_iter()
+        .map(|(name, file_type)| Entry::Upper(name, file_type))
+        .collect::<Vec<_>>();
+    if opaque {
+        hidden_entries(path, &mut entries)?;
+    }
+    entries.sort_by(|a, b| a.name().cmp(b.name()));
+
+    for entry in entries {
+        if sink.is_full() {
+            break;
+        }
+        let (name, file_type) = match entry {
+            Entry::Upper(name, file_type) => (name, file_type),
+            Entry::Hidden(name) => {
+                sink.push(&path.join(name), ChangeKind::Deleted, false);
+                continue;
+            }
+        };
+        let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+        if !sink.wants(&path) {
+            continue;
+        }
+        let stat = statat(dir, &name, AtFlags::SYMLINK_NOFOLLOW)
+            .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+
+        let host = statat(CWD, &*path, AtFlags::SYMLINK_NOFOLLOW).ok();
+        if is_whiteout(file_type, &stat) {
+            if let Some(host) = host {
+                sink.push(&path, ChangeKind::Deleted, changed_after(&host, &stat));
+            }
+            continue;
+        }
+        let Some(host) = host else {
+            sink.push(&path, ChangeKind::Added, false);
+            if file_type == FileType::Directory {
+                let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+                    .map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?;
+                diff_tree(&child, &mut path, true, sink)?;
+            }
+            continue;
+        };
+        if file_type != FileType::Directory {
+            let kind = if sink.metadata_only
+                && same_contents(dir, &name, file_type, &stat, &path, &host)?
+            {
+                ChangeKind::MetadataOnly
+            } else {
+                ChangeKind::Modified
+            };
+            sink.push(&path, kind, changed_after(&host, &stat));
+            continue;
+        }
+
+        let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+            .map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?;
+        let replaced = FileType::from_raw_mode(host.st_mode) != FileType::Directory
+            || has_overlay_xattr(&child, "redirect", path.as_path())?;
+        let opaque = replaced || opaque || has_overlay_xattr(&child, "opaque", path.as_path())?;
+        if opaque {
+            sink.push(&path, ChangeKind::Modified, false);
+        } else if sink.metadata_only && !same_metadata(&child, &stat, &path, &host)? {
+            sink.push(&path, ChangeKind::MetadataOnly, false);
+        }
+        diff_tree(&child, &mut path, opaque, sink)?;
+    }
+    Ok(())
+}
+
+/// Whether an upper entry has the same contents as the real one. Files copied
+/// up with `metacopy` only have their metadata in the upper directory, while
+/// other files of the same size are read until they first differ.
+fn same_contents(
+    dir: &OwnedFd,
+    name: &CString,
+    file_type: FileType,
+    upper: &Stat,
+    path: &Path,
+    host: &Stat,
+) -> Result<bool, Error> {
+    if FileType::from_raw_mode(host.st_mode) != file_type {
+        return Ok(false);
+    }
+    let error = || format!("Failed to compare {} with the real file", path.display());
+    Ok(match file_type {
+        FileType::RegularFile if upper.st_size != host.st_size => false,
+        FileType::RegularFile => {
+            let fd = walk::open_beneath(dir, name, OFlags::RDONLY).map_io_err_lazy(error)?;
+            has_overlay_xattr(&fd, "metacopy", path)?
+                || same_bytes(File::from(fd), File::open(path).map_io_err_lazy(error)?)
+                    .map_io_err_lazy(error)?
+        }
+        FileType::Symlink => {
+            readlinkat(dir, name.as_c_str(), Vec::new()).map_io_err_lazy(error)?
+                == readlinkat(CWD, path, Vec::new()).map_io_err_lazy(error)?
+        }
+        FileType::BlockDevice | FileType::CharacterDevice => upper.st_rdev == host.st_rdev,
+        _ => true,
+    })
+}
+
+/// Whether a directory copied up into the upper directory still has the real
+/// one's mode, owner, and extended attributes. Its timestamps change along
+/// with its entries, so they aren't compared.
+fn same_metadata(upper: &OwnedFd, stat: &Stat, path: &Path, host: &Stat) -> Result<bool, Error> {
+    if (stat.st_mode, stat.st_uid, stat.st_gid) != (host.st_mode, host.st_uid, host.st_gid) {
+        return Ok(false);
+    }
+    let error = || {
+        format!(
+            "Failed to read the extended attributes of {}",
+            path.display()
+        )
+    };
+    let real = File::open(path).map_io_err_lazy(error)?;
+    Ok(xattrs(upper).map_io_err_lazy(error)? == xattrs(&real).map_io_err_lazy(error)?)
+}
+
+/// The extended attributes of a file other than overlayfs' own markers.
+fn xattrs(fd: impl AsFd) -> io::Result<BTreeMap<Vec<u8>, Vec<u8>>> {
+    let mut list = vec![0; 256];
+    let len = loop {
+        match flistxattr(&fd, &mut list) {
+            Err(Errno::RANGE) => list.resize(list.len() * 2, 0),
+            Err(Errno::OPNOTSUPP) => return Ok(BTreeMap::new()),
+            r => break r?,
+        }
+    };
+    list.truncate(len);
+    let list = list.iter().map(|c| c.to_ne_bytes()[0]).collect::<Vec<u8>>();
+
+    let mut xattrs = BTreeMap::new();
+    for name in list.split(|&c| c == 0).filter(|name| {
+        !name.is_empty()
+            && !name.starts_with(b"trusted.overlay.")
+            && !name.starts_with(b"user.overlay.")
+    }) {
+        let name = OsStr::from_bytes(name);
+        let mut value = vec![0; 256];
+        let len = loop {
+            match fgetxattr(&fd, name, &mut value) {
+                Err(Errno::RANGE) => value.resize(value.len() * 2, 0),
+                r => break r?,
+            }
+        };
+        value.truncate(len);
+        xattrs.insert(name.as_bytes().to_vec(), value);
+    }
+    Ok(xattrs)
+}
+
+fn same_bytes(mut a: File, mut b: File) -> io::Result<bool> {
+    let mut a_buf = vec![0; 1 << 16];
+    let mut b_buf = vec![0; 1 << 16];
+    loop {
+        let read = fill(&mut a, &mut a_buf)?;
+        let other = fill(&mut b, &mut b_buf)?;
+        if a_buf[..read] != b_buf[..other] {
+            return Ok(false);
+        }
+        if read == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Reads until `buf` is full or the file ends, so that both files being
+/// compared are read in lockstep.
+fn fill(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+fn changed_after(host: &Stat, upper: &Stat) -> bool {
+    (host.st_ctime, host.st_ctime_nsec) > (upper.st_ctime, upper.st_ctime_nsec)
+}
+
+/// Adds the real entries an opaque directory hides, which were deleted.
+fn hidden_entries(path: &Path, entries: &mut Vec<Entry>) -> Result<(), Error> {
+    // The real path may not be a directory if it was replaced by one, or may
+    // not exist at all below an added directory
+    let Ok(lower) = fs::read_dir(path) else {
+        return Ok(());
+    };
+    let upper = entries.len();
+    for entry in lower {
+        let entry =
+            entry.map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?;
+        let name = entry.file_name();
+        if !entries[..upper]
+            .iter()
+            .any(|upper| upper.name() == name.as_bytes())
+        {
+            entries.push(Entry::Hidden(name));
+        }
+    }
+    Ok(())
+}
+
+/// Checks for one of overlayfs' markers, which are stored in the trusted
+/// namespace or the user namespace for unprivileged mounts.
+pub fn has_overlay_xattr(fd: &OwnedFd, marker: &str, path: &Path) -> Result<bool, Error> {
+    for namespace in ["trusted", "user"] {
+        match fgetxattr(fd, format!("{namespace}.overlay.{marker}"), &mut []) {
+            Ok(_) => return Ok(true),
+            Err(Errno::NODATA | Errno::OPNOTSUPP) => {}
+            Err(e) => {
+                return Err(e).map_io_err_lazy(|| {
+                    format!("Failed to read extended attributes of {}", path.display())
+                });
+            }
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,192 @@
+//! Checking whether the live system still matches what a session was made
+//! against.
+//!
+//! Applying a session is only safe if the files it changed weren't also
+//! changed outside of it in the meantime. Each changed path is compared by
+//! content with the session's lower copy, the version the session started
+//! from, and with its upper copy, the session's own version.
+
+use std::{
+    ffi::{CString, OsStr},
+    fs::{self, File},
+    io::{self, ErrorKind, Read},
+    os::{
+        fd::OwnedFd,
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::{FileTypeExt, MetadataExt},
+        },
+    },
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{FileType, OFlags, fstat, readlinkat},
+    io::Errno,
+    process::getuid,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Error, IoErr,
+    diff::{changes, is_whiteout},
+    get_sessions_dir, lower,
+    run::validate_permissions,
+    sessions::{is_active_session, open_diff, resolve_session},
+    subtree, walk,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DriftKind {
+    /// The live file is the one the session started from, so applying the
+    /// session's version loses nothing.
+    Lower,
+    /// The live file already is the session's version, which is also
+    /// reported when the session only changed the file's metadata.
+    Upper,
+    /// The live file changed independently of the session, and applying the
+    /// session's version would overwrite those changes.
+    Neither,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Drift {
+    /// The changed path as seen from inside the session.
+    pub path: PathBuf,
+    pub kind: DriftKind,
+}
+
+/// What a file is made of, ignoring its metadata.
+#[derive(Debug, Eq, PartialEq)]
+enum Contents {
+    Missing,
+    Directory,
+    File([u8; 32]),
+    Symlink(Vec<u8>),
+    Special(FileType, u64),
+}
+
+/// Compares the live version of every path a session changed with the
+/// session's lower and upper versions, sorted by path.
+///
+/// Files are compared by their SHA-256, symlinks by their target, and
+/// directories and special files by their type. For sessions layered on the
+/// live system, the lower version is whatever the live file was before the
+/// session last touched it: a live file changed since then is reported as
+/// [`DriftKind::Neither`] unless it matches the session's version.
+pub fn drift(session: impl AsRef<OsStr>) -> Result<Vec<Drift>, Error> {
+    let session = session.as_ref();
+    validate_permissions(getuid())?;
+    let upper = open_diff(session)?;
+    let changes = changes(session)?;
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    let subtree = subtree::get(&mut dir)?;
+
+    // Lower devices are only mounted while their session is active
+    let mount = lower::recorded(&mut dir)?.is_some() && !is_active_session(&mut dir, true)?;
+    let root = if mount {
+        lower::mount_device(&mut dir)?
+    } else {
+        lower::root(&mut dir)?
+    };
+    let result: Result<Vec<_>, Error> = changes
+        .into_iter()
+        .map(|change| {
+            let live = path_contents(&change.path)?;
+            let lower = root.join(change.path.strip_prefix("/").unwrap());
+            let kind = if upper_contents(&upper, &subtree, &change.path)? == live {
+                DriftKind::Upper
+            } else if root == Path::new("/") && !change.diverged
+                || root != Path::new("/") && path_contents(&lower)? == live
+            {
+                DriftKind::Lower
+            } else {
+                DriftKind::Neither
+            };
+            Ok(Drift {
+                path: change.path,
+                kind,
+            })
+        })
+        .collect();
+    if mount {
+        lower::unmount_device(&mut dir)?;
+    }
+    result
+}
+
+fn path_contents(path: &Path) -> Result<Contents, Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Contents::Missing),
+        r => r.map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?,
+    };
+    let file_type = metadata.file_type();
+    Ok(if file_type.is_dir() {
+        Contents::Directory
+    } else if file_type.is_symlink() {
+        Contents::Symlink(
+            fs::read_link(path)
+                .map_io_err_lazy(|| format!("Failed to read link {}", path.display()))?
+                .into_os_string()
+                .into_vec(),
+        )
+    } else if file_type.is_file() {
+        let contents = File::open(path).and_then(hash);
+        Contents::File(contents.map_io_err_lazy(|| format!("Failed to read {}", path.display()))?)
+    } else {
+        let file_type = if file_type.is_block_device() {
+            FileType::BlockDevice
+        } else if file_type.is_char_device() {
+            FileType::CharacterDevice
+        } else if file_type.is_fifo() {
+            FileType::Fifo
+        } else {
+            FileType::Socket
+        };
+        Contents::Special(file_type, metadata.rdev())
+    })
+}
+
+/// Reads a path from the upper directory, where deleted files are whiteouts
+/// and the entries below a deleted directory are missing.
+fn upper_contents(upper: &OwnedFd, subtree: &Path, path: &Path) -> Result<Contents, Error> {
+    let name = CString::new(subtree::relative(subtree, path)?.as_os_str().as_bytes())
+        .change_context(Error::InvalidArgument)
+        .attach_printable_lazy(|| format!("Invalid path {}", path.display()))?;
+    let error = || format!("Failed to read the session's version of {}", path.display());
+    let fd = match walk::open_beneath(upper, &name, OFlags::PATH) {
+        Err(Errno::NOENT) => return Ok(Contents::Missing),
+        r => r.map_io_err_lazy(error)?,
+    };
+    let stat = fstat(&fd).map_io_err_lazy(error)?;
+    let file_type = FileType::from_raw_mode(stat.st_mode);
+    Ok(match file_type {
+        _ if is_whiteout(file_type, &stat) => Contents::Missing,
+        FileType::Directory => Contents::Directory,
+        FileType::Symlink => Contents::Symlink(
+            readlinkat(&fd, c"", Vec::new())
+                .map_io_err_lazy(error)?
+                .into_bytes(),
+        ),
+        FileType::RegularFile => {
+            let file = File::from(
+                walk::open_beneath(upper, &name, OFlags::RDONLY).map_io_err_lazy(error)?,
+            );
+            Contents::File(hash(file).map_io_err_lazy(error)?)
+        }
+        _ => Contents::Special(file_type, stat.st_rdev),
+    })
+}
+
+pub fn hash(mut file: File) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::default();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finalize().into()),
+            read => hasher.update(&buf[..read]),
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Editing a session's files from the outside.
+
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+use error_stack::Result;
+use rustix::process::{Uid, getuid};
+
+use crate::{
+    Backend, Error, IoErr, get_sessions_dir,
+    run::{unprivileged_uid, validate_permissions},
+    sessions::{mark_used, maybe_create_session, resolve_alias},
+    subtree,
+};
+
+/// Opens a file as the session sees it in the user's `$VISUAL` or `$EDITOR`,
+/// activating the session if necessary, and waits for the editor to exit.
+///
+/// The editor runs outside the session on the session's mount point, so the
+/// file is copied into the session when it is first written to while the real
+/// file is left untouched. Relative paths are resolved against the current
+/// directory.
+pub fn edit(session: impl AsRef<OsStr>, path: &Path) -> Result<ExitStatus, Error> {
+    let uid = getuid();
+    validate_permissions(uid)?;
+
+    let mut session_dir = get_sessions_dir()?;
+    session_dir.push(resolve_alias(session.as_ref())?);
+    maybe_create_session(&mut session_dir, None, Backend::Auto)?;
+    mark_used(&mut session_dir)?;
+
+    let path = env::current_dir()
+        .map_io_err("Failed to get current directory")?
+        .join(path);
+    let mut file = session_dir.join("merged");
+    file.push(subtree::relative(&subtree::get(&mut session_dir)?, &path)?);
+    open_editor(&file, uid)
+}
+
+/// Opens `file` in the user's `$VISUAL` or `$EDITOR`, running it as the user
+/// behind `sudo` if there is one, and waits for the editor to exit.
+pub fn open_editor(file: &Path, uid: Uid) -> Result<ExitStatus, Error> {
+    let editor = ["VISUAL", "EDITOR"]
+        .into_iter()
+        .filter_map(env::var_os)
+        .find(|editor| !editor.is_empty())
+        .unwrap_or_else(|| OsString::from("vi"));
+    // Like git, let the shell split the editor into a program and arguments
+    let mut script = editor.clone();
+    script.push(r#" "$@""#);
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(script).arg("forkfs").arg(file);
+    if let Some(uid) = unprivileged_uid(uid) {
+        command.uid(uid.as_raw());
+    }
+    command
+        .status()
+        .map_io_err_lazy(|| format!("Failed to run editor {}", editor.display()))
+}
@@ -0,0 +1,239 @@
+use std::{
+    fmt::{Debug, Formatter},
+    fs,
+    fs::File,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    io::Errno,
+    ioctl::{ReadOpcode, ReadWriteOpcode, Updater, ioctl},
+};
+
+use crate::{Error, IoErr, path_undo::TmpPath};
+
+const MARKER: &str = "encryption";
+
+/// A raw fscrypt master key used to encrypt a session's changes at rest.
+pub struct EncryptionKey(Box<[u8; Self::LEN]>);
+
+impl EncryptionKey {
+    pub const LEN: usize = 64;
+
+    /// Loads a key from a file containing exactly [`Self::LEN`] random bytes,
+    /// such as one created with `head -c 64 /dev/urandom`.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let bytes =
+            fs::read(path).map_io_err_lazy(|| format!("Failed to read key {}", path.display()))?;
+        let len = bytes.len();
+        let Ok(bytes) = <Box<[u8; Self::LEN]>>::try_from(bytes.into_boxed_slice()) else {
+            return Err(Error::InvalidArgument).attach_printable(format!(
+                "Key file {} must contain exactly {} bytes, found {len}.",
+                path.display(),
+                Self::LEN,
+            ));
+        };
+        Ok(Self(bytes))
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+#[repr(C)]
+struct KeySpecifier {
+    kind: u32,
+    reserved: u32,
+    identifier: [u8; 32],
+}
+
+impl KeySpecifier {
+    const TYPE_IDENTIFIER: u32 = 2;
+
+    fn new(identifier: [u8; 16]) -> Self {
+        let mut id = [0; 32];
+        id[..16].copy_from_slice(&identifier);
+        Self {
+            kind: Self::TYPE_IDENTIFIER,
+            reserved: 0,
+            identifier: id,
+        }
+    }
+}
+
+#[repr(C)]
+struct AddKeyHeader {
+    key_spec: KeySpecifier,
+    raw_size: u32,
+    key_id: u32,
+    reserved: [u32; 8],
+}
+
+#[repr(C)]
+struct AddKey {
+    header: AddKeyHeader,
+    raw: [u8; EncryptionKey::LEN],
+}
+
+#[repr(C)]
+struct RemoveKey {
+    key_spec: KeySpecifier,
+    removal_status_flags: u32,
+    reserved: [u32; 5],
+}
+
+#[repr(C)]
+struct PolicyV2 {
+    version: u8,
+    contents_encryption_mode: u8,
+    filenames_encryption_mode: u8,
+    flags: u8,
+    reserved: [u8; 4],
+    master_key_identifier: [u8; 16],
+}
+
+/// Returns whether the session was created with encryption enabled.
+pub fn is_encrypted(session: &mut PathBuf) -> bool {
+    TmpPath::new(session, MARKER).exists()
+}
+
+/// Encrypts a freshly created session's upper and work directories.
+pub fn setup(session: &mut PathBuf, key: &EncryptionKey) -> Result<(), Error> {
+    let identifier = add_key(session, key)?;
+    for dir in ["diff", "work"] {
+        let dir = TmpPath::new(session, dir);
+        set_policy(&dir, identifier)?;
+    }
+
+    let marker = TmpPath::new(session, MARKER);
+    fs::write(&marker, identifier)
+        .map_io_err_lazy(|| format!("Failed to write encryption marker {marker:?}"))
+}
+
+/// Makes an encrypted session's contents accessible.
+pub fn unlock(session: &mut PathBuf, key: &EncryptionKey) -> Result<(), Error> {
+    let expected = read_identifier(session)?;
+    let identifier = add_key(session, key)?;
+    if identifier == expected {
+        return Ok(());
+    }
+
+    remove_key(session, identifier)?;
+    Err(Error::InvalidArgument).attach_printable("The key does not match the session's key.")
+}
+
+/// Removes an encrypted session's key from the file system, making its contents
+/// inaccessible until the session is unlocked again.
+pub fn lock(session: &mut PathBuf) -> Result<(), Error> {
+    let identifier = read_identifier(session)?;
+    remove_key(session, identifier)
+}
+
+fn read_identifier(session: &mut PathBuf) -> Result<[u8; 16], Error> {
+    let marker = TmpPath::new(session, MARKER);
+    let identifier = fs::read(&marker)
+        .map_io_err_lazy(|| format!("Failed to read encryption marker {marker:?}"))?;
+    let Ok(identifier) = identifier.try_into() else {
+        return Err(Error::InvalidArgument)
+            .attach_printable_lazy(|| format!("Corrupted encryption marker {marker:?}"));
+    };
+    Ok(identifier)
+}
+
+fn add_key(dir: &Path, key: &EncryptionKey) -> Result<[u8; 16], Error> {
+    let mut arg = AddKey {
+        header: AddKeyHeader {
+            key_spec: KeySpecifier::new([0; 16]),
+            raw_size: u32::try_from(EncryptionKey::LEN).unwrap(),
+            key_id: 0,
+            reserved: [0; 8],
+        },
+        raw: *key.0,
+    };
+
+    let file = open_dir(dir)?;
+    // SAFETY: the argument layouts match the kernel's fscrypt_add_key_arg.
+    unsafe {
+        ioctl(
+            &file,
+            Updater::<ReadWriteOpcode<b'f', 23, AddKeyHeader>, _>::new(&mut arg),
+        )
+    }
+    .map_err(unsupported)
+    .map_io_err_lazy(|| format!("Failed to add encryption key to {}", dir.display()))?;
+    arg.raw.fill(0);
+
+    let mut identifier = [0; 16];
+    identifier.copy_from_slice(&arg.header.key_spec.identifier[..16]);
+    Ok(identifier)
+}
+
+fn remove_key(dir: &Path, identifier: [u8; 16]) -> Result<(), Error> {
+    let mut arg = RemoveKey {
+        key_spec: KeySpecifier::new(identifier),
+        removal_status_flags: 0,
+        reserved: [0; 5],
+    };
+
+    let file = open_dir(dir)?;
+    // SAFETY: the argument layout matches the kernel's fscrypt_remove_key_arg.
+    match unsafe {
+        ioctl(
+            &file,
+            Updater::<ReadWriteOpcode<b'f', 24, RemoveKey>, _>::new(&mut arg),
+        )
+    } {
+        // The key was never added or has already been removed
+        Err(Errno::NOKEY) => Ok(()),
+        r => {
+            r.map_io_err_lazy(|| format!("Failed to remove encryption key from {}", dir.display()))
+        }
+    }
+}
+
+fn set_policy(dir: &Path, identifier: [u8; 16]) -> Result<(), Error> {
+    let mut policy = PolicyV2 {
+        version: 2,
+        // AES-256-XTS for contents and AES-256-CTS for file names
+        contents_encryption_mode: 1,
+        filenames_encryption_mode: 4,
+        // Pad file names to 32 bytes
+        flags: 0x03,
+        reserved: [0; 4],
+        master_key_identifier: identifier,
+    };
+
+    let file = open_dir(dir)?;
+    // SAFETY: the kernel identifies the policy version from the first byte. The
+    // opcode is historically defined with the 12 byte v1 policy size.
+    unsafe {
+        ioctl(
+            &file,
+            Updater::<ReadOpcode<b'f', 19, [u8; 12]>, _>::new(&mut policy),
+        )
+    }
+    .map_err(unsupported)
+    .map_io_err_lazy(|| format!("Failed to encrypt directory {}", dir.display()))
+}
+
+fn open_dir(dir: &Path) -> Result<File, Error> {
+    File::open(dir).map_io_err_lazy(|| format!("Failed to open directory {}", dir.display()))
+}
+
+fn unsupported(e: Errno) -> std::io::Error {
+    let e = std::io::Error::from(e);
+    if e.kind() == ErrorKind::Unsupported {
+        std::io::Error::new(
+            ErrorKind::Unsupported,
+            "the sessions file system does not support encryption (for ext4, enable it with \
+             `tune2fs -O encrypt`)",
+        )
+    } else {
+        e
+    }
+}
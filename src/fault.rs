@@ -0,0 +1,47 @@
+//! Deliberate failures at points where forkfs would otherwise leave a session
+//! half set up or half torn down, so that recovery from them can be tested.
+
+use std::cell::Cell;
+
+use error_stack::{Result, ResultExt};
+
+use crate::Error;
+
+/// Where to fail.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FaultPoint {
+    /// After the overlay is mounted but before the system directories are
+    /// bound into it.
+    AfterMount,
+    /// While stopping a session, after its bind mounts were removed but before
+    /// the overlay itself is unmounted.
+    BeforeUnmount,
+    /// While applying a session, after its changes were copied next to their
+    /// destinations but before any of them replaced a real file.
+    MidCopy,
+    /// While applying a session, after the real entries it deletes were moved
+    /// aside but before any staged entry replaced a real one.
+    MidCommit,
+}
+
+thread_local! {
+    static FAULT: Cell<Option<FaultPoint>> = const { Cell::new(None) };
+}
+
+/// Makes subsequent operations on this thread fail at `point`, or stops
+/// injecting faults if `None`.
+pub fn inject_fault(point: Option<FaultPoint>) {
+    FAULT.set(point);
+}
+
+/// The fault being injected on this thread.
+pub fn injected() -> Option<FaultPoint> {
+    FAULT.get()
+}
+
+pub fn check(point: FaultPoint) -> Result<(), Error> {
+    if FAULT.get() == Some(point) {
+        return Err(Error::Io).attach_printable(format!("Injected fault at {point:?}"));
+    }
+    Ok(())
+}
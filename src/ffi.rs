@@ -0,0 +1,306 @@
+//! C bindings for embedding session management in non-Rust programs.
+//!
+//! Every function returns `0` on success and `-1` on failure, in which case
+//! [`forkfs_last_error`] describes what went wrong. See `include/forkfs.h` for
+//! the corresponding declarations.
+//!
+//! Lists of changes are handed over as arrays allocated here, which callers
+//! give back to [`forkfs_changes_free`] once they are done with them.
+
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString, OsStr, c_char, c_int},
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::PathBuf,
+    ptr,
+};
+
+use error_stack::{AttachmentKind, FrameKind, Report, Result, ResultExt};
+use rustix::process::getuid;
+
+use crate::{
+    Backend, Change, ChangeKind, Error, RunOp, SessionOperand, apply_session, delete_sessions,
+    get_sessions_dir,
+    run::validate_permissions,
+    session_diff,
+    sessions::{mark_used, maybe_create_session, resolve_alias},
+    stop_sessions,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Returns a description of the last error that occurred on this thread, or
+/// `NULL` if none has.
+///
+/// The string remains valid until the next failing call on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn forkfs_last_error() -> *const c_char {
+    LAST_ERROR.with_borrow(|error| error.as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Creates the session if necessary and activates it without running
+/// anything in it.
+///
+/// # Safety
+///
+/// `session` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn forkfs_create(session: *const c_char) -> c_int {
+    // SAFETY: upheld by the caller.
+    report(unsafe { session_name(session) }.and_then(|session| {
+        validate_permissions(getuid())?;
+
+        let mut session_dir = get_sessions_dir()?;
+        session_dir.push(resolve_alias(session)?);
+        maybe_create_session(&mut session_dir, None, Backend::Auto)?;
+        mark_used(&mut session_dir)
+    }))
+}
+
+/// Runs a command in the session, creating and activating it if necessary.
+///
+/// Like [`crate::run`], this replaces the current process with the command
+/// and therefore only returns on failure. Fork first to keep the calling
+/// program alive.
+///
+/// # Safety
+///
+/// `session` must be a valid NUL-terminated string and `argv` a
+/// `NULL`-terminated array of at least one valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn forkfs_run(session: *const c_char, argv: *const *const c_char) -> c_int {
+    // SAFETY: upheld by the caller.
+    report(unsafe { session_name(session) }.and_then(|session| {
+        // SAFETY: upheld by the caller.
+        let command = unsafe { arguments(argv) };
+        if command.is_empty() {
+            return Err(Error::InvalidArgument).attach_printable("No command to run.");
+        }
+        RunOp::builder()
+            .session(session)
+            .command(command.as_slice())
+            .build()
+            .run()
+            .map(drop)
+    }))
+}
+
+/// Unmounts the session if it is active.
+///
+/// # Safety
+///
+/// `session` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn forkfs_stop(session: *const c_char) -> c_int {
+    // SAFETY: upheld by the caller.
+    report(
+        unsafe { session_name(session) }
+            .and_then(|session| stop_sessions(SessionOperand::List(&[session]))),
+    )
+}
+
+/// Stops and deletes the session.
+///
+/// # Safety
+///
+/// `session` must be a valid NUL-terminated string.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn forkfs_delete(session: *const c_char) -> c_int {
+    // SAFETY: upheld by the caller.
+    report(
+        unsafe { session_name(session) }
+            .and_then(|session| delete_sessions(SessionOperand::List(&[session]), false)),
+    )
+}
+
+/// The kind of a [`ForkfsChange`], mirroring [`ChangeKind`].
+#[repr(C)]
+pub enum ForkfsChangeKind {
+    Added,
+    Modified,
+    Deleted,
+    MetadataOnly,
+}
+
+/// A change in an array returned by [`forkfs_diff`].
+#[repr(C)]
+pub struct ForkfsChange {
+    /// The changed path as seen from inside the session.
+    pub path: *mut c_char,
+    pub kind: ForkfsChangeKind,
+}
+
+/// Lists the changes the session made to the real file system, sorted by
+/// path.
+///
+/// On success, `*changes` points to an array of `*len` changes that must be
+/// released with [`forkfs_changes_free`].
+///
+/// # Safety
+///
+/// `session` must be a valid NUL-terminated string, and `changes` and `len`
+/// valid pointers.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn forkfs_diff(
+    session: *const c_char,
+    changes: *mut *mut ForkfsChange,
+    len: *mut usize,
+) -> c_int {
+    // SAFETY: upheld by the caller.
+    report(
+        unsafe { session_name(session) }
+            .and_then(session_diff)
+            // SAFETY: upheld by the caller.
+            .and_then(|diff| unsafe { hand_over(diff, changes, len) }),
+    )
+}
+
+/// Replays the session's changes onto the real file system like
+/// [`crate::apply_session`], emptying the session.
+///
+/// `allowed` is `NULL` or a `NULL`-terminated array of paths at or below which
+/// changes flagged as risky are applied anyway, and a non-zero `force`
+/// overwrites files that were also changed outside the session. On success,
+/// `*changes` points to an array of the `*len` changes that were applied,
+/// which must be released with [`forkfs_changes_free`].
+///
+/// # Safety
+///
+/// `session` must be a valid NUL-terminated string, `allowed` `NULL` or a
+/// `NULL`-terminated array of valid NUL-terminated strings, and `changes` and
+/// `len` valid pointers.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn forkfs_apply(
+    session: *const c_char,
+    allowed: *const *const c_char,
+    force: c_int,
+    changes: *mut *mut ForkfsChange,
+    len: *mut usize,
+) -> c_int {
+    // SAFETY: upheld by the caller.
+    report(unsafe { session_name(session) }.and_then(|session| {
+        // SAFETY: upheld by the caller.
+        let allowed = unsafe { arguments(allowed) }
+            .into_iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        let applied = apply_session(session, &allowed, force != 0, false)?;
+        // SAFETY: upheld by the caller.
+        unsafe { hand_over(applied, changes, len) }
+    }))
+}
+
+/// Releases an array of changes returned by [`forkfs_diff`] or
+/// [`forkfs_apply`].
+///
+/// # Safety
+///
+/// `changes` must be `NULL` or an array returned by this library along with
+/// its `len`, and must not be used afterwards.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn forkfs_changes_free(changes: *mut ForkfsChange, len: usize) {
+    if changes.is_null() {
+        return;
+    }
+    // SAFETY: upheld by the caller.
+    let changes = unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(changes, len)) };
+    for change in changes {
+        // SAFETY: the path was allocated by `hand_over`.
+        drop(unsafe { CString::from_raw(change.path) });
+    }
+}
+
+/// Moves changes into an array owned by the caller.
+///
+/// # Safety
+///
+/// `changes` and `len` must be `NULL` or valid pointers.
+unsafe fn hand_over(
+    diff: Vec<Change>,
+    changes: *mut *mut ForkfsChange,
+    len: *mut usize,
+) -> Result<(), Error> {
+    if changes.is_null() || len.is_null() {
+        return Err(Error::InvalidArgument).attach_printable("The output pointers are NULL.");
+    }
+    let array = diff
+        .into_iter()
+        .map(|Change { path, kind, .. }| ForkfsChange {
+            // Paths never contain NUL bytes
+            path: CString::new(path.into_os_string().into_vec())
+                .unwrap_or_default()
+                .into_raw(),
+            kind: match kind {
+                ChangeKind::Added => ForkfsChangeKind::Added,
+                ChangeKind::Modified => ForkfsChangeKind::Modified,
+                ChangeKind::Deleted => ForkfsChangeKind::Deleted,
+                ChangeKind::MetadataOnly => ForkfsChangeKind::MetadataOnly,
+            },
+        })
+        .collect::<Box<[_]>>();
+    // SAFETY: upheld by the caller.
+    unsafe {
+        *len = array.len();
+        *changes = Box::into_raw(array).cast();
+    }
+    Ok(())
+}
+
+/// # Safety
+///
+/// `session` must be `NULL` or a valid NUL-terminated string.
+unsafe fn session_name<'a>(session: *const c_char) -> Result<&'a OsStr, Error> {
+    if session.is_null() {
+        return Err(Error::InvalidArgument).attach_printable("The session name is NULL.");
+    }
+    // SAFETY: upheld by the caller.
+    Ok(OsStr::from_bytes(
+        unsafe { CStr::from_ptr(session) }.to_bytes(),
+    ))
+}
+
+/// # Safety
+///
+/// `argv` must be `NULL` or a `NULL`-terminated array of valid NUL-terminated
+/// strings.
+unsafe fn arguments<'a>(mut argv: *const *const c_char) -> Vec<&'a OsStr> {
+    let mut arguments = Vec::new();
+    if argv.is_null() {
+        return arguments;
+    }
+    // SAFETY: upheld by the caller.
+    unsafe {
+        while !(*argv).is_null() {
+            arguments.push(OsStr::from_bytes(CStr::from_ptr(*argv).to_bytes()));
+            argv = argv.add(1);
+        }
+    }
+    arguments
+}
+
+fn report(result: Result<(), Error>) -> c_int {
+    let Err(report) = result else {
+        return 0;
+    };
+    let message = describe(&report).replace('\0', "");
+    LAST_ERROR.set(CString::new(message).ok());
+    -1
+}
+
+/// Flattens a report into its contexts and printable attachments, one per
+/// line and outermost first.
+fn describe(report: &Report<Error>) -> String {
+    report
+        .frames()
+        .filter_map(|frame| match frame.kind() {
+            FrameKind::Context(context) => Some(context.to_string()),
+            FrameKind::Attachment(AttachmentKind::Printable(attachment)) => {
+                Some(attachment.to_string())
+            }
+            FrameKind::Attachment(_) => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
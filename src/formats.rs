@@ -0,0 +1,546 @@
+//! Summaries of changes to files in well-known formats.
+//!
+//! A line-based diff of a JSON document or a binary diff of a database says
+//! little about what a sandboxed program actually changed. For formats forkfs
+//! understands, the old and new versions are compared structurally instead:
+//!
+//! - JSON documents by key, named by their path like `.server.ports[0]`.
+//! - INI files by key within each section.
+//! - sqlite databases by the number of rows in each table. Changes still in a
+//!   write-ahead log next to the database aren't counted.
+//! - Tar archives by member.
+//!
+//! Added files are compared with an empty document of the same format.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::{Display, Write},
+    path::Path,
+};
+
+use serde_json::Value;
+
+use crate::ChangeKind;
+
+/// The most lines a single file's summary is cut down to.
+const MAX_LINES: usize = 20;
+/// Values longer than this many characters are elided.
+const MAX_VALUE: usize = 60;
+
+/// One line of a summary.
+#[derive(Debug)]
+pub struct Line {
+    pub kind: ChangeKind,
+    pub text: String,
+}
+
+/// Compares the two versions of a file, returning `None` if it isn't in a
+/// known format. `old` is `None` for added files.
+pub fn summarize(path: &Path, old: Option<&[u8]>, new: &[u8]) -> Option<Vec<Line>> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default();
+    let mut lines = if is_sqlite(new) && old.is_none_or(is_sqlite) {
+        sqlite(old, new)?
+    } else if is_tar(new) && old.is_none_or(is_tar) {
+        tar(old, new)?
+    } else if extension.eq_ignore_ascii_case("json") {
+        json(old, new)?
+    } else if ["ini", "cfg", "desktop"]
+        .iter()
+        .any(|known| extension.eq_ignore_ascii_case(known))
+    {
+        ini(old, new)?
+    } else {
+        return None;
+    };
+
+    truncate(&mut lines, MAX_LINES);
+    Some(lines)
+}
+
+/// Cuts `lines` down to `max` lines, the last of which says how many were
+/// left out.
+pub fn truncate(lines: &mut Vec<Line>, max: usize) {
+    if lines.len() > max {
+        let more = lines.len() - (max - 1);
+        lines.truncate(max - 1);
+        lines.push(Line {
+            kind: ChangeKind::Modified,
+            text: format!("… and {more} more"),
+        });
+    }
+}
+
+fn added(text: impl Display) -> Line {
+    Line {
+        kind: ChangeKind::Added,
+        text: format!("+ {text}"),
+    }
+}
+
+fn modified(text: impl Display) -> Line {
+    Line {
+        kind: ChangeKind::Modified,
+        text: format!("~ {text}"),
+    }
+}
+
+fn deleted(text: impl Display) -> Line {
+    Line {
+        kind: ChangeKind::Deleted,
+        text: format!("- {text}"),
+    }
+}
+
+fn elide(mut value: String) -> String {
+    if let Some((end, _)) = value.char_indices().nth(MAX_VALUE) {
+        value.truncate(end);
+        value.push('…');
+    }
+    value
+}
+
+/// Lines up two sorted maps, calling `f` for every key along with its old and
+/// new value.
+fn compare<K: Ord, V>(
+    old: BTreeMap<K, V>,
+    mut new: BTreeMap<K, V>,
+    mut f: impl FnMut(&K, Option<V>, Option<V>),
+) {
+    for (key, old) in old {
+        let new = new.remove(&key);
+        f(&key, Some(old), new);
+    }
+    for (key, new) in new {
+        f(&key, None, Some(new));
+    }
+}
+
+// JSON
+
+fn json(old: Option<&[u8]>, new: &[u8]) -> Option<Vec<Line>> {
+    let new = serde_json::from_slice::<Value>(new).ok()?;
+    let old = match old {
+        Some(old) => serde_json::from_slice(old).ok()?,
+        None if new.is_array() => Value::Array(Vec::new()),
+        None => Value::Object(serde_json::Map::new()),
+    };
+    let mut lines = Vec::new();
+    json_values(&mut String::new(), &old, &new, &mut lines);
+    Some(lines)
+}
+
+fn json_values(path: &mut String, old: &Value, new: &Value, lines: &mut Vec<Line>) {
+    let len = path.len();
+    let name = |path: &str| {
+        if path.is_empty() {
+            ".".to_owned()
+        } else {
+            path.to_owned()
+        }
+    };
+    match (old, new) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, old) in old {
+                json_key(path, key);
+                match new.get(key) {
+                    Some(new) => json_values(path, old, new, lines),
+                    None => lines.push(deleted(path.clone())),
+                }
+                path.truncate(len);
+            }
+            for (key, new) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+                json_key(path, key);
+                lines.push(added(format!("{path}: {}", elide(new.to_string()))));
+                path.truncate(len);
+            }
+        }
+        (Value::Array(old), Value::Array(new)) => {
+            for (i, old) in old.iter().enumerate() {
+                let _ = write!(path, "[{i}]");
+                match new.get(i) {
+                    Some(new) => json_values(path, old, new, lines),
+                    None => lines.push(deleted(path.clone())),
+                }
+                path.truncate(len);
+            }
+            for (i, new) in new.iter().enumerate().skip(old.len()) {
+                let _ = write!(path, "[{i}]");
+                lines.push(added(format!("{path}: {}", elide(new.to_string()))));
+                path.truncate(len);
+            }
+        }
+        _ if old == new => {}
+        _ => lines.push(modified(format!(
+            "{}: {} -> {}",
+            name(path),
+            elide(old.to_string()),
+            elide(new.to_string())
+        ))),
+    }
+}
+
+fn json_key(path: &mut String, key: &str) {
+    let identifier = key
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if identifier {
+        path.push('.');
+        path.push_str(key);
+    } else {
+        let _ = write!(path, "[{}]", Value::from(key));
+    }
+}
+
+// INI
+
+fn ini(old: Option<&[u8]>, new: &[u8]) -> Option<Vec<Line>> {
+    let new = ini_keys(std::str::from_utf8(new).ok()?);
+    let old = match old {
+        Some(old) => ini_keys(std::str::from_utf8(old).ok()?),
+        None => BTreeMap::new(),
+    };
+    let mut lines = Vec::new();
+    compare(old, new, |(section, key), old, new| {
+        let name = if section.is_empty() {
+            key.clone()
+        } else {
+            format!("[{section}] {key}")
+        };
+        match (old, new) {
+            (Some(old), Some(new)) if old == new => {}
+            (Some(old), Some(new)) => lines.push(modified(format!(
+                "{name}: {} -> {}",
+                elide(old),
+                elide(new)
+            ))),
+            (Some(_), None) => lines.push(deleted(name)),
+            (None, new) => lines.push(added(format!(
+                "{name} = {}",
+                elide(new.unwrap_or_default())
+            ))),
+        }
+    });
+    Some(lines)
+}
+
+/// Returns the value of every key by section. Later keys override earlier
+/// ones, as most INI parsers do.
+fn ini_keys(contents: &str) -> BTreeMap<(String, String), String> {
+    let mut keys = BTreeMap::new();
+    let mut section = "";
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with([';', '#']) {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim();
+        } else if let Some((key, value)) = line.split_once('=') {
+            keys.insert(
+                (section.to_owned(), key.trim().to_owned()),
+                value.trim().to_owned(),
+            );
+        }
+    }
+    keys
+}
+
+// sqlite, see https://www.sqlite.org/fileformat.html
+
+fn is_sqlite(contents: &[u8]) -> bool {
+    contents.starts_with(b"SQLite format 3\0")
+}
+
+fn sqlite(old: Option<&[u8]>, new: &[u8]) -> Option<Vec<Line>> {
+    let new = Database::new(new)?.tables()?;
+    let old = match old {
+        Some(old) => Database::new(old)?.tables()?,
+        None => BTreeMap::new(),
+    };
+    let rows = |count: u64| format!("{count} row{}", if count == 1 { "" } else { "s" });
+    let mut lines = Vec::new();
+    compare(old, new, |table, old, new| match (old, new) {
+        (Some(old), Some(new)) if old == new => {}
+        (Some(old), Some(new)) => {
+            lines.push(modified(format!("table {table}: {old} -> {}", rows(new))));
+        }
+        (Some(_), None) => lines.push(deleted(format!("table {table}"))),
+        (None, new) => lines.push(added(format!(
+            "table {table}: {}",
+            rows(new.unwrap_or_default())
+        ))),
+    });
+    Some(lines)
+}
+
+struct Database<'a> {
+    contents: &'a [u8],
+    page_size: usize,
+    usable: usize,
+}
+
+/// The value of a record column.
+enum Column<'a> {
+    Int(i64),
+    Text(&'a [u8]),
+    Other,
+}
+
+impl<'a> Database<'a> {
+    fn new(contents: &'a [u8]) -> Option<Self> {
+        let page_size = match u16::from_be_bytes(contents.get(16..18)?.try_into().ok()?) {
+            1 => 1 << 16,
+            size => usize::from(size),
+        };
+        let reserved = usize::from(*contents.get(20)?);
+        (page_size >= 512).then_some(Self {
+            contents,
+            page_size,
+            usable: page_size.checked_sub(reserved)?,
+        })
+    }
+
+    /// Counts the rows of every table named in the schema table.
+    fn tables(&self) -> Option<BTreeMap<String, u64>> {
+        let mut schema = Vec::new();
+        self.walk(1, &mut HashSet::new(), &mut |payload| schema.push(payload))?;
+        let mut tables = BTreeMap::new();
+        for payload in schema {
+            // Rows of the schema table: type, name, tbl_name, rootpage, sql
+            let columns = record(payload)?;
+            let (Some(Column::Text(b"table")), Some(Column::Text(name)), Some(&Column::Int(root))) =
+                (columns.first(), columns.get(1), columns.get(3))
+            else {
+                continue;
+            };
+            // Virtual tables have no pages of their own
+            let Ok(root @ 1..) = u32::try_from(root) else {
+                continue;
+            };
+            let mut rows = 0;
+            self.walk(root, &mut HashSet::new(), &mut |_| rows += 1)?;
+            tables.insert(String::from_utf8_lossy(name).into_owned(), rows);
+        }
+        Some(tables)
+    }
+
+    /// Calls `f` with the locally stored payload of every entry in the b-tree
+    /// rooted at `page`. Overflow pages aren't followed.
+    fn walk(&self, page: u32, seen: &mut HashSet<u32>, f: &mut impl FnMut(&'a [u8])) -> Option<()> {
+        if !seen.insert(page) {
+            return None;
+        }
+        let start = usize::try_from(page).ok()?.checked_sub(1)? * self.page_size;
+        let data = self.contents.get(start..start + self.page_size)?;
+        let header = if page == 1 { 100 } else { 0 };
+        let kind = *data.get(header)?;
+        let cells = usize::from(u16::from_be_bytes(
+            data.get(header + 3..header + 5)?.try_into().ok()?,
+        ));
+        let interior = matches!(kind, 0x02 | 0x05);
+        let pointers = header + if interior { 12 } else { 8 };
+        for i in 0..cells {
+            let at = pointers + 2 * i;
+            let cell = usize::from(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?));
+            let mut cell = data.get(cell..)?;
+            if interior {
+                let child = u32::from_be_bytes(cell.get(..4)?.try_into().ok()?);
+                self.walk(child, seen, f)?;
+                cell = &cell[4..];
+            }
+            match kind {
+                // Table leaves: payload size, rowid, payload
+                0x0d => {
+                    let (size, cell) = varint(cell)?;
+                    let (_, cell) = varint(cell)?;
+                    f(cell.get(..self.local_size(size, true).min(cell.len()))?);
+                }
+                // Index entries, which interior pages hold as well
+                0x02 | 0x0a => {
+                    let (size, cell) = varint(cell)?;
+                    f(cell.get(..self.local_size(size, false).min(cell.len()))?);
+                }
+                // Interior table cells only hold keys
+                0x05 => {}
+                _ => return None,
+            }
+        }
+        if interior {
+            let right = u32::from_be_bytes(data.get(header + 8..header + 12)?.try_into().ok()?);
+            self.walk(right, seen, f)?;
+        }
+        Some(())
+    }
+
+    /// How much of a payload is stored in the b-tree page rather than on
+    /// overflow pages.
+    fn local_size(&self, payload: u64, table: bool) -> usize {
+        let usable = self.usable;
+        let max = if table {
+            usable - 35
+        } else {
+            (usable - 12) * 64 / 255 - 23
+        };
+        let min = (usable - 12) * 32 / 255 - 23;
+        match usize::try_from(payload) {
+            Ok(payload) if payload <= max => payload,
+            _ => {
+                let rest = (payload - min as u64) % (usable as u64 - 4);
+                let local = min + usize::try_from(rest).unwrap_or(usize::MAX);
+                if local <= max { local } else { min }
+            }
+        }
+    }
+}
+
+/// Decodes the columns of a record that are stored locally.
+fn record(payload: &[u8]) -> Option<Vec<Column<'_>>> {
+    let (header_size, _) = varint(payload)?;
+    let header_size = usize::try_from(header_size).ok()?;
+    let (mut header, mut body) = (payload.get(..header_size)?, payload.get(header_size..)?);
+    header = varint(header)?.1;
+    let mut columns = Vec::new();
+    while !header.is_empty() {
+        let (serial, rest) = varint(header)?;
+        header = rest;
+        let size = match serial {
+            0 | 8 | 9 => 0,
+            1..=4 => usize::try_from(serial).ok()?,
+            5 => 6,
+            6 | 7 => 8,
+            12.. => usize::try_from((serial - 12) / 2).ok()?,
+            _ => return None,
+        };
+        let Some(value) = body.get(..size) else {
+            break;
+        };
+        body = &body[size..];
+        columns.push(match serial {
+            1..=6 => {
+                let mut int = i64::from(value[0].cast_signed());
+                for &byte in &value[1..] {
+                    int = int << 8 | i64::from(byte);
+                }
+                Column::Int(int)
+            }
+            8 => Column::Int(0),
+            9 => Column::Int(1),
+            13.. if serial % 2 == 1 => Column::Text(value),
+            _ => Column::Other,
+        });
+    }
+    Some(columns)
+}
+
+/// Decodes one of sqlite's big-endian variable-length integers.
+fn varint(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let mut value = 0;
+    for (i, &byte) in bytes.iter().enumerate().take(9) {
+        if i == 8 {
+            return Some((value << 8 | u64::from(byte), &bytes[9..]));
+        }
+        value = value << 7 | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+    }
+    None
+}
+
+// Tar
+
+fn is_tar(contents: &[u8]) -> bool {
+    contents.get(257..262) == Some(b"ustar")
+}
+
+fn tar(old: Option<&[u8]>, new: &[u8]) -> Option<Vec<Line>> {
+    let new = tar_members(new)?;
+    let old = match old {
+        Some(old) => tar_members(old)?,
+        None => BTreeMap::new(),
+    };
+    let bytes = |data: &[u8]| format!("{} bytes", data.len());
+    let mut lines = Vec::new();
+    compare(old, new, |name, old, new| match (old, new) {
+        (Some(old), Some(new)) if old == new => {}
+        (Some(old), Some(new)) if old.len() == new.len() => {
+            lines.push(modified(name.clone()));
+        }
+        (Some(old), Some(new)) => {
+            lines.push(modified(format!("{name}: {} -> {}", old.len(), bytes(new))));
+        }
+        (Some(_), None) => lines.push(deleted(name.clone())),
+        (None, new) => lines.push(added(format!(
+            "{name} ({})",
+            bytes(new.unwrap_or_default())
+        ))),
+    });
+    Some(lines)
+}
+
+/// Returns the contents of every member of a tar archive by name, including
+/// long names from GNU and pax extension headers.
+fn tar_members(mut archive: &[u8]) -> Option<BTreeMap<String, &[u8]>> {
+    let mut members = BTreeMap::new();
+    let mut long_name = None;
+    while let Some(header) = archive.get(..512) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = tar_size(&header[124..136])?;
+        let data = archive.get(512..512 + size)?;
+        archive = archive
+            .get((512 + size).next_multiple_of(512)..)
+            .unwrap_or_default();
+        match header[156] {
+            b'L' => long_name = Some(String::from_utf8_lossy(field(data)).into_owned()),
+            b'x' => {
+                long_name = String::from_utf8_lossy(data).lines().find_map(|record| {
+                    Some(record.split_once(' ')?.1.strip_prefix("path=")?.to_owned())
+                });
+            }
+            b'g' => {}
+            _ => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let (prefix, name) = (field(&header[345..500]), field(&header[..100]));
+                    let mut path = String::from_utf8_lossy(prefix).into_owned();
+                    if !path.is_empty() {
+                        path.push('/');
+                    }
+                    path.push_str(&String::from_utf8_lossy(name));
+                    path
+                });
+                members.insert(name, data);
+            }
+        }
+    }
+    Some(members)
+}
+
+/// A header field up to its first NUL.
+fn field(bytes: &[u8]) -> &[u8] {
+    bytes.split(|&b| b == 0).next().unwrap_or_default()
+}
+
+/// Parses a member's size, which is either octal or, for large members, a
+/// big-endian number flagged by the top bit.
+fn tar_size(field: &[u8]) -> Option<usize> {
+    if field[0] & 0x80 != 0 {
+        let size = field[1..]
+            .iter()
+            .fold(u64::from(field[0] & 0x7f), |size, &b| {
+                size << 8 | u64::from(b)
+            });
+        return usize::try_from(size).ok();
+    }
+    let digits = self::field(field);
+    let digits = std::str::from_utf8(digits).ok()?.trim();
+    if digits.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(digits, 8).ok()
+}
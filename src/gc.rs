@@ -0,0 +1,155 @@
+//! Reclaiming the space taken up by state nothing needs anymore.
+//!
+//! Besides sessions themselves, forkfs keeps snapshots, the logs of detached
+//! commands, and the chunks of stored archives. Operations that are
+//! interrupted, such as clones, imports, and snapshots, leave their partial
+//! results behind under hidden names ending in the ID of the process that made
+//! them, so they can be told apart from those still in progress.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirEntryExt2, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use error_stack::Result;
+
+use crate::{
+    Config, Error, IoErr, clock, detach, disk_usage, get_sessions_dir,
+    path_undo::TmpPath,
+    scheduler,
+    sessions::{delete_session, session_names},
+    snapshot, store,
+};
+
+/// Something [`collect()`] found that nothing needs anymore.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Garbage {
+    pub path: PathBuf,
+    pub kind: GarbageKind,
+    /// The space it takes up, in bytes.
+    pub size: u64,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GarbageKind {
+    /// The partial result of an interrupted operation.
+    Leftover,
+    /// An untagged snapshot the config file's retention policy no longer
+    /// keeps.
+    ExpiredSnapshot,
+    /// A chunk none of a store's archives use anymore.
+    UnusedChunk,
+}
+
+/// Deletes what interrupted operations left behind and the snapshots past the
+/// retention policy, returning what was deleted.
+///
+/// Given a store, its unused chunks are deleted too. Whole sessions are never
+/// deleted.
+///
+/// With `dry_run`, nothing is deleted and only what would be is returned.
+pub fn collect(store: Option<&Path>, dry_run: bool) -> Result<Vec<Garbage>, Error> {
+    let retention = Config::load()?.retention;
+    let now = clock::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let mut garbage = Vec::new();
+    let mut sessions_dir = get_sessions_dir()?;
+    leftovers(&sessions_dir, &mut garbage)?;
+    for name in session_names()? {
+        let mut session = TmpPath::new(&mut sessions_dir, name);
+        leftovers(&session, &mut garbage)?;
+        for dir in [snapshot::SNAPSHOTS, detach::LOGS] {
+            leftovers(&TmpPath::new(&mut session, dir), &mut garbage)?;
+        }
+        for path in snapshot::expired(&mut session, retention, now)? {
+            garbage.push(Garbage {
+                size: size(&path)?,
+                path,
+                kind: GarbageKind::ExpiredSnapshot,
+            });
+        }
+    }
+    if !dry_run {
+        scheduler::run_all(
+            garbage
+                .iter()
+                .map(|Garbage { path, .. }| || remove(path))
+                .collect(),
+        )?;
+    }
+
+    if let Some(store) = store {
+        garbage.extend(scheduler::background(|| store::collect(store, dry_run))?);
+    }
+    Ok(garbage)
+}
+
+/// Finds the entries of `dir` left behind by processes that are gone.
+fn leftovers(dir: &Path, garbage: &mut Vec<Garbage>) -> Result<(), Error> {
+    let entries = match fs::read_dir(dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        r => r.map_io_err_lazy(|| format!("Failed to open directory {}", dir.display()))?,
+    };
+    for entry in entries {
+        let entry =
+            entry.map_io_err_lazy(|| format!("Failed to read directory {}", dir.display()))?;
+        let Some(pid) = owner(entry.file_name_ref().as_bytes()) else {
+            continue;
+        };
+        if Path::new("/proc").join(pid).exists() {
+            continue;
+        }
+        let path = entry.path();
+        garbage.push(Garbage {
+            size: size(&path)?,
+            path,
+            kind: GarbageKind::Leftover,
+        });
+    }
+    Ok(())
+}
+
+/// Returns the ID of the process a hidden entry is named after, such as
+/// `.name.clone-1234` or a detached command's pending `.1234.log`.
+fn owner(name: &[u8]) -> Option<&str> {
+    let name = name.strip_prefix(b".")?;
+    let pid = [b".log".as_slice(), b".restarts"]
+        .into_iter()
+        .find_map(|extension| name.strip_suffix(extension))
+        .or_else(|| Some(&name[name.iter().rposition(|&b| b == b'-')? + 1..]))?;
+    if pid.is_empty() || !pid.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(pid).ok()
+}
+
+fn size(path: &Path) -> Result<u64, Error> {
+    let metadata = fs::symlink_metadata(path)
+        .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+    let size = metadata.blocks() * 512;
+    Ok(if metadata.is_dir() {
+        size + disk_usage(path)?
+    } else {
+        size
+    })
+}
+
+fn remove(path: &Path) -> Result<(), Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        r => r.map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?,
+    };
+    if metadata.is_dir() {
+        delete_session(path)
+    } else {
+        fs::remove_file(path).map_io_err_lazy(|| format!("Failed to delete {}", path.display()))
+    }
+}
@@ -0,0 +1,143 @@
+//! Sessions driven from other programs.
+//!
+//! A [`Session`] names a session the way the command line does, and
+//! [activating](Session::activate) it returns a guard that stops the session
+//! again once dropped, so that a program embedding forkfs doesn't leave mounts
+//! behind when it bails out early. The guard can also move the calling thread
+//! into the session, after which its file system changes are captured like
+//! those of `forkfs run`.
+
+use std::{
+    ffi::{OsStr, OsString},
+    mem,
+    path::PathBuf,
+};
+
+use error_stack::Result;
+use rustix::{
+    process::{Uid, getuid},
+    thread::set_thread_uid,
+};
+
+use crate::{
+    Backend, Error, IoErr, Network, SessionOperand, get_sessions_dir,
+    run::{Namespace, validate_permissions},
+    sessions::{mark_used, maybe_create_session, resolve_alias, validate_name},
+    stop_sessions, subtree, system_dirs,
+};
+
+/// A session, which need not exist until it is first activated.
+pub struct Session {
+    name: OsString,
+    dir: PathBuf,
+    uid: Uid,
+}
+
+/// An active [`Session`] that is stopped when dropped, unless processes still
+/// use it.
+#[must_use = "the session is stopped as soon as the guard is dropped"]
+pub struct ActiveSession<'a> {
+    session: &'a Session,
+}
+
+impl Session {
+    /// Refers to the session called `name`, or to the most or second most
+    /// recently used one for `@last` and `@prev`.
+    ///
+    /// This checks that forkfs has the privileges it needs, switching the
+    /// calling thread to root if it has them through file capabilities.
+    pub fn open(name: impl AsRef<OsStr>) -> Result<Self, Error> {
+        let uid = getuid();
+        validate_permissions(uid)?;
+        let name = resolve_alias(name.as_ref())?.into_owned();
+        validate_name(&name)?;
+        let mut dir = get_sessions_dir()?;
+        dir.push(&name);
+        Ok(Self { name, dir, uid })
+    }
+
+    /// The session's name, with aliases resolved.
+    #[must_use]
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// Mounts the session, creating it first if it doesn't exist.
+    ///
+    /// Sessions that are already active are left as they are, but are still
+    /// stopped once the returned guard is dropped.
+    pub fn activate(&self) -> Result<ActiveSession<'_>, Error> {
+        let mut dir = self.dir.clone();
+        maybe_create_session(&mut dir, None, Backend::Auto)?;
+        mark_used(&mut dir)?;
+        Ok(ActiveSession { session: self })
+    }
+
+    /// Unmounts the session, refusing to while processes still use it.
+    pub fn stop(&self) -> Result<(), Error> {
+        stop_sessions(SessionOperand::List(&[&self.name]))
+    }
+}
+
+impl ActiveSession<'_> {
+    /// The session this guard keeps active.
+    #[must_use]
+    pub const fn session(&self) -> &Session {
+        self.session
+    }
+
+    /// Where the session's view of the file system is mounted.
+    #[must_use]
+    pub fn root(&self) -> PathBuf {
+        self.session.dir.join("merged")
+    }
+
+    /// Moves the calling thread into the session, in the same current
+    /// directory as before, and gives up the privileges [`Session::open`]
+    /// acquired.
+    ///
+    /// Other threads are left outside. The session then stays active since
+    /// the thread can no longer reach it to stop it.
+    pub fn enter(self) -> Result<(), Error> {
+        let mut dir = self.session.dir.clone();
+        let merged = self.root();
+        Namespace::new(
+            &merged,
+            &subtree::get(&mut dir)?,
+            &system_dirs::get(&mut dir)?,
+            &[],
+            false,
+            Network::Host,
+            None,
+        )?
+        .enter()
+        .map_io_err_lazy(|| format!("Failed to enter session {}", merged.display()))?;
+        let uid = self.session.uid;
+        mem::forget(self);
+        if !uid.is_root() {
+            set_thread_uid(uid).map_io_err("Failed to drop privileges")?;
+        }
+        Ok(())
+    }
+
+    /// Stops the session now, reporting why it couldn't be stopped instead of
+    /// ignoring it like dropping the guard does.
+    pub fn stop(self) -> Result<(), Error> {
+        let session = self.session;
+        mem::forget(self);
+        session.stop()
+    }
+}
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        drop(self.session.stop());
+    }
+}
+
+/// Lets sessions be passed to the rest of the API.
+impl AsRef<OsStr> for Session {
+    fn as_ref(&self) -> &OsStr {
+        &self.name
+    }
+}
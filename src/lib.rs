@@ -1,39 +1,248 @@
-#![feature(let_chains)]
 #![feature(dir_entry_ext2)]
 
+// Sessions are built on overlayfs, the new mount API, and /proc, none of which
+// have an equivalent other kernels could stand in for without a separate
+// backend.
+#[cfg(not(target_os = "linux"))]
+compile_error!("ForkFS is Linux-only.");
+
 use std::{
+    ffi::OsStr,
     fmt::{Debug, Display},
     io,
-    path::PathBuf,
+    os::{fd::OwnedFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+    sync::OnceLock,
 };
 
+pub use apply::{
+    Resolution, abort as abort_apply, apply as apply_session, apply_resolved,
+    resume as resume_apply,
+};
+pub use archive::{
+    Filter as ExportFilter, Omission, OmissionKind, export as export_session,
+    export_filtered as export_filtered_session, export_redacted as export_redacted_session,
+    import as import_session,
+};
+pub use backend::Backend;
+pub use clock::{Clock, FakeClock, set_clock};
+pub use clone::clone as clone_session;
+pub use config::{
+    Config, Diagnostic as ConfigDiagnostic, Maintenance, Profile, Redaction, Retention,
+    sessions_dir_override, static_paths, use_sessions_dir, use_static_paths,
+};
+pub use conflicts::{
+    Conflict, Version as ConflictVersion, conflicts as session_conflicts, report as conflict_report,
+};
+pub use consistency::{Issue, Problem, check as check_session};
+pub use detach::{
+    DetachedProcess, Restart, attach as attach_process, processes as detached_processes,
+    stop as stop_process,
+};
+pub use diff::{
+    Change, ChangeKind, Cursor as DiffCursor, Page as DiffPage, Query as DiffQuery,
+    changes as session_diff, iter as iter_changes, query as diff_query,
+};
+#[cfg(feature = "cli")]
+pub use diff::{Detail as DiffDetail, print as diff_session};
+pub use drift::{Drift, DriftKind, drift as session_drift};
+pub use edit::edit as edit_session;
+pub use encryption::EncryptionKey;
 use error_stack::{Result, ResultExt};
-pub use run::run;
+pub use fault::{FaultPoint, inject_fault};
+pub use gc::{Garbage, GarbageKind, collect as collect_garbage};
+pub use handle::{ActiveSession, Session};
+use messages::Message;
+pub use meta::{
+    ChangeStats, CommandRecord, DirUsage, RunStats, SessionInfo, info as session_info,
+    trend as session_trend,
+};
+pub use mountinfo::{Mount, current_session};
+pub use overlay_options::OverlayOption;
+#[cfg(feature = "cli")]
+pub use patch::export as export_patch_series;
+use path_undo::TmpPath;
+pub use pipeline::{Pipeline, Step as PipelineStep};
+pub use presets::Preset;
+#[cfg(feature = "cli")]
+pub use prompt::Input;
+pub use publish::{Viewer, publish as publish_session};
+#[cfg(feature = "cli")]
+pub use resolve::resolve as resolve_conflicts;
+pub use revert::revert as revert_paths;
+pub use review::{Concern, Finding, review as review_session};
+pub use run::{Bind, DEFAULT_MIN_FREE_SPACE, Network, RunOp, run};
+use rustix::{
+    fs::{AtFlags, FileType, OFlags, statat},
+    io::Errno,
+};
+pub use scheduler::IoPriority;
+pub use seed::seed as seed_session;
+#[cfg(feature = "cli")]
+pub use sessions::list as list_sessions;
 pub use sessions::{
-    Op as SessionOperand, delete as delete_sessions, list as list_sessions, stop as stop_sessions,
+    Entry as SessionEntry, Op as SessionOperand, SessionStatus, delete as delete_sessions,
+    iter as iter_sessions, listing as session_listing, mounts as session_mounts,
+    rename as rename_session, resolve_alias as resolve_session_alias, status as session_status,
+    stop as stop_sessions, unmount_abandoned as unmount_abandoned_sessions,
 };
+pub use setup::{exe_path, setup};
+pub use snapshot::{
+    Snapshot, create as create_snapshot, list as session_snapshots, restore as restore_snapshot,
+};
+pub use store::{StoredArchive, export as export_to_store, import as import_from_store};
+#[cfg(feature = "cli")]
+pub use style::{ColorChoice, Style};
+pub use summary::summarize as summarize_run;
+pub use system_dirs::SystemDir;
+pub use verify::{Deviation, DeviationKind, verify as verify_session};
 
+mod apply;
+mod archive;
+mod backend;
+mod clock;
+mod clone;
+mod config;
+mod conflicts;
+mod consistency;
+#[cfg(feature = "cli")]
+mod content;
+mod detach;
+mod diff;
+mod drift;
+mod edit;
+mod encryption;
+mod fault;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(feature = "cli")]
+mod formats;
+mod gc;
+mod handle;
+mod lower;
+mod messages;
+mod meta;
+mod mountinfo;
+pub mod output;
+mod overlay_options;
+#[cfg(feature = "cli")]
+mod patch;
+mod pin;
+mod pipeline;
+mod presets;
+#[cfg(feature = "cli")]
+mod prompt;
+mod publish;
+mod redact;
+#[cfg(feature = "cli")]
+mod resolve;
+mod revert;
+mod review;
 mod run;
+mod scheduler;
+mod seed;
 mod sessions;
+mod setup;
+mod sha256;
+mod snapshot;
+mod store;
+#[cfg(feature = "cli")]
+mod style;
+mod subtree;
+mod summary;
+mod system_dirs;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod verify;
+mod volatile;
+mod walk;
+mod wsl;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
-    #[error("An IO error occurred.")]
+    #[error("{}", Message::ErrorIo)]
     Io,
-    #[error("Invalid argument.")]
+    #[error("{}", Message::ErrorInvalidArgument)]
     InvalidArgument,
-    #[error("ForkFS must be run as root.")]
+    #[error("{}", Message::ErrorInputRequired)]
+    InputRequired,
+    #[error("{}", Message::ErrorInsufficientSpace)]
+    InsufficientSpace,
+    #[error("{}", Message::ErrorNotRoot)]
     NotRoot,
-    #[error("Session not found.")]
+    #[error("{}", Message::ErrorSessionNotFound)]
     SessionNotFound,
-    #[error("Setup required.")]
+    #[error("{}", Message::ErrorSetupRequired)]
     SetupRequired,
+    #[error("{}", Message::ErrorWriteLimitExceeded)]
+    WriteLimitExceeded,
+    #[error("{}", Message::ErrorConflict)]
+    Conflict,
+}
+
+/// What a directory tree takes up on its file system.
+#[derive(Copy, Clone, Debug, Default)]
+struct Usage {
+    bytes: u64,
+    /// One per entry, since every file, directory, and whiteout takes up an
+    /// inode of its own.
+    inodes: u64,
 }
 
-fn get_sessions_dir() -> PathBuf {
-    let mut sessions_dir = dirs::cache_dir().unwrap_or_else(|| PathBuf::from("/tmp"));
-    sessions_dir.push("forkfs");
-    sessions_dir
+/// Returns the space allocated to a directory tree, tolerating concurrent
+/// modifications.
+fn disk_usage(dir: &Path) -> Result<u64, Error> {
+    usage(dir).map(|usage| usage.bytes)
+}
+
+/// Returns the space and inodes allocated to a directory tree, tolerating
+/// concurrent modifications.
+fn usage(dir: &Path) -> Result<Usage, Error> {
+    let fd = match walk::open_root(dir) {
+        Err(Errno::NOENT) => return Ok(Usage::default()),
+        r => r.map_io_err_lazy(|| format!("Failed to open directory {}", dir.display()))?,
+    };
+    let mut usage = Usage::default();
+    tree_usage(&fd, &mut dir.to_path_buf(), &mut usage)?;
+    Ok(usage)
+}
+
+fn tree_usage(dir: &OwnedFd, path: &mut PathBuf, usage: &mut Usage) -> Result<(), Error> {
+    // Entries may be removed or replaced by the sandboxed processes under our feet
+    let vanished = |e: &Errno| matches!(*e, Errno::NOENT | Errno::NOTDIR | Errno::LOOP);
+
+    for (name, file_type) in walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+        let stat = match statat(dir, &name, AtFlags::SYMLINK_NOFOLLOW) {
+            Err(e) if vanished(&e) => continue,
+            r => r.map_io_err_lazy(|| format!("Failed to stat {path:?}"))?,
+        };
+
+        usage.bytes += stat.st_blocks.cast_unsigned() * 512;
+        usage.inodes += 1;
+        if file_type == FileType::Directory {
+            let child = match walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY) {
+                Err(e) if vanished(&e) => continue,
+                r => r.map_io_err_lazy(|| format!("Failed to open directory {path:?}"))?,
+            };
+            tree_usage(&child, &mut path, usage)?;
+        }
+    }
+    Ok(())
+}
+
+/// The directory sessions are stored in, as configured. Only a config that
+/// loaded successfully is cached, so a broken config file keeps being reported
+/// rather than silently replaced with the defaults.
+fn get_sessions_dir() -> Result<PathBuf, Error> {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    if let Some(config) = CONFIG.get() {
+        return Ok(config.sessions_dir());
+    }
+    let config = Config::load()?;
+    Ok(CONFIG.get_or_init(|| config).sessions_dir())
 }
 
 trait IoErr<Out> {
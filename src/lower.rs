@@ -0,0 +1,161 @@
+//! Sessions layered on a frozen image of the system, such as an LVM or btrfs
+//! snapshot, instead of the live root.
+//!
+//! The image is picked when the session is created and recorded in its
+//! `lower-device` file, since an upper directory only makes sense on top of
+//! the lower layer it was made against. It is mounted read-only at the
+//! session's `lower` directory while the session is active.
+
+use std::{
+    ffi::{CString, OsStr},
+    fs,
+    io::ErrorKind,
+    os::unix::{ffi::OsStrExt, fs::FileTypeExt},
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    io::Errno,
+    mount::{MountFlags, UnmountFlags, mount, mount_bind, mount_remount, unmount},
+};
+
+use crate::{Error, IoErr, path_undo::TmpPath, sessions::is_mount_point};
+
+const RECORD: &str = "lower-device";
+const MOUNT_POINT: &str = "lower";
+
+/// Records that a new session is layered on `device`, a block device or a
+/// directory such as a btrfs snapshot, or checks that an existing session
+/// already is.
+pub fn assign(session: &mut PathBuf, device: &Path) -> Result<(), Error> {
+    let device = fs::canonicalize(device)
+        .map_io_err_lazy(|| format!("Failed to resolve path {}", device.display()))?;
+    if TmpPath::new(session, "diff").exists() {
+        return match recorded(session)? {
+            Some(recorded) if recorded == device => Ok(()),
+            Some(recorded) => Err(Error::InvalidArgument).attach_printable(format!(
+                "This session is layered on {}, not {}.",
+                recorded.display(),
+                device.display()
+            )),
+            None => Err(Error::InvalidArgument).attach_printable(
+                "Only new sessions can be layered on a lower device, but this session already \
+                 exists.",
+            ),
+        };
+    }
+
+    let metadata =
+        fs::metadata(&device).map_io_err_lazy(|| format!("Failed to stat {}", device.display()))?;
+    if !metadata.file_type().is_block_device() && !metadata.is_dir() {
+        return Err(Error::InvalidArgument).attach_printable(format!(
+            "Lower device {} must be a block device or a directory.",
+            device.display()
+        ));
+    }
+    fs::create_dir_all(&*session)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", session.display()))?;
+    record(session, &device)
+}
+
+/// Layers a copy of a session on the same device as the original.
+pub fn inherit(source: &mut PathBuf, copy: &mut PathBuf) -> Result<(), Error> {
+    recorded(source)?.map_or(Ok(()), |device| record(copy, &device))
+}
+
+fn record(session: &mut PathBuf, device: &Path) -> Result<(), Error> {
+    let record = TmpPath::new(session, RECORD);
+    fs::write(&record, device.as_os_str().as_bytes())
+        .map_io_err_lazy(|| format!("Failed to write {}", record.display()))
+}
+
+/// Returns the device a session is layered on, if any.
+pub fn recorded(session: &mut PathBuf) -> Result<Option<PathBuf>, Error> {
+    let record = TmpPath::new(session, RECORD);
+    match fs::read(&record) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        r => r
+            .map(|device| Some(PathBuf::from(OsStr::from_bytes(&device))))
+            .map_io_err_lazy(|| format!("Failed to read {}", record.display())),
+    }
+}
+
+/// Returns the directory a session's overlay uses as its lower layer.
+pub fn root(session: &mut PathBuf) -> Result<PathBuf, Error> {
+    Ok(if recorded(session)?.is_some() {
+        session.join(MOUNT_POINT)
+    } else {
+        PathBuf::from("/")
+    })
+}
+
+/// Mounts a session's lower device read-only unless it already is, returning
+/// the directory to use as the overlay's lower layer.
+pub fn mount_device(session: &mut PathBuf) -> Result<PathBuf, Error> {
+    let Some(device) = recorded(session)? else {
+        return Ok(PathBuf::from("/"));
+    };
+    let target = session.join(MOUNT_POINT);
+    fs::create_dir_all(&target)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", target.display()))?;
+    if is_mount_point(&target)? {
+        return Ok(target);
+    }
+
+    let metadata = fs::metadata(&device)
+        .map_io_err_lazy(|| format!("Failed to stat lower device {}", device.display()))?;
+    if metadata.is_dir() {
+        mount_bind(&device, &target)
+            .map_io_err_lazy(|| format!("Failed to bind mount {}", device.display()))?;
+        // Binds can only be made read-only after the fact
+        if let Err(e) = mount_remount(&target, MountFlags::BIND | MountFlags::RDONLY, c"")
+            .map_io_err_lazy(|| format!("Failed to make {} read-only", target.display()))
+        {
+            let _ = unmount(&target, UnmountFlags::DETACH);
+            return Err(e);
+        }
+    } else {
+        mount_block_device(&device, &target)?;
+    }
+    Ok(target)
+}
+
+/// Unmounts a session's lower device if it is mounted.
+pub fn unmount_device(session: &mut PathBuf) -> Result<(), Error> {
+    let target = TmpPath::new(session, MOUNT_POINT);
+    if !target.exists() || !is_mount_point(&target)? {
+        return Ok(());
+    }
+    unmount(&*target, UnmountFlags::empty())
+        .map_io_err_lazy(|| format!("Failed to unmount directory {}", target.display()))
+}
+
+/// Mounts a block device read-only, trying every file system the kernel
+/// knows of like `mount` does when no type is given.
+fn mount_block_device(device: &Path, target: &Path) -> Result<(), Error> {
+    let file_systems = fs::read_to_string("/proc/filesystems")
+        .map_io_err("Failed to read the list of file systems")?;
+    for fs_type in file_systems
+        .lines()
+        .filter(|line| !line.starts_with("nodev"))
+        .map(str::trim)
+    {
+        let Ok(fs_type) = CString::new(fs_type) else {
+            continue;
+        };
+        match mount(device, target, fs_type.as_c_str(), MountFlags::RDONLY, c"") {
+            // The device doesn't hold this kind of file system
+            Err(Errno::INVAL | Errno::NODEV) => {}
+            r => {
+                return r.map_io_err_lazy(|| {
+                    format!("Failed to mount lower device {}", device.display())
+                });
+            }
+        }
+    }
+    Err(Error::InvalidArgument).attach_printable(format!(
+        "Lower device {} doesn't hold a file system the kernel can mount.",
+        device.display()
+    ))
+}
@@ -1,13 +1,21 @@
 use std::{
-    ffi::OsString,
-    io,
+    env,
+    ffi::{OsStr, OsString},
+    fmt,
+    fmt::{Display, Formatter, Write as _},
+    fs, io,
     io::Write,
-    process::{ExitCode, Termination},
+    mem,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
+    process,
+    process::{Command, ExitCode, ExitStatus, Termination},
+    time::Duration,
 };
 
-use clap::{ArgAction, Args, Parser, Subcommand};
-use error_stack::Result;
-use forkfs::SessionOperand;
+use clap::{ArgAction, ArgGroup, Args, Parser, Subcommand, ValueEnum, ValueHint};
+use error_stack::{Result, ResultExt};
+use forkfs::{DiffDetail, Input, SessionOperand, Style};
 
 #[allow(clippy::doc_markdown)]
 /// A sandboxing file system emulator
@@ -32,6 +40,45 @@ struct ForkFs {
     #[command(subcommand)]
     cmd: Cmd,
 
+    /// Never wait for user input
+    ///
+    /// Questions that would normally be asked fail with an error instead. This
+    /// is the default when stdin or stderr isn't a terminal.
+    #[arg(long, global = true)]
+    no_input: bool,
+
+    /// When to use colors
+    ///
+    /// `auto` uses colors when writing to a terminal unless the `NO_COLOR`
+    /// environment variable is set.
+    #[arg(long, value_name = "WHEN", value_enum, global = true)]
+    #[arg(default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Only print ASCII characters
+    #[arg(long, global = true)]
+    ascii: bool,
+
+    /// Keep files in fixed system locations
+    ///
+    /// The config file is read from `/etc/forkfs/config.toml` and sessions are
+    /// stored in `/var/lib/forkfs/sessions` no matter who runs forkfs or what
+    /// `$HOME` is. Setting the `FORKFS_STATIC_PATHS` environment variable has
+    /// the same effect.
+    #[arg(long, global = true)]
+    static_paths: bool,
+
+    /// Store sessions in the given directory
+    ///
+    /// This takes precedence over the `FORKFS_DATA_DIR` environment variable,
+    /// which in turn takes precedence over the config file's `sessions-dir`.
+    #[arg(long, value_name = "DIR", global = true)]
+    sessions_dir: Option<PathBuf>,
+
+    /// Fail at the given point to test recovery from interrupted operations
+    #[arg(long, value_name = "POINT", value_enum, global = true, hide = true)]
+    inject_fault: Option<FaultPoint>,
+
     #[arg(short, long, short_alias = '?', global = true)]
     #[arg(action = ArgAction::Help, help = "Print help (use `--help` for more detail)")]
     #[arg(long_help = "Print help (use `-h` for a summary)")]
@@ -42,7 +89,13 @@ struct ForkFs {
 enum Cmd {
     /// Run commands inside the sandbox
     #[command(alias = "execute")]
-    Run(Run),
+    Run(Box<Run>),
+
+    /// Open your shell inside the sandbox
+    ///
+    /// This runs `$SHELL -i` (or `/bin/sh -i`) in the session, with the
+    /// session's name in `FORKFS_SESSION` so that your prompt can show it.
+    Shell(Box<Shell>),
 
     /// Manage sessions
     ///
@@ -59,21 +112,537 @@ enum Cmd {
     /// restore clean behavior in such cases.
     #[command(subcommand)]
     Sessions(Sessions),
+
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCmd),
+
+    /// Reclaim the space taken up by state nothing needs anymore
+    ///
+    /// This deletes what interrupted clones, imports, snapshots, and detached
+    /// commands left behind, along with the untagged snapshots past the
+    /// `[retention]` limits of the config file. Sessions themselves are never
+    /// deleted.
+    Gc(Gc),
+
+    /// Carry out the steps described by a pipeline file
+    ///
+    /// A pipeline names a session, optionally a template session to clone it
+    /// from, and a list of steps: running commands, reviewing the changes,
+    /// exporting them, and finally deleting the session. Each step is logged as
+    /// it runs. If one fails, the pipeline stops and discards what it did: its
+    /// archives are never written and a session it created is deleted.
+    Pipeline(RunPipeline),
+
+    /// Print the session forkfs is running in
+    ///
+    /// Nothing is printed outside of sessions, where the command fails instead,
+    /// so that shell prompts and scripts can tell whether they run sandboxed.
+    Status,
+
+    /// Grant forkfs the privileges it needs
+    ///
+    /// This sets file capabilities on the forkfs binary and must be run as
+    /// root, e.g. with `sudo forkfs setup`. It needs to be repeated whenever
+    /// the binary is replaced, such as after an upgrade.
+    Setup,
+}
+
+#[derive(Args, Debug)]
+struct Gc {
+    /// Also delete the chunks no archive in this store uses
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    store: Option<PathBuf>,
+
+    /// Print what would be deleted without deleting anything
+    #[arg(long = "dry-run")]
+    preview: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct RunPipeline {
+    /// The pipeline file to run
+    #[arg(value_hint = ValueHint::FilePath)]
+    file: PathBuf,
 }
 
+#[allow(clippy::struct_excessive_bools)]
 #[derive(Args, Debug)]
 #[command(arg_required_else_help = true)]
+#[command(group(ArgGroup::new("discarded").args(["ephemeral", "discard_on_success"]).multiple(true)))]
 struct Run {
     /// The command to run in isolation
-    #[arg(required = true)]
+    ///
+    /// When running a script, these are passed to it as arguments instead.
+    #[arg(required_unless_present = "script")]
     command: Vec<OsString>,
 
+    /// Run a script through your shell instead of a single command
+    ///
+    /// The script is interpreted by `$SHELL` (or `/bin/sh`) inside the sandbox.
+    /// Use `-` to read the script from stdin.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    script: Option<PathBuf>,
+
+    /// The fork/sandbox to use
+    ///
+    /// If it does not exist or is inactive, it will be created and activated.
+    /// Use `@last` or `@prev` to pick the most or second most recently used
+    /// session. Defaults to the config file's `session`, or `default`.
+    #[arg(short = 's', long = "session", short_alias = 'n', aliases = & ["name", "id"])]
+    session: Option<OsString>,
+
+    #[command(flatten)]
+    options: RunOptions,
+
+    /// Mount a host file or directory into the sandbox
+    ///
+    /// SRC shows up at DST, which defaults to SRC itself, so that datasets,
+    /// caches, or sockets can be shared with the sandbox without being copied
+    /// into the session. Add `:ro` to keep the sandbox from writing to SRC.
+    /// Can be repeated.
+    #[arg(long = "bind", value_name = "SRC[:DST[:ro]]", value_parser = parse_bind)]
+    binds: Vec<forkfs::Bind>,
+
+    /// Only apply the session while a file keeps its current contents
+    ///
+    /// The file's fs-verity digest, or the SHA-256 of its contents if it
+    /// doesn't have fs-verity enabled, is recorded when the session is first
+    /// created. `sessions apply` refuses to replay the session once the file
+    /// changed, such as a config file the session's changes were made for.
+    /// Can be repeated.
+    #[arg(long = "pin", value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pins: Vec<PathBuf>,
+
+    /// Print the effective options instead of running anything
+    ///
+    /// The options that would be used after combining profiles with the
+    /// command line are printed in the config file format.
+    #[arg(long = "dry-run")]
+    preview: bool,
+
+    /// Guarantee that only the command writes to stdout
+    ///
+    /// Anything forkfs itself would print to stdout goes to stderr instead so
+    /// the command can safely be used in the middle of a pipeline.
+    #[arg(long)]
+    pipe: bool,
+
+    /// Start the command in the background and return immediately
+    ///
+    /// The command's output is logged in the session. Use `sessions attach`
+    /// to follow it and `sessions stop-process` to stop the command.
+    #[arg(short, long, conflicts_with_all = ["pipe", "owner"])]
+    detach: bool,
+
+    /// Start a detached command again whenever it exits unsuccessfully
+    ///
+    /// Restarts are delayed by a second and counted in `sessions info`.
+    #[arg(long, value_enum, requires = "detach", default_value_t = Restart::Never)]
+    restart: Restart,
+
+    /// Run in a new session that is deleted once the command exits
+    ///
+    /// The session is named after forkfs' process ID. Anything the command
+    /// leaves running in it is killed along with it, so only changes to
+    /// passthroughs outlive the run.
+    #[arg(long, conflicts_with_all = ["session", "detach"])]
+    ephemeral: bool,
+
+    /// Keep an ephemeral or discarded session if its command fails
+    ///
+    /// The session is only deleted after successful runs, leaving failed ones
+    /// around for debugging. Its name is printed once the command exits.
+    /// Requires `--ephemeral` or `--discard-on-success`.
+    #[arg(long, requires = "discarded")]
+    keep_on_failure: bool,
+
+    /// Delete the session if the command succeeds
+    ///
+    /// Implies `--wait`. Anything the command left running in the session is
+    /// killed along with it, while sessions whose command failed are kept for
+    /// debugging.
+    #[arg(long, conflicts_with = "detach")]
+    discard_on_success: bool,
+
+    /// Wait for the command to exit instead of turning into it
+    ///
+    /// forkfs stays around as the command's parent, passing on the signals it
+    /// receives and exiting with the command's exit status.
+    #[arg(long, conflicts_with = "detach")]
+    wait: bool,
+
+    /// Stop the session once nothing uses it anymore
+    ///
+    /// Implies `--wait`. Once the command exits, forkfs keeps waiting for
+    /// anything the command or other runs left running in the session before
+    /// unmounting it.
+    #[arg(long, conflicts_with = "detach")]
+    stop: bool,
+
+    /// Snapshot the session periodically while the command runs
+    ///
+    /// Implies `--wait`. Accepts durations such as `90s`, `30m`, or `2h`. The
+    /// snapshots are untagged, so the retention policy from the config file
+    /// eventually cleans them up, and any of them can be restored if the
+    /// command goes wrong late into a long run.
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    #[arg(conflicts_with = "detach")]
+    checkpoint_every: Option<Duration>,
+
+    /// Write a summary of the run for CI systems to publish
+    ///
+    /// Implies `--wait`. Once the command exits, its exit status, the changes
+    /// it made, and the changes `sessions apply` would refuse are written to
+    /// PATH as markdown, ready to be appended to `$GITHUB_STEP_SUMMARY` or
+    /// uploaded as an artifact. The same summary is written as JSON next to it,
+    /// with a `.json` extension.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    #[arg(conflicts_with_all = ["detach", "ephemeral", "discard_on_success"])]
+    ci_summary: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct Shell {
     /// The fork/sandbox to use
     ///
     /// If it does not exist or is inactive, it will be created and activated.
+    /// Use `@last` or `@prev` to pick the most or second most recently used
+    /// session. Defaults to the config file's `session`, or `default`.
     #[arg(short = 's', long = "session", short_alias = 'n', aliases = & ["name", "id"])]
-    #[arg(default_value = "default")]
-    session: String,
+    session: Option<OsString>,
+
+    #[command(flatten)]
+    options: RunOptions,
+}
+
+/// Options that can also be set by profiles.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Args, Debug)]
+struct RunOptions {
+    /// Pass a build system's caches through to the real file system
+    ///
+    /// Cache directories are bind mounted read-write into the sandbox so builds
+    /// stay fast and sessions don't balloon in size. The `dev` preset passes
+    /// the current directory through instead. Can be repeated.
+    #[arg(short = 'p', long = "preset", value_name = "PRESET", value_enum)]
+    presets: Vec<Preset>,
+
+    /// Pass a directory through to the real file system
+    ///
+    /// The directory is bind mounted read-write into the sandbox so changes
+    /// made to it are real. Can be repeated.
+    #[arg(long = "passthrough", value_name = "DIR", value_hint = ValueHint::DirPath)]
+    passthroughs: Vec<PathBuf>,
+
+    /// Use the options bundled in a profile from the config file
+    ///
+    /// Profiles are defined as `[profile.<name>]` tables in the config file.
+    /// When repeated, later profiles override earlier ones and options given
+    /// on the command line override them all. Presets and passthroughs are
+    /// combined instead.
+    #[arg(short = 'P', long = "profile", value_name = "NAME")]
+    profiles: Vec<String>,
+
+    /// Kill the command if it writes more than this amount of data
+    ///
+    /// The session's growth is monitored during the run and all sandboxed
+    /// processes are killed once it exceeds the limit. Accepts sizes such as
+    /// `512M` or `10G`.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_write: Option<u64>,
+
+    /// Refuse to run when less than this amount of disk space is available
+    ///
+    /// Running out of space mid-run leaves the session in a partially written
+    /// state, so the sessions file system is checked before starting. It must
+    /// also have one free inode per 16K of this amount, since many small files
+    /// can run out of inodes first. Use `0` to disable the check. Defaults to
+    /// 256M.
+    #[arg(long = "min-free", value_name = "SIZE", value_parser = parse_size)]
+    min_free_space: Option<u64>,
+
+    /// Encrypt the session's changes at rest with this key
+    ///
+    /// The file must contain exactly 64 random bytes (e.g. from `head -c 64
+    /// /dev/urandom`). Encryption can only be enabled when a session is first
+    /// created, after which the key is required whenever the session is
+    /// activated. Stopping the session locks it again. Requires a file system
+    /// with fscrypt support such as ext4 with the `encrypt` feature.
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    key_file: Option<PathBuf>,
+
+    /// Layer the session on a frozen image of the system
+    ///
+    /// The block device, such as an LVM snapshot, or directory, such as a btrfs
+    /// snapshot, is mounted read-only and used in place of the live root
+    /// whenever the session is active. It can only be chosen when a session is
+    /// first created. Commands like `sessions diff` and `sessions apply` still
+    /// compare against the live root.
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::AnyPath)]
+    lower_device: Option<PathBuf>,
+
+    /// Only overlay this directory
+    ///
+    /// Changes below it are captured in the session as usual, while the rest
+    /// of the file system is the real one, only read-only apart from `/proc`,
+    /// `/dev`, `/run`, and `/tmp`. It can only be chosen when a session is
+    /// first created.
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    root: Option<PathBuf>,
+
+    /// Don't bind a system directory into the session
+    ///
+    /// `/proc`, `/dev`, `/run`, and `/tmp` are normally the real ones, so
+    /// writes to them aren't part of the session. Leaving one out captures
+    /// writes to it in the overlay like any others, or makes it read-only with
+    /// `--root`. It can only be chosen when a session is first created. Can be
+    /// repeated.
+    #[arg(long = "no-bind", value_name = "DIR", value_enum)]
+    no_bind: Vec<SystemDir>,
+
+    /// Mount the session with an optional overlayfs feature
+    ///
+    /// `metacopy` only copies up a file's metadata until its contents are
+    /// written, `redirect-dir` lets directories be renamed without copying
+    /// them, `index` keeps hard links to copied up files linked, and
+    /// `volatile` skips syncing the session's changes to disk at the risk of
+    /// losing the session in a crash. They speed up work on big trees but
+    /// aren't supported by every kernel or backend. They can only be chosen
+    /// when a session is first created. Can be repeated.
+    #[arg(long = "overlay-option", value_name = "OPTION", value_enum)]
+    overlay_options: Vec<OverlayOption>,
+
+    /// Keep the session's changes in memory
+    ///
+    /// The changes are stored in a tmpfs instead of on disk, which speeds up
+    /// write-heavy commands and leaves nothing behind to clean up. They are
+    /// lost once the session is deleted or the machine restarts, and count
+    /// against its memory. It can only be chosen when a session is first
+    /// created and can't be combined with `--key-file`.
+    #[arg(long, conflicts_with = "key_file")]
+    volatile_storage: bool,
+
+    /// Hide the host's processes from the sandbox
+    ///
+    /// The command runs in its own PID namespace with a fresh `/proc`, so it
+    /// and its children can't see or signal processes outside the session.
+    /// The command is the namespace's init process: once it exits, everything
+    /// it left running is killed.
+    #[arg(long)]
+    private_pids: bool,
+
+    /// Cut the sandbox off from the network
+    ///
+    /// The command runs in its own network namespace without any interfaces,
+    /// so it can't reach the internet or any other machine while its file
+    /// system changes are still captured as usual.
+    #[arg(long)]
+    no_net: bool,
+
+    /// Keep a working loopback interface without the network
+    ///
+    /// Servers started inside the sandbox can then be reached through
+    /// `localhost` from inside it, but not from the host.
+    #[arg(long, requires = "no_net")]
+    loopback: bool,
+
+    /// Create files with this octal permission mask
+    ///
+    /// The sandboxed command starts with this umask, such as `022`, instead
+    /// of the one forkfs was started with, so the files it creates come out
+    /// with the same modes whoever runs it.
+    #[arg(long, value_name = "MODE", value_parser = parse_umask)]
+    umask: Option<u32>,
+
+    /// Hand the files the command adds over to this user
+    ///
+    /// Once the command exits, the files and directories it added that are
+    /// owned by the user it ran as, which is the user that invoked sudo when
+    /// run through sudo and root otherwise, are given to this user and their
+    /// primary group instead, so that applying or exporting the session doesn't
+    /// leave files owned by the wrong user behind. Takes a name or a numeric
+    /// id. Can't be combined with `--detach`.
+    #[arg(long, value_name = "USER")]
+    owner: Option<String>,
+
+    /// How to mount the session
+    ///
+    /// Kernel overlayfs can't layer some file systems, such as another
+    /// overlay, and may be missing from older or locked down kernels.
+    /// `fuse-overlayfs` works anywhere FUSE does, but is slower. By default,
+    /// `fuse-overlayfs` is used if it is installed and kernel overlayfs fails.
+    #[arg(long, value_name = "BACKEND")]
+    backend: Option<Backend>,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl From<ColorChoice> for forkfs::ColorChoice {
+    fn from(choice: ColorChoice) -> Self {
+        match choice {
+            ColorChoice::Auto => Self::Auto,
+            ColorChoice::Always => Self::Always,
+            ColorChoice::Never => Self::Never,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Format {
+    Human,
+    Json,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, Eq, PartialEq)]
+enum ExportFormat {
+    /// A tar archive that `import` can turn back into a session
+    Tar,
+    /// A directory of patches, one per command
+    PatchSeries,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum ReportFormat {
+    Json,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Restart {
+    Never,
+    OnFailure,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Backend {
+    Auto,
+    Kernel,
+    Fuse,
+}
+
+impl From<Backend> for forkfs::Backend {
+    fn from(backend: Backend) -> Self {
+        match backend {
+            Backend::Auto => Self::Auto,
+            Backend::Kernel => Self::Kernel,
+            Backend::Fuse => Self::Fuse,
+        }
+    }
+}
+
+impl From<Restart> for forkfs::Restart {
+    fn from(restart: Restart) -> Self {
+        match restart {
+            Restart::Never => Self::Never,
+            Restart::OnFailure => Self::OnFailure,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum SystemDir {
+    Proc,
+    Dev,
+    Run,
+    Tmp,
+}
+
+impl From<SystemDir> for forkfs::SystemDir {
+    fn from(dir: SystemDir) -> Self {
+        match dir {
+            SystemDir::Proc => Self::Proc,
+            SystemDir::Dev => Self::Dev,
+            SystemDir::Run => Self::Run,
+            SystemDir::Tmp => Self::Tmp,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum OverlayOption {
+    Metacopy,
+    RedirectDir,
+    Index,
+    Volatile,
+}
+
+impl From<OverlayOption> for forkfs::OverlayOption {
+    fn from(option: OverlayOption) -> Self {
+        match option {
+            OverlayOption::Metacopy => Self::Metacopy,
+            OverlayOption::RedirectDir => Self::RedirectDir,
+            OverlayOption::Index => Self::Index,
+            OverlayOption::Volatile => Self::Volatile,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum FaultPoint {
+    AfterMount,
+    BeforeUnmount,
+    MidCommit,
+}
+
+impl From<FaultPoint> for forkfs::FaultPoint {
+    fn from(point: FaultPoint) -> Self {
+        match point {
+            FaultPoint::AfterMount => Self::AfterMount,
+            FaultPoint::BeforeUnmount => Self::BeforeUnmount,
+            FaultPoint::MidCommit => Self::MidCommit,
+        }
+    }
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug)]
+enum Preset {
+    /// Cargo's registry and git checkouts
+    Cargo,
+    /// The ccache compiler cache
+    Ccache,
+    /// The current directory, so that build output is real
+    Dev,
+    /// Gradle's dependency caches and wrapper distributions
+    Gradle,
+    /// npm's package cache
+    Npm,
+}
+
+impl From<Preset> for forkfs::Preset {
+    fn from(preset: Preset) -> Self {
+        match preset {
+            Preset::Cargo => Self::Cargo,
+            Preset::Ccache => Self::Ccache,
+            Preset::Dev => Self::Dev,
+            Preset::Gradle => Self::Gradle,
+            Preset::Npm => Self::Npm,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCmd {
+    /// Print the effective configuration along with where each setting comes
+    /// from
+    ///
+    /// Settings are combined from the built-in defaults, environment
+    /// variables, the config file, the selected profiles, and the command
+    /// line, each overriding the previous ones. Pass the same options as to
+    /// `forkfs run` to see what it would use.
+    Show(Box<RunOptions>),
+
+    /// Look for mistakes in the config file
+    ///
+    /// Besides syntax errors, this reports profiles whose paths don't exist or
+    /// whose options contradict each other. Each problem is printed with its
+    /// location in the file.
+    Check,
 }
 
 #[derive(Subcommand, Debug)]
@@ -81,9 +650,10 @@ enum Sessions {
     /// List sessions
     ///
     /// `[active]` sessions are denoted with brackets while `inactive` sessions
-    /// are bare.
+    /// are bare. Each session is prefixed with the index that can be used to
+    /// refer to it.
     #[command(alias = "ls")]
-    List,
+    List(ListSessions),
 
     /// Unmount active sessions
     #[command(alias = "close")]
@@ -91,72 +661,2166 @@ enum Sessions {
 
     /// Delete sessions
     #[command(alias = "destroy")]
-    Delete(SessionCmd),
+    Delete(DeleteSessions),
+
+    /// Rename an inactive session
+    #[command(alias = "mv")]
+    Rename(RenameSession),
+
+    /// Copy a session's changes into a new session
+    ///
+    /// This lets you branch an experiment off an existing session without
+    /// redoing its work. Files are shared with reflinks on file systems that
+    /// support them, so cloning is cheap until either session changes them.
+    /// The new session stays inactive until it is first used.
+    Clone(CloneSession),
+
+    /// Expose a read-only view of a session at another location
+    ///
+    /// This lets other users inspect a sandbox's results without giving them
+    /// access to the sessions directory. The view is removed when the session
+    /// is stopped.
+    Publish(PublishSession),
+
+    /// Show the files a session added, modified, or deleted
+    ///
+    /// Deleted directories are listed once rather than once per entry they
+    /// contained. Files that were also changed outside the session after it
+    /// last touched them are marked since the session's version doesn't
+    /// include those changes.
+    Diff(DiffSession),
+
+    /// Check whether the files a session changed were also changed outside it
+    ///
+    /// Each changed file is listed with `lower` if the real file still is the
+    /// one the session started from, `upper` if it already matches the
+    /// session's version, or `neither` if it was changed independently, in
+    /// which case applying the session would overwrite those changes. Files
+    /// are compared by their contents' hashes.
+    Drift(DriftSession),
+
+    /// Compare a session's changes with those of a known-good run
+    ///
+    /// The manifest is the output of `forkfs sessions diff` for a session the
+    /// same commands are known to have worked in. Changes the session made
+    /// that the manifest doesn't list are reported as `unexpected`, and those
+    /// it lists that the session didn't make as `missing`, which makes this
+    /// useful to catch regressions in install scripts.
+    VerifyAgainst(VerifySession),
+
+    /// Copy a session's changes to the real file system
+    ///
+    /// The session is stopped and emptied afterwards so that it continues from
+    /// the updated real file system. Changes that could be used to escalate
+    /// privileges, like setuid files, device nodes, or edits to `/etc/passwd`,
+    /// are refused unless explicitly allowed.
+    Apply(ApplySession),
+
+    /// Discard a session's changes to some files
+    ///
+    /// The session is stopped and sees the real files again, as if it had
+    /// never touched them. Directories are reverted recursively, and files
+    /// inside a directory the session replaced or deleted can only be reverted
+    /// along with that directory.
+    Revert(RevertPaths),
+
+    /// Check that a session's directories only contain what the kernel expects
+    ///
+    /// Editing a session's `diff` directory by hand can leave behind entries
+    /// the kernel doesn't understand, which are then silently ignored or break
+    /// the session.
+    Check(CheckSession),
+
+    /// Edit a file as a session sees it
+    ///
+    /// Opens `$VISUAL` or `$EDITOR` on the session's version of the file
+    /// without entering the session. The real file is left untouched.
+    Edit(EditSession),
+
+    /// Archive a session's changes to move them to another machine
+    ///
+    /// Deletions are recorded the way OCI image layers do, with `.wh.` files.
+    /// The archive is compressed according to its extension: `.zst`, `.gz`,
+    /// or `.xz`. With `--store`, the archive is instead added to a store that
+    /// shares the parts of archives that are the same between them.
+    Export(ExportSession),
+
+    /// Copy real files into a session before running anything in it
+    ///
+    /// The files show up as modified in the session and stop following
+    /// changes to the real files, which is useful to tweak configuration ahead
+    /// of the first run. Directories are copied recursively.
+    Seed(SeedSession),
+
+    /// Recreate a session from an archive made by `export`
+    ///
+    /// The archive is decompressed according to its extension. An existing
+    /// inactive session of the same name is replaced, and the imported session
+    /// stays inactive until it is first used.
+    Import(ImportSession),
+
+    /// Copy the changes in an archive made by `export` to the real file system
+    ///
+    /// The archive is imported into a session of its own and applied as with
+    /// `apply`, which deletes the session again afterwards. Since archives
+    /// don't hold the files their changes were made against, only files
+    /// changed while the archive is being applied count as changed outside
+    /// the session. A session whose apply was interrupted is kept to continue
+    /// or abort it with `apply`.
+    ApplyArchive(ApplyArchive),
+
+    /// Follow the output of a command started with `run --detach`
+    ///
+    /// Output is printed from the start until the command exits. Interrupting
+    /// this leaves the command running.
+    Attach(ProcessCmd),
+
+    /// Stop a command started with `run --detach`
+    StopProcess(StopProcess),
+
+    /// Show a session's status, mounts, and history
+    ///
+    /// This includes the size of the session's changes, the file systems
+    /// mounted inside it while it is active, when it was created and last
+    /// activated, and the last few commands run in it. Encrypted sessions keep
+    /// no command history since it is stored unencrypted. Commands started with
+    /// `run --detach` are listed along with whether they are still running.
+    Info(InfoSession),
+
+    /// Show how a session's changes grew with each command run in it
+    ///
+    /// Every run records how many files the session had changed and how much
+    /// space they took up when it started, so a tool that keeps rewriting
+    /// more of the file system stands out as growing with each run. The
+    /// directories whose changes take up the most space are listed after
+    /// each run. Changes made between runs count toward the run before them.
+    Trend(InfoSession),
+
+    /// List the mounts that make up an active session
+    ///
+    /// This includes the session's overlay and everything mounted inside it,
+    /// along with their options and propagation, as read from
+    /// `/proc/self/mountinfo`. Mounts whose propagation has a `master` peer
+    /// group receive the mounts made in that group on the host, which
+    /// explains why something is or isn't visible inside the session.
+    Mounts(MountsSession),
+
+    /// Save and restore checkpoints of a session's changes
+    #[command(subcommand)]
+    Snapshot(SnapshotCmd),
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCmd {
+    /// Save a copy of a session's current changes
+    ///
+    /// Files are shared with reflinks on file systems that support them, so
+    /// snapshots are cheap until the session changes them. Encrypted sessions
+    /// can't be snapshotted.
+    Create(CreateSnapshot),
+
+    /// List a session's snapshots, oldest first
+    #[command(alias = "ls")]
+    List(ListSnapshots),
+
+    /// Roll a session back to a snapshot
+    ///
+    /// The session is stopped and every change made since the snapshot was
+    /// taken is discarded. The snapshot itself is kept.
+    Restore(RestoreSnapshot),
 }
 
 #[derive(Args, Debug)]
 #[command(arg_required_else_help = true)]
-struct SessionCmd {
-    /// The session(s) to operate on
-    #[arg(required = true, group = "names")]
-    sessions: Vec<String>,
+struct CreateSnapshot {
+    /// The session to snapshot (or `@last` and `@prev`)
+    session: OsString,
 
-    /// Operate on all sessions
-    #[arg(short = 'a', long = "all", group = "names")]
-    all: bool,
+    /// A name to refer to the snapshot by instead of its number
+    tag: Option<String>,
 }
 
-fn main() -> ExitCode {
-    #[cfg(not(debug_assertions))]
-    error_stack::Report::install_debug_hook::<std::panic::Location>(|_, _| {});
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct ListSnapshots {
+    /// The session whose snapshots to list (or `@last` and `@prev`)
+    session: OsString,
+}
 
-    let args = ForkFs::parse();
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct RestoreSnapshot {
+    /// The session to roll back (or `@last` and `@prev`)
+    session: OsString,
 
-    match forkfs(args) {
-        Ok(o) => o.report(),
-        Err(err) => {
-            drop(writeln!(io::stderr(), "Error: {err:?}"));
-            err.report()
-        }
-    }
+    /// The snapshot's number or tag, defaulting to the latest snapshot
+    snapshot: Option<String>,
 }
 
-fn forkfs(ForkFs { cmd, help: _ }: ForkFs) -> Result<(), forkfs::Error> {
-    match cmd {
-        Cmd::Run(r) => run(r),
-        Cmd::Sessions(s) => sessions(s),
-    }
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct MountsSession {
+    /// The session whose mounts to list (or `@last` and `@prev`)
+    session: OsString,
 }
 
-fn run(Run { command, session }: Run) -> Result<(), forkfs::Error> {
-    forkfs::run(&session, command.as_slice())
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct InfoSession {
+    /// The session to describe (or `@last` and `@prev`)
+    session: OsString,
 }
 
-fn sessions(sessions: Sessions) -> Result<(), forkfs::Error> {
-    match sessions {
-        Sessions::List => forkfs::list_sessions(),
-        Sessions::Stop(SessionCmd { sessions, all }) => forkfs::stop_sessions(if all {
-            SessionOperand::All
-        } else {
-            SessionOperand::List(sessions.as_slice())
-        }),
-        Sessions::Delete(SessionCmd { sessions, all }) => forkfs::delete_sessions(if all {
-            SessionOperand::All
-        } else {
-            SessionOperand::List(sessions.as_slice())
-        }),
-    }
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct ProcessCmd {
+    /// The session the command runs in (or `@last` and `@prev`)
+    session: OsString,
+
+    /// The command's pid, defaulting to the most recently started one
+    #[arg(long)]
+    pid: Option<u32>,
 }
 
-#[cfg(test)]
-mod cli_tests {
-    use clap::CommandFactory;
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct StopProcess {
+    #[command(flatten)]
+    process: ProcessCmd,
 
-    use super::*;
+    /// Kill the command instead of asking it to terminate
+    #[arg(short, long)]
+    kill: bool,
+}
 
-    #[test]
-    fn verify_app() {
-        ForkFs::command().debug_assert();
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct RenameSession {
+    /// The session to rename (or `@last` and `@prev`)
+    session: OsString,
+
+    /// The session's new name
+    name: OsString,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct CloneSession {
+    /// The session to clone (or `@last` and `@prev`)
+    session: OsString,
+
+    /// The name of the session to create
+    name: OsString,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct ImportSession {
+    /// The archive to import, or its name with `--store`
+    #[arg(value_hint = ValueHint::FilePath)]
+    archive: PathBuf,
+
+    /// Take the archive from a store made by `export --store`
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    store: Option<PathBuf>,
+
+    /// The name of the session to create
+    #[arg(short, long)]
+    name: OsString,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct ApplyArchive {
+    /// The archive to apply, or its name with `--store`
+    #[arg(value_hint = ValueHint::FilePath)]
+    archive: PathBuf,
+
+    /// Take the archive from a store made by `export --store`
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    store: Option<PathBuf>,
+
+    /// Apply changes at or below this path even if they look dangerous
+    #[arg(long = "allow", value_name = "PATH", value_hint = ValueHint::AnyPath)]
+    allowed: Vec<PathBuf>,
+
+    /// Overwrite files that were changed outside the session without asking
+    ///
+    /// Also applies the session even if files it pins changed.
+    #[arg(short, long)]
+    force: bool,
+
+    /// Don't wait for the changes to reach the disk
+    #[arg(long)]
+    fast: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct SeedSession {
+    /// The session to seed (or `@last` and `@prev`)
+    session: OsString,
+
+    /// The files and directories to copy
+    #[arg(required = true, value_hint = ValueHint::AnyPath)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ListSessions {
+    /// How to print the sessions
+    ///
+    /// The JSON output also includes each session's creation time and size,
+    /// and follows a versioned schema that only changes in backwards
+    /// compatible ways.
+    #[arg(long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct ExportSession {
+    /// The session to export (or `@last` and `@prev`)
+    session: OsString,
+
+    /// Where to write the archive instead of stdout
+    #[arg(short, long, value_hint = ValueHint::FilePath)]
+    #[arg(required_if_eq("format", "patch-series"))]
+    output: Option<PathBuf>,
+
+    /// What to write the session's changes as
+    ///
+    /// A patch series is a directory of patches laid out like `git
+    /// format-patch` writes them, one per command run in the session with the
+    /// command as its subject, which `git am` or `patch -p1` can apply from
+    /// `/` without forkfs. Each change goes with the last command that made
+    /// it. Patches can only hold text files and symlinks, so other files are
+    /// left out and reported.
+    #[arg(long, value_enum, default_value_t = ExportFormat::Tar)]
+    #[arg(conflicts_with = "store")]
+    format: ExportFormat,
+
+    /// Add the archive to a deduplicating store instead
+    ///
+    /// Archives are split into chunks at boundaries picked from their
+    /// contents, and each chunk is only stored once no matter how many
+    /// archives contain it. Many similar sessions thus take up little more
+    /// space than one. The archive is stored under the session's name,
+    /// replacing any previous one, and can be restored with `import --store`.
+    /// Chunks no longer used by any archive are deleted by `gc --store`.
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    #[arg(conflicts_with = "output")]
+    store: Option<PathBuf>,
+
+    /// Keep setuid and setgid bits, file capabilities, and device nodes
+    ///
+    /// These are stripped by default so that a shared archive can't be used
+    /// to escalate privileges. Sockets can't be archived either way. Every
+    /// entry left out of the archive is reported.
+    #[arg(long)]
+    preserve_special: bool,
+
+    /// Archive files as they are, without the config file's redactions
+    ///
+    /// The config file's `[[redact]]` tables otherwise replace details such as
+    /// user names, host names, or tokens in the archived files so that the
+    /// archive can be shared. Archives added to a store are never redacted.
+    #[arg(long, conflicts_with = "store")]
+    no_redact: bool,
+
+    /// Leave out files larger than this (e.g. `512K` or `10M`)
+    ///
+    /// Links to a file that was left out are left out along with it. Archives
+    /// added to a store are never filtered.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    #[arg(conflicts_with = "store")]
+    max_file_size: Option<u64>,
+
+    /// Leave out files that look binary
+    ///
+    /// A file is considered binary if its first 8 KiB contain a NUL byte or
+    /// aren't valid UTF-8, which is how `git` tells them apart.
+    #[arg(long, conflicts_with = "store")]
+    exclude_binary: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct RevertPaths {
+    /// The session to revert the files in (or `@last` and `@prev`)
+    session: OsString,
+
+    /// The files and directories to revert
+    #[arg(required = true, value_hint = ValueHint::AnyPath)]
+    paths: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct EditSession {
+    /// The session to edit (or `@last` and `@prev`)
+    session: OsString,
+
+    /// The file to edit
+    #[arg(value_hint = ValueHint::FilePath)]
+    path: PathBuf,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct CheckSession {
+    /// The session to check (or `@last` and `@prev`)
+    session: OsString,
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct ApplySession {
+    /// The session to apply (or `@last` and `@prev`)
+    session: OsString,
+
+    /// Apply changes at or below this path even if they look dangerous
+    #[arg(long = "allow", value_name = "PATH", value_hint = ValueHint::AnyPath)]
+    allowed: Vec<PathBuf>,
+
+    /// Overwrite files that were changed outside the session without asking
+    #[arg(short, long)]
+    force: bool,
+
+    /// Decide what to do with each file that was changed outside the session
+    ///
+    /// Every such file is shown next to the session's version and the one it
+    /// started from, if known, to either keep one of them or edit a merge of
+    /// both in `$VISUAL` or `$EDITOR`. Nothing is applied if you quit part way.
+    #[arg(short, long, conflicts_with = "force")]
+    resolve: bool,
+
+    /// Don't wait for the changes to reach the disk
+    ///
+    /// Every file is otherwise synced before it replaces the real one, and
+    /// the directories holding them once everything was replaced, so that a
+    /// crash can't leave half written files behind or lose the changes. Fast
+    /// applies leave that to the kernel, which is quicker for many small
+    /// files.
+    #[arg(long)]
+    fast: bool,
+
+    /// Also print a report of the files changed outside the session to stdout
+    ///
+    /// The JSON report lists why each file conflicts, the SHA-256 of the
+    /// session's and the live version, and a suggested resolution, and follows
+    /// a versioned schema that only changes in backwards compatible ways.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    report: Option<ReportFormat>,
+
+    /// Finish an apply that was interrupted
+    ///
+    /// Real files the apply hadn't replaced yet are replaced and the session
+    /// is emptied. An apply that was interrupted before replacing anything
+    /// starts over.
+    #[arg(long = "continue", conflicts_with = "resolve")]
+    resume: bool,
+
+    /// Undo an apply that was interrupted
+    ///
+    /// Real files the apply already replaced or deleted are put back and the
+    /// session is left as it was.
+    #[arg(
+        long,
+        conflicts_with_all = ["allowed", "force", "resolve", "fast", "report", "resume"]
+    )]
+    abort: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct DriftSession {
+    /// The session to check (or `@last` and `@prev`)
+    session: OsString,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct VerifySession {
+    /// The expected changes, as printed by `forkfs sessions diff`
+    #[arg(value_hint = ValueHint::FilePath)]
+    manifest: PathBuf,
+
+    /// The session to check (or `@last` and `@prev`)
+    ///
+    /// Defaults to the session `forkfs run` uses.
+    session: Option<OsString>,
+}
+
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct DiffSession {
+    /// The session to compare (or `@last` and `@prev`)
+    session: OsString,
+
+    /// Summarize what changed inside files of known formats
+    ///
+    /// Added and modified JSON documents are compared by key, INI files by
+    /// key within each section, sqlite databases by the number of rows in each
+    /// table, and tar archives by member. Other files are only listed.
+    #[arg(long)]
+    structural: bool,
+
+    /// Show what changed inside added and modified files
+    ///
+    /// Text files are compared line by line. Binary files are described by
+    /// their size and hash instead of being printed.
+    #[arg(long)]
+    content: bool,
+
+    /// Show a hexdump of the regions of binary files that differ
+    #[arg(long, requires = "content")]
+    hexdump: bool,
+
+    /// Leave out added and modified files now larger than this (e.g. `10M`)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_file_size: Option<u64>,
+
+    /// Leave out added and modified files that now look binary
+    ///
+    /// A file is considered binary if its first 8 KiB contain a NUL byte or
+    /// aren't valid UTF-8, which is how `git` tells them apart.
+    #[arg(long)]
+    exclude_binary: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct PublishSession {
+    /// The session to publish (or `@last` and `@prev`)
+    session: OsString,
+
+    /// Where to mount the session's file system
+    #[arg(value_hint = ValueHint::DirPath)]
+    mount_point: PathBuf,
+
+    /// Show files owned by you as owned by this user (name or uid)
+    ///
+    /// Without this option, file ownership is unchanged and the view may be
+    /// unreadable to other users.
+    #[arg(long = "uid", value_name = "USER")]
+    viewer: Option<String>,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct DeleteSessions {
+    #[command(flatten)]
+    target: SessionCmd,
+
+    /// Overwrite the sessions' files with zeros before deleting them
+    ///
+    /// Use this when the sandbox held confidential data. Note that overwriting
+    /// data in place offers no guarantees on copy-on-write file systems or
+    /// SSDs which remap writes.
+    #[arg(long)]
+    shred: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(arg_required_else_help = true)]
+struct SessionCmd {
+    /// The session(s) to operate on
+    ///
+    /// Sessions may be referred to by name, by their index in `forkfs sessions
+    /// list`, by a unique prefix of their name, or as `@last` and `@prev` for
+    /// the most recently used sessions.
+    #[arg(required = true, group = "names")]
+    sessions: Vec<OsString>,
+
+    /// Operate on all sessions
+    #[arg(short = 'a', long = "all", group = "names")]
+    all: bool,
+}
+
+fn main() -> ExitCode {
+    #[cfg(not(debug_assertions))]
+    error_stack::Report::install_debug_hook::<std::panic::Location>(|_, _| {});
+
+    let args = ForkFs::parse();
+
+    match forkfs(args) {
+        Ok(o) => o.report(),
+        Err(err) => {
+            drop(writeln!(io::stderr(), "Error: {err:?}"));
+            err.report()
+        }
+    }
+}
+
+fn forkfs(
+    ForkFs {
+        cmd,
+        no_input,
+        color,
+        ascii,
+        static_paths,
+        sessions_dir,
+        inject_fault,
+        help: _,
+    }: ForkFs,
+) -> Result<ExitCode, forkfs::Error> {
+    if static_paths || env::var_os("FORKFS_STATIC_PATHS").is_some_and(|var| !var.is_empty()) {
+        forkfs::use_static_paths();
+    }
+    if let Some(dir) = sessions_dir.or_else(|| {
+        env::var_os("FORKFS_DATA_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    }) {
+        let dir = std::path::absolute(&dir)
+            .map_err(|e| error_stack::Report::new(e).change_context(forkfs::Error::Io))
+            .attach_printable_lazy(|| format!("Failed to resolve {}", dir.display()))?;
+        forkfs::use_sessions_dir(dir)?;
+    }
+    forkfs::inject_fault(inject_fault.map(Into::into));
+    let input = Input::detect(no_input);
+    let style = Style::new(color.into(), ascii);
+    style.install();
+    // Let config check report a broken config file instead of failing on it,
+    // and status work from inside sessions
+    if !matches!(cmd, Cmd::Config(ConfigCmd::Check) | Cmd::Status) {
+        forkfs::Config::load()?;
+        for merged in forkfs::unmount_abandoned_sessions().unwrap_or_default() {
+            drop(writeln!(
+                io::stderr(),
+                "Unmounted {} whose session was deleted while active.",
+                merged.display()
+            ));
+        }
+    }
+    let onboard_if_needed = |result: Result<_, forkfs::Error>| match result {
+        Err(e)
+            if input == Input::Interactive
+                && matches!(e.current_context(), forkfs::Error::SetupRequired) =>
+        {
+            onboard(input, e)
+        }
+        r => r,
+    };
+    match cmd {
+        Cmd::Run(r) => onboard_if_needed(run(*r)),
+        Cmd::Shell(s) => onboard_if_needed(shell(*s).and_then(run)),
+        Cmd::Sessions(s) => sessions(s, input, style).map(|()| ExitCode::SUCCESS),
+        Cmd::Config(c) => config(c).map(|()| ExitCode::SUCCESS),
+        Cmd::Gc(g) => gc(g).map(|()| ExitCode::SUCCESS),
+        Cmd::Pipeline(RunPipeline { file }) => forkfs::Pipeline::load(&file)?
+            .run(io::stderr())
+            .map(|()| ExitCode::SUCCESS),
+        Cmd::Status => Ok(match forkfs::current_session()? {
+            Some(session) => {
+                print(&format!("{}\n", session.to_string_lossy()))?;
+                ExitCode::SUCCESS
+            }
+            None => ExitCode::FAILURE,
+        }),
+        Cmd::Setup => {
+            let exe = forkfs::setup()?;
+            drop(writeln!(
+                io::stderr(),
+                "Granted the required capabilities to {}.",
+                exe.display()
+            ));
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+/// Walks first-time users through setup instead of only printing instructions.
+///
+/// Declining any step falls back to the original error and its manual setup
+/// instructions.
+fn onboard(
+    input: Input,
+    setup_required: error_stack::Report<forkfs::Error>,
+) -> Result<ExitCode, forkfs::Error> {
+    drop(writeln!(
+        io::stderr(),
+        "Welcome to ForkFS! Mounting sandboxes requires elevated privileges, which can be granted \
+         by giving the forkfs binary a few file capabilities."
+    ));
+    if !input.confirm("Grant them now by running `sudo forkfs setup`?")? {
+        return Err(setup_required);
+    }
+
+    let Some(exe) = forkfs::exe_path() else {
+        return Err(setup_required);
+    };
+    let granted = Command::new("sudo")
+        .arg(&exe)
+        .arg("setup")
+        .status()
+        .is_ok_and(|status| status.success());
+    if !granted {
+        return Err(setup_required);
+    }
+
+    // The static config file belongs to the administrator
+    if !forkfs::static_paths()
+        && let Some(path) = forkfs::Config::path()
+        && !path.exists()
+    {
+        let mut config = forkfs::Config::default();
+        let default = config.sessions_dir();
+        let sessions_dir = input.ask(
+            "Where should sessions be stored?",
+            &default.to_string_lossy(),
+        )?;
+        if Path::new(&sessions_dir) != default {
+            config.sessions_dir = Some(sessions_dir.into());
+        }
+        config.save()?;
+        drop(writeln!(io::stderr(), "Wrote {}.", path.display()));
+    }
+
+    // Restart with the newly granted capabilities
+    Err(Command::new(exe).args(env::args_os().skip(1)).exec())
+        .map_err(|e| error_stack::Report::new(e).change_context(forkfs::Error::Io))
+        .attach_printable("Failed to restart forkfs")
+}
+
+fn run(
+    Run {
+        mut command,
+        script,
+        session,
+        options,
+        binds,
+        pins,
+        preview,
+        pipe,
+        detach,
+        restart,
+        ephemeral,
+        keep_on_failure,
+        discard_on_success,
+        wait,
+        stop,
+        checkpoint_every,
+        ci_summary,
+    }: Run,
+) -> Result<ExitCode, forkfs::Error> {
+    if let Some(script) = script {
+        command = script_command(script, command);
+    }
+    if let Some(path) = &ci_summary
+        && path.extension() == Some(OsStr::new("json"))
+    {
+        return Err(forkfs::Error::InvalidArgument).attach_printable(
+            "The CI summary path is for the markdown summary, the JSON one is written next to it \
+             with a .json extension.",
+        );
+    }
+    let options = options.resolve()?;
+    let min_free_space = options
+        .min_free_space
+        .unwrap_or(forkfs::DEFAULT_MIN_FREE_SPACE);
+    if preview {
+        let options = toml::to_string(&forkfs::Profile {
+            min_free_space: Some(min_free_space),
+            ..options
+        })
+        .change_context(forkfs::Error::InvalidArgument)
+        .attach_printable("Failed to serialize options")?;
+        print(&options)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let encryption_key = options
+        .key_file
+        .as_deref()
+        .map(forkfs::EncryptionKey::load)
+        .transpose()?;
+    let owner = options
+        .owner
+        .as_deref()
+        .map(forkfs::Viewer::lookup)
+        .transpose()?;
+    let session = if ephemeral {
+        format!("ephemeral-{}", process::id()).into()
+    } else if let Some(session) = session {
+        session
+    } else {
+        default_session()?
+    };
+    let status = forkfs::RunOp::builder()
+        .session(&session)
+        .command(command.as_slice())
+        .presets(&options.presets)
+        .passthroughs(&options.passthroughs)
+        .binds(&binds)
+        .max_write(options.max_write)
+        .min_free_space(min_free_space)
+        .encryption_key(encryption_key.as_ref())
+        .lower_device(options.lower_device.as_deref())
+        .root(options.root.as_deref())
+        .unbound(&options.no_bind)
+        .overlay_options(&options.overlay_options)
+        .volatile(options.volatile_storage.unwrap_or_default())
+        .pins(&pins)
+        .private_pids(options.private_pids.unwrap_or_default())
+        .network(options.network.unwrap_or_default())
+        .umask(options.umask)
+        .owner(owner)
+        .backend(options.backend.unwrap_or_default())
+        .pipe(pipe)
+        .release_stdio(true)
+        .detach(detach)
+        .restart(restart.into())
+        .ephemeral(ephemeral)
+        .keep_on_failure(keep_on_failure)
+        .discard_on_success(discard_on_success)
+        .wait(wait || ci_summary.is_some())
+        .stop(stop)
+        .checkpoint_every(checkpoint_every)
+        .build()
+        .run()?;
+
+    if let Some(path) = ci_summary {
+        write_ci_summary(&forkfs::summarize_run(&session, &command, status)?, &path)?;
+    }
+    if keep_on_failure && !status.success() {
+        drop(writeln!(
+            io::stderr(),
+            "Kept session {} for debugging.",
+            session.to_string_lossy()
+        ));
+    }
+    Ok(exit_code(status))
+}
+
+fn write_ci_summary(
+    summary: &forkfs::output::v1::RunSummary,
+    path: &Path,
+) -> Result<(), forkfs::Error> {
+    let forkfs::output::v1::RunSummary {
+        session,
+        command,
+        exit_code,
+        signal,
+        changes,
+        bytes_before,
+        bytes_after,
+        violations,
+        ..
+    } = summary;
+    let mut markdown = String::new();
+    let _ = writeln!(markdown, "### forkfs run in `{session}`\n");
+    let _ = writeln!(markdown, "| | |\n| --- | --- |");
+    let _ = writeln!(
+        markdown,
+        "| Command | `{}` |",
+        // Pipes would end the table cell early
+        command_line(command).replace('|', "\\|")
+    );
+    let status = match (exit_code, signal) {
+        (Some(code), _) => format!("exited with {code}"),
+        (None, Some(signal)) => format!("killed by signal {signal}"),
+        (None, None) => "unknown".to_string(),
+    };
+    let _ = writeln!(markdown, "| Status | {status} |");
+    let _ = writeln!(
+        markdown,
+        "| Changes | {} added, {} modified, {} deleted |",
+        changes.added, changes.modified, changes.deleted
+    );
+    let _ = writeln!(markdown, "| Size | {bytes_before} -> {bytes_after} bytes |");
+    if violations.is_empty() {
+        let _ = writeln!(markdown, "\nNo changes were flagged for review.");
+    } else {
+        let _ = writeln!(markdown, "\n#### Flagged for review\n");
+        for forkfs::output::v1::Violation {
+            path, description, ..
+        } in violations
+        {
+            let _ = writeln!(markdown, "- `{path}`: {description}");
+        }
+    }
+
+    let json = serde_json::to_string_pretty(summary)
+        .change_context(forkfs::Error::InvalidArgument)
+        .attach_printable("Failed to serialize the CI summary")?;
+    let json_path = path.with_extension("json");
+    for (path, contents) in [(path, markdown), (json_path.as_path(), json + "\n")] {
+        fs::write(path, contents)
+            .change_context(forkfs::Error::Io)
+            .attach_printable_lazy(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Turns `forkfs shell` into the equivalent `forkfs run`.
+fn shell(Shell { session, options }: Shell) -> Result<Run, forkfs::Error> {
+    let session = match session {
+        Some(session) => session,
+        None => default_session()?,
+    };
+    let session = forkfs::resolve_session_alias(&session)?.into_owned();
+    // SAFETY: no other threads have been started yet.
+    unsafe { env::set_var("FORKFS_SESSION", &session) };
+
+    Ok(Run {
+        command: vec![user_shell(), "-i".into()],
+        script: None,
+        session: Some(session),
+        options,
+        binds: Vec::new(),
+        pins: Vec::new(),
+        preview: false,
+        pipe: false,
+        detach: false,
+        restart: Restart::Never,
+        ephemeral: false,
+        keep_on_failure: false,
+        discard_on_success: false,
+        wait: false,
+        stop: false,
+        checkpoint_every: None,
+        ci_summary: None,
+    })
+}
+
+fn default_session() -> Result<OsString, forkfs::Error> {
+    Ok(forkfs::Config::load()?
+        .session
+        .map_or_else(|| "default".into(), Into::into))
+}
+
+fn user_shell() -> OsString {
+    env::var_os("SHELL")
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| "/bin/sh".into())
+}
+
+fn script_command(script: PathBuf, args: Vec<OsString>) -> Vec<OsString> {
+    let mut command = vec![user_shell()];
+    if script == Path::new("-") {
+        // Let the shell read commands from the inherited stdin
+        command.push("-s".into());
+        if !args.is_empty() {
+            command.push("--".into());
+        }
+    } else {
+        command.push(script.into_os_string());
+    }
+    command.extend(args);
+    command
+}
+
+/// Mirrors the shell convention of reporting signal deaths as `128 + signal`.
+fn exit_code(status: ExitStatus) -> ExitCode {
+    let code = status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(1);
+    ExitCode::from(u8::try_from(code).unwrap_or(u8::MAX))
+}
+
+fn parse_bind(bind: &str) -> std::result::Result<forkfs::Bind, String> {
+    let mut parts = bind.split(':');
+    let source = PathBuf::from(parts.next().unwrap_or_default());
+    if source.as_os_str().is_empty() {
+        return Err("the source can't be empty".to_string());
+    }
+    let source = std::path::absolute(&source).map_err(|e| format!("invalid source: {e}"))?;
+    let target = parts
+        .next()
+        .filter(|target| !target.is_empty())
+        .map_or_else(|| source.clone(), PathBuf::from);
+    let read_only = match parts.next() {
+        None | Some("rw") => false,
+        Some("ro") => true,
+        Some(mode) => return Err(format!("unknown mode {mode:?}, expected \"ro\" or \"rw\"")),
+    };
+    if parts.next().is_some() {
+        return Err("expected SRC[:DST[:ro]]".to_string());
+    }
+    Ok(forkfs::Bind {
+        source,
+        target,
+        read_only,
+    })
+}
+
+fn parse_size(size: &str) -> std::result::Result<u64, String> {
+    let size = size.trim();
+    let size = size.strip_suffix(['B', 'b']).unwrap_or(size);
+    let size = size.strip_suffix('i').unwrap_or(size);
+    let (digits, shift) = match size.as_bytes().last().map(u8::to_ascii_uppercase) {
+        Some(b'K') => (&size[..size.len() - 1], 10),
+        Some(b'M') => (&size[..size.len() - 1], 20),
+        Some(b'G') => (&size[..size.len() - 1], 30),
+        Some(b'T') => (&size[..size.len() - 1], 40),
+        _ => (size, 0),
+    };
+
+    let value = digits
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid size {size:?}: {e}"))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {size:?} is too large"))
+}
+
+fn parse_umask(umask: &str) -> std::result::Result<u32, String> {
+    let umask = umask.trim();
+    let digits = umask.strip_prefix("0o").unwrap_or(umask);
+    let value =
+        u32::from_str_radix(digits, 8).map_err(|e| format!("invalid umask {umask:?}: {e}"))?;
+    if value > 0o777 {
+        return Err(format!("umask {umask:?} has bits beyond 777"));
+    }
+    Ok(value)
+}
+
+fn parse_duration(duration: &str) -> std::result::Result<Duration, String> {
+    let duration = duration.trim();
+    let (digits, unit) = match duration.as_bytes().last() {
+        Some(b's') => (&duration[..duration.len() - 1], 1),
+        Some(b'm') => (&duration[..duration.len() - 1], 60),
+        Some(b'h') => (&duration[..duration.len() - 1], 60 * 60),
+        Some(b'd') => (&duration[..duration.len() - 1], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+
+    let value = digits
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid duration {duration:?}: {e}"))?;
+    if value == 0 {
+        return Err("the duration can't be zero".to_string());
+    }
+    value
+        .checked_mul(unit)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration {duration:?} is too long"))
+}
+
+fn config(config: ConfigCmd) -> Result<(), forkfs::Error> {
+    match config {
+        ConfigCmd::Show(options) => show_config(*options),
+        ConfigCmd::Check => check_config(),
+    }
+}
+
+fn export_session(
+    session: &OsStr,
+    output: Option<&Path>,
+    preserve_special: bool,
+    redactions: &[forkfs::Redaction],
+    filter: forkfs::ExportFilter,
+) -> Result<Vec<forkfs::Omission>, forkfs::Error> {
+    let io_err = |e, message: String| {
+        error_stack::Report::new(e)
+            .change_context(forkfs::Error::Io)
+            .attach_printable(message)
+    };
+    let Some(output) = output else {
+        return forkfs::export_filtered_session(
+            session,
+            io::stdout().lock(),
+            preserve_special,
+            redactions,
+            filter,
+        );
+    };
+
+    let file = fs::File::create(output)
+        .map_err(|e| io_err(e, format!("Failed to create {}", output.display())))?;
+    let Some(compressor) = compressor(output) else {
+        return forkfs::export_filtered_session(
+            session,
+            io::BufWriter::new(file),
+            preserve_special,
+            redactions,
+            filter,
+        );
+    };
+
+    let mut child = Command::new(compressor)
+        .arg("-c")
+        .stdin(std::process::Stdio::piped())
+        .stdout(file)
+        .spawn()
+        .map_err(|e| io_err(e, format!("Failed to run {compressor}")))?;
+    let result = forkfs::export_filtered_session(
+        session,
+        child.stdin.take().unwrap(),
+        preserve_special,
+        redactions,
+        filter,
+    );
+    let status = child
+        .wait()
+        .map_err(|e| io_err(e, format!("Failed to wait for {compressor}")))?;
+    let omissions = result?;
+    if status.success() {
+        Ok(omissions)
+    } else {
+        Err(forkfs::Error::Io).attach_printable(format!("{compressor} failed: {status}"))
+    }
+}
+
+fn report_omissions(omissions: &[forkfs::Omission]) {
+    let mut stderr = io::stderr().lock();
+    for forkfs::Omission { path, kind } in omissions {
+        let reason = match kind {
+            forkfs::OmissionKind::Socket => "sockets can't be archived",
+            forkfs::OmissionKind::Device => {
+                "device nodes are only archived with --preserve-special"
+            }
+            forkfs::OmissionKind::TooLarge => "it is larger than --max-file-size",
+            forkfs::OmissionKind::Binary => "it looks binary",
+            forkfs::OmissionKind::Unpatchable => "patches can only hold text files and symlinks",
+        };
+        drop(writeln!(stderr, "Left out {}: {reason}.", path.display()));
+    }
+}
+
+fn import_session(session: &OsStr, archive: &Path) -> Result<(), forkfs::Error> {
+    let io_err = |e, message: String| {
+        error_stack::Report::new(e)
+            .change_context(forkfs::Error::Io)
+            .attach_printable(message)
+    };
+    let file = fs::File::open(archive)
+        .map_err(|e| io_err(e, format!("Failed to open {}", archive.display())))?;
+    let Some(compressor) = compressor(archive) else {
+        return forkfs::import_session(session, io::BufReader::new(file));
+    };
+
+    let mut child = Command::new(compressor)
+        .arg("-dc")
+        .stdin(file)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| io_err(e, format!("Failed to run {compressor}")))?;
+    let result = forkfs::import_session(session, child.stdout.take().unwrap());
+    let status = child
+        .wait()
+        .map_err(|e| io_err(e, format!("Failed to wait for {compressor}")))?;
+    // Stopping early makes the decompressor fail, so its status is secondary
+    result?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(forkfs::Error::Io).attach_printable(format!("{compressor} failed: {status}"))
+    }
+}
+
+fn apply_archive(
+    ApplyArchive {
+        archive,
+        store,
+        allowed,
+        force,
+        fast,
+    }: ApplyArchive,
+    input: Input,
+    style: Style,
+) -> Result<(), forkfs::Error> {
+    let session = OsString::from(format!("apply-archive-{}", process::id()));
+    match store {
+        Some(store) => forkfs::import_from_store(&session, &store, &archive)?,
+        None => import_session(&session, &archive)?,
+    }
+    let result = sessions(
+        Sessions::Apply(ApplySession {
+            session: session.clone(),
+            allowed,
+            force,
+            resolve: false,
+            fast,
+            report: None,
+            resume: false,
+            abort: false,
+        }),
+        input,
+        style,
+    );
+    // Interrupted applies are undone if possible, or else need the session
+    if result.is_err()
+        && let Err(e) = forkfs::abort_apply(&session)
+        && !matches!(e.current_context(), forkfs::Error::InvalidArgument)
+    {
+        return result.attach_printable(format!(
+            "Kept session {} to continue or abort the apply.",
+            session.display()
+        ));
+    }
+    forkfs::delete_sessions(SessionOperand::List(&[&session]), false)?;
+    result
+}
+
+/// The program that handles an archive's compression, judging by its
+/// extension.
+fn compressor(archive: &Path) -> Option<&'static str> {
+    match archive.extension().and_then(OsStr::to_str) {
+        Some("zst") => Some("zstd"),
+        Some("gz" | "tgz") => Some("gzip"),
+        Some("xz") => Some("xz"),
+        _ => None,
+    }
+}
+
+fn check_session(session: &OsStr) -> Result<(), forkfs::Error> {
+    let problems = forkfs::check_session(session)?;
+    if problems.is_empty() {
+        drop(writeln!(
+            io::stderr(),
+            "No problems found in session {}.",
+            session.display()
+        ));
+        return Ok(());
+    }
+
+    let mut out = String::new();
+    for forkfs::Problem { path, issue } in &problems {
+        let message = match issue {
+            forkfs::Issue::ForeignWhiteout => {
+                "named like an aufs whiteout, which OverlayFS treats as a regular file".to_string()
+            }
+            forkfs::Issue::MisplacedXattr { name } => {
+                format!("{name} doesn't apply to this kind of file")
+            }
+            forkfs::Issue::InvalidXattr { name } => format!("{name} has an invalid value"),
+            forkfs::Issue::WorkLeftover => "left over from an interrupted operation".to_string(),
+            forkfs::Issue::HiddenByMount => {
+                "created while the session was inactive and hidden while it is active".to_string()
+            }
+        };
+        let _ = writeln!(out, "{}: {message}", path.display());
+    }
+    print(&out)?;
+    Err(forkfs::Error::InvalidArgument).attach_printable(format!(
+        "Found {} problem(s) in session {}.",
+        problems.len(),
+        session.display()
+    ))
+}
+
+fn session_drift(session: &OsStr) -> Result<(), forkfs::Error> {
+    let drift = forkfs::session_drift(session)?;
+    let mut out = String::new();
+    let mut diverged = 0;
+    for forkfs::Drift { path, kind } in &drift {
+        let kind = match kind {
+            forkfs::DriftKind::Lower => "lower",
+            forkfs::DriftKind::Upper => "upper",
+            forkfs::DriftKind::Neither => {
+                diverged += 1;
+                "neither"
+            }
+        };
+        let _ = writeln!(out, "{kind:<7} {}", path.display());
+    }
+    print(&out)?;
+    if diverged == 0 {
+        return Ok(());
+    }
+    Err(forkfs::Error::Conflict).attach_printable(format!(
+        "{diverged} file(s) changed both in session {} and outside it.",
+        session.display()
+    ))
+}
+
+fn verify_session(session: &OsStr, manifest: &Path) -> Result<(), forkfs::Error> {
+    let deviations = forkfs::verify_session(session, manifest)?;
+    if deviations.is_empty() {
+        drop(writeln!(
+            io::stderr(),
+            "Session {} matches the manifest.",
+            session.display()
+        ));
+        return Ok(());
+    }
+
+    let mut out = String::new();
+    for forkfs::Deviation { path, change, kind } in &deviations {
+        let kind = match kind {
+            forkfs::DeviationKind::Unexpected => "unexpected",
+            forkfs::DeviationKind::Missing => "missing",
+        };
+        let change = match change {
+            forkfs::ChangeKind::Added => 'A',
+            forkfs::ChangeKind::Modified | forkfs::ChangeKind::MetadataOnly => 'M',
+            forkfs::ChangeKind::Deleted => 'D',
+        };
+        let _ = writeln!(out, "{kind:<10} {change} {}", path.display());
+    }
+    print(&out)?;
+    Err(forkfs::Error::InvalidArgument).attach_printable(format!(
+        "Session {} deviates from the manifest in {} path(s).",
+        session.display(),
+        deviations.len()
+    ))
+}
+
+fn session_info(session: &OsStr) -> Result<(), forkfs::Error> {
+    const RECENT_COMMANDS: usize = 10;
+
+    let forkfs::SessionStatus {
+        active,
+        mount_id,
+        mounts,
+        size,
+        inodes,
+        changes,
+    } = forkfs::session_status(session)?;
+    let forkfs::SessionInfo {
+        created,
+        last_activated,
+        commands,
+    } = forkfs::session_info(session)?;
+
+    let mut out = String::new();
+    match (active, mount_id) {
+        (true, Some(id)) => {
+            let _ = writeln!(out, "Status: active (mount {id})");
+        }
+        (true, None) => out.push_str("Status: active\n"),
+        (false, _) => out.push_str("Status: inactive\n"),
+    }
+    let _ = writeln!(
+        out,
+        "Changes: {changes} path(s) taking up {size} bytes and {inodes} inode(s)"
+    );
+    if !mounts.is_empty() {
+        let _ = writeln!(out, "Mounts:");
+    }
+    for forkfs::Mount { id, path, .. } in mounts {
+        let _ = writeln!(out, "  {}  (mount {id})", path.display());
+    }
+    let _ = writeln!(out, "Created: {}", timestamp(created));
+    let _ = writeln!(out, "Last activated: {}", timestamp(last_activated));
+    if commands.is_empty() {
+        let _ = writeln!(out, "No commands recorded.");
+    } else {
+        let _ = writeln!(out, "Recent commands:");
+        let recent = commands.len().saturating_sub(RECENT_COMMANDS);
+        for forkfs::CommandRecord { time, args, .. } in &commands[recent..] {
+            let _ = writeln!(out, "  {}  {}", timestamp(Some(*time)), command_line(args));
+        }
+    }
+
+    let processes = forkfs::detached_processes(session)?;
+    if !processes.is_empty() {
+        let _ = writeln!(out, "Detached processes:");
+    }
+    for process in processes {
+        let _ = write!(out, "  {}  ", process.pid);
+        match process.uptime() {
+            Some(uptime) => {
+                let _ = write!(out, "running for {}", uptime_text(uptime));
+            }
+            None => out.push_str("exited"),
+        }
+        if process.restart == forkfs::Restart::OnFailure {
+            let _ = write!(out, ", restarted {} time(s)", process.restarts);
+        }
+        let _ = writeln!(out, "  {}", command_line(&process.command));
+    }
+    print(&out)
+}
+
+fn session_trend(session: &OsStr) -> Result<(), forkfs::Error> {
+    let runs = forkfs::session_trend(session)?;
+    if runs.is_empty() {
+        return print("No change statistics recorded.\n");
+    }
+
+    let growth = |before: u64, after: u64| {
+        if after >= before {
+            format!("+{}", after - before)
+        } else {
+            format!("-{}", before - after)
+        }
+    };
+    let mut out = String::new();
+    for forkfs::RunStats {
+        command,
+        before,
+        after,
+    } in runs
+    {
+        let _ = writeln!(
+            out,
+            "{}  {}",
+            timestamp(Some(command.time)),
+            command_line(&command.args)
+        );
+        let _ = writeln!(
+            out,
+            "  files: {} -> {} ({}), bytes: {} -> {} ({})",
+            before.files,
+            after.files,
+            growth(before.files, after.files),
+            before.bytes,
+            after.bytes,
+            growth(before.bytes, after.bytes)
+        );
+        if !after.top_dirs.is_empty() {
+            let dirs = after
+                .top_dirs
+                .iter()
+                .map(|forkfs::DirUsage { path, bytes }| {
+                    format!("{} ({bytes} bytes)", path.display())
+                })
+                .collect::<Vec<_>>();
+            let _ = writeln!(out, "  largest: {}", dirs.join(", "));
+        }
+    }
+    print(&out)
+}
+
+fn session_mounts(session: &OsStr) -> Result<(), forkfs::Error> {
+    let mounts = forkfs::session_mounts(session)?;
+    if mounts.is_empty() {
+        return print(&format!(
+            "Session {} is inactive, so nothing is mounted.\n",
+            session.display()
+        ));
+    }
+
+    let mut out = String::new();
+    for forkfs::Mount {
+        id,
+        parent,
+        path,
+        fs_type,
+        source,
+        options,
+        fs_options,
+        propagation,
+    } in mounts
+    {
+        let _ = writeln!(out, "{}  (mount {id} on {parent})", path.display());
+        let _ = writeln!(out, "  type: {fs_type} from {source}");
+        let _ = writeln!(out, "  options: {options}");
+        let _ = writeln!(out, "  file system options: {fs_options}");
+        if propagation.is_empty() {
+            out.push_str("  propagation: private\n");
+        } else {
+            let _ = writeln!(out, "  propagation: {}", propagation.join(" "));
+        }
+    }
+    print(&out)
+}
+
+fn gc(Gc { store, preview }: Gc) -> Result<(), forkfs::Error> {
+    let garbage = forkfs::collect_garbage(store.as_deref(), preview)?;
+    let mut out = String::new();
+    let (mut chunks, mut chunk_bytes) = (0, 0);
+    for forkfs::Garbage { path, kind, size } in &garbage {
+        let kind = match kind {
+            forkfs::GarbageKind::Leftover => "leftover",
+            forkfs::GarbageKind::ExpiredSnapshot => "expired snapshot",
+            forkfs::GarbageKind::UnusedChunk => {
+                chunks += 1;
+                chunk_bytes += size;
+                continue;
+            }
+        };
+        let _ = writeln!(out, "{kind} {} ({size} bytes)", path.display());
+    }
+    if chunks > 0 {
+        let _ = writeln!(out, "{chunks} unused chunk(s) ({chunk_bytes} bytes)");
+    }
+    let total = garbage.iter().map(|garbage| garbage.size).sum::<u64>();
+    let _ = writeln!(
+        out,
+        "{} {total} bytes.",
+        if preview {
+            "Would reclaim"
+        } else {
+            "Reclaimed"
+        }
+    );
+    print(&out)
+}
+
+fn snapshot(cmd: SnapshotCmd) -> Result<(), forkfs::Error> {
+    let describe = |forkfs::Snapshot { number, tag, .. }: &forkfs::Snapshot| {
+        tag.as_ref()
+            .map_or_else(|| number.to_string(), |tag| format!("{number} ({tag})"))
+    };
+    match cmd {
+        SnapshotCmd::Create(CreateSnapshot { session, tag }) => {
+            let snapshot = forkfs::create_snapshot(&session, tag.as_deref())?;
+            drop(writeln!(
+                io::stderr(),
+                "Saved snapshot {} of session {}.",
+                describe(&snapshot),
+                session.display()
+            ));
+            Ok(())
+        }
+        SnapshotCmd::List(ListSnapshots { session }) => {
+            let snapshots = forkfs::session_snapshots(&session)?;
+            if snapshots.is_empty() {
+                return print("No snapshots.\n");
+            }
+            let mut out = String::new();
+            for snapshot in snapshots {
+                let _ = writeln!(
+                    out,
+                    "{}  {}",
+                    timestamp(Some(snapshot.created)),
+                    describe(&snapshot)
+                );
+            }
+            print(&out)
+        }
+        SnapshotCmd::Restore(RestoreSnapshot { session, snapshot }) => {
+            let snapshot = forkfs::restore_snapshot(&session, snapshot.as_deref())?;
+            drop(writeln!(
+                io::stderr(),
+                "Rolled session {} back to snapshot {}.",
+                session.display(),
+                describe(&snapshot)
+            ));
+            Ok(())
+        }
+    }
+}
+
+fn command_line(args: &[String]) -> String {
+    let mut line = String::new();
+    for arg in args {
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        // Keep arguments containing spaces recognizable
+        if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"') {
+            let _ = write!(line, "{arg:?}");
+        } else {
+            line.push_str(arg);
+        }
+    }
+    line
+}
+
+fn uptime_text(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    match secs {
+        ..60 => format!("{secs}s"),
+        60..3600 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs / 60 % 60),
+    }
+}
+
+/// Formats seconds since the Unix epoch as a UTC date and time.
+fn timestamp(secs: Option<u64>) -> String {
+    let Some(secs) = secs else {
+        return "unknown".to_string();
+    };
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn check_config() -> Result<(), forkfs::Error> {
+    let diagnostics = forkfs::Config::check()?;
+    let path = forkfs::Config::path().unwrap_or_default();
+    if diagnostics.is_empty() {
+        drop(writeln!(
+            io::stderr(),
+            "No problems found in {}.",
+            path.display()
+        ));
+        return Ok(());
+    }
+
+    let mut out = String::new();
+    for forkfs::ConfigDiagnostic {
+        line,
+        column,
+        message,
+    } in &diagnostics
+    {
+        let _ = writeln!(out, "{}:{line}:{column}: {message}", path.display());
+    }
+    print(&out)?;
+    Err(forkfs::Error::InvalidArgument).attach_printable(format!(
+        "Found {} problem(s) in {}.",
+        diagnostics.len(),
+        path.display()
+    ))
+}
+
+/// Where an effective setting was taken from.
+#[derive(Clone, Debug)]
+enum Source {
+    Default,
+    Environment(&'static str),
+    ConfigFile,
+    Profile(String),
+    Preset(forkfs::Preset),
+    CommandLine,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => write!(f, "default"),
+            Self::Environment(var) => write!(f, "${var}"),
+            Self::ConfigFile => write!(f, "config file"),
+            Self::Profile(name) => write!(f, "profile {name}"),
+            Self::Preset(preset) => write!(f, "preset {}", preset_name(*preset)),
+            Self::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+fn show_config(options: RunOptions) -> Result<(), forkfs::Error> {
+    fn scalar(out: &mut String, key: &str, (value, source): (String, Source)) {
+        let _ = writeln!(out, "{key} = {value} # {source}");
+    }
+    fn list(out: &mut String, key: &str, values: Vec<(String, Source)>) {
+        if values.is_empty() {
+            let _ = writeln!(out, "{key} = []");
+            return;
+        }
+        let _ = writeln!(out, "{key} = [");
+        for (value, source) in values {
+            let _ = writeln!(out, "    {value}, # {source}");
+        }
+        let _ = writeln!(out, "]");
+    }
+
+    let config = forkfs::Config::load()?;
+    let layers = options.layers()?;
+    let quote = |path: &Path| toml::Value::from(path.to_string_lossy().into_owned()).to_string();
+
+    let mut out = String::new();
+    let paths = forkfs::Config::paths();
+    let user = forkfs::Config::path();
+    for path in paths {
+        // The system-wide file is only worth mentioning when there is one
+        if Some(&path) == user.as_ref() || path.exists() {
+            let _ = writeln!(out, "# {}", path.display());
+        }
+    }
+
+    let xdg = |var| {
+        env::var_os(var)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+    };
+    let source = if let Some(dir) = forkfs::sessions_dir_override() {
+        if env::var_os("FORKFS_DATA_DIR").is_some_and(|var| Path::new(&var) == dir) {
+            Source::Environment("FORKFS_DATA_DIR")
+        } else {
+            Source::CommandLine
+        }
+    } else if config.sessions_dir.is_some() {
+        Source::ConfigFile
+    } else if forkfs::static_paths() {
+        Source::Default
+    } else if config.sessions_dir().parent() == xdg("XDG_CACHE_HOME").as_deref() {
+        Source::Environment("XDG_CACHE_HOME")
+    } else if config.sessions_dir().parent() == xdg("XDG_DATA_HOME").as_deref() {
+        Source::Environment("XDG_DATA_HOME")
+    } else {
+        Source::Default
+    };
+    scalar(
+        &mut out,
+        "sessions-dir",
+        (quote(&config.sessions_dir()), source),
+    );
+    scalar(
+        &mut out,
+        "session",
+        config.session.as_ref().map_or_else(
+            || ("\"default\"".to_string(), Source::Default),
+            |session| {
+                (
+                    toml::Value::from(session.as_str()).to_string(),
+                    Source::ConfigFile,
+                )
+            },
+        ),
+    );
+
+    let mut presets = Vec::<(forkfs::Preset, Source)>::new();
+    for (source, layer) in &layers {
+        for &preset in &layer.presets {
+            if !presets.iter().any(|&(p, _)| p == preset) {
+                presets.push((preset, source.clone()));
+            }
+        }
+    }
+    // Mirror the order in which passthroughs are mounted
+    let mut passthroughs = presets
+        .iter()
+        .flat_map(|&(preset, _)| {
+            preset
+                .passthroughs()
+                .into_iter()
+                .map(move |dir| (dir, Source::Preset(preset)))
+        })
+        .collect::<Vec<_>>();
+    for (source, layer) in &layers {
+        for dir in &layer.passthroughs {
+            if !passthroughs.iter().any(|(d, _)| d == dir) {
+                passthroughs.push((dir.clone(), source.clone()));
+            }
+        }
+    }
+    list(
+        &mut out,
+        "presets",
+        presets
+            .into_iter()
+            .map(|(preset, source)| (toml::Value::from(preset_name(preset)).to_string(), source))
+            .collect(),
+    );
+    list(
+        &mut out,
+        "passthroughs",
+        passthroughs
+            .into_iter()
+            .map(|(dir, source)| (quote(&dir), source))
+            .collect(),
+    );
+    let mut no_bind = Vec::<(forkfs::SystemDir, Source)>::new();
+    for (source, layer) in &layers {
+        for &dir in &layer.no_bind {
+            if !no_bind.iter().any(|&(d, _)| d == dir) {
+                no_bind.push((dir, source.clone()));
+            }
+        }
+    }
+    list(
+        &mut out,
+        "no-bind",
+        no_bind
+            .into_iter()
+            .map(|(dir, source)| (toml::Value::from(dir.name()).to_string(), source))
+            .collect(),
+    );
+    let mut overlay_options = Vec::<(forkfs::OverlayOption, Source)>::new();
+    for (source, layer) in &layers {
+        for &option in &layer.overlay_options {
+            if !overlay_options.iter().any(|&(o, _)| o == option) {
+                overlay_options.push((option, source.clone()));
+            }
+        }
+    }
+    list(
+        &mut out,
+        "overlay-options",
+        overlay_options
+            .into_iter()
+            .map(|(option, source)| (toml::Value::from(option.name()).to_string(), source))
+            .collect(),
+    );
+
+    let last = |get: &dyn Fn(&forkfs::Profile) -> Option<String>| {
+        layers
+            .iter()
+            .rev()
+            .find_map(|(source, layer)| Some((get(layer)?, source.clone())))
+    };
+    if let Some(setting) = last(&|layer| layer.max_write.map(|size| size.to_string())) {
+        scalar(&mut out, "max-write", setting);
+    }
+    let min_free_space = last(&|layer| layer.min_free_space.map(|size| size.to_string()))
+        .unwrap_or_else(|| (forkfs::DEFAULT_MIN_FREE_SPACE.to_string(), Source::Default));
+    scalar(&mut out, "min-free-space", min_free_space);
+    if let Some(setting) = last(&|layer| layer.key_file.as_deref().map(quote)) {
+        scalar(&mut out, "key-file", setting);
+    }
+    if let Some(setting) = last(&|layer| layer.lower_device.as_deref().map(quote)) {
+        scalar(&mut out, "lower-device", setting);
+    }
+    if let Some(setting) = last(&|layer| layer.root.as_deref().map(quote)) {
+        scalar(&mut out, "root", setting);
+    }
+    if let Some(setting) =
+        last(&|layer| layer.volatile_storage.map(|volatile| volatile.to_string()))
+    {
+        scalar(&mut out, "volatile-storage", setting);
+    }
+    if let Some(setting) = last(&|layer| layer.private_pids.map(|private| private.to_string())) {
+        scalar(&mut out, "private-pids", setting);
+    }
+    if let Some(setting) = last(&|layer| {
+        layer
+            .network
+            .map(|network| toml::Value::from(format!("{network:?}").to_lowercase()).to_string())
+    }) {
+        scalar(&mut out, "network", setting);
+    }
+    if let Some(setting) = last(&|layer| layer.umask.map(|umask| format!("0o{umask:03o}"))) {
+        scalar(&mut out, "umask", setting);
+    }
+    if let Some(setting) = last(&|layer| {
+        layer
+            .owner
+            .as_deref()
+            .map(|owner| toml::Value::from(owner).to_string())
+    }) {
+        scalar(&mut out, "owner", setting);
+    }
+    if let Some(setting) = last(&|layer| {
+        layer
+            .backend
+            .map(|backend| toml::Value::from(format!("{backend:?}").to_lowercase()).to_string())
+    }) {
+        scalar(&mut out, "backend", setting);
+    }
+
+    print(&out)
+}
+
+fn preset_name(preset: forkfs::Preset) -> String {
+    format!("{preset:?}").to_lowercase()
+}
+
+fn print(output: &str) -> Result<(), forkfs::Error> {
+    io::stdout()
+        .write_all(output.as_bytes())
+        .map_err(|e| error_stack::Report::new(e).change_context(forkfs::Error::Io))
+        .attach_printable("Failed to write to stdout")
+}
+
+fn sessions(sessions: Sessions, input: Input, style: Style) -> Result<(), forkfs::Error> {
+    match sessions {
+        Sessions::List(ListSessions {
+            format: Format::Human,
+        }) => forkfs::list_sessions(style),
+        Sessions::List(ListSessions {
+            format: Format::Json,
+        }) => {
+            let mut stdout = io::stdout().lock();
+            serde_json::to_writer(&mut stdout, &forkfs::session_listing()?)
+                .map_err(io::Error::from)
+                .and_then(|()| writeln!(stdout))
+                .change_context(forkfs::Error::Io)
+                .attach_printable("Failed to write to stdout")
+        }
+        Sessions::Stop(target) => forkfs::stop_sessions(target.operand()),
+        Sessions::Delete(DeleteSessions { target, shred }) => {
+            forkfs::delete_sessions(target.operand(), shred)
+        }
+        Sessions::Rename(RenameSession { session, name }) => {
+            forkfs::rename_session(&session, &name)
+        }
+        Sessions::Clone(CloneSession { session, name }) => forkfs::clone_session(&session, &name),
+        Sessions::Publish(PublishSession {
+            session,
+            mount_point,
+            viewer,
+        }) => {
+            let viewer = viewer.as_deref().map(forkfs::Viewer::lookup).transpose()?;
+            if fs::read_dir(&mount_point).is_ok_and(|mut entries| entries.next().is_some())
+                && !input.confirm(&format!(
+                    "{} is not empty and its contents will be hidden. Continue?",
+                    mount_point.display()
+                ))?
+            {
+                return Ok(());
+            }
+            forkfs::publish_session(&session, &mount_point, viewer)
+        }
+        Sessions::Diff(DiffSession {
+            session,
+            structural,
+            content,
+            hexdump,
+            max_file_size,
+            exclude_binary,
+        }) => forkfs::diff_session(
+            &session,
+            style,
+            DiffDetail {
+                structural,
+                content,
+                hexdump,
+                max_file_size,
+                exclude_binary,
+            },
+        ),
+        Sessions::Drift(DriftSession { session }) => session_drift(&session),
+        Sessions::VerifyAgainst(VerifySession { manifest, session }) => {
+            let session = match session {
+                Some(session) => session,
+                None => default_session()?,
+            };
+            verify_session(&session, &manifest)
+        }
+        Sessions::Apply(ApplySession {
+            session,
+            allowed,
+            mut force,
+            resolve,
+            fast,
+            report,
+            resume,
+            abort,
+        }) => {
+            if abort {
+                return forkfs::abort_apply(&session);
+            }
+            if resume {
+                return forkfs::resume_apply(&session, &allowed, force, fast);
+            }
+            let conflicts = forkfs::session_diff(&session)?
+                .into_iter()
+                .filter(|change| change.diverged)
+                .collect::<Vec<_>>();
+            if matches!(report, Some(ReportFormat::Json)) {
+                let mut stdout = io::stdout().lock();
+                serde_json::to_writer(&mut stdout, &forkfs::conflict_report(&session)?)
+                    .map_err(io::Error::from)
+                    .and_then(|()| writeln!(stdout))
+                    .change_context(forkfs::Error::Io)
+                    .attach_printable("Failed to write to stdout")?;
+            }
+            if resolve && !conflicts.is_empty() {
+                // Refuse dangerous changes before asking about any conflicts
+                if !forkfs::review_session(&session, &allowed)?.is_empty() {
+                    return forkfs::apply_session(&session, &allowed, false, fast).map(drop);
+                }
+                let Some(resolutions) = forkfs::resolve_conflicts(&session, input, style)? else {
+                    return Ok(());
+                };
+                return forkfs::apply_resolved(&session, &allowed, &resolutions, fast).map(drop);
+            }
+            if !force && !conflicts.is_empty() {
+                for change in &conflicts {
+                    drop(writeln!(io::stderr(), "{}", change.path.display()));
+                }
+                if !input
+                    .confirm("These files were changed outside the session. Overwrite them?")?
+                {
+                    return Ok(());
+                }
+                force = true;
+            }
+            forkfs::apply_session(&session, &allowed, force, fast).map(drop)
+        }
+        Sessions::Revert(RevertPaths { session, paths }) => {
+            if forkfs::revert_paths(&session, &paths)?.is_empty() {
+                drop(writeln!(
+                    io::stderr(),
+                    "The session hasn't changed any of these files."
+                ));
+            }
+            Ok(())
+        }
+        Sessions::Check(CheckSession { session }) => check_session(&session),
+        Sessions::Export(ExportSession {
+            session,
+            output: _,
+            format: _,
+            store: Some(store),
+            preserve_special,
+            no_redact: _,
+            max_file_size: _,
+            exclude_binary: _,
+        }) => {
+            let forkfs::StoredArchive {
+                name,
+                size,
+                added,
+                omissions,
+            } = forkfs::export_to_store(&session, &store, preserve_special)?;
+            report_omissions(&omissions);
+            drop(writeln!(
+                io::stderr(),
+                "Stored {} in {}: {size} bytes, {added} of which were new.",
+                name.display(),
+                store.display()
+            ));
+            Ok(())
+        }
+        Sessions::Export(ExportSession {
+            session,
+            output,
+            format,
+            store: None,
+            preserve_special,
+            no_redact,
+            max_file_size,
+            exclude_binary,
+        }) => {
+            let redactions = if no_redact {
+                Vec::new()
+            } else {
+                forkfs::Config::load()?.redact
+            };
+            let filter = forkfs::ExportFilter {
+                max_file_size,
+                exclude_binary,
+            };
+            if let (ExportFormat::PatchSeries, Some(dir)) = (format, &output) {
+                return forkfs::export_patch_series(&session, dir, &redactions, filter)
+                    .map(|omissions| report_omissions(&omissions));
+            }
+            export_session(
+                &session,
+                output.as_deref(),
+                preserve_special,
+                &redactions,
+                filter,
+            )
+            .map(|omissions| report_omissions(&omissions))
+        }
+        Sessions::Seed(SeedSession { session, paths }) => forkfs::seed_session(&session, &paths),
+        Sessions::Import(ImportSession {
+            archive,
+            store: Some(store),
+            name,
+        }) => forkfs::import_from_store(&name, &store, &archive),
+        Sessions::Import(ImportSession {
+            archive,
+            store: None,
+            name,
+        }) => import_session(&name, &archive),
+        Sessions::ApplyArchive(archive) => apply_archive(archive, input, style),
+        Sessions::Attach(ProcessCmd { session, pid }) => {
+            forkfs::attach_process(&session, pid, io::stdout().lock())
+        }
+        Sessions::StopProcess(StopProcess {
+            process: ProcessCmd { session, pid },
+            kill,
+        }) => forkfs::stop_process(&session, pid, kill),
+        Sessions::Info(InfoSession { session }) => session_info(&session),
+        Sessions::Trend(InfoSession { session }) => session_trend(&session),
+        Sessions::Mounts(MountsSession { session }) => session_mounts(&session),
+        Sessions::Snapshot(cmd) => snapshot(cmd),
+        Sessions::Edit(EditSession { session, path }) => {
+            let status = forkfs::edit_session(&session, &path)?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(forkfs::Error::Io).attach_printable(format!("The editor failed: {status}"))
+            }
+        }
+    }
+}
+
+impl RunOptions {
+    /// The config file's defaults, the selected profiles, and the options given
+    /// on the command line, in increasing order of precedence.
+    fn layers(self) -> Result<Vec<(Source, forkfs::Profile)>, forkfs::Error> {
+        let Self {
+            presets,
+            passthroughs,
+            profiles,
+            max_write,
+            min_free_space,
+            key_file,
+            lower_device,
+            root,
+            no_bind,
+            overlay_options,
+            volatile_storage,
+            private_pids,
+            no_net,
+            loopback,
+            umask,
+            owner,
+            backend,
+        } = self;
+
+        let mut config = forkfs::Config::load()?;
+        let mut layers = Vec::new();
+        if !config.defaults.is_empty() {
+            layers.push((Source::ConfigFile, mem::take(&mut config.defaults)));
+        }
+        for name in profiles {
+            let profile = config.profile(&name)?.clone();
+            layers.push((Source::Profile(name), profile));
+        }
+        layers.push((
+            Source::CommandLine,
+            forkfs::Profile {
+                presets: presets.into_iter().map(Into::into).collect(),
+                passthroughs,
+                max_write,
+                min_free_space,
+                key_file,
+                lower_device,
+                root,
+                no_bind: no_bind.into_iter().map(Into::into).collect(),
+                overlay_options: overlay_options.into_iter().map(Into::into).collect(),
+                volatile_storage: volatile_storage.then_some(true),
+                private_pids: private_pids.then_some(true),
+                network: if loopback {
+                    Some(forkfs::Network::Loopback)
+                } else {
+                    no_net.then_some(forkfs::Network::Isolated)
+                },
+                umask,
+                owner,
+                backend: backend.map(Into::into),
+            },
+        ));
+        Ok(layers)
+    }
+
+    fn resolve(self) -> Result<forkfs::Profile, forkfs::Error> {
+        let mut options = forkfs::Profile::default();
+        for (_, layer) in self.layers()? {
+            options.merge(layer);
+        }
+        Ok(options)
+    }
+}
+
+impl SessionCmd {
+    const fn operand(&self) -> SessionOperand<'_, OsString> {
+        if self.all {
+            SessionOperand::All
+        } else {
+            SessionOperand::List(self.sessions.as_slice())
+        }
+    }
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn verify_app() {
+        ForkFs::command().debug_assert();
+    }
+
+    #[test]
+    fn timestamps_are_utc() {
+        assert_eq!(timestamp(Some(0)), "1970-01-01 00:00:00 UTC");
+        assert_eq!(timestamp(Some(951_782_400)), "2000-02-29 00:00:00 UTC");
+        assert_eq!(timestamp(Some(1_700_000_000)), "2023-11-14 22:13:20 UTC");
+        assert_eq!(timestamp(Some(4_107_542_400)), "2100-03-01 00:00:00 UTC");
+        assert_eq!(timestamp(None), "unknown");
     }
 
     #[test]
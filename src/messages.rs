@@ -0,0 +1,255 @@
+//! The catalog of user-facing messages.
+//!
+//! Every message must be translated into every supported locale, which the
+//! exhaustive matches below enforce. Placeholders are written as `{name}` and
+//! filled in with [`Message::fill`].
+
+use std::{
+    env,
+    fmt::{Display, Formatter},
+    sync::OnceLock,
+};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Locale {
+    En,
+    Fr,
+}
+
+impl Locale {
+    /// Picks the locale from the standard environment variables, falling back
+    /// to English for unsupported languages.
+    fn current() -> Self {
+        static LOCALE: OnceLock<Locale> = OnceLock::new();
+        *LOCALE.get_or_init(|| {
+            let lang = ["LC_ALL", "LC_MESSAGES", "LANG"]
+                .into_iter()
+                .filter_map(env::var_os)
+                .find(|lang| !lang.is_empty())
+                .unwrap_or_default();
+            if lang.as_encoded_bytes().starts_with(b"fr") {
+                Self::Fr
+            } else {
+                Self::En
+            }
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Message {
+    /// Placeholders: `path`.
+    CapabilitiesLost,
+    ErrorConflict,
+    ErrorInputRequired,
+    ErrorInsufficientSpace,
+    ErrorInvalidArgument,
+    ErrorIo,
+    ErrorNotRoot,
+    ErrorSessionNotFound,
+    ErrorSetupRequired,
+    ErrorWriteLimitExceeded,
+    /// Placeholders: `available`, `dir`, `required`.
+    InsufficientInodes,
+    /// Placeholders: `available`, `dir`, `required`.
+    InsufficientSpace,
+    /// Placeholders: `question`.
+    #[cfg(feature = "cli")]
+    NonInteractivePrompt,
+    /// Placeholders: `path`.
+    Setup,
+    /// Placeholders: `inodes`, `limit`, `written`.
+    WriteLimitExceeded,
+    /// The choices appended to yes/no questions.
+    #[cfg(feature = "cli")]
+    YesNo,
+}
+
+impl Message {
+    /// Returns the message in the user's locale.
+    pub fn get(self) -> &'static str {
+        match Locale::current() {
+            Locale::En => self.en(),
+            Locale::Fr => self.fr(),
+        }
+    }
+
+    /// Returns the message with its placeholders replaced.
+    pub fn fill(self, args: &[(&str, &dyn Display)]) -> String {
+        let mut message = self.get().to_string();
+        for (name, value) in args {
+            message = message.replace(&format!("{{{name}}}"), &value.to_string());
+        }
+        message
+    }
+
+    /// Returns whether an answer to a yes/no question means yes.
+    #[cfg(feature = "cli")]
+    pub fn is_yes(answer: &str) -> bool {
+        let answer = answer.trim().to_lowercase();
+        let localized: &[&str] = match Locale::current() {
+            Locale::En => &[],
+            Locale::Fr => &["o", "oui"],
+        };
+        ["y", "yes"].contains(&answer.as_str()) || localized.contains(&answer.as_str())
+    }
+
+    const fn en(self) -> &'static str {
+        match self {
+            Self::CapabilitiesLost => {
+                "ForkFS was set up before, but {path} no longer has the capabilities it needs. \
+                 This usually happens after upgrading or reinstalling the binary since file \
+                 capabilities don't survive replacing it. Grant them again with:
+
+    $ sudo {path} setup"
+            }
+            Self::ErrorConflict => "Conflicting changes.",
+            Self::ErrorInputRequired => "Interactive input required.",
+            Self::ErrorInsufficientSpace => "Not enough free disk space.",
+            Self::ErrorInvalidArgument => "Invalid argument.",
+            Self::ErrorIo => "An IO error occurred.",
+            Self::ErrorNotRoot => "ForkFS must be run as root.",
+            Self::ErrorSessionNotFound => "Session not found.",
+            Self::ErrorSetupRequired => "Setup required.",
+            Self::ErrorWriteLimitExceeded => "Write limit exceeded.",
+            Self::InsufficientInodes => {
+                "Only {available} inodes are free in {dir} but at least {required} are required. \
+                 Many small files, such as those of node_modules, can use up inodes long before \
+                 space. Delete unused sessions or lower the threshold with `--min-free`."
+            }
+            Self::InsufficientSpace => {
+                "Only {available} bytes are available in {dir} but at least {required} bytes are \
+                 required. Free up some space (deleting unused sessions may help) or lower the \
+                 threshold with `--min-free`."
+            }
+            #[cfg(feature = "cli")]
+            Self::NonInteractivePrompt => "Cannot prompt in non-interactive mode: {question}",
+            Self::Setup => {
+                "Welcome to ForkFS!
+
+Under the hood, ForkFS is implemented as a wrapper around OverlayFS. As a
+consequence, elevated privileges are required and can be granted in one of
+three ways (ordered by recommendation):
+
+- $ sudo setcap \
+                 cap_chown,cap_sys_chroot,cap_sys_admin,cap_net_admin,cap_dac_override,cap_fowner,\
+                 cap_setpcap,cap_mknod,cap_lease,cap_setfcap+ep {path}
+
+  This grants `forkfs` precisely the capabilities it needs. Running
+  `sudo {path} setup` does the same.
+
+  cap_dac_override onwards are capabilities that are required for OverlayFS to
+  be able to perform those actions.
+
+- $ sudo chown root {path}; sudo chmod u+s {path}
+
+  This transfers ownership of the `forkfs` binary to root and specifies that
+  the binary should be executed as its owner (i.e. root).
+
+- $ sudo -E forkfs ...
+
+  This simply invokes `forkfs` as root. This option is problematic because
+  sudo alters the environment, causing PATH lookups to fail and changing
+  your home directory.
+
+  If you do go down this route, be consistent with your usage of `-E`. Bare
+  `sudo` vs `sudo -E` will change the forkfs environment, meaning sessions
+  that appear in `sudo` will not appear in `sudo -E` and vice versa.
+
+PS: if you've already seen this message, then you probably upgraded to a new
+version of ForkFS and will therefore need to rerun this setup."
+            }
+            Self::WriteLimitExceeded => {
+                "The sandboxed command wrote {written} bytes, exceeding the limit of {limit} \
+                 bytes. It created {inodes} inode(s)."
+            }
+            #[cfg(feature = "cli")]
+            Self::YesNo => "[y/N]",
+        }
+    }
+
+    const fn fr(self) -> &'static str {
+        match self {
+            Self::CapabilitiesLost => {
+                "ForkFS a déjà été configuré, mais {path} n'a plus les capacités dont il a besoin. \
+                 Cela arrive généralement après une mise à jour ou une réinstallation du binaire, \
+                 car les capacités de fichier ne survivent pas à son remplacement. Accordez-les à \
+                 nouveau avec :
+
+    $ sudo {path} setup"
+            }
+            Self::ErrorConflict => "Modifications en conflit.",
+            Self::ErrorInputRequired => "Une saisie interactive est requise.",
+            Self::ErrorInsufficientSpace => "Espace disque insuffisant.",
+            Self::ErrorInvalidArgument => "Argument invalide.",
+            Self::ErrorIo => "Une erreur d'E/S s'est produite.",
+            Self::ErrorNotRoot => "ForkFS doit être exécuté en tant que root.",
+            Self::ErrorSessionNotFound => "Session introuvable.",
+            Self::ErrorSetupRequired => "Configuration requise.",
+            Self::ErrorWriteLimitExceeded => "Limite d'écriture dépassée.",
+            Self::InsufficientInodes => {
+                "Seuls {available} inodes sont libres dans {dir} alors qu'au moins {required} sont \
+                 requis. De nombreux petits fichiers, comme ceux de node_modules, peuvent épuiser \
+                 les inodes bien avant l'espace. Supprimez les sessions inutilisées ou abaissez le \
+                 seuil avec `--min-free`."
+            }
+            Self::InsufficientSpace => {
+                "Seuls {available} octets sont disponibles dans {dir} alors qu'au moins {required} \
+                 octets sont requis. Libérez de l'espace (supprimer les sessions inutilisées peut \
+                 aider) ou abaissez le seuil avec `--min-free`."
+            }
+            #[cfg(feature = "cli")]
+            Self::NonInteractivePrompt => {
+                "Impossible de poser une question en mode non interactif : {question}"
+            }
+            Self::Setup => {
+                "Bienvenue dans ForkFS !
+
+ForkFS repose sur OverlayFS. Par conséquent, des privilèges élevés sont
+nécessaires et peuvent être accordés de trois manières (par ordre de
+préférence) :
+
+- $ sudo setcap \
+                 cap_chown,cap_sys_chroot,cap_sys_admin,cap_net_admin,cap_dac_override,cap_fowner,\
+                 cap_setpcap,cap_mknod,cap_lease,cap_setfcap+ep {path}
+
+  Cela accorde à `forkfs` précisément les capacités dont il a besoin.
+  `sudo {path} setup` fait de même.
+
+  Les capacités à partir de cap_dac_override sont nécessaires pour qu'OverlayFS
+  puisse effectuer ces opérations.
+
+- $ sudo chown root {path}; sudo chmod u+s {path}
+
+  Cela transfère la propriété du binaire `forkfs` à root et indique que le
+  binaire doit être exécuté en tant que son propriétaire (c.-à-d. root).
+
+- $ sudo -E forkfs ...
+
+  Cela exécute simplement `forkfs` en tant que root. Cette option pose
+  problème car sudo modifie l'environnement, ce qui fait échouer les
+  recherches dans le PATH et change votre répertoire personnel.
+
+  Si vous choisissez cette voie, utilisez `-E` de manière cohérente : `sudo`
+  seul et `sudo -E` donnent des environnements différents à forkfs, si bien
+  que les sessions visibles avec `sudo` ne le sont pas avec `sudo -E` et
+  inversement.
+
+PS : si vous avez déjà vu ce message, vous avez probablement mis à jour ForkFS
+et devez donc refaire cette configuration."
+            }
+            Self::WriteLimitExceeded => {
+                "La commande isolée a écrit {written} octets, dépassant la limite de {limit} \
+                 octets. Elle a créé {inodes} inode(s)."
+            }
+            #[cfg(feature = "cli")]
+            Self::YesNo => "[o/N]",
+        }
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.get())
+    }
+}
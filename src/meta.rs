@@ -0,0 +1,278 @@
+//! Bookkeeping about a session's history, kept in its `meta.toml`.
+//!
+//! The record is informational only: nothing in forkfs depends on it, so
+//! sessions created before it existed simply have gaps in their history.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs,
+    io::ErrorKind,
+    os::{fd::OwnedFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{AtFlags, FileType, OFlags, statat},
+    io::Errno,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, IoErr, clock, encryption, get_sessions_dir, path_undo::TmpPath,
+    sessions::resolve_session, subtree, walk,
+};
+
+const RECORD: &str = "meta.toml";
+/// The number of commands kept in the history, oldest first to go.
+const HISTORY_LEN: usize = 100;
+/// Directories deeper than this below a session's root count toward their
+/// ancestor at this depth in [`ChangeStats::top_dirs`].
+const TOP_DEPTH: usize = 2;
+/// The number of directories kept in [`ChangeStats::top_dirs`].
+const TOP_DIRS: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SessionInfo {
+    /// When the session was created, in seconds since the Unix epoch.
+    pub created: Option<u64>,
+    /// When the session's overlay was last mounted, in seconds since the Unix
+    /// epoch.
+    pub last_activated: Option<u64>,
+    /// The most recent commands run in the session, oldest first.
+    #[serde(default, rename = "command")]
+    pub commands: Vec<CommandRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CommandRecord {
+    /// When the command was started, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The command line, lossily converted to UTF-8.
+    pub args: Vec<String>,
+    /// How much the session had changed when the command started, `None` for
+    /// commands recorded before forkfs kept track.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub changes_before: Option<ChangeStats>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ChangeStats {
+    /// The number of files the session added, modified, or deleted, not
+    /// counting directories.
+    pub files: u64,
+    /// The space taken up by the session's changes.
+    pub bytes: u64,
+    /// The directories whose changes take up the most space, largest first.
+    #[serde(default, rename = "top-dir")]
+    pub top_dirs: Vec<DirUsage>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DirUsage {
+    pub path: PathBuf,
+    /// The space taken up by the changed files in the directory, and in its
+    /// subdirectories for directories at the deepest tallied level.
+    pub bytes: u64,
+}
+
+/// How a session's changes grew over the course of a command.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RunStats {
+    pub command: CommandRecord,
+    /// The session's changes when the command started.
+    pub before: ChangeStats,
+    /// The session's changes when the next command started, or now for the
+    /// last one.
+    pub after: ChangeStats,
+}
+
+/// Returns what is known about a session's history.
+pub fn info(session: impl AsRef<OsStr>) -> Result<SessionInfo, Error> {
+    let session = session.as_ref();
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    if !dir.exists() {
+        return Err(Error::SessionNotFound)
+            .attach_printable(format!("Session {} does not exist", session.display()));
+    }
+    load(&mut dir)
+}
+
+/// Returns how the session's changes grew during each recorded command,
+/// oldest first.
+///
+/// Statistics are taken as commands start, so changes made while no command
+/// was started, such as by `sessions revert`, count toward the command before
+/// them, and so do those of commands still running when the next one started.
+pub fn trend(session: impl AsRef<OsStr>) -> Result<Vec<RunStats>, Error> {
+    let session = session.as_ref();
+    let commands = info(session)?
+        .commands
+        .into_iter()
+        .filter(|command| command.changes_before.is_some())
+        .collect::<Vec<_>>();
+    if commands.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    let now = measure(&mut dir)?;
+
+    let mut runs = Vec::with_capacity(commands.len());
+    let mut commands = commands.into_iter().peekable();
+    while let Some(command) = commands.next() {
+        let after = commands
+            .peek()
+            .and_then(|next| next.changes_before.clone())
+            .unwrap_or_else(|| now.clone());
+        runs.push(RunStats {
+            before: command.changes_before.clone().unwrap_or_default(),
+            after,
+            command,
+        });
+    }
+    Ok(runs)
+}
+
+/// Measures a session's changes as they stand in its upper directory.
+fn measure(session: &mut PathBuf) -> Result<ChangeStats, Error> {
+    let mut root = subtree::get(session)?;
+    let upper = TmpPath::new(session, "diff");
+    let fd = match walk::open_root(&upper) {
+        Err(Errno::NOENT) => return Ok(ChangeStats::default()),
+        r => r.map_io_err_lazy(|| format!("Failed to open directory {}", upper.display()))?,
+    };
+    let mut stats = ChangeStats::default();
+    let mut dirs = HashMap::new();
+    let bucket = root.clone();
+    tally(&fd, &mut root, 0, &bucket, &mut stats, &mut dirs)?;
+
+    let mut top_dirs = dirs
+        .into_iter()
+        .filter(|&(_, bytes)| bytes > 0)
+        .map(|(path, bytes)| DirUsage { path, bytes })
+        .collect::<Vec<_>>();
+    top_dirs.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.path.cmp(&b.path)));
+    top_dirs.truncate(TOP_DIRS);
+    stats.top_dirs = top_dirs;
+    Ok(stats)
+}
+
+fn tally(
+    dir: &OwnedFd,
+    path: &mut PathBuf,
+    depth: usize,
+    bucket: &Path,
+    stats: &mut ChangeStats,
+    dirs: &mut HashMap<PathBuf, u64>,
+) -> Result<(), Error> {
+    // Detached commands may be changing the session under our feet
+    let vanished = |e: &Errno| matches!(*e, Errno::NOENT | Errno::NOTDIR | Errno::LOOP);
+
+    let mut bytes = 0;
+    for (name, file_type) in walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+        let stat = match statat(dir, &name, AtFlags::SYMLINK_NOFOLLOW) {
+            Err(e) if vanished(&e) => continue,
+            r => r.map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?,
+        };
+        let size = stat.st_blocks.cast_unsigned() * 512;
+        stats.bytes += size;
+        if file_type != FileType::Directory {
+            stats.files += 1;
+            bytes += size;
+            continue;
+        }
+
+        let child = match walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY) {
+            Err(e) if vanished(&e) => continue,
+            r => r.map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?,
+        };
+        if depth < TOP_DEPTH {
+            let bucket = path.to_path_buf();
+            tally(&child, &mut path, depth + 1, &bucket, stats, dirs)?;
+        } else {
+            tally(&child, &mut path, depth + 1, bucket, stats, dirs)?;
+        }
+    }
+    *dirs.entry(bucket.to_path_buf()).or_default() += bytes;
+    Ok(())
+}
+
+/// Records that a session was just activated, and created if `is_new`.
+pub fn record_activation(session: &mut PathBuf, is_new: bool) -> Result<(), Error> {
+    let now = now();
+    update(session, |info| {
+        if is_new {
+            info.created = Some(now);
+        }
+        info.last_activated = Some(now);
+    })
+}
+
+/// Records a session's creation without activating it.
+pub fn record_creation(session: &mut PathBuf) -> Result<(), Error> {
+    let now = now();
+    update(session, |info| info.created = Some(now))
+}
+
+/// Adds a command to a session's history along with how much the session had
+/// changed before it.
+///
+/// Encrypted sessions keep no history since the record is stored in the
+/// clear.
+pub fn record_command(session: &mut PathBuf, args: &[impl AsRef<OsStr>]) -> Result<(), Error> {
+    if encryption::is_encrypted(session) {
+        return Ok(());
+    }
+    let record = CommandRecord {
+        time: now(),
+        args: args
+            .iter()
+            .map(|arg| arg.as_ref().to_string_lossy().into_owned())
+            .collect(),
+        changes_before: Some(measure(session)?),
+    };
+    update(session, |info| {
+        let excess = (info.commands.len() + 1).saturating_sub(HISTORY_LEN);
+        info.commands.drain(..excess);
+        info.commands.push(record);
+    })
+}
+
+fn now() -> u64 {
+    clock::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn update(session: &mut PathBuf, f: impl FnOnce(&mut SessionInfo)) -> Result<(), Error> {
+    let mut info = load(session)?;
+    f(&mut info);
+
+    let path = TmpPath::new(session, RECORD);
+    let contents = toml::to_string(&info)
+        .change_context(Error::InvalidArgument)
+        .attach_printable("Failed to serialize the session's metadata")?;
+    fs::write(&path, contents).map_io_err_lazy(|| format!("Failed to write {}", path.display()))
+}
+
+fn load(session: &mut PathBuf) -> Result<SessionInfo, Error> {
+    let path = TmpPath::new(session, RECORD);
+    let info = match fs::read_to_string(&path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SessionInfo::default()),
+        r => r.map_io_err_lazy(|| format!("Failed to read {}", path.display()))?,
+    };
+    toml::from_str(&info)
+        .change_context(Error::InvalidArgument)
+        .attach_printable_lazy(|| format!("Invalid session metadata {}", path.display()))
+}
@@ -0,0 +1,192 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::ErrorKind,
+    os::unix::ffi::OsStringExt,
+    path::{Path, PathBuf},
+};
+
+use error_stack::Result;
+
+use crate::{Error, IoErr, backend::SOURCE_PREFIX};
+
+/// Returns the mount points strictly below `root` in mount order, i.e. parents
+/// always come before their children.
+pub fn mounts_under(root: &Path) -> Result<Vec<PathBuf>, Error> {
+    let root = fs::canonicalize(root)
+        .map_io_err_lazy(|| format!("Failed to resolve path {}", root.display()))?;
+    Ok(entries()?
+        .into_iter()
+        .map(|entry| entry.mount_point)
+        .filter(|mount_point| mount_point != &root && mount_point.starts_with(&root))
+        .collect())
+}
+
+/// A file system mounted inside a session.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Mount {
+    /// The mount's ID, as listed in `/proc/self/mountinfo`.
+    pub id: u64,
+    /// The ID of the mount this one is mounted on.
+    pub parent: u64,
+    /// Where the file system is mounted, as seen from inside the session.
+    pub path: PathBuf,
+    /// The file system's type, such as `overlay` or `proc`.
+    pub fs_type: String,
+    /// What was mounted, such as a device or `none`.
+    pub source: String,
+    /// The options of this mount, such as `ro` or `nosuid`.
+    pub options: String,
+    /// The options of the underlying file system, shared by all of its mounts.
+    pub fs_options: String,
+    /// The mount's peer groups, such as `shared:1` or `master:2`, which
+    /// determine whether mounts made elsewhere show up below it. Empty for
+    /// private mounts.
+    pub propagation: Vec<String>,
+}
+
+/// Returns the ID of the topmost mount at `root` along with the mounts made
+/// directly on top of it, in mount order, or `None` if nothing is mounted at
+/// `root`.
+pub fn mounts_on(root: &Path) -> Result<Option<(u64, Vec<Mount>)>, Error> {
+    let root = fs::canonicalize(root)
+        .map_io_err_lazy(|| format!("Failed to resolve path {}", root.display()))?;
+    let mounts = entries()?;
+    let Some(root_id) = mounts
+        .iter()
+        .rfind(|entry| entry.mount_point == root)
+        .map(|entry| entry.id)
+    else {
+        return Ok(None);
+    };
+    let children = mounts
+        .into_iter()
+        .filter(|entry| entry.parent == root_id)
+        .filter_map(|entry| entry.into_mount(&root))
+        .collect();
+    Ok(Some((root_id, children)))
+}
+
+/// Returns every mount at or below `root`, in mount order, with paths
+/// relative to `root`.
+pub fn mount_tree(root: &Path) -> Result<Vec<Mount>, Error> {
+    let root = fs::canonicalize(root)
+        .map_io_err_lazy(|| format!("Failed to resolve path {}", root.display()))?;
+    Ok(entries()?
+        .into_iter()
+        .filter_map(|entry| entry.into_mount(&root))
+        .collect())
+}
+
+/// Returns where overlay file systems are mounted below `dir` relative to it,
+/// in mount order.
+pub fn overlays_under(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let dir = match fs::canonicalize(dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        r => r.map_io_err_lazy(|| format!("Failed to resolve path {}", dir.display()))?,
+    };
+    Ok(entries()?
+        .into_iter()
+        .filter(|entry| entry.fs_type == b"overlay" || entry.fs_type == b"fuse.fuse-overlayfs")
+        .filter_map(|entry| Some(entry.mount_point.strip_prefix(&dir).ok()?.to_path_buf()))
+        .collect())
+}
+
+/// Returns the name of the session the calling process runs in, or `None`
+/// outside of sessions.
+///
+/// Session overlays are mounted from `forkfs:<name>`. Outside of sessions,
+/// they only show up at their session's `merged` directory, while inside, the
+/// overlay is the root or the subtree the session was created for.
+pub fn current_session() -> Result<Option<OsString>, Error> {
+    Ok(entries()?.into_iter().rev().find_map(|entry| {
+        let name = unescape(entry.source.strip_prefix(SOURCE_PREFIX)?).into_os_string();
+        let host = Path::new(&name).join("merged");
+        (!entry.mount_point.ends_with(&host)).then_some(name)
+    }))
+}
+
+struct Entry {
+    id: u64,
+    parent: u64,
+    mount_point: PathBuf,
+    options: Vec<u8>,
+    propagation: Vec<Vec<u8>>,
+    fs_type: Vec<u8>,
+    source: Vec<u8>,
+    fs_options: Vec<u8>,
+}
+
+impl Entry {
+    fn into_mount(self, root: &Path) -> Option<Mount> {
+        let text = |field: &[u8]| {
+            unescape(field)
+                .into_os_string()
+                .to_string_lossy()
+                .into_owned()
+        };
+        Some(Mount {
+            id: self.id,
+            parent: self.parent,
+            path: Path::new("/").join(self.mount_point.strip_prefix(root).ok()?),
+            fs_type: text(&self.fs_type),
+            source: text(&self.source),
+            options: text(&self.options),
+            fs_options: text(&self.fs_options),
+            propagation: self.propagation.iter().map(|field| text(field)).collect(),
+        })
+    }
+}
+
+fn entries() -> Result<Vec<Entry>, Error> {
+    let mountinfo = fs::read("/proc/self/mountinfo").map_io_err("Failed to read mount table")?;
+    Ok(mountinfo
+        .split(|&b| b == b'\n')
+        .filter_map(|line| {
+            let mut fields = line.split(|&b| b == b' ');
+            let mut id = || {
+                fields
+                    .next()
+                    .and_then(|id| str::from_utf8(id).ok()?.parse::<u64>().ok())
+            };
+            let (id, parent) = (id()?, id()?);
+            let mount_point = unescape(fields.nth(2)?);
+            let options = fields.next()?.to_vec();
+            // The optional fields end with a lone dash
+            let propagation = fields
+                .by_ref()
+                .take_while(|&field| field != b"-")
+                .map(<[u8]>::to_vec)
+                .collect();
+            Some(Entry {
+                id,
+                parent,
+                mount_point,
+                options,
+                propagation,
+                fs_type: fields.next()?.to_vec(),
+                source: fields.next()?.to_vec(),
+                fs_options: fields.next()?.to_vec(),
+            })
+        })
+        .collect())
+}
+
+/// Undoes the octal escaping the kernel applies to whitespace and backslashes.
+fn unescape(field: &[u8]) -> PathBuf {
+    let mut path = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        if field[i] == b'\\'
+            && let Some(code) = field.get(i + 1..i + 4)
+            && let Ok(code) = u8::from_str_radix(&String::from_utf8_lossy(code), 8)
+        {
+            path.push(code);
+            i += 4;
+        } else {
+            path.push(field[i]);
+            i += 1;
+        }
+    }
+    PathBuf::from(OsString::from_vec(path))
+}
@@ -0,0 +1,149 @@
+//! Machine-readable representations of forkfs' output.
+//!
+//! Each schema version lives in its own module and only ever changes in
+//! backwards compatible ways: fields may be added, but never renamed, removed,
+//! or given new meanings. Consumers should ignore fields they don't know about.
+//! Incompatible changes get a new module instead.
+
+pub mod v1 {
+    use serde::{Deserialize, Serialize};
+
+    /// The schema version written by this module.
+    pub const VERSION: u32 = 1;
+
+    /// The sessions known to forkfs, sorted by name.
+    #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    #[non_exhaustive]
+    pub struct SessionList {
+        /// Always [`VERSION`] so consumers can detect the schema.
+        pub version: u32,
+        pub sessions: Vec<Session>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    #[non_exhaustive]
+    pub struct Session {
+        /// The session's name, lossily converted to UTF-8.
+        pub name: String,
+        /// The 1-based index that can be used to refer to the session.
+        pub index: usize,
+        /// Whether the session's overlay is currently mounted.
+        pub active: bool,
+        /// When the session was created, in seconds since the Unix epoch, if
+        /// the file system records creation times.
+        #[serde(default)]
+        pub created: Option<u64>,
+        /// The space taken up by the session's changes, in bytes.
+        #[serde(default)]
+        pub size: u64,
+    }
+
+    /// What a run did to its session, for CI systems to publish.
+    #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    #[non_exhaustive]
+    pub struct RunSummary {
+        /// Always [`VERSION`] so consumers can detect the schema.
+        pub version: u32,
+        /// The session's name, lossily converted to UTF-8.
+        pub session: String,
+        /// The command line, lossily converted to UTF-8.
+        pub command: Vec<String>,
+        /// The command's exit code, or `None` if it was killed by a signal.
+        pub exit_code: Option<i32>,
+        /// The signal that killed the command.
+        #[serde(default)]
+        pub signal: Option<i32>,
+        /// The session's changes once the command exited.
+        pub changes: ChangeCounts,
+        /// The space taken up by the session's changes when the command
+        /// started, in bytes.
+        pub bytes_before: u64,
+        /// The space taken up by the session's changes once the command
+        /// exited, in bytes.
+        pub bytes_after: u64,
+        /// The changes that shouldn't be applied without a closer look, sorted
+        /// by path.
+        pub violations: Vec<Violation>,
+    }
+
+    /// The number of paths a session added, modified, and deleted.
+    #[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    #[non_exhaustive]
+    pub struct ChangeCounts {
+        pub added: u64,
+        pub modified: u64,
+        pub deleted: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    #[non_exhaustive]
+    pub struct Violation {
+        /// The affected path as seen from inside the session, lossily
+        /// converted to UTF-8.
+        pub path: String,
+        /// One of `set-id`, `device`, `unexpected-owner`, or `sensitive-path`.
+        pub concern: String,
+        /// A human-readable explanation of the concern.
+        pub description: String,
+    }
+
+    /// The files a session changed that were also changed outside of it, for
+    /// tools to triage before applying the session.
+    #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    #[non_exhaustive]
+    pub struct ConflictReport {
+        /// Always [`VERSION`] so consumers can detect the schema.
+        pub version: u32,
+        /// The session's name, lossily converted to UTF-8.
+        pub session: String,
+        /// The conflicts, sorted by path.
+        pub conflicts: Vec<Conflict>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    #[non_exhaustive]
+    pub struct Conflict {
+        /// The changed path as seen from inside the session, lossily converted
+        /// to UTF-8.
+        pub path: String,
+        /// One of `same-change`, `modified-in-both`, `deleted-in-session`,
+        /// `deleted-outside`, or `type-changed`.
+        pub reason: String,
+        /// The session's version.
+        pub session: ConflictVersion,
+        /// The version on the real file system.
+        pub live: ConflictVersion,
+        /// `session` or `live` if keeping that version loses nothing the
+        /// other changed, `merge` if both are files whose common ancestor is
+        /// known, and `manual` otherwise.
+        pub suggested_resolution: String,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+    #[serde(rename_all = "kebab-case")]
+    #[non_exhaustive]
+    pub struct ConflictVersion {
+        /// One of `missing`, `file`, `symlink`, `directory`, or `special`.
+        pub kind: String,
+        /// The hex-encoded SHA-256 of a file's contents or a symlink's target.
+        /// Files too large to be compared have none.
+        #[serde(default)]
+        pub sha256: Option<String>,
+    }
+
+    impl SessionList {
+        pub(crate) const fn new(sessions: Vec<Session>) -> Self {
+            Self {
+                version: VERSION,
+                sessions,
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Optional overlayfs features sessions are mounted with.
+//!
+//! overlayfs leaves several features that trade compatibility for speed off by
+//! default. Sessions can be created with some of them turned on, recorded in
+//! their `overlay-options` file, in which case they are added to the overlay's
+//! mount options whenever the session is activated. They can't be changed
+//! afterwards as the upper directory is only understood with the features it
+//! was written with.
+
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use error_stack::{Result, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, IoErr, path_undo::TmpPath};
+
+const RECORD: &str = "overlay-options";
+
+/// An overlayfs feature a session can be mounted with.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlayOption {
+    /// Only copy up a file's metadata until its contents are written, making
+    /// `chmod` and `chown` on large files cheap. Implies `redirect-dir`.
+    Metacopy,
+    /// Rename directories by redirecting to their original location instead
+    /// of failing with `EXDEV` and making programs copy them.
+    RedirectDir,
+    /// Keep track of copied up files so that hard links to them stay linked.
+    Index,
+    /// Skip syncing the upper directory to disk. A crash may lose or corrupt
+    /// the session's changes, after which it can't be mounted until deleted.
+    Volatile,
+}
+
+impl OverlayOption {
+    /// Every option, in the order they are passed to overlayfs.
+    pub const ALL: [Self; 4] = [
+        Self::Metacopy,
+        Self::RedirectDir,
+        Self::Index,
+        Self::Volatile,
+    ];
+
+    /// The option's name in config files and records.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Metacopy => "metacopy",
+            Self::RedirectDir => "redirect-dir",
+            Self::Index => "index",
+            Self::Volatile => "volatile",
+        }
+    }
+
+    /// The overlayfs mount option turning the feature on.
+    #[must_use]
+    pub const fn mount_option(self) -> &'static str {
+        match self {
+            Self::Metacopy => "metacopy=on",
+            Self::RedirectDir => "redirect_dir=on",
+            Self::Index => "index=on",
+            Self::Volatile => "volatile",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|option| option.name() == name)
+    }
+}
+
+/// Records that a new session is mounted with `options`, or checks that an
+/// existing session already is.
+pub fn assign(session: &mut PathBuf, options: &[OverlayOption]) -> Result<(), Error> {
+    let options = OverlayOption::ALL
+        .into_iter()
+        .filter(|option| options.contains(option))
+        .collect::<Vec<_>>();
+    if TmpPath::new(session, "diff").exists() {
+        let recorded = get(session)?;
+        return if recorded == options {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument).attach_printable(format!(
+                "This session is mounted with {}, not {}.",
+                describe(&recorded),
+                describe(&options)
+            ))
+        };
+    }
+    if options.is_empty() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(&*session)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", session.display()))?;
+    record(session, &options)
+}
+
+/// Makes a copy of a session mount with the same options as the original.
+pub fn inherit(source: &mut PathBuf, copy: &mut PathBuf) -> Result<(), Error> {
+    let options = get(source)?;
+    if options.is_empty() {
+        Ok(())
+    } else {
+        record(copy, &options)
+    }
+}
+
+fn record(session: &mut PathBuf, options: &[OverlayOption]) -> Result<(), Error> {
+    let record = TmpPath::new(session, RECORD);
+    let contents = options.iter().fold(String::new(), |mut contents, option| {
+        contents.push_str(option.name());
+        contents.push('\n');
+        contents
+    });
+    fs::write(&record, contents).map_io_err_lazy(|| format!("Failed to write {}", record.display()))
+}
+
+/// Returns the options a session is mounted with, in the order they are
+/// passed to overlayfs.
+pub fn get(session: &mut PathBuf) -> Result<Vec<OverlayOption>, Error> {
+    let record = TmpPath::new(session, RECORD);
+    let contents = match fs::read_to_string(&record) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        r => r.map_io_err_lazy(|| format!("Failed to read {}", record.display()))?,
+    };
+    contents
+        .lines()
+        .map(|name| {
+            OverlayOption::parse(name)
+                .ok_or(Error::InvalidArgument)
+                .attach_printable_lazy(|| {
+                    format!("Unknown overlay option {name:?} in {}", record.display())
+                })
+        })
+        .collect()
+}
+
+fn describe(options: &[OverlayOption]) -> String {
+    if options.is_empty() {
+        return "no overlay options".to_string();
+    }
+    options
+        .iter()
+        .map(|option| option.name())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,467 @@
+//! Sessions as series of patches, one per command run in them.
+//!
+//! Each patch is laid out the way `git format-patch` writes them, with the
+//! command as its subject, so that a session's changes can be reviewed with
+//! the usual tools and applied with `git am` or `patch -p1` from `/` on
+//! machines without forkfs. Sessions only keep their latest version of each
+//! file, so every change lands in the patch of the last recorded command that
+//! made it, judging by when the session last changed the file. Applying the
+//! whole series in order reproduces the session's changes.
+//!
+//! Patches can only describe text files and symlinks. Directories are implied
+//! by the files in them, and binary and special files are left out and
+//! reported.
+
+use std::{
+    ffi::{CString, OsStr},
+    fmt::Write as _,
+    fs::{self, File},
+    io::{ErrorKind, Read},
+    os::{
+        fd::OwnedFd,
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::{MetadataExt, PermissionsExt},
+        },
+    },
+    path::Path,
+    str,
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{FileType, OFlags, fstat, readlinkat},
+    io::Errno,
+};
+
+use crate::{
+    ChangeKind, Error, ExportFilter, IoErr, Omission, OmissionKind, Redaction,
+    content::{Edit, edits, range},
+    diff::{self, is_whiteout},
+    meta,
+    redact::Rules,
+    scheduler,
+    sessions::{open_diff, resolve_session},
+    subtree, walk,
+};
+
+/// Lines of unchanged context around each hunk, as many as `diff -u` shows.
+const CONTEXT: usize = 3;
+
+/// Writes a session's changes to `dir` as a series of patches named like
+/// `0001-<command>.patch`, returning the entries that had to be left out.
+///
+/// `redactions` and `filter` apply to both versions of each file as they do
+/// to [`export_filtered`](crate::export_filtered_session) archives, except
+/// that binary files are always left out. The directory is created if it
+/// doesn't exist.
+pub fn export(
+    session: impl AsRef<OsStr>,
+    dir: &Path,
+    redactions: &[Redaction],
+    filter: ExportFilter,
+) -> Result<Vec<Omission>, Error> {
+    let session = session.as_ref();
+    let name = resolve_session(session)?.into_owned();
+    let rules = Rules::compile(redactions)?;
+    let commands = meta::info(session)?.commands;
+    let changes = diff::changes(session)?;
+    let upper = open_diff(session)?;
+    let subtree = subtree::of(session)?;
+
+    let mut writer = Writer {
+        rules,
+        filter,
+        omissions: Vec::new(),
+        patches: vec![String::new(); commands.len().max(1)],
+    };
+    scheduler::background(|| {
+        for change in changes {
+            let (old, new) = if change.kind == ChangeKind::Deleted {
+                (host_version(&change.path)?, Version::Missing)
+            } else {
+                (
+                    host_version(&change.path)?,
+                    upper_version(&upper, &subtree, &change.path)?,
+                )
+            };
+            let ctime = changed_at(&upper, &subtree, &change.path)?;
+            // Changes from before the first recorded command go with it
+            let run = commands
+                .iter()
+                .rposition(|command| command.time <= ctime)
+                .unwrap_or_default();
+            writer.change(run, &change.path, old, new)?;
+        }
+        Ok::<_, error_stack::Report<Error>>(())
+    })?;
+
+    fs::create_dir_all(dir)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", dir.display()))?;
+    let subjects = if commands.is_empty() {
+        vec![(format!("Changes of session {}", name.display()), None)]
+    } else {
+        commands
+            .iter()
+            .map(|command| (command.args.join(" "), Some(command.time)))
+            .collect()
+    };
+    let series = subjects
+        .into_iter()
+        .zip(writer.patches)
+        .filter(|(_, diffs)| !diffs.is_empty())
+        .collect::<Vec<_>>();
+    for (i, ((subject, time), diffs)) in series.iter().enumerate() {
+        // Subjects are a single line
+        let subject = subject.split_whitespace().collect::<Vec<_>>().join(" ");
+        let file = dir.join(format!("{:04}-{}.patch", i + 1, slug(&subject)));
+        let patch = format!(
+            "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001\nFrom: forkfs \
+             <forkfs@localhost>\nDate: {}\nSubject: [PATCH {}/{}] {subject}\n\nRecorded in forkfs \
+             session {}.\n---\n{diffs}-- \nforkfs {}\n\n",
+            date(time.unwrap_or_default()),
+            i + 1,
+            series.len(),
+            name.display(),
+            env!("CARGO_PKG_VERSION"),
+        );
+        fs::write(&file, patch)
+            .map_io_err_lazy(|| format!("Failed to write {}", file.display()))?;
+    }
+    Ok(writer.omissions)
+}
+
+/// A version of a changed path.
+enum Version {
+    Missing,
+    /// A regular file's contents and whether it is executable.
+    File(Vec<u8>, bool),
+    Symlink(Vec<u8>),
+    /// A directory, which is only described through its entries.
+    Directory,
+    /// A device, FIFO, or socket.
+    Special(FileType),
+}
+
+struct Writer {
+    rules: Rules,
+    filter: ExportFilter,
+    omissions: Vec<Omission>,
+    /// The diffs of each command's patch.
+    patches: Vec<String>,
+}
+
+impl Writer {
+    fn change(&mut self, run: usize, path: &Path, old: Version, new: Version) -> Result<(), Error> {
+        let (old, new) = (self.prepare(path, old), self.prepare(path, new));
+        let (Some(old), Some(new)) = (old, new) else {
+            return Ok(());
+        };
+        if matches!(old, Version::Directory) && !matches!(new, Version::Directory) {
+            self.delete_tree(run, path)?;
+            self.diff(run, path, &Version::Missing, &new);
+            return Ok(());
+        }
+        // Git can't turn files into symlinks or back in place
+        if matches!(
+            (&old, &new),
+            (Version::File(..), Version::Symlink(_)) | (Version::Symlink(_), Version::File(..))
+        ) {
+            self.diff(run, path, &old, &Version::Missing);
+            self.diff(run, path, &Version::Missing, &new);
+            return Ok(());
+        }
+        self.diff(run, path, &old, &new);
+        Ok(())
+    }
+
+    /// Redacts a version, or returns `None` after reporting it if it can't be
+    /// part of a patch.
+    fn prepare(&mut self, path: &Path, version: Version) -> Option<Version> {
+        let kind = match version {
+            Version::File(ref contents, _)
+                if self
+                    .filter
+                    .max_file_size
+                    .is_some_and(|max| contents.len() as u64 > max) =>
+            {
+                OmissionKind::TooLarge
+            }
+            Version::File(contents, executable) => {
+                let contents = if self.rules.covers(path) {
+                    self.rules.apply(path, contents)
+                } else {
+                    contents
+                };
+                if !contents.contains(&0) && str::from_utf8(&contents).is_ok() {
+                    return Some(Version::File(contents, executable));
+                }
+                OmissionKind::Binary
+            }
+            Version::Special(FileType::Socket) => OmissionKind::Socket,
+            Version::Special(_) => OmissionKind::Unpatchable,
+            version => return Some(version),
+        };
+        if self.omissions.last().is_none_or(|last| last.path != path) {
+            self.omissions.push(Omission {
+                path: path.to_path_buf(),
+                kind,
+            });
+        }
+        None
+    }
+
+    /// Deletes the files below a real directory the session deleted or
+    /// replaced.
+    fn delete_tree(&mut self, run: usize, dir: &Path) -> Result<(), Error> {
+        let mut entries = fs::read_dir(dir)
+            .and_then(Iterator::collect::<std::io::Result<Vec<_>>>)
+            .map_io_err_lazy(|| format!("Failed to read directory {}", dir.display()))?;
+        entries.sort_by_key(fs::DirEntry::file_name);
+        for entry in entries {
+            let path = entry.path();
+            let old = host_version(&path)?;
+            self.change(run, &path, old, Version::Missing)?;
+        }
+        Ok(())
+    }
+
+    fn diff(&mut self, run: usize, path: &Path, old: &Version, new: &Version) {
+        let (old_mode, old_lines) = lines(old);
+        let (new_mode, new_lines) = lines(new);
+        if old_mode == new_mode && old_lines == new_lines {
+            return;
+        }
+        let out = &mut self.patches[run];
+        let relative = path.strip_prefix("/").unwrap_or(path);
+        let (a, b) = (quote("a/", relative), quote("b/", relative));
+        let _ = writeln!(out, "diff --git {a} {b}");
+        match (old_mode, new_mode) {
+            (None, Some(mode)) => {
+                let _ = writeln!(out, "new file mode {mode}");
+            }
+            (Some(mode), None) => {
+                let _ = writeln!(out, "deleted file mode {mode}");
+            }
+            (Some(old), Some(new)) if old != new => {
+                let _ = writeln!(out, "old mode {old}\nnew mode {new}");
+            }
+            _ => {}
+        }
+        if old_lines == new_lines {
+            return;
+        }
+        let from = if old_mode.is_some() { &*a } else { "/dev/null" };
+        let to = if new_mode.is_some() { &*b } else { "/dev/null" };
+        let _ = writeln!(out, "--- {from}\n+++ {to}");
+        hunks(&old_lines, &new_lines, out);
+    }
+}
+
+/// The git mode and lines, each with its line break, of a version.
+fn lines(version: &Version) -> (Option<&'static str>, Vec<&str>) {
+    let (mode, contents) = match version {
+        Version::File(contents, false) => ("100644", &**contents),
+        Version::File(contents, true) => ("100755", &**contents),
+        Version::Symlink(target) => ("120000", &**target),
+        _ => return (None, Vec::new()),
+    };
+    let text = str::from_utf8(contents).unwrap_or_default();
+    (Some(mode), text.split_inclusive('\n').collect())
+}
+
+/// Writes the hunks turning `old` into `new`, merging those whose context
+/// would overlap like `diff -u` does.
+fn hunks(old: &[&str], new: &[&str], out: &mut String) {
+    let mut steps = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for edit in edits(old, new) {
+        steps.push((edit, i, j));
+        match edit {
+            Edit::Keep => (i, j) = (i + 1, j + 1),
+            Edit::Delete => i += 1,
+            Edit::Insert => j += 1,
+        }
+    }
+    let changed = |k: usize| steps[k].0 != Edit::Keep;
+
+    let mut next = 0;
+    while let Some(first) = (next..steps.len()).find(|&k| changed(k)) {
+        let mut last = first;
+        while let Some(k) =
+            (last + 1..steps.len().min(last + 2 * CONTEXT + 2)).find(|&k| changed(k))
+        {
+            last = k;
+        }
+        let hunk = &steps[first.saturating_sub(CONTEXT)..steps.len().min(last + CONTEXT + 1)];
+        let (_, i, j) = hunk[0];
+        let removed = hunk.iter().filter(|step| step.0 != Edit::Insert).count();
+        let added = hunk.iter().filter(|step| step.0 != Edit::Delete).count();
+        let _ = writeln!(out, "@@ -{} +{} @@", range(i, removed), range(j, added));
+        for &(edit, i, j) in hunk {
+            let (prefix, line) = match edit {
+                Edit::Keep => (' ', old[i]),
+                Edit::Delete => ('-', old[i]),
+                Edit::Insert => ('+', new[j]),
+            };
+            out.push(prefix);
+            out.push_str(line);
+            if !line.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+        next = last + CONTEXT + 1;
+    }
+}
+
+/// Quotes a path the way git does if it contains anything but printable
+/// ASCII.
+fn quote(prefix: &str, path: &Path) -> String {
+    let bytes = path.as_os_str().as_bytes();
+    if bytes
+        .iter()
+        .all(|&b| b.is_ascii_graphic() && b != b'"' && b != b'\\' || b == b' ')
+    {
+        return format!("{prefix}{}", path.display());
+    }
+    let mut quoted = format!("\"{prefix}");
+    for &b in bytes {
+        match b {
+            b'"' | b'\\' => {
+                quoted.push('\\');
+                quoted.push(char::from(b));
+            }
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            b' ' => quoted.push(' '),
+            _ if b.is_ascii_graphic() => quoted.push(char::from(b)),
+            _ => {
+                let _ = write!(quoted, "\\{b:03o}");
+            }
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Turns a subject into a file name the way `git format-patch` does.
+fn slug(subject: &str) -> String {
+    let mut slug = String::new();
+    for c in subject.chars() {
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.truncate(52);
+    slug.trim_end_matches(['-', '.']).to_string()
+}
+
+/// Formats seconds since the Unix epoch as an RFC 2822 date in UTC.
+fn date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    format!(
+        "{}, {day} {} {year} {:02}:{:02}:{:02} +0000",
+        DAYS[usize::try_from(days % 7).unwrap_or_default()],
+        MONTHS[usize::try_from(month - 1).unwrap_or_default()],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+fn host_version(path: &Path) -> Result<Version, Error> {
+    let metadata = match fs::symlink_metadata(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Version::Missing),
+        r => r.map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?,
+    };
+    let file_type = metadata.file_type();
+    Ok(if file_type.is_dir() {
+        Version::Directory
+    } else if file_type.is_symlink() {
+        Version::Symlink(
+            fs::read_link(path)
+                .map_io_err_lazy(|| format!("Failed to read link {}", path.display()))?
+                .into_os_string()
+                .into_vec(),
+        )
+    } else if file_type.is_file() {
+        Version::File(
+            fs::read(path).map_io_err_lazy(|| format!("Failed to read {}", path.display()))?,
+            metadata.permissions().mode() & 0o111 != 0,
+        )
+    } else {
+        Version::Special(FileType::from_raw_mode(metadata.mode()))
+    })
+}
+
+/// Reads a path from the upper directory, where deleted files are whiteouts
+/// and the entries below a deleted directory are missing.
+fn upper_version(upper: &OwnedFd, subtree: &Path, path: &Path) -> Result<Version, Error> {
+    let name = upper_name(subtree, path)?;
+    let error = || format!("Failed to read the session's version of {}", path.display());
+    let fd = match walk::open_beneath(upper, &name, OFlags::PATH) {
+        Err(Errno::NOENT) => return Ok(Version::Missing),
+        r => r.map_io_err_lazy(error)?,
+    };
+    let stat = fstat(&fd).map_io_err_lazy(error)?;
+    let file_type = FileType::from_raw_mode(stat.st_mode);
+    Ok(match file_type {
+        _ if is_whiteout(file_type, &stat) => Version::Missing,
+        FileType::Directory => Version::Directory,
+        FileType::Symlink => Version::Symlink(
+            readlinkat(&fd, c"", Vec::new())
+                .map_io_err_lazy(error)?
+                .into_bytes(),
+        ),
+        FileType::RegularFile => {
+            let mut contents = Vec::new();
+            File::from(walk::open_beneath(upper, &name, OFlags::RDONLY).map_io_err_lazy(error)?)
+                .read_to_end(&mut contents)
+                .map_io_err_lazy(error)?;
+            Version::File(contents, stat.st_mode & 0o111 != 0)
+        }
+        file_type => Version::Special(file_type),
+    })
+}
+
+/// When the session last changed a path, in seconds since the Unix epoch.
+/// Entries below a directory the session deleted or replaced were changed
+/// along with it.
+fn changed_at(upper: &OwnedFd, subtree: &Path, path: &Path) -> Result<u64, Error> {
+    for ancestor in path.ancestors() {
+        let Ok(name) = upper_name(subtree, ancestor) else {
+            break;
+        };
+        let fd = match walk::open_beneath(upper, &name, OFlags::PATH) {
+            Err(Errno::NOENT) => continue,
+            r => r.map_io_err_lazy(|| format!("Failed to open {}", ancestor.display()))?,
+        };
+        let stat =
+            fstat(&fd).map_io_err_lazy(|| format!("Failed to stat {}", ancestor.display()))?;
+        return Ok(stat.st_ctime);
+    }
+    Ok(0)
+}
+
+fn upper_name(subtree: &Path, path: &Path) -> Result<CString, Error> {
+    let relative = subtree::relative(subtree, path)?.as_os_str().as_bytes();
+    CString::new(if relative.is_empty() { b"." } else { relative })
+        .change_context(Error::InvalidArgument)
+        .attach_printable_lazy(|| format!("Invalid path {}", path.display()))
+}
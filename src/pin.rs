@@ -0,0 +1,219 @@
+//! Pinning the real files a session's changes were made against.
+//!
+//! Some changes only make sense on top of particular versions of other files,
+//! such as a config snippet written for the main config file it is included
+//! from. Sessions can be created with such files pinned, recorded along with
+//! their digests in the session's `pins` file, and are only applied while
+//! every pinned file still has the recorded digest.
+//!
+//! Files with fs-verity enabled are pinned by their verity digest, which the
+//! kernel maintains and which can't change without the file being replaced.
+//! Others are pinned by the SHA-256 of their contents. IMA's `security.ima`
+//! hashes aren't used since they're only kept up to date under an appraisal
+//! policy.
+
+use std::{
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, ErrorKind},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    io::Errno,
+    ioctl::{ReadWriteOpcode, Updater, ioctl},
+};
+
+use crate::{Error, IoErr, drift, path_undo::TmpPath, sha256};
+
+const RECORD: &str = "pins";
+
+/// The largest digest fs-verity produces, that of SHA-512.
+const MAX_VERITY_DIGEST: usize = 64;
+
+/// Pins `paths` to their current digests in a new session, or checks that an
+/// existing session already pins the same files.
+pub fn assign(session: &mut PathBuf, paths: &[PathBuf]) -> Result<(), Error> {
+    let mut paths = paths
+        .iter()
+        .map(|path| {
+            fs::canonicalize(path)
+                .map_io_err_lazy(|| format!("Failed to resolve path {}", path.display()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    paths.sort();
+    paths.dedup();
+    if TmpPath::new(session, "diff").exists() {
+        let recorded = get(session)?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        return if recorded == paths {
+            Ok(())
+        } else if recorded.is_empty() {
+            Err(Error::InvalidArgument).attach_printable(
+                "Files can only be pinned when a session is first created, but this session \
+                 already exists.",
+            )
+        } else {
+            Err(Error::InvalidArgument).attach_printable(format!(
+                "This session pins {}, not {}.",
+                describe(&recorded),
+                describe(&paths)
+            ))
+        };
+    }
+
+    let pins = paths
+        .into_iter()
+        .map(|path| {
+            let digest =
+                measure(&path).map_io_err_lazy(|| format!("Failed to pin {}", path.display()))?;
+            Ok((path, digest))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    fs::create_dir_all(&*session)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", session.display()))?;
+    record(session, &pins)
+}
+
+/// Makes a copy of a session pin the same files as the original.
+pub fn inherit(source: &mut PathBuf, copy: &mut PathBuf) -> Result<(), Error> {
+    let pins = get(source)?;
+    if pins.is_empty() {
+        Ok(())
+    } else {
+        record(copy, &pins)
+    }
+}
+
+/// Returns the pinned files that were changed or deleted since they were
+/// pinned.
+pub fn changed(session: &mut PathBuf) -> Result<Vec<PathBuf>, Error> {
+    let mut changed = Vec::new();
+    for (path, digest) in get(session)? {
+        match measure(&path) {
+            Ok(current) if current == digest => {}
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).map_io_err_lazy(|| format!("Failed to check {}", path.display()));
+            }
+            _ => changed.push(path),
+        }
+    }
+    Ok(changed)
+}
+
+/// Pins the files again once the session's changes are part of the real file
+/// system, dropping those that no longer exist.
+pub fn refresh(session: &mut PathBuf) -> Result<(), Error> {
+    let pins = get(session)?;
+    if pins.is_empty() {
+        return Ok(());
+    }
+    let mut refreshed = Vec::with_capacity(pins.len());
+    for (path, _) in pins {
+        match measure(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            r => {
+                let digest = r.map_io_err_lazy(|| format!("Failed to pin {}", path.display()))?;
+                refreshed.push((path, digest));
+            }
+        }
+    }
+    record(session, &refreshed)
+}
+
+fn record(session: &mut PathBuf, pins: &[(PathBuf, String)]) -> Result<(), Error> {
+    let record = TmpPath::new(session, RECORD);
+    let mut contents = Vec::new();
+    for (path, digest) in pins {
+        contents.extend_from_slice(path.as_os_str().as_bytes());
+        contents.push(0);
+        contents.extend_from_slice(digest.as_bytes());
+        contents.push(0);
+    }
+    fs::write(&record, contents).map_io_err_lazy(|| format!("Failed to write {}", record.display()))
+}
+
+/// Returns the files a session pins along with their recorded digests.
+fn get(session: &mut PathBuf) -> Result<Vec<(PathBuf, String)>, Error> {
+    let record = TmpPath::new(session, RECORD);
+    let contents = match fs::read(&record) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        r => r.map_io_err_lazy(|| format!("Failed to read {}", record.display()))?,
+    };
+    let mut fields = contents.split(|&b| b == 0);
+    let mut pins = Vec::new();
+    while let (Some(path), Some(digest)) = (fields.next(), fields.next()) {
+        pins.push((
+            PathBuf::from(OsStr::from_bytes(path)),
+            String::from_utf8_lossy(digest).into_owned(),
+        ));
+    }
+    Ok(pins)
+}
+
+fn describe(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Computes a file's digest, prefixed with how it was computed.
+fn measure(path: &Path) -> io::Result<String> {
+    let file = File::open(path)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "only regular files can be pinned",
+        ));
+    }
+    if let Some(digest) = verity_digest(&file)? {
+        return Ok(digest);
+    }
+    Ok(format!("sha256:{}", sha256::hex(&drift::hash(file)?)))
+}
+
+/// The kernel's `fsverity_digest` followed by room for the digest itself.
+#[repr(C)]
+struct VerityDigest {
+    algorithm: u16,
+    size: u16,
+    digest: [u8; MAX_VERITY_DIGEST],
+}
+
+fn verity_digest(file: &File) -> io::Result<Option<String>> {
+    let mut arg = VerityDigest {
+        algorithm: 0,
+        size: u16::try_from(MAX_VERITY_DIGEST).unwrap(),
+        digest: [0; MAX_VERITY_DIGEST],
+    };
+    // SAFETY: the argument layout matches the kernel's fsverity_digest, whose
+    // opcode is defined with the size of the header alone.
+    match unsafe {
+        ioctl(
+            file,
+            Updater::<ReadWriteOpcode<b'f', 134, [u8; 4]>, _>::new(&mut arg),
+        )
+    } {
+        // Verity isn't enabled on the file or supported by its file system
+        Err(Errno::NODATA | Errno::NOTTY | Errno::OPNOTSUPP) => Ok(None),
+        Err(e) => Err(e.into()),
+        Ok(()) => {
+            let algorithm = match arg.algorithm {
+                1 => "sha256",
+                2 => "sha512",
+                _ => "unknown",
+            };
+            let size = usize::from(arg.size).min(MAX_VERITY_DIGEST);
+            Ok(Some(format!(
+                "fsverity-{algorithm}:{}",
+                sha256::hex(&arg.digest[..size])
+            )))
+        }
+    }
+}
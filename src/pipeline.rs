@@ -0,0 +1,247 @@
+//! Sequences of session operations run as a unit.
+//!
+//! A pipeline file names a session, optionally a template session to start it
+//! from, and the steps to carry out in it. Nothing a failed pipeline did is
+//! left behind: archives are staged next to their destination and only moved
+//! into place once every step succeeded, and a session the pipeline created is
+//! deleted again. Deleting the session is therefore only allowed as the last
+//! step.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    time::Instant,
+};
+
+use error_stack::{Report, Result, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Config, DEFAULT_MIN_FREE_SPACE, EncryptionKey, Error, IoErr, RunOp, SessionOperand, Viewer,
+    apply::describe, clone_session, delete_sessions, export_session, get_sessions_dir, review, run,
+    sessions::resolve_alias,
+};
+
+/// A sequence of operations on a session described by a TOML file and run
+/// with [`Pipeline::run`].
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Pipeline {
+    /// The session the steps operate on.
+    pub session: String,
+    /// A session to clone as the starting point, in which case the pipeline's
+    /// session must not exist yet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// The config file profiles commands are run with.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<String>,
+    pub steps: Vec<Step>,
+}
+
+/// One operation of a [`Pipeline`].
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(
+    tag = "op",
+    deny_unknown_fields,
+    rename_all = "kebab-case",
+    rename_all_fields = "kebab-case"
+)]
+pub enum Step {
+    /// Run a command in the session, failing unless it exits successfully.
+    Run { command: Vec<String> },
+    /// Fail if [`review_session`](crate::review_session) flags any changes
+    /// outside the `allow`ed paths.
+    Review {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        allow: Vec<PathBuf>,
+    },
+    /// Archive the session's changes like
+    /// [`export_session`](crate::export_session) to a file.
+    Export {
+        path: PathBuf,
+        #[serde(default)]
+        preserve_special: bool,
+    },
+    /// Delete the session.
+    Delete,
+}
+
+impl Step {
+    fn describe(&self) -> String {
+        match self {
+            Self::Run { command } => format!("run {}", command.join(" ")),
+            Self::Review { .. } => "review".to_string(),
+            Self::Export { path, .. } => format!("export {}", path.display()),
+            Self::Delete => "delete".to_string(),
+        }
+    }
+}
+
+impl Pipeline {
+    /// Reads a pipeline file.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let pipeline = fs::read_to_string(path)
+            .map_io_err_lazy(|| format!("Failed to read pipeline {}", path.display()))?;
+        toml::from_str(&pipeline)
+            .change_context(Error::InvalidArgument)
+            .attach_printable_lazy(|| format!("Invalid pipeline {}", path.display()))
+    }
+
+    /// Carries out the pipeline's steps in order, logging each one to `log`.
+    ///
+    /// The first step to fail stops the pipeline and undoes what it did: the
+    /// archives it exported are discarded and the session is deleted if the
+    /// pipeline created it.
+    pub fn run(&self, mut log: impl Write) -> Result<(), Error> {
+        let Self {
+            session,
+            template,
+            steps,
+            ..
+        } = self;
+        let (delete, steps) = match steps.split_last() {
+            Some((Step::Delete, steps)) => (true, steps),
+            _ => (false, &steps[..]),
+        };
+        if steps.contains(&Step::Delete) {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Deleting the session must be the last step of a pipeline.");
+        }
+
+        let mut dir = get_sessions_dir()?;
+        dir.push(resolve_alias(session.as_ref())?);
+        let created = !dir.exists();
+        if let Some(template) = template {
+            if !created {
+                return Err(Error::Conflict).attach_printable(format!(
+                    "Session {session} already exists, pipelines starting from a template need a \
+                     new one."
+                ));
+            }
+            drop(writeln!(log, "Creating {session} from {template}"));
+            clone_session(template, session)?;
+        }
+
+        let mut exports = Vec::new();
+        let total = steps.len() + usize::from(delete);
+        let result = steps.iter().enumerate().try_for_each(|(i, step)| {
+            let number = i + 1;
+            drop(writeln!(log, "[{number}/{total}] {}", step.describe()));
+            let start = Instant::now();
+            let result = self.step(step, &mut exports);
+            let elapsed = start.elapsed().as_secs_f64();
+            drop(if result.is_ok() {
+                writeln!(log, "[{number}/{total}] done in {elapsed:.2}s")
+            } else {
+                writeln!(log, "[{number}/{total}] failed after {elapsed:.2}s")
+            });
+            result
+        });
+        let result = result.and_then(|()| {
+            exports.iter().try_for_each(|(staged, path)| {
+                fs::rename(staged, path)
+                    .map_io_err_lazy(|| format!("Failed to move archive to {}", path.display()))
+            })
+        });
+        if let Err(e) = result {
+            for (staged, _) in &exports {
+                drop(fs::remove_file(staged));
+            }
+            if created && dir.exists() {
+                drop(run::discard(&mut dir));
+            }
+            return Err(e);
+        }
+
+        if delete {
+            drop(writeln!(log, "[{total}/{total}] delete"));
+            delete_sessions(SessionOperand::List(&[session]), false)?;
+        }
+        Ok(())
+    }
+
+    fn step(&self, step: &Step, exports: &mut Vec<(PathBuf, PathBuf)>) -> Result<(), Error> {
+        match step {
+            Step::Run { command } => run_command(&self.session, command, &self.profiles),
+            Step::Review { allow } => {
+                let findings = review::review(&self.session, allow)?;
+                if findings.is_empty() {
+                    return Ok(());
+                }
+                let mut report = Report::new(Error::InvalidArgument);
+                for finding in findings {
+                    report = report.attach_printable(format!(
+                        "{}: {}",
+                        finding.path.display(),
+                        describe(finding.concern)
+                    ));
+                }
+                Err(report).attach_printable("The session's changes didn't pass review.")
+            }
+            Step::Export {
+                path,
+                preserve_special,
+            } => {
+                let mut name = OsString::from(".");
+                name.push(path.file_name().unwrap_or_default());
+                name.push(format!(".export-{}", process::id()));
+                let staged = path.with_file_name(name);
+                let file = File::create(&staged)
+                    .map_io_err_lazy(|| format!("Failed to create {}", staged.display()))?;
+                exports.push((staged, path.clone()));
+                export_session(&self.session, BufWriter::new(file), *preserve_special).map(drop)
+            }
+            Step::Delete => unreachable!("Deleting is only allowed as the last step"),
+        }
+    }
+}
+
+fn run_command(session: &str, command: &[String], profiles: &[String]) -> Result<(), Error> {
+    let config = Config::load()?;
+    let mut options = config.defaults.clone();
+    for name in profiles {
+        options.merge(config.profile(name)?.clone());
+    }
+    let encryption_key = options
+        .key_file
+        .as_deref()
+        .map(EncryptionKey::load)
+        .transpose()?;
+    let owner = options.owner.as_deref().map(Viewer::lookup).transpose()?;
+
+    let status = RunOp::builder()
+        .session(session.as_ref())
+        .command(command)
+        .presets(&options.presets)
+        .passthroughs(&options.passthroughs)
+        .max_write(options.max_write)
+        .min_free_space(options.min_free_space.unwrap_or(DEFAULT_MIN_FREE_SPACE))
+        .encryption_key(encryption_key.as_ref())
+        .lower_device(options.lower_device.as_deref())
+        .root(options.root.as_deref())
+        .unbound(&options.no_bind)
+        .overlay_options(&options.overlay_options)
+        .volatile(options.volatile_storage.unwrap_or_default())
+        .private_pids(options.private_pids.unwrap_or_default())
+        .network(options.network.unwrap_or_default())
+        .umask(options.umask)
+        .owner(owner)
+        .backend(options.backend.unwrap_or_default())
+        .wait(true)
+        .build()
+        .run();
+    // Waiting for the command hands signals over to it, so they are taken back
+    // for the steps after it
+    run::stop_forwarding_signals();
+
+    let status = status?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Io).attach_printable(format!("{} failed: {status}", command.join(" ")))
+    }
+}
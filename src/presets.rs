@@ -0,0 +1,57 @@
+use std::{env, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// Well-known build system cache directories that should bypass the sandbox.
+///
+/// Passthrough directories are bind mounted read-write into the session so
+/// that sandboxed builds can reuse (and populate) the real caches instead of
+/// copying them into the session. The [`Dev`](Self::Dev) preset passes the
+/// current directory through instead, so that a build's output is real while
+/// the rest of the system is protected from it.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    Cargo,
+    Ccache,
+    Dev,
+    Gradle,
+    Npm,
+}
+
+impl Preset {
+    /// The directories covered by this preset.
+    ///
+    /// Directories which do not exist on the real file system are omitted.
+    #[must_use]
+    pub fn passthroughs(self) -> Vec<PathBuf> {
+        // Without a home directory, the defaults are relative and dropped below
+        let home = dirs::home_dir().unwrap_or_default();
+        let env_or_home =
+            |var, default| env::var_os(var).map_or_else(|| home.join(default), PathBuf::from);
+
+        let mut dirs = match self {
+            Self::Cargo => {
+                let cargo_home = env_or_home("CARGO_HOME", ".cargo");
+                vec![cargo_home.join("registry"), cargo_home.join("git")]
+            }
+            Self::Ccache => vec![
+                env_or_home("CCACHE_DIR", ".cache/ccache"),
+                home.join(".ccache"),
+            ],
+            // Passing the root through would leave nothing sandboxed
+            Self::Dev => env::current_dir()
+                .into_iter()
+                .filter(|dir| dir.parent().is_some())
+                .collect(),
+            Self::Gradle => {
+                let gradle_home = env_or_home("GRADLE_USER_HOME", ".gradle");
+                vec![gradle_home.join("caches"), gradle_home.join("wrapper")]
+            }
+            Self::Npm => vec![env_or_home("npm_config_cache", ".npm")],
+        };
+        dirs.retain(|dir| dir.is_absolute() && dir.is_dir());
+        dirs.dedup();
+        dirs
+    }
+}
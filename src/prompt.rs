@@ -0,0 +1,81 @@
+use std::{
+    io,
+    io::{BufRead, IsTerminal, Write},
+};
+
+use error_stack::{Result, ResultExt};
+
+use crate::{Error, IoErr, messages::Message};
+
+/// Whether forkfs may stop to ask the user questions.
+///
+/// Scripts and CI jobs have nobody to answer prompts, so prompting is disabled
+/// whenever stdin or stderr isn't a terminal. Instead of hanging, prompts then
+/// fail with [`Error::InputRequired`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Input {
+    Interactive,
+    Disabled,
+}
+
+impl Input {
+    /// Detects whether prompts can be answered, unless `disabled` is set.
+    #[must_use]
+    pub fn detect(disabled: bool) -> Self {
+        if disabled || !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+            Self::Disabled
+        } else {
+            Self::Interactive
+        }
+    }
+
+    /// Asks a yes/no question, defaulting to no.
+    pub fn confirm(self, question: &str) -> Result<bool, Error> {
+        let answer = self.prompt(&format!("{question} {}", Message::YesNo))?;
+        Ok(Message::is_yes(&answer))
+    }
+
+    /// Asks an open question, returning `default` if the answer is empty.
+    pub fn ask(self, question: &str, default: &str) -> Result<String, Error> {
+        let answer = self.prompt(&format!("{question} [{default}]"))?;
+        let answer = answer.trim();
+        Ok(if answer.is_empty() { default } else { answer }.to_string())
+    }
+
+    /// Asks until the answer starts with one of `choices`, returning it.
+    pub fn choose(self, question: &str, choices: &[char]) -> Result<char, Error> {
+        let choices_list = choices.iter().map(char::to_string).collect::<Vec<_>>();
+        let question = format!("{question} [{}]", choices_list.join("/"));
+        loop {
+            let answer = self.prompt(&question)?;
+            // Not even a newline means stdin was closed and nobody will answer
+            if answer.is_empty() {
+                return Err(Error::InputRequired).attach_printable("Stdin was closed.");
+            }
+            if let Some(choice) = answer
+                .trim()
+                .chars()
+                .next()
+                .map(|c| c.to_ascii_lowercase())
+                .filter(|c| choices.contains(c))
+            {
+                return Ok(choice);
+            }
+        }
+    }
+
+    fn prompt(self, question: &str) -> Result<String, Error> {
+        if self == Self::Disabled {
+            return Err(Error::InputRequired)
+                .attach_printable(Message::NonInteractivePrompt.fill(&[("question", &question)]));
+        }
+
+        write!(io::stderr(), "{question} ").map_io_err("Failed to write to stderr")?;
+        let mut answer = String::new();
+        io::stdin()
+            .lock()
+            .read_line(&mut answer)
+            .map_io_err("Failed to read from stdin")?;
+        Ok(answer)
+    }
+}
@@ -0,0 +1,305 @@
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    os::{
+        fd::{AsFd, AsRawFd, OwnedFd},
+        unix::{
+            ffi::{OsStrExt, OsStringExt},
+            fs::DirBuilderExt,
+        },
+    },
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{CWD, Mode, OFlags, UnmountFlags, openat, unmount},
+    io::Errno,
+    mount::{
+        FsMountFlags, FsOpenFlags, MountAttrFlags, MoveMountFlags, OpenTreeFlags, fsconfig_create,
+        fsconfig_set_fd, fsconfig_set_flag, fsconfig_set_string, fsmount, fsopen, move_mount,
+        open_tree,
+    },
+    process::{Pid, Signal, WaitOptions, getgid, getuid, kill_process, waitpid},
+    thread::{UnshareFlags, unshare},
+};
+
+use crate::{
+    Backend, Error, IoErr, OverlayOption, get_sessions_dir, lower, overlay_options,
+    path_undo::TmpPath,
+    sessions::{maybe_create_session, resolve_alias},
+    subtree, volatile,
+};
+
+const PUBLICATIONS: &str = "publications";
+
+/// The user an idmapped publication exposes the session's files to.
+#[derive(Copy, Clone, Debug)]
+pub struct Viewer {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl Viewer {
+    /// Looks up a user by name or numeric id in `/etc/passwd`.
+    pub fn lookup(user: &str) -> Result<Self, Error> {
+        let passwd = fs::read_to_string("/etc/passwd").map_io_err("Failed to read /etc/passwd")?;
+        for line in passwd.lines() {
+            let mut fields = line.split(':');
+            let (Some(name), Some(_), Some(uid), Some(gid)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            if (name == user || uid == user)
+                && let (Ok(uid), Ok(gid)) = (uid.parse(), gid.parse())
+            {
+                return Ok(Self { uid, gid });
+            }
+        }
+
+        if let Ok(uid) = user.parse() {
+            return Ok(Self { uid, gid: uid });
+        }
+        Err(Error::InvalidArgument).attach_printable(format!("Unknown user {user:?}"))
+    }
+}
+
+/// Exposes a read-only view of a session's file system at `mount_point`.
+///
+/// If a `viewer` is given, files owned by the invoking user appear to be owned
+/// by the viewer instead so that they can be inspected without elevated
+/// privileges. The publication is removed when the session is stopped.
+pub fn publish(
+    session: impl AsRef<OsStr>,
+    mount_point: &Path,
+    viewer: Option<Viewer>,
+) -> Result<(), Error> {
+    let mut session_dir = get_sessions_dir()?;
+    session_dir.push(resolve_alias(session.as_ref())?);
+    maybe_create_session(&mut session_dir, None, Backend::Auto)?;
+
+    if !mount_point.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o755)
+            .create(mount_point)
+            .map_io_err_lazy(|| format!("Failed to create directory {}", mount_point.display()))?;
+    }
+
+    let tree = if let Some(viewer) = viewer {
+        // Overlay mounts can't be idmapped themselves, so stack a read-only
+        // overlay on idmapped views of the session's layers instead.
+        let storage = volatile::storage(&mut session_dir);
+        let work = storage.join("publish-work");
+        fs::create_dir_all(&work)
+            .map_io_err_lazy(|| format!("Failed to create directory {}", work.display()))?;
+
+        let user_namespace = idmapping(viewer)?;
+        let session = clone_tree(&storage, Some(&user_namespace), false)?;
+        let mut lower = lower::root(&mut session_dir)?;
+        let subtree = subtree::get(&mut session_dir)?;
+        lower.push(subtree.strip_prefix("/").unwrap_or(&subtree));
+        let lower = clone_tree(&lower, Some(&user_namespace), true)?;
+        let overlay = fsopen("overlay", FsOpenFlags::FSOPEN_CLOEXEC)
+            .map_io_err("Failed to create overlay file system")?;
+        for (key, dir) in [("upperdir", "diff"), ("workdir", "publish-work")] {
+            let dir = openat(
+                &session,
+                dir,
+                OFlags::DIRECTORY | OFlags::CLOEXEC,
+                Mode::empty(),
+            )
+            .map_io_err_lazy(|| format!("Failed to open directory {dir}"))?;
+            fsconfig_set_fd(overlay.as_fd(), key, dir.as_fd())
+                .map_io_err("Failed to configure overlay file system")?;
+        }
+        fsconfig_set_fd(overlay.as_fd(), "lowerdir+", lower.as_fd())
+            .map_io_err("Failed to configure overlay file system")?;
+        // Only the features changing how the upper directory reads matter here
+        for option in overlay_options::get(&mut session_dir)? {
+            let key = match option {
+                OverlayOption::Metacopy => "metacopy",
+                OverlayOption::RedirectDir => "redirect_dir",
+                OverlayOption::Index | OverlayOption::Volatile => continue,
+            };
+            fsconfig_set_string(overlay.as_fd(), key, "on")
+                .map_io_err("Failed to configure overlay file system")?;
+        }
+        // Keep the shared upper directory untouched
+        fsconfig_set_flag(overlay.as_fd(), "ro")
+            .map_io_err("Failed to configure overlay file system")?;
+        fsconfig_create(overlay.as_fd()).map_io_err("Failed to create overlay file system")?;
+        fsmount(
+            overlay.as_fd(),
+            FsMountFlags::FSMOUNT_CLOEXEC,
+            MountAttrFlags::MOUNT_ATTR_RDONLY,
+        )
+        .map_io_err("Failed to mount overlay file system")?
+    } else {
+        clone_tree(&session_dir.join("merged"), None, true)?
+    };
+
+    move_mount(
+        tree.as_fd(),
+        "",
+        CWD,
+        mount_point,
+        MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH,
+    )
+    .map_io_err_lazy(|| format!("Failed to mount directory {}", mount_point.display()))?;
+
+    let mount_point = fs::canonicalize(mount_point)
+        .map_io_err_lazy(|| format!("Failed to resolve path {}", mount_point.display()))?;
+    let mut record = mount_point.into_os_string().into_vec();
+    record.push(b'\n');
+    let publications = TmpPath::new(&mut session_dir, PUBLICATIONS);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&publications)
+        .and_then(|mut file| file.write_all(&record))
+        .map_io_err_lazy(|| format!("Failed to write {publications:?}"))
+}
+
+/// Removes all of a session's publications.
+pub fn unpublish(session: &mut PathBuf) -> Result<(), Error> {
+    let publications = TmpPath::new(session, PUBLICATIONS);
+    let records = match fs::read(&publications) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        r => r.map_io_err_lazy(|| format!("Failed to read {publications:?}"))?,
+    };
+
+    for mount_point in records.split(|&b| b == b'\n').filter(|r| !r.is_empty()) {
+        let mount_point = Path::new(OsStr::from_bytes(mount_point));
+        match unmount(mount_point, UnmountFlags::DETACH) {
+            // Already unmounted by hand
+            Err(Errno::INVAL | Errno::NOENT) => {}
+            r => r.map_io_err_lazy(|| {
+                format!("Failed to unmount directory {}", mount_point.display())
+            })?,
+        }
+    }
+    fs::remove_file(&publications).map_io_err_lazy(|| format!("Failed to delete {publications:?}"))
+}
+
+/// Creates a detached copy of the mount at `path`.
+fn clone_tree(
+    path: &Path,
+    user_namespace: Option<&OwnedFd>,
+    read_only: bool,
+) -> Result<OwnedFd, Error> {
+    let tree = open_tree(
+        CWD,
+        path,
+        OpenTreeFlags::OPEN_TREE_CLONE | OpenTreeFlags::OPEN_TREE_CLOEXEC,
+    )
+    .map_io_err_lazy(|| format!("Failed to clone mount {}", path.display()))?;
+    set_attributes(&tree, user_namespace, read_only)
+        .map_io_err_lazy(|| format!("Failed to configure mount {}", path.display()))?;
+    Ok(tree)
+}
+
+/// Creates a user namespace mapping the invoking user onto the viewer.
+fn idmapping(viewer: Viewer) -> Result<OwnedFd, Error> {
+    let from_env = |var| env::var(var).ok().and_then(|id| id.parse().ok());
+    let owner = Viewer {
+        uid: from_env("SUDO_UID").unwrap_or_else(|| getuid().as_raw()),
+        gid: from_env("SUDO_GID").unwrap_or_else(|| getgid().as_raw()),
+    };
+
+    // SAFETY: the child only makes raw syscalls before being killed.
+    let child = match unsafe { libc::fork() } {
+        -1 => return Err(std::io::Error::last_os_error()).map_io_err("Failed to fork"),
+        0 => {
+            if unshare(UnshareFlags::NEWUSER).is_ok() {
+                loop {
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+            // SAFETY: we're in the forked child.
+            unsafe { libc::_exit(1) }
+        }
+        pid => Pid::from_raw(pid).unwrap(),
+    };
+
+    let result = (|| {
+        let proc = PathBuf::from(format!("/proc/{}", child.as_raw_nonzero()));
+        // Wait for the child to move into its namespace
+        let own_namespace =
+            fs::read_link("/proc/self/ns/user").map_io_err("Failed to read user namespace")?;
+        while fs::read_link(proc.join("ns/user")).map_io_err("Failed to read user namespace")?
+            == own_namespace
+        {
+            thread::yield_now();
+        }
+
+        for (file, from, to) in [
+            ("uid_map", owner.uid, viewer.uid),
+            ("gid_map", owner.gid, viewer.gid),
+        ] {
+            let map = proc.join(file);
+            fs::write(&map, format!("{from} {to} 1"))
+                .map_io_err_lazy(|| format!("Failed to write {}", map.display()))?;
+        }
+        fs::File::open(proc.join("ns/user"))
+            .map(OwnedFd::from)
+            .map_io_err("Failed to open user namespace")
+    })();
+
+    let _ = kill_process(child, Signal::Kill);
+    let _ = waitpid(Some(child), WaitOptions::empty());
+    result
+}
+
+#[repr(C)]
+struct MountAttr {
+    attr_set: u64,
+    attr_clr: u64,
+    propagation: u64,
+    userns_fd: u64,
+}
+
+fn set_attributes(
+    tree: &OwnedFd,
+    user_namespace: Option<&OwnedFd>,
+    read_only: bool,
+) -> std::io::Result<()> {
+    let mut attr = MountAttr {
+        attr_set: 0,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    if read_only {
+        attr.attr_set |= u64::from(MountAttrFlags::MOUNT_ATTR_RDONLY.bits());
+    }
+    if let Some(user_namespace) = user_namespace {
+        attr.attr_set |= u64::from(MountAttrFlags::MOUNT_ATTR_IDMAP.bits());
+        attr.userns_fd = u64::try_from(user_namespace.as_raw_fd()).unwrap();
+    }
+
+    // SAFETY: the attribute layout matches the kernel's struct mount_attr.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            tree.as_raw_fd(),
+            c"".as_ptr(),
+            libc::AT_EMPTY_PATH,
+            &raw mut attr,
+            size_of::<MountAttr>(),
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(Errno::from_io_error(&std::io::Error::last_os_error())
+            .unwrap_or(Errno::INVAL)
+            .into())
+    }
+}
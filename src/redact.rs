@@ -0,0 +1,90 @@
+//! Scrubbing personal details from archives before they are shared.
+//!
+//! Each [`Redaction`] replaces the matches of a regular expression in the
+//! files whose paths match one of its globs. Globs are matched against the
+//! whole path as seen from inside the session, with `*` stopping at slashes
+//! and `**` crossing them.
+
+use std::path::Path;
+
+use error_stack::{Result, ResultExt};
+use glob::{MatchOptions, Pattern};
+use regex::bytes::Regex;
+
+use crate::{Error, Redaction};
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// [`Redaction`]s ready to be applied.
+pub struct Rules(Vec<Rule>);
+
+struct Rule {
+    paths: Vec<Pattern>,
+    pattern: Regex,
+    replacement: Vec<u8>,
+}
+
+impl Rules {
+    pub fn compile(redactions: &[Redaction]) -> Result<Self, Error> {
+        redactions
+            .iter()
+            .map(
+                |Redaction {
+                     paths,
+                     pattern,
+                     replacement,
+                 }| {
+                    Ok(Rule {
+                        paths: paths
+                            .iter()
+                            .map(|glob| {
+                                Pattern::new(glob)
+                                    .change_context(Error::InvalidArgument)
+                                    .attach_printable_lazy(|| {
+                                        format!("Invalid redaction path {glob:?}")
+                                    })
+                            })
+                            .collect::<Result<_, _>>()?,
+                        pattern: Regex::new(pattern)
+                            .change_context(Error::InvalidArgument)
+                            .attach_printable_lazy(|| {
+                                format!("Invalid redaction pattern {pattern:?}")
+                            })?,
+                        replacement: replacement.clone().into_bytes(),
+                    })
+                },
+            )
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Whether any rule covers `path`, an absolute path inside the session.
+    pub fn covers(&self, path: &Path) -> bool {
+        self.0.iter().any(|rule| rule.covers(path))
+    }
+
+    /// Applies the rules covering `path` to its contents in order.
+    pub fn apply(&self, path: &Path, mut contents: Vec<u8>) -> Vec<u8> {
+        for rule in self.0.iter().filter(|rule| rule.covers(path)) {
+            contents = rule
+                .pattern
+                .replace_all(&contents, rule.replacement.as_slice())
+                .into_owned();
+        }
+        contents
+    }
+}
+
+impl Rule {
+    fn covers(&self, path: &Path) -> bool {
+        self.paths.is_empty()
+            || self
+                .paths
+                .iter()
+                .any(|glob| glob.matches_path_with(path, MATCH_OPTIONS))
+    }
+}
@@ -0,0 +1,306 @@
+//! Resolving conflicts with the real file system interactively.
+//!
+//! Each conflicted file gets a screen of its own showing three panes side by
+//! side: the version the session started from, the session's, and the current
+//! one on the real file system. Picking a side or editing a merge of both
+//! records a [`Resolution`] that [`apply_resolved`](crate::apply_resolved)
+//! then carries out along with the rest of the session.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Write as _,
+    fs,
+    io::{self, IsTerminal, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process,
+};
+
+use error_stack::Result;
+use rustix::{fs::fchown, process::getuid, termios::tcgetwinsize};
+
+use crate::{
+    Conflict, ConflictVersion, Error, Input, IoErr, Resolution, Style, edit::open_editor,
+    run::unprivileged_uid, session_conflicts,
+};
+
+/// The fewest lines each pane shows on small terminals.
+const MIN_LINES: usize = 10;
+/// Lines the header and prompt take up around the panes.
+const CHROME: usize = 6;
+/// How the lines left in a merge by [`merge`] start, besides the `=======`
+/// separator.
+const MARKERS: [&str; 3] = ["<<<<<<< ", "||||||| ", ">>>>>>> "];
+
+/// Walks through the files a session changed that were also changed outside
+/// of it, asking how to resolve each one.
+///
+/// Returns `None` if the user quit before resolving every conflict.
+pub fn resolve(
+    session: impl AsRef<std::ffi::OsStr>,
+    input: Input,
+    style: Style,
+) -> Result<Option<BTreeMap<PathBuf, Resolution>>, Error> {
+    let conflicts = session_conflicts(session)?;
+    let mut resolutions = BTreeMap::new();
+    let total = conflicts.len();
+    for (i, conflict) in conflicts.into_iter().enumerate() {
+        let mut screen = String::new();
+        let stderr = io::stderr();
+        if stderr.is_terminal() {
+            screen.push_str("\x1b[H\x1b[2J");
+        }
+        let _ = writeln!(
+            screen,
+            "[{}/{total}] {} was changed outside the session\n",
+            i + 1,
+            style.text(&conflict.path.to_string_lossy())
+        );
+        render(&mut screen, &conflict, style);
+        stderr
+            .lock()
+            .write_all(screen.as_bytes())
+            .map_io_err("Failed to write to stderr")?;
+
+        let editable = [&conflict.session, &conflict.live]
+            .into_iter()
+            .all(|version| matches!(version, ConflictVersion::File(_) | ConflictVersion::Missing));
+        let resolution = loop {
+            let choice = if editable {
+                input.choose(
+                    "Keep the session's or the current version, edit a merge, or quit?",
+                    &['s', 'c', 'e', 'q'],
+                )?
+            } else {
+                input.choose(
+                    "Keep the session's or the current version, or quit?",
+                    &['s', 'c', 'q'],
+                )?
+            };
+            match choice {
+                's' => break Resolution::Session,
+                'c' => break Resolution::Live,
+                'e' => {
+                    if let Some(contents) = edit(&conflict)? {
+                        break Resolution::Contents(contents);
+                    }
+                }
+                _ => return Ok(None),
+            }
+        };
+        resolutions.insert(conflict.path, resolution);
+    }
+    Ok(Some(resolutions))
+}
+
+/// Lays out the three versions of a conflicted file next to each other, cut
+/// down to fit the terminal.
+fn render(out: &mut String, conflict: &Conflict, style: Style) {
+    let (columns, rows) = tcgetwinsize(io::stderr())
+        .ok()
+        .filter(|size| size.ws_col > 0)
+        .map_or((120, 40), |size| {
+            (usize::from(size.ws_col), usize::from(size.ws_row))
+        });
+    let separator = if style.ascii() { " | " } else { " │ " };
+    let width = columns.saturating_sub(2 * separator.chars().count()) / 3;
+    let height = rows.saturating_sub(CHROME).max(MIN_LINES);
+    let colors = style.colors(&io::stderr());
+
+    let panes = [
+        ("lower", lines(&conflict.lower)),
+        ("session", lines(&conflict.session)),
+        ("current", lines(&conflict.live)),
+    ];
+    let longest = panes
+        .iter()
+        .map(|(_, lines)| lines.len())
+        .max()
+        .unwrap_or(0);
+    let titles = panes
+        .iter()
+        .map(|(title, _)| fit(title, width))
+        .collect::<Vec<_>>();
+    let _ = writeln!(out, "{}", titles.join(separator));
+    for row in 0..longest.min(height) {
+        let cell = |pane: usize| {
+            panes[pane]
+                .1
+                .get(row)
+                .map_or_else(|| " ".repeat(width), |line| fit(&style.text(line), width))
+        };
+        let (session, live) = (panes[1].1.get(row), panes[2].1.get(row));
+        let differs = session != live;
+        let _ = writeln!(
+            out,
+            "{}{separator}{}{separator}{}",
+            cell(0),
+            if differs {
+                Style::added(colors, cell(1)).to_string()
+            } else {
+                cell(1)
+            },
+            if differs {
+                Style::modified(colors, cell(2)).to_string()
+            } else {
+                cell(2)
+            },
+        );
+    }
+    if longest > height {
+        let _ = writeln!(out, "({} more lines)", longest - height);
+    }
+    let _ = writeln!(out);
+}
+
+/// The lines a pane shows for a version.
+fn lines(version: &ConflictVersion) -> Vec<String> {
+    match version {
+        ConflictVersion::Unknown => vec!["(not kept)".to_string()],
+        ConflictVersion::Missing => vec!["(missing)".to_string()],
+        ConflictVersion::File(contents) => match std::str::from_utf8(contents) {
+            Ok(text) if !text.contains('\0') => text
+                .lines()
+                .map(|line| {
+                    line.replace('\t', "    ")
+                        .chars()
+                        .map(|c| if c.is_control() { '?' } else { c })
+                        .collect()
+                })
+                .collect(),
+            _ => vec![format!("(binary, {} bytes)", contents.len())],
+        },
+        ConflictVersion::Large(size) => vec![format!("({size} bytes, too large to show)")],
+        ConflictVersion::Symlink(target) => vec![format!("-> {}", target.display())],
+        ConflictVersion::Directory => vec!["(directory)".to_string()],
+        ConflictVersion::Special => vec!["(special file)".to_string()],
+    }
+}
+
+/// Pads or cuts `text` to exactly `width` characters.
+fn fit(text: &str, width: usize) -> String {
+    let count = text.chars().count();
+    if count <= width {
+        let mut text = text.to_string();
+        text.extend(std::iter::repeat_n(' ', width - count));
+        text
+    } else {
+        text.chars()
+            .take(width.saturating_sub(1))
+            .chain(std::iter::once('~'))
+            .collect()
+    }
+}
+
+/// Opens a merge of the session's and the current version in the user's
+/// editor, returning the result unless the editor failed or left conflict
+/// markers behind.
+fn edit(conflict: &Conflict) -> Result<Option<Vec<u8>>, Error> {
+    let mut name = format!("forkfs-merge-{}-", process::id());
+    name.push_str(
+        &conflict
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy(),
+    );
+    let file = env::temp_dir().join(name);
+    let result = write_merge(&file, conflict).and_then(|()| {
+        let status = open_editor(&file, getuid())?;
+        if !status.success() {
+            drop(writeln!(io::stderr(), "The editor failed: {status}"));
+            return Ok(None);
+        }
+        let contents =
+            fs::read(&file).map_io_err_lazy(|| format!("Failed to read {}", file.display()))?;
+        if contents.split(|&b| b == b'\n').any(|line| {
+            line == b"======="
+                || MARKERS
+                    .iter()
+                    .any(|marker| line.starts_with(marker.as_bytes()))
+        }) {
+            drop(writeln!(
+                io::stderr(),
+                "The merge still has conflict markers."
+            ));
+            return Ok(None);
+        }
+        Ok(Some(contents))
+    });
+    drop(fs::remove_file(&file));
+    result
+}
+
+fn write_merge(file: &Path, conflict: &Conflict) -> Result<(), Error> {
+    let text = |version: &ConflictVersion| match version {
+        ConflictVersion::File(contents) => Some(contents.clone()),
+        ConflictVersion::Missing => Some(Vec::new()),
+        _ => None,
+    };
+    let session = text(&conflict.session).unwrap_or_default();
+    let live = text(&conflict.live).unwrap_or_default();
+    let lower = text(&conflict.lower);
+
+    let uid = unprivileged_uid(getuid());
+    let mut out = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(file)
+        .map_io_err_lazy(|| format!("Failed to create {}", file.display()))?;
+    if let Some(uid) = uid {
+        fchown(&out, Some(uid), None)
+            .map_io_err_lazy(|| format!("Failed to change the owner of {}", file.display()))?;
+    }
+    out.write_all(&merge(&session, lower.as_deref(), &live))
+        .map_io_err_lazy(|| format!("Failed to write {}", file.display()))
+}
+
+/// Marks up the lines in which the session's and the current version differ
+/// like `git merge` does, including the lower version if it is known.
+fn merge(session: &[u8], lower: Option<&[u8]>, live: &[u8]) -> Vec<u8> {
+    let split = |contents: &[u8]| {
+        contents
+            .split_inclusive(|&b| b == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect::<Vec<_>>()
+    };
+    let (session, live) = (split(session), split(live));
+    let lower = lower.map(split);
+    let prefix = session
+        .iter()
+        .zip(&live)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = session[prefix..]
+        .iter()
+        .rev()
+        .zip(live[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut out = Vec::new();
+    section(&mut out, &session[..prefix]);
+    out.extend_from_slice(b"<<<<<<< session\n");
+    section(&mut out, &session[prefix..session.len() - suffix]);
+    if let Some(lower) = &lower {
+        out.extend_from_slice(b"||||||| lower\n");
+        section(&mut out, lower);
+    }
+    out.extend_from_slice(b"=======\n");
+    section(&mut out, &live[prefix..live.len() - suffix]);
+    out.extend_from_slice(b">>>>>>> current\n");
+    section(&mut out, &session[session.len() - suffix..]);
+    out
+}
+
+/// Appends `lines`, ending them with a newline so that a marker can follow.
+fn section(out: &mut Vec<u8>, lines: &[Vec<u8>]) {
+    for line in lines {
+        out.extend_from_slice(line);
+    }
+    if out.last().is_some_and(|&b| b != b'\n') {
+        out.push(b'\n');
+    }
+}
@@ -0,0 +1,195 @@
+//! Discarding a session's changes to individual paths.
+//!
+//! Removing an entry from the upper directory makes the real one show through
+//! again, but only if nothing above it hides the real directory it lives in.
+//! The walk down to each entry therefore checks every directory on the way for
+//! overlayfs' opaque and redirect markers.
+
+use std::{
+    env,
+    ffi::{CString, OsStr},
+    fs,
+    os::{
+        fd::OwnedFd,
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{AtFlags, FileType, OFlags, Stat, statat, unlinkat},
+    io::Errno,
+    process::getuid,
+};
+
+use crate::{
+    Error, IoErr, SessionOperand,
+    diff::has_overlay_xattr,
+    encryption, get_sessions_dir,
+    run::validate_permissions,
+    sessions::{open_diff, remove_tree, resolve_session},
+    stop_sessions, subtree, walk,
+};
+
+/// Discards a session's changes to files and directories, recursively, so
+/// that the session sees the real ones again. Returns the paths that had
+/// changes to discard.
+///
+/// The session is stopped first since overlayfs doesn't support changing its
+/// layers while they are mounted. Relative paths are resolved against the
+/// current directory. Paths inside a directory the session replaced, deleted,
+/// or renamed can't be reverted on their own since the real directory doesn't
+/// show through it; revert the directory instead. Directories that were only
+/// copied into the session to hold the reverted paths are removed too.
+pub fn revert(session: impl AsRef<OsStr>, paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let session = session.as_ref();
+    validate_permissions(getuid())?;
+    let upper = open_diff(session)?;
+    stop_sessions(SessionOperand::List(&[session]))?;
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    if encryption::is_encrypted(&mut dir) {
+        return Err(Error::InvalidArgument).attach_printable(
+            "Encrypted sessions can't be read once stopped and so can't be reverted.",
+        );
+    }
+
+    let subtree = subtree::get(&mut dir)?;
+    let cwd = env::current_dir().map_io_err("Failed to get current directory")?;
+    let mut reverted = Vec::new();
+    for path in paths {
+        let path = resolve(&cwd.join(path))?;
+        if revert_path(&upper, &subtree, &path)? {
+            reverted.push(path);
+        }
+    }
+    Ok(reverted)
+}
+
+/// Resolves symlinks in a path's parent the way the real file system would so
+/// that the path can be looked up in the upper directory component by
+/// component.
+fn resolve(path: &Path) -> Result<PathBuf, Error> {
+    let resolved = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            fs::canonicalize(parent).map_or_else(|_| path.to_path_buf(), |parent| parent.join(name))
+        }
+        _ => path.to_path_buf(),
+    };
+    if resolved
+        .components()
+        .any(|component| matches!(component, Component::ParentDir | Component::CurDir))
+    {
+        return Err(Error::InvalidArgument).attach_printable(format!(
+            "Path {} can't be resolved since its parent doesn't exist.",
+            path.display()
+        ));
+    }
+    Ok(resolved)
+}
+
+fn revert_path(upper: &OwnedFd, subtree: &Path, path: &Path) -> Result<bool, Error> {
+    let names = subtree::relative(subtree, path)?
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(CString::new(name.as_bytes())),
+            _ => None,
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .change_context(Error::InvalidArgument)
+        .attach_printable_lazy(|| format!("Invalid path {}", path.display()))?;
+    let Some((name, parents)) = names.split_last() else {
+        let changed = !walk::entries(upper)
+            .map_io_err("Failed to read the session's changes")?
+            .is_empty();
+        remove_tree(upper, &mut subtree.to_path_buf())?;
+        return Ok(changed);
+    };
+
+    let upper = upper
+        .try_clone()
+        .map_io_err("Failed to open the session's changes")?;
+    let mut dirs = vec![upper];
+    let mut ancestor = subtree.to_path_buf();
+    let mut stats = Vec::with_capacity(parents.len());
+    for parent in parents {
+        ancestor.push(OsStr::from_bytes(parent.as_bytes()));
+        let dir = dirs.last().unwrap();
+        let Some(stat) = stat(dir, parent, &ancestor)? else {
+            return Ok(false);
+        };
+        let hidden = || {
+            Err(Error::InvalidArgument).attach_printable(format!(
+                "The session replaced, deleted, or renamed {}, so the real files inside it don't \
+                 show through. Revert {} instead of {}.",
+                ancestor.display(),
+                ancestor.display(),
+                path.display()
+            ))
+        };
+        if FileType::from_raw_mode(stat.st_mode) != FileType::Directory {
+            return hidden();
+        }
+        let child = walk::open_beneath(dir, parent, OFlags::RDONLY | OFlags::DIRECTORY)
+            .map_io_err_lazy(|| format!("Failed to open directory {}", ancestor.display()))?;
+        if has_overlay_xattr(&child, "opaque", &ancestor)?
+            || has_overlay_xattr(&child, "redirect", &ancestor)?
+        {
+            return hidden();
+        }
+        dirs.push(child);
+        stats.push(stat);
+    }
+
+    let dir = dirs.last().unwrap();
+    let Some(stat) = stat(dir, name, path)? else {
+        return Ok(false);
+    };
+    let flags = if FileType::from_raw_mode(stat.st_mode) == FileType::Directory {
+        let child = walk::open_beneath(dir, name, OFlags::RDONLY | OFlags::DIRECTORY)
+            .map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?;
+        remove_tree(&child, &mut path.to_path_buf())?;
+        AtFlags::REMOVEDIR
+    } else {
+        AtFlags::empty()
+    };
+    unlinkat(dir, name, flags)
+        .map_io_err_lazy(|| format!("Failed to delete {}", path.display()))?;
+
+    // Working on a file copies every directory above it into the session, which
+    // can go again if that's all that happened to them
+    for (index, (parent, stat)) in parents.iter().zip(&stats).enumerate().rev() {
+        if !walk::entries(&dirs[index + 1])
+            .map_io_err_lazy(|| format!("Failed to read directory {}", ancestor.display()))?
+            .is_empty()
+            || !is_copy_of_real_dir(&ancestor, stat)
+        {
+            break;
+        }
+        unlinkat(&dirs[index], parent, AtFlags::REMOVEDIR)
+            .map_io_err_lazy(|| format!("Failed to delete {}", ancestor.display()))?;
+        ancestor.pop();
+    }
+    Ok(true)
+}
+
+fn stat(dir: &OwnedFd, name: &CString, path: &Path) -> Result<Option<Stat>, Error> {
+    match statat(dir, name, AtFlags::SYMLINK_NOFOLLOW) {
+        Err(Errno::NOENT) => Ok(None),
+        r => r
+            .map(Some)
+            .map_io_err_lazy(|| format!("Failed to stat {}", path.display())),
+    }
+}
+
+/// Whether an upper directory is indistinguishable from the real one, apart
+/// from its timestamps which change whenever its entries do.
+fn is_copy_of_real_dir(path: &Path, upper: &Stat) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|real| {
+        real.is_dir()
+            && real.mode() == upper.st_mode
+            && real.uid() == upper.st_uid
+            && real.gid() == upper.st_gid
+    })
+}
@@ -0,0 +1,142 @@
+//! Hardening checks for sessions whose changes were made by untrusted code.
+//!
+//! Replaying a session onto the real file system runs with whatever privileges
+//! the caller has, so anything a sandboxed program left behind in the upper
+//! directory is trusted by default. Reviewing the session first surfaces the
+//! changes that could be used to escalate privileges.
+
+use std::{
+    ffi::OsStr,
+    os::{fd::OwnedFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+};
+
+use error_stack::Result;
+use rustix::{
+    fs::{AtFlags, CWD, FileType, Mode, OFlags, statat},
+    process::getuid,
+};
+
+use crate::{
+    Error, IoErr, diff::is_whiteout, path_undo::TmpPath, sessions::open_diff, subtree, walk,
+};
+
+/// Paths whose modification commonly grants code execution as another user.
+const SENSITIVE: &[&str] = &[
+    "/boot",
+    "/etc/cron.d",
+    "/etc/crontab",
+    "/etc/group",
+    "/etc/gshadow",
+    "/etc/ld.so.conf",
+    "/etc/ld.so.conf.d",
+    "/etc/ld.so.preload",
+    "/etc/pam.d",
+    "/etc/passwd",
+    "/etc/profile",
+    "/etc/profile.d",
+    "/etc/security",
+    "/etc/shadow",
+    "/etc/ssh",
+    "/etc/sudoers",
+    "/etc/sudoers.d",
+    "/etc/systemd",
+    "/root",
+    "/usr/lib/systemd",
+];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Concern {
+    /// The file has its setuid or setgid bit set.
+    SetId,
+    /// The file is a block or character device rather than a whiteout.
+    Device,
+    /// The file is owned by neither the reviewer nor the file it replaces.
+    UnexpectedOwner { uid: u32 },
+    /// The change touches a path that controls authentication, boot, or
+    /// what runs on login.
+    SensitivePath,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Finding {
+    /// The affected path as seen from inside the session.
+    pub path: PathBuf,
+    pub concern: Concern,
+}
+
+/// Lists the changes in a session that shouldn't be applied to the real file
+/// system without a closer look, sorted by path.
+///
+/// Changes at or below one of the `allowed` paths are never reported.
+pub fn review(session: impl AsRef<OsStr>, allowed: &[PathBuf]) -> Result<Vec<Finding>, Error> {
+    let session = session.as_ref();
+    let fd = open_diff(session)?;
+
+    let mut findings = Vec::new();
+    review_tree(&fd, &mut subtree::of(session)?, allowed, &mut findings)?;
+    findings.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(findings)
+}
+
+fn review_tree(
+    dir: &OwnedFd,
+    path: &mut PathBuf,
+    allowed: &[PathBuf],
+    findings: &mut Vec<Finding>,
+) -> Result<(), Error> {
+    for (name, file_type) in walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+        if allowed.iter().any(|allowed| path.starts_with(allowed)) {
+            continue;
+        }
+        let stat = statat(dir, &name, AtFlags::SYMLINK_NOFOLLOW)
+            .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+        let mut report = |concern| {
+            findings.push(Finding {
+                path: path.to_path_buf(),
+                concern,
+            });
+        };
+
+        if file_type != FileType::Directory && is_sensitive(&path) {
+            report(Concern::SensitivePath);
+        }
+        if is_whiteout(file_type, &stat) {
+            continue;
+        }
+        if matches!(file_type, FileType::BlockDevice | FileType::CharacterDevice) {
+            report(Concern::Device);
+        }
+        if Mode::from_raw_mode(stat.st_mode).intersects(Mode::SUID | Mode::SGID)
+            && file_type != FileType::Directory
+        {
+            report(Concern::SetId);
+        }
+        if stat.st_uid != getuid().as_raw() && Some(stat.st_uid) != host_owner(&path) {
+            report(Concern::UnexpectedOwner { uid: stat.st_uid });
+        }
+
+        if file_type == FileType::Directory {
+            let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+                .map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?;
+            review_tree(&child, &mut path, allowed, findings)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_sensitive(path: &Path) -> bool {
+    SENSITIVE
+        .iter()
+        .any(|sensitive| path.starts_with(sensitive))
+}
+
+/// The owner of the real file a change replaces, if there is one.
+fn host_owner(path: &Path) -> Option<u32> {
+    statat(CWD, path, AtFlags::SYMLINK_NOFOLLOW)
+        .ok()
+        .map(|stat| stat.st_uid)
+}
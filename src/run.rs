@@ -1,56 +1,1019 @@
 use std::{
     env,
-    env::{current_dir, set_current_dir},
-    ffi::{CStr, OsStr},
-    os::unix::{fs::chroot, process::CommandExt},
-    path::Path,
-    process::Command,
+    env::current_dir,
+    ffi::{CStr, CString, OsStr},
+    fs,
+    fs::File,
+    io, mem,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, process::CommandExt},
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus},
+    sync::atomic::{AtomicI32, Ordering},
+    thread,
+    time::{Duration, Instant},
 };
 
 use error_stack::{Result, ResultExt};
 use rustix::{
-    fs::{CWD, readlinkat},
-    io::Errno,
-    process::{Uid, getuid},
-    thread::{CapabilityFlags, capabilities, set_thread_uid},
+    fs::{
+        AtFlags, Gid, Mode, MountFlags, MountPropagationFlags, OFlags, UnmountFlags, change_mount,
+        chownat, fstat, mount, recursive_bind_mount, statvfs, unmount,
+    },
+    io::{Errno, dup},
+    mount::mount_remount,
+    net::{AddressFamily, SocketType, socket},
+    process::{
+        Signal, Uid, chdir, getuid, kill_process, pivot_root, set_parent_process_death_signal,
+        umask,
+    },
+    stdio::{dup2_stdin, dup2_stdout},
+    thread::{CapabilityFlags, UnshareFlags, capabilities, set_thread_uid, unshare},
 };
+use serde::{Deserialize, Serialize};
+use typed_builder::TypedBuilder;
 
-use crate::{Error, IoErr, get_sessions_dir, sessions::maybe_create_session};
+use crate::{
+    Backend, Change, ChangeKind, EncryptionKey, Error, IoErr, Preset, Restart, Usage, Viewer,
+    detach,
+    diff::changes,
+    encryption, get_sessions_dir, lower,
+    messages::Message,
+    meta,
+    overlay_options::{self, OverlayOption},
+    pin,
+    sessions::{
+        delete_session, is_active_session, mark_used, maybe_create_session, open_diff,
+        resolve_alias, session_processes, stop_session,
+    },
+    setup::{exe_path, had_capabilities, remember_capabilities},
+    snapshot, subtree,
+    system_dirs::{self, SystemDir},
+    usage, volatile, walk, wsl,
+};
 
-pub fn run<T: AsRef<OsStr>>(session: &str, command: &[T]) -> Result<(), Error> {
-    let uid = getuid();
-    validate_permissions(uid)?;
+/// Runs a command inside a session, creating and activating it if necessary.
+pub fn run<T: AsRef<OsStr>>(
+    session: impl AsRef<OsStr>,
+    command: &[T],
+) -> Result<ExitStatus, Error> {
+    RunOp::builder()
+        .session(session.as_ref())
+        .command(command)
+        .build()
+        .run()
+}
 
-    let mut session_dir = get_sessions_dir();
-    session_dir.push(session);
+#[allow(clippy::struct_excessive_bools)]
+#[derive(TypedBuilder, Debug)]
+pub struct RunOp<'a, T: AsRef<OsStr>> {
+    session: &'a OsStr,
+    command: &'a [T],
+    /// Build system caches to pass through to the real file system.
+    #[builder(default)]
+    presets: &'a [Preset],
+    /// Other directories to pass through to the real file system.
+    #[builder(default)]
+    passthroughs: &'a [PathBuf],
+    /// Host files and directories to mount elsewhere in the sandbox, after
+    /// the passthroughs.
+    #[builder(default)]
+    binds: &'a [Bind],
+    /// Kill the sandboxed processes if the session grows by more than this
+    /// many bytes during the run.
+    #[builder(default)]
+    max_write: Option<u64>,
+    /// Refuse to run if the file system holding the session has less than
+    /// this many bytes available, or too few free inodes to match.
+    #[builder(default = DEFAULT_MIN_FREE_SPACE)]
+    min_free_space: u64,
+    /// Keep the session's changes encrypted at rest, only unlocking them while
+    /// the session is active. The key must be supplied on every activation.
+    #[builder(default)]
+    encryption_key: Option<&'a EncryptionKey>,
+    /// Layer a new session on this block device or directory, such as an LVM
+    /// or btrfs snapshot, instead of the live root. Existing sessions must
+    /// have been created with the same device.
+    #[builder(default)]
+    lower_device: Option<&'a Path>,
+    /// Only overlay this directory in a new session, leaving the rest of the
+    /// file system read-only apart from `/proc`, `/dev`, `/run`, and `/tmp`.
+    /// Existing sessions must have been created with the same directory.
+    #[builder(default)]
+    root: Option<&'a Path>,
+    /// Leave these system directories out of a new session so that writes to
+    /// them are captured like any others, or read-only in sessions of a
+    /// subtree. Existing sessions must have been created without the same
+    /// ones.
+    #[builder(default)]
+    unbound: &'a [SystemDir],
+    /// Mount a new session with these overlayfs features turned on. Existing
+    /// sessions must have been created with the same ones.
+    #[builder(default)]
+    overlay_options: &'a [OverlayOption],
+    /// Keep a new session's changes in memory rather than on disk, losing
+    /// them once the session is deleted or the machine restarts. Existing
+    /// sessions must have been created the same way.
+    #[builder(default)]
+    volatile: bool,
+    /// Pin these files to their current contents in a new session, refusing
+    /// to apply it once they changed. Existing sessions must have been created
+    /// with the same files pinned.
+    #[builder(default)]
+    pins: &'a [PathBuf],
+    /// Run the command in its own PID namespace with a fresh `/proc` so that
+    /// it can't see or signal the host's processes.
+    #[builder(default)]
+    private_pids: bool,
+    /// Which network the command can reach.
+    #[builder(default)]
+    network: Network,
+    /// Run the command with this file mode creation mask instead of the one
+    /// forkfs was started with.
+    #[builder(default)]
+    umask: Option<u32>,
+    /// Once the command exits, hand the files it added over to this user.
+    /// Only entries owned by the user the command ran as change hands, and the
+    /// run must be supervised to do so.
+    #[builder(default)]
+    owner: Option<Viewer>,
+    /// How to mount the session if it isn't active yet.
+    #[builder(default)]
+    backend: Backend,
+    /// Reserve stdout for the command by sending anything forkfs itself would
+    /// print there to stderr instead.
+    #[builder(default)]
+    pipe: bool,
+    /// Once a waited for command was started, point this process's stdin and
+    /// stdout at `/dev/null` so that only the command holds on to them, letting
+    /// the programs at the other end see it close them. They aren't restored,
+    /// so this is meant for binaries that exit once the command does.
+    #[builder(default)]
+    release_stdio: bool,
+    /// Start the command in the background and return immediately, see
+    /// [`attach_process`](crate::attach_process).
+    #[builder(default)]
+    detach: bool,
+    /// Whether to start a detached command again after it exits.
+    #[builder(default)]
+    restart: Restart,
+    /// Create the session for this run only and delete it once the command
+    /// exits, killing anything it left running. The session must not exist
+    /// yet.
+    #[builder(default)]
+    ephemeral: bool,
+    /// Keep an ephemeral session whose command failed so that it can be
+    /// inspected, only deleting it after successful runs.
+    #[builder(default)]
+    keep_on_failure: bool,
+    /// Wait for the command and delete the session if it exits successfully,
+    /// killing anything it left running.
+    #[builder(default)]
+    discard_on_success: bool,
+    /// Wait for the command in this process instead of replacing it with the
+    /// command, passing on the signals it receives.
+    #[builder(default)]
+    wait: bool,
+    /// Wait for the command and stop the session once the last process using
+    /// it exits, including those the command left running.
+    #[builder(default)]
+    stop: bool,
+    /// Wait for the command and snapshot the session this often while it
+    /// runs, so that it can be rolled back to how it was part way through.
+    #[builder(default)]
+    checkpoint_every: Option<Duration>,
+}
 
-    maybe_create_session(&mut session_dir)?;
+/// A host file or directory mounted into the sandbox.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Bind {
+    pub source: PathBuf,
+    /// Where the source shows up in the sandbox, which must be an absolute
+    /// path unless it is the source itself.
+    pub target: PathBuf,
+    pub read_only: bool,
+}
 
-    session_dir.push("merged");
-    enter_session(&session_dir)?;
+impl Bind {
+    /// Mounts a directory onto itself so that writes to it reach the real file
+    /// system.
+    #[must_use]
+    pub fn passthrough(dir: PathBuf) -> Self {
+        Self {
+            target: dir.clone(),
+            source: dir,
+            read_only: false,
+        }
+    }
+}
+
+/// The free space [`RunOp`] requires unless told otherwise.
+pub const DEFAULT_MIN_FREE_SPACE: u64 = 256 << 20;
+/// The average file size assumed when deciding how many free inodes the
+/// required free space calls for.
+const BYTES_PER_INODE: u64 = 16 << 10;
 
-    run_command(command, uid)
+/// The network a sandboxed command is connected to.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Network {
+    /// The host's network, like any other process.
+    #[default]
+    Host,
+    /// A network namespace of its own without any interfaces, so connections
+    /// fail immediately, even to `localhost`.
+    Isolated,
+    /// A network namespace of its own with only a loopback interface, so
+    /// servers started in the sandbox can be reached from inside it.
+    Loopback,
 }
 
-fn enter_session(target: &Path) -> Result<(), Error> {
-    // Must be retrieved before chroot-ing
-    let current_dir = current_dir().map_io_err("Failed to get current directory")?;
+impl<T: AsRef<OsStr>> RunOp<'_, T> {
+    /// Consume and run this operation.
+    ///
+    /// Unless the run needs to be supervised or waited for, the current
+    /// process is replaced with the sandboxed command and this function only
+    /// returns on error.
+    /// Detached commands report success as soon as they were started, while
+    /// discarded sessions are deleted by the time this function returns.
+    pub fn run(self) -> Result<ExitStatus, Error> {
+        let Self {
+            session,
+            command,
+            presets,
+            passthroughs,
+            binds,
+            max_write,
+            min_free_space,
+            encryption_key,
+            lower_device,
+            root,
+            unbound,
+            overlay_options,
+            volatile,
+            pins,
+            private_pids,
+            network,
+            umask,
+            owner,
+            backend,
+            pipe,
+            release_stdio,
+            detach,
+            restart,
+            ephemeral,
+            keep_on_failure,
+            discard_on_success,
+            wait,
+            stop,
+            checkpoint_every,
+        } = self;
+        if detach && max_write.is_some() {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Writes can't be limited for detached commands.");
+        }
+        if !detach && restart != Restart::Never {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Only detached commands can be restarted.");
+        }
+        if detach && ephemeral {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Ephemeral sessions can't run detached commands.");
+        }
+        if keep_on_failure && !ephemeral && !discard_on_success {
+            return Err(Error::InvalidArgument).attach_printable(
+                "Only ephemeral sessions are discarded when their command fails.",
+            );
+        }
+        if detach
+            && (wait || stop || checkpoint_every.is_some() || discard_on_success || owner.is_some())
+        {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Detached commands can't be waited for.");
+        }
+        if umask.is_some_and(|umask| umask > 0o777) {
+            return Err(Error::InvalidArgument)
+                .attach_printable(format!("Invalid umask {:o}.", umask.unwrap_or_default()));
+        }
+        if checkpoint_every.is_some_and(|every| every.is_zero()) {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Checkpoints must be taken at least a moment apart.");
+        }
+        if volatile && encryption_key.is_some() {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Sessions kept in memory can't be encrypted.");
+        }
+
+        let stdout = if pipe {
+            let stdout = dup(io::stdout()).map_io_err("Failed to duplicate stdout")?;
+            dup2_stdout(io::stderr()).map_io_err("Failed to redirect stdout")?;
+            Some(stdout)
+        } else {
+            None
+        };
 
-    chroot(target).map_io_err_lazy(|| format!("Failed to change root {target:?}"))?;
-    set_current_dir(current_dir)
-        .map_io_err_lazy(|| format!("Failed to change current directory {target:?}"))
+        let uid = getuid();
+        validate_permissions(uid)?;
+
+        let mut session_dir = get_sessions_dir()?;
+        session_dir.push(resolve_alias(session)?);
+        if ephemeral && session_dir.exists() {
+            return Err(Error::Conflict).attach_printable(format!(
+                "Session {} already exists, ephemeral runs need a new one.",
+                session.display()
+            ));
+        }
+        let cleanup = Discard((ephemeral && !keep_on_failure).then(|| session_dir.clone()));
+        let discard_on_success = discard_on_success || ephemeral;
+
+        check_free_space(&session_dir, min_free_space)?;
+        if let Some(device) = lower_device {
+            lower::assign(&mut session_dir, device)?;
+        }
+        if let Some(root) = root {
+            subtree::assign(&mut session_dir, root)?;
+        }
+        if !unbound.is_empty() {
+            system_dirs::assign(&mut session_dir, unbound)?;
+        }
+        if !overlay_options.is_empty() {
+            overlay_options::assign(&mut session_dir, overlay_options)?;
+        }
+        if volatile {
+            volatile::assign(&mut session_dir)?;
+        }
+        if !pins.is_empty() {
+            pin::assign(&mut session_dir, pins)?;
+        }
+        let subtree = subtree::get(&mut session_dir)?;
+        let unbound = system_dirs::get(&mut session_dir)?;
+        maybe_create_session(&mut session_dir, encryption_key, backend)?;
+        if checkpoint_every.is_some() && encryption::is_encrypted(&mut session_dir) {
+            return Err(Error::InvalidArgument)
+                .attach_printable("Encrypted sessions can't be checkpointed.");
+        }
+        mark_used(&mut session_dir)?;
+        meta::record_command(&mut session_dir, command)?;
+
+        let merged = session_dir.join("merged");
+        let binds = presets
+            .iter()
+            .flat_map(|preset| preset.passthroughs())
+            .chain(passthroughs.iter().cloned())
+            .map(Bind::passthrough)
+            .chain(binds.iter().cloned())
+            .collect::<Vec<_>>();
+        let namespace = Namespace::new(
+            &merged,
+            &subtree,
+            &unbound,
+            &binds,
+            private_pids,
+            network,
+            umask,
+        )?;
+
+        if detach {
+            detach::spawn(&mut session_dir, command, uid, namespace, restart)?;
+            return Ok(ExitStatus::default());
+        }
+        if max_write.is_none()
+            && checkpoint_every.is_none()
+            && !discard_on_success
+            && !wait
+            && !stop
+            && owner.is_none()
+        {
+            namespace
+                .enter()
+                .map_io_err_lazy(|| format!("Failed to enter session {}", merged.display()))?;
+            if let Some(stdout) = stdout {
+                dup2_stdout(stdout).map_io_err("Failed to restore stdout")?;
+            }
+            return exec_command(command, uid);
+        }
+
+        let child = spawn_command(command, uid, namespace, stdout)?;
+        if release_stdio {
+            drop_stdio()?;
+        }
+        forward_signals(child.id().cast_signed());
+        session_dir.push("diff");
+        let watchdog = Watchdog {
+            max_write,
+            checkpoint_every,
+            session,
+            diff: &session_dir,
+            merged: &merged,
+            subtree: &subtree,
+        };
+        let status = watchdog.supervise(child)?;
+        session_dir.pop();
+        if discard_on_success && status.success() {
+            discard(&mut session_dir)?;
+        } else {
+            let stop = stop && cleanup.0.is_none();
+            if stop {
+                while !session_processes(&merged, &subtree)?.is_empty() {
+                    thread::sleep(Watchdog::POLL_INTERVAL);
+                }
+            }
+            if let Some(owner) = owner {
+                // The command ran as the user that invoked sudo, if any
+                hand_over(
+                    session,
+                    &subtree,
+                    unprivileged_uid(uid).unwrap_or(uid),
+                    owner,
+                )?;
+            }
+            if stop {
+                stop_session(&mut session_dir)?;
+            }
+        }
+        cleanup.finish()?;
+        Ok(status)
+    }
 }
 
-fn run_command(args: &[impl AsRef<OsStr>], prev_uid: Uid) -> Result<(), Error> {
-    let mut command = Command::new(args[0].as_ref());
+/// Gives the entries a run added that are owned by `uid` to `owner`, leaving
+/// copied up files with the owner they had on the real file system.
+fn hand_over(session: &OsStr, subtree: &Path, uid: Uid, owner: Viewer) -> Result<(), Error> {
+    let upper = open_diff(session)?;
+    for Change { path, kind, .. } in changes(session)? {
+        if kind != ChangeKind::Added {
+            continue;
+        }
+        let error = || format!("Failed to hand over {}", path.display());
+        let name = CString::new(subtree::relative(subtree, &path)?.as_os_str().as_bytes())
+            .change_context(Error::InvalidArgument)
+            .attach_printable_lazy(|| format!("Invalid path {}", path.display()))?;
+        let fd = walk::open_beneath(&upper, &name, OFlags::PATH).map_io_err_lazy(error)?;
+        if fstat(&fd).map_io_err_lazy(error)?.st_uid != uid.as_raw() {
+            continue;
+        }
+        // SAFETY: any id can own a file, whether or not a user has it.
+        let (user, group) = unsafe { (Uid::from_raw(owner.uid), Gid::from_raw(owner.gid)) };
+        // O_PATH descriptors can't be passed to fchown
+        chownat(&fd, c"", Some(user), Some(group), AtFlags::EMPTY_PATH).map_io_err_lazy(error)?;
+    }
+    Ok(())
+}
 
-    // Downgrade privilege level to pre-sudo if possible
-    if !prev_uid.is_root() {
-        command.uid(prev_uid.as_raw());
-    } else if let Some(uid) = env::var_os("SUDO_UID").as_ref().and_then(|s| s.to_str())
-        && let Ok(uid) = uid.parse()
-    {
-        command.uid(uid);
+/// Deletes an ephemeral session once its run is over, or on the way out if
+/// the run fails before that.
+struct Discard(Option<PathBuf>);
+
+impl Discard {
+    fn finish(mut self) -> Result<(), Error> {
+        self.0
+            .take()
+            .map_or(Ok(()), |mut session| discard(&mut session))
+    }
+}
+
+impl Drop for Discard {
+    fn drop(&mut self) {
+        if let Some(mut session) = self.0.take() {
+            drop(discard(&mut session));
+        }
+    }
+}
+
+pub fn discard(session: &mut PathBuf) -> Result<(), Error> {
+    const ATTEMPTS: usize = 100;
+
+    if !session.exists() {
+        return Ok(());
+    }
+    if is_active_session(session, false)? {
+        let merged = session.join("merged");
+        let subtree = subtree::get(session)?;
+        // Killed processes only stop counting once they've been reaped
+        for _ in 0..ATTEMPTS {
+            if !kill_session_processes(&merged, &subtree)? {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    stop_session(session)?;
+    delete_session(session)
+}
+
+/// Kills every process running in a session, returning whether there were
+/// any.
+fn kill_session_processes(merged: &Path, subtree: &Path) -> Result<bool, Error> {
+    let pids = session_processes(merged, subtree)?;
+    for &pid in &pids {
+        match kill_process(pid, Signal::Kill) {
+            Err(Errno::SRCH) => {}
+            r => r.map_io_err_lazy(|| format!("Failed to kill process {pid:?}"))?,
+        }
+    }
+    Ok(!pids.is_empty())
+}
+
+struct Watchdog<'a> {
+    max_write: Option<u64>,
+    checkpoint_every: Option<Duration>,
+    session: &'a OsStr,
+    diff: &'a Path,
+    merged: &'a Path,
+    subtree: &'a Path,
+}
+
+impl Watchdog<'_> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    fn supervise(self, mut child: Child) -> Result<ExitStatus, Error> {
+        let Self {
+            max_write,
+            checkpoint_every,
+            session,
+            diff,
+            merged,
+            subtree,
+        } = self;
+        if max_write.is_none() && checkpoint_every.is_none() {
+            return child.wait().map_io_err("Failed to wait for child");
+        }
+
+        let baseline = if max_write.is_some() {
+            usage(diff)?
+        } else {
+            Usage::default()
+        };
+        let mut next_checkpoint = checkpoint_every.map(|every| Instant::now() + every);
+        loop {
+            if let Some(status) = child.try_wait().map_io_err("Failed to wait for child")? {
+                return Ok(status);
+            }
+
+            if let Some(max_write) = max_write {
+                let now = usage(diff)?;
+                let written = now.bytes.saturating_sub(baseline.bytes);
+                if written > max_write {
+                    kill_session_processes(merged, subtree)?;
+                    child.wait().map_io_err("Failed to wait for child")?;
+
+                    return Err(Error::WriteLimitExceeded).attach_printable(
+                        Message::WriteLimitExceeded.fill(&[
+                            ("written", &written),
+                            ("limit", &max_write),
+                            ("inodes", &now.inodes.saturating_sub(baseline.inodes)),
+                        ]),
+                    );
+                }
+            }
+            if let Some((due, every)) = next_checkpoint.zip(checkpoint_every)
+                && Instant::now() >= due
+            {
+                snapshot::create(session, None)?;
+                // Counted from the end of the snapshot so slow ones don't pile up
+                next_checkpoint = Some(Instant::now() + every);
+            }
+
+            thread::sleep(Self::POLL_INTERVAL);
+        }
+    }
+}
+
+/// Overlay writes failing with ENOSPC mid-run leave the session in a confusing
+/// partial state, so we'd rather not start at all.
+///
+/// Running out of inodes fails writes the same way, so at least one inode per
+/// [`BYTES_PER_INODE`] of the required space must be free too on file systems
+/// with a fixed inode table.
+fn check_free_space(session_dir: &Path, min_free_space: u64) -> Result<(), Error> {
+    if min_free_space == 0 {
+        return Ok(());
+    }
+    let Some(existing) = session_dir.ancestors().find(|dir| dir.exists()) else {
+        return Ok(());
+    };
+
+    let stats = statvfs(existing)
+        .map_io_err_lazy(|| format!("Failed to stat file system of {}", existing.display()))?;
+    let available = stats.f_bavail.saturating_mul(stats.f_frsize);
+    if available < min_free_space {
+        return Err(Error::InsufficientSpace).attach_printable(Message::InsufficientSpace.fill(&[
+            ("available", &available),
+            ("dir", &existing.display()),
+            ("required", &min_free_space),
+        ]));
+    }
+
+    // File systems that allocate inodes on demand report having none
+    let required = min_free_space.div_ceil(BYTES_PER_INODE);
+    if stats.f_files > 0 && stats.f_favail < required {
+        return Err(Error::InsufficientSpace).attach_printable(Message::InsufficientInodes.fill(
+            &[
+                ("available", &stats.f_favail),
+                ("dir", &existing.display()),
+                ("required", &required),
+            ],
+        ));
+    }
+    Ok(())
+}
+
+/// A private mount namespace rooted at a session's merged directory.
+///
+/// Unlike a chroot, the rest of the host's file system isn't reachable from
+/// inside at all, and mounts made for a single run disappear along with the
+/// last process using them. Everything is prepared up front since only
+/// syscalls can be made between fork and exec.
+///
+/// Sessions of a subtree keep the host's root instead, made read-only, with
+/// the merged directory mounted over the subtree.
+pub struct Namespace {
+    root: CString,
+    /// Where the merged directory is mounted along with the real system
+    /// directories that stay writable, for sessions of a subtree.
+    subtree: Option<(CString, Vec<CString>)>,
+    /// Sources and targets, and whether they're read-only, in mount order.
+    binds: Vec<(CString, CString, bool)>,
+    current_dir: CString,
+    private_pids: bool,
+    network: Network,
+    umask: Option<Mode>,
+}
+
+impl Namespace {
+    /// Prepares a namespace in which `binds` are mounted from the real file
+    /// system, optionally along with PID and network namespaces of its own and
+    /// a different `umask`.
+    /// Sessions of a subtree can write to the system directories that aren't
+    /// `unbound`.
+    ///
+    /// On WSL, Windows drives that aren't passed through are bind mounted
+    /// read-only since they can't be part of the overlay.
+    pub fn new(
+        merged: &Path,
+        subtree: &Path,
+        unbound: &[SystemDir],
+        binds: &[Bind],
+        private_pids: bool,
+        network: Network,
+        umask: Option<u32>,
+    ) -> Result<Self, Error> {
+        if network == Network::Loopback
+            && !capabilities(None)
+                .map_io_err("Failed to retrieve capabilities")?
+                .effective
+                .contains(CapabilityFlags::NET_ADMIN)
+        {
+            let path = exe_path();
+            let path = path.as_deref().map(Path::to_string_lossy);
+            let path = path.as_deref().unwrap_or("<path-to-forkfs>");
+            return Err(Error::SetupRequired).attach_printable(format!(
+                "Setting up a loopback interface requires the cap_net_admin capability, which \
+                 this forkfs binary wasn't granted. Grant it by running `sudo {path} setup` again."
+            ));
+        }
+
+        let mut sources = Vec::with_capacity(binds.len());
+        for Bind {
+            source,
+            target,
+            read_only,
+        } in binds
+        {
+            // Symlinks must be resolved on the host as they'd otherwise escape the session
+            let resolved = fs::canonicalize(source)
+                .map_io_err_lazy(|| format!("Failed to resolve path {}", source.display()))?;
+            let target = if source == target {
+                resolved.clone()
+            } else if target.is_absolute() {
+                target.clone()
+            } else {
+                return Err(Error::InvalidArgument).attach_printable(format!(
+                    "Bind target {} must be an absolute path",
+                    target.display()
+                ));
+            };
+            sources.push((resolved, target, *read_only));
+        }
+        let drives = wsl::windows_drives()?
+            .into_iter()
+            .filter(|drive| {
+                !sources
+                    .iter()
+                    .any(|(_, target, _)| drive.starts_with(target))
+            })
+            .map(|drive| (drive.clone(), drive, true))
+            .collect::<Vec<_>>();
+        // Passthroughs may be inside a drive
+        sources.splice(0..0, drives);
+
+        let mut binds = Vec::with_capacity(sources.len());
+        for (source, target, read_only) in sources {
+            let target = match target.strip_prefix(subtree) {
+                Ok(relative) => merged.join(relative),
+                // Outside of a subtree, everything is already read-only, so
+                // directories are only bound onto themselves to be made
+                // writable
+                Err(_) if read_only && source == target => continue,
+                Err(_) if source == target => target,
+                // Nothing may be created on the real file system though
+                Err(_) if !target.exists() => {
+                    return Err(Error::InvalidArgument).attach_printable(format!(
+                        "Bind target {} outside of the session's root must already exist",
+                        target.display()
+                    ));
+                }
+                Err(_) => target,
+            };
+            if source.is_dir() {
+                fs::create_dir_all(&target)
+            } else {
+                // Files, such as sockets, can only be mounted onto files
+                target
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|()| {
+                        File::options()
+                            .create(true)
+                            .append(true)
+                            .open(&target)
+                            .map(drop)
+                    })
+            }
+            .map_io_err_lazy(|| format!("Failed to create mount point {}", target.display()))?;
+            binds.push((to_c_string(&source)?, to_c_string(&target)?, read_only));
+        }
+        let subtree = if subtree == Path::new("/") {
+            None
+        } else {
+            // Sessions of a subtree may still write to the real system
+            // directories, as other sessions bind mount them
+            let mut writable = Vec::new();
+            for dir in SystemDir::ALL {
+                if unbound.contains(&dir) {
+                    continue;
+                }
+                let dir = Path::new("/").join(dir.name());
+                if dir.exists() {
+                    writable.push(to_c_string(&dir)?);
+                }
+            }
+            Some((to_c_string(subtree)?, writable))
+        };
+
+        Ok(Self {
+            root: to_c_string(merged)?,
+            subtree,
+            binds,
+            current_dir: to_c_string(
+                &current_dir().map_io_err("Failed to get current directory")?,
+            )?,
+            private_pids,
+            network,
+            umask: umask.map(Mode::from_raw_mode),
+        })
+    }
+
+    /// Moves the calling process into the namespace, in the same current
+    /// directory as before.
+    ///
+    /// With private PIDs, only children join the new PID namespace, so the
+    /// calling process stays behind to wait for them and returns in a
+    /// grandchild instead.
+    pub fn enter(&self) -> io::Result<()> {
+        if let Some(mask) = self.umask {
+            umask(mask);
+        }
+        let mut flags = UnshareFlags::NEWNS;
+        if self.private_pids {
+            flags |= UnshareFlags::NEWPID;
+        }
+        if self.network != Network::Host {
+            flags |= UnshareFlags::NEWNET;
+        }
+        unshare(flags)?;
+        if self.network == Network::Loopback {
+            bring_up_loopback()?;
+        }
+        // Keep receiving the host's mounts without leaking ours back to it
+        change_mount(
+            c"/",
+            MountPropagationFlags::SLAVE | MountPropagationFlags::REC,
+        )?;
+        if let Some((_, writable)) = &self.subtree {
+            set_read_only(c"/", true)?;
+            for dir in writable {
+                // Only whole mounts can be made writable again
+                recursive_bind_mount(dir.as_c_str(), dir.as_c_str())?;
+                set_read_only(dir, false)?;
+            }
+            set_read_only(&self.root, false)?;
+        }
+        for (source, target, read_only) in &self.binds {
+            recursive_bind_mount(source.as_c_str(), target.as_c_str())?;
+            if *read_only {
+                // Binds can only be made read-only after the fact
+                mount_remount(
+                    target.as_c_str(),
+                    MountFlags::BIND | MountFlags::RDONLY,
+                    c"",
+                )?;
+            } else if self.subtree.is_some() {
+                set_read_only(target, false)?;
+            }
+        }
+        if let Some((subtree, _)) = &self.subtree {
+            recursive_bind_mount(self.root.as_c_str(), subtree.as_c_str())?;
+            chdir(self.current_dir.as_c_str())?;
+            return self.enter_pid_namespace();
+        }
+        chdir(self.root.as_c_str())?;
+        // Stacks the old root on top of the new one so that it can be detached
+        // without needing a directory to put it in
+        pivot_root(c".", c".")?;
+        unmount(c".", UnmountFlags::DETACH)?;
+        chdir(self.current_dir.as_c_str())?;
+        self.enter_pid_namespace()
+    }
+
+    fn enter_pid_namespace(&self) -> io::Result<()> {
+        if !self.private_pids {
+            return Ok(());
+        }
+
+        // SAFETY: we're either between fork and exec or about to exec.
+        unsafe { fork_and_wait()? };
+        // The first process in the namespace is its init, which must reap the
+        // orphans and can't be killed by signals it doesn't handle, so it is
+        // kept apart from the command
+        set_parent_process_death_signal(Some(Signal::Kill))?;
+        mount(
+            c"proc",
+            c"/proc",
+            c"proc",
+            MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
+            c"",
+        )?;
+        // SAFETY: as above.
+        unsafe { fork_and_wait() }
+    }
+}
+
+/// Makes a mount and every mount below it read-only or writable.
+fn set_read_only(path: &CStr, read_only: bool) -> io::Result<()> {
+    let mut attr = libc::mount_attr {
+        attr_set: 0,
+        attr_clr: 0,
+        propagation: 0,
+        userns_fd: 0,
+    };
+    if read_only {
+        attr.attr_set = libc::MOUNT_ATTR_RDONLY;
+    } else {
+        attr.attr_clr = libc::MOUNT_ATTR_RDONLY;
+    }
+    // SAFETY: the path and attributes are valid for the duration of the call.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mount_setattr,
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::AT_RECURSIVE,
+            &raw const attr,
+            mem::size_of::<libc::mount_attr>(),
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Brings up the `lo` interface every new network namespace starts out with,
+/// equivalent to `ip link set lo up`.
+fn bring_up_loopback() -> io::Result<()> {
+    let socket = socket(AddressFamily::INET, SocketType::DGRAM, None)?;
+    // SAFETY: ifreq is plain old data.
+    let mut request = unsafe { mem::zeroed::<libc::ifreq>() };
+    for (dest, &c) in request.ifr_name.iter_mut().zip(b"lo") {
+        *dest = c.cast_signed();
+    }
+    // SAFETY: the request is a valid ifreq that outlives the calls.
+    unsafe {
+        if libc::ioctl(
+            socket.as_raw_fd(),
+            libc::SIOCGIFFLAGS as _,
+            &raw mut request,
+        ) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        let up = libc::IFF_UP as libc::c_short;
+        request.ifr_ifru.ifru_flags |= up;
+        if libc::ioctl(
+            socket.as_raw_fd(),
+            libc::SIOCSIFFLAGS as _,
+            &raw const request,
+        ) == -1
+        {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+static FORWARD_TO: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    // SAFETY: kill is async-signal-safe.
+    unsafe { libc::kill(FORWARD_TO.load(Ordering::Relaxed), signal) };
+}
+
+/// Passes requests to stop on to a child so that it gets to exit first. Only
+/// makes async-signal-safe calls.
+fn forward_signals(child: libc::pid_t) {
+    FORWARD_TO.store(child, Ordering::Relaxed);
+    let forward = forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only makes async-signal-safe calls.
+    unsafe {
+        libc::signal(libc::SIGHUP, forward);
+        libc::signal(libc::SIGTERM, forward);
+        // The terminal already sends these to the whole process group
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+        libc::signal(libc::SIGQUIT, libc::SIG_IGN);
+    }
+}
+
+/// Restores the default handling of the signals [`forward_signals`] took over
+/// once the child they were passed on to is gone.
+pub fn stop_forwarding_signals() {
+    // SAFETY: restoring the default dispositions is always sound.
+    unsafe {
+        for signal in [libc::SIGHUP, libc::SIGTERM, libc::SIGINT, libc::SIGQUIT] {
+            libc::signal(signal, libc::SIG_DFL);
+        }
+    }
+    FORWARD_TO.store(0, Ordering::Relaxed);
+}
+
+/// Leaves the rest of the run to a child process and stays behind to wait for
+/// it, passing on requests to stop along with its exit status. Only returns in
+/// the child.
+///
+/// # Safety
+///
+/// Only async-signal-safe calls may follow in the child, so the process must
+/// be single-threaded or between fork and exec.
+unsafe fn fork_and_wait() -> io::Result<()> {
+    // SAFETY: forking is fine as per the caller's guarantees.
+    let child = unsafe { libc::fork() };
+    match child {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => return Ok(()),
+        _ => {}
+    }
+
+    forward_signals(child);
+    // Holding on to the pipe std uses to report exec failures would make
+    // spawning wait for the command to exit
+    // SAFETY: only async-signal-safe syscalls are made from here on.
+    unsafe { libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0) };
+
+    let mut status = 0;
+    loop {
+        // Waiting for any child also reaps the orphans when this is init
+        // SAFETY: status is a valid pointer.
+        match unsafe { libc::waitpid(-1, &raw mut status, 0) } {
+            pid if pid == child => break,
+            -1 if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted => {
+                status = 1 << 8;
+                break;
+            }
+            _ => {}
+        }
+    }
+    let code = if libc::WIFEXITED(status) {
+        libc::WEXITSTATUS(status)
+    } else {
+        128 + libc::WTERMSIG(status)
+    };
+    // SAFETY: exiting without running destructors is exactly what we want.
+    unsafe { libc::_exit(code) }
+}
+
+fn to_c_string(path: &Path) -> Result<CString, Error> {
+    CString::new(path.as_os_str().as_bytes())
+        .attach_printable("Invalid path bytes")
+        .change_context(Error::InvalidArgument)
+}
+
+fn exec_command(args: &[impl AsRef<OsStr>], prev_uid: Uid) -> Result<ExitStatus, Error> {
+    let mut command = Command::new(args[0].as_ref());
+    if let Some(uid) = unprivileged_uid(prev_uid) {
+        command.uid(uid.as_raw());
     }
 
     Err(command.args(&args[1..]).exec()).map_io_err_lazy(|| {
@@ -61,7 +1024,84 @@ fn run_command(args: &[impl AsRef<OsStr>], prev_uid: Uid) -> Result<(), Error> {
     })
 }
 
-fn validate_permissions(uid: Uid) -> Result<(), Error> {
+/// Stops holding on to the child's stdin and stdout so that closing either end
+/// of a pipeline is seen by the other side as soon as the child is done with
+/// it, rather than when forkfs exits.
+fn drop_stdio() -> Result<(), Error> {
+    let null = File::options()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_io_err("Failed to open /dev/null")?;
+    dup2_stdin(&null).map_io_err("Failed to redirect stdin")?;
+    dup2_stdout(&null).map_io_err("Failed to redirect stdout")
+}
+
+pub fn spawn_command(
+    args: &[impl AsRef<OsStr>],
+    prev_uid: Uid,
+    namespace: Namespace,
+    stdout: Option<OwnedFd>,
+) -> Result<Child, Error> {
+    let mut command = sandboxed_command(args, prev_uid, namespace);
+    if let Some(stdout) = stdout {
+        command.stdout(stdout);
+    }
+    command.spawn().map_io_err_lazy(|| {
+        format!(
+            "Failed to spawn {:?}",
+            args.iter().map(AsRef::as_ref).collect::<Vec<_>>()
+        )
+    })
+}
+
+/// Prepares a command that runs inside a session's namespace as the pre-sudo
+/// user.
+pub fn sandboxed_command(
+    args: &[impl AsRef<OsStr>],
+    prev_uid: Uid,
+    namespace: Namespace,
+) -> Command {
+    let mut command = Command::new(args[0].as_ref());
+    command.args(&args[1..]);
+    confine(&mut command, prev_uid, namespace);
+    command
+}
+
+/// Makes a command run inside a session's namespace as the pre-sudo user,
+/// after any setup the command already does before exec-ing.
+pub fn confine(command: &mut Command, prev_uid: Uid, namespace: Namespace) {
+    let uid = unprivileged_uid(prev_uid);
+    // SAFETY: only async-signal-safe syscalls are made between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            namespace.enter()?;
+            // The uid must be changed after entering the namespace which is why
+            // we can't use Command::uid.
+            if let Some(uid) = uid {
+                set_thread_uid(uid)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Downgrade privilege level to pre-sudo if possible
+pub fn unprivileged_uid(prev_uid: Uid) -> Option<Uid> {
+    if !prev_uid.is_root() {
+        Some(prev_uid)
+    } else if let Some(uid) = env::var_os("SUDO_UID").as_ref().and_then(|s| s.to_str())
+        && let Ok(uid) = uid.parse()
+        && uid != u32::MAX
+    {
+        // SAFETY: -1 is the only invalid uid.
+        Some(unsafe { Uid::from_raw(uid) })
+    } else {
+        None
+    }
+}
+
+pub fn validate_permissions(uid: Uid) -> Result<(), Error> {
     if uid.is_root() {
         return Ok(());
     }
@@ -82,46 +1122,19 @@ fn validate_permissions(uid: Uid) -> Result<(), Error> {
         if effective_capabilities.contains(
             CapabilityFlags::CHOWN | CapabilityFlags::SYS_CHROOT | CapabilityFlags::SYS_ADMIN,
         ) {
+            remember_capabilities();
             return Ok(());
         }
     }
 
-    let path = readlinkat(CWD, "/proc/self/exe", Vec::new());
-    let path = path.as_deref().map(CStr::to_string_lossy);
-    let path = path.as_deref().ok().unwrap_or("<path-to-forkfs>");
-
-    Err(Error::SetupRequired).attach_printable(format!(
-        "Welcome to ForkFS!
-
-Under the hood, ForkFS is implemented as a wrapper around OverlayFS. As a
-consequence, elevated privileges are required and can be granted in one of
-three ways (ordered by recommendation):
-
-- $ sudo setcap \
-         cap_chown,cap_sys_chroot,cap_sys_admin,cap_dac_override,cap_fowner,cap_setpcap,cap_mknod,\
-         cap_lease,cap_setfcap+ep {path}
-
-  This grants `forkfs` precisely the capabilities it needs.
-
-  cap_dac_override onwards are capabilities that are required for OverlayFS to
-  be able to perform those actions.
-
-- $ sudo chown root {path}; sudo chmod u+s {path}
-
-  This transfers ownership of the `forkfs` binary to root and specifies that
-  the binary should be executed as its owner (i.e. root).
-
-- $ sudo -E forkfs ...
-
-  This simply invokes `forkfs` as root. This option is problematic because
-  sudo alters the environment, causing PATH lookups to fail and changing
-  your home directory.
-
-  If you do go down this route, be consistent with your usage of `-E`. Bare
-  `sudo` vs `sudo -E` will change the forkfs environment, meaning sessions
-  that appear in `sudo` will not appear in `sudo -E` and vice versa.
+    let path = exe_path();
+    let path = path.as_deref().map(Path::to_string_lossy);
+    let path = path.as_deref().unwrap_or("<path-to-forkfs>");
 
-PS: if you've already seen this message, then you probably upgraded to a new
-version of ForkFS and will therefore need to rerun this setup.",
-    ))
+    let message = if had_capabilities() {
+        Message::CapabilitiesLost
+    } else {
+        Message::Setup
+    };
+    Err(Error::SetupRequired).attach_printable(message.fill(&[("path", &path)]))
 }
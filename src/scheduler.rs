@@ -0,0 +1,141 @@
+//! Runs forkfs' own heavy IO, such as deleting or exporting sessions, without
+//! starving interactive work.
+//!
+//! Such jobs run at the IO priority set by the config file's `[maintenance]`
+//! table, which the kernel's IO schedulers use to serve other processes first,
+//! and independent jobs are spread over a bounded number of threads.
+
+use std::{
+    iter,
+    num::NonZeroUsize,
+    sync::{
+        Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+};
+
+use error_stack::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{Config, Error, fault};
+
+/// How much of the disk's attention maintenance jobs get.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoPriority {
+    /// The same as any other process.
+    Normal,
+    /// The lowest priority that still gets a share of the disk when it is
+    /// busy.
+    #[default]
+    Low,
+    /// Only served when no other process needs the disk.
+    Idle,
+}
+
+/// Changes the calling thread's IO priority, putting the previous one back on
+/// drop.
+struct Prioritized {
+    previous: Option<i32>,
+}
+
+impl Prioritized {
+    const WHO_PROCESS: libc::c_long = 1;
+    const CLASS_SHIFT: i32 = 13;
+    const CLASS_BEST_EFFORT: i32 = 2;
+    const CLASS_IDLE: i32 = 3;
+
+    fn new(priority: IoPriority) -> Self {
+        let value = match priority {
+            IoPriority::Normal => return Self { previous: None },
+            IoPriority::Low => (Self::CLASS_BEST_EFFORT << Self::CLASS_SHIFT) | 7,
+            IoPriority::Idle => Self::CLASS_IDLE << Self::CLASS_SHIFT,
+        };
+        // SAFETY: ioprio_get only reads its integer arguments. A `which` of 0
+        // refers to the calling thread.
+        let previous = unsafe { libc::syscall(libc::SYS_ioprio_get, Self::WHO_PROCESS, 0) };
+        // Lowering the priority is only a courtesy, so kernels or sandboxes
+        // that refuse it shouldn't stop the job from running
+        let Ok(previous) = i32::try_from(previous) else {
+            return Self { previous: None };
+        };
+        if previous < 0 || Self::set(value) < 0 {
+            return Self { previous: None };
+        }
+        Self {
+            previous: Some(previous),
+        }
+    }
+
+    fn set(value: i32) -> libc::c_long {
+        // SAFETY: as above, ioprio_set only takes integers.
+        unsafe { libc::syscall(libc::SYS_ioprio_set, Self::WHO_PROCESS, 0, value) }
+    }
+}
+
+impl Drop for Prioritized {
+    fn drop(&mut self) {
+        if let Some(previous) = self.previous {
+            Self::set(previous);
+        }
+    }
+}
+
+/// Runs `job` on the calling thread at the maintenance IO priority.
+pub fn background<T>(job: impl FnOnce() -> T) -> T {
+    let _priority = Prioritized::new(Config::load().unwrap_or_default().maintenance.io_priority);
+    job()
+}
+
+/// Runs independent jobs at the maintenance IO priority, as many at a time as
+/// the config file allows, returning their results in order.
+///
+/// No new jobs are started once one fails, and the first failure in job order
+/// is returned.
+pub fn run_all<T: Send, F: FnOnce() -> Result<T, Error> + Send>(
+    jobs: Vec<F>,
+) -> Result<Vec<T>, Error> {
+    let maintenance = Config::load().unwrap_or_default().maintenance;
+    let threads = maintenance
+        .jobs
+        .or_else(|| thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get)
+        .min(jobs.len());
+    if threads <= 1 {
+        let _priority = Prioritized::new(maintenance.io_priority);
+        return jobs.into_iter().map(|job| job()).collect();
+    }
+
+    let mut results = iter::repeat_with(|| None)
+        .take(jobs.len())
+        .collect::<Vec<_>>();
+    let queue = Mutex::new(jobs.into_iter().zip(results.iter_mut()));
+    let failed = AtomicBool::new(false);
+    // Threads don't inherit injected faults
+    let fault = fault::injected();
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                fault::inject_fault(fault);
+                let _priority = Prioritized::new(maintenance.io_priority);
+                while !failed.load(Ordering::Relaxed) {
+                    let Some((job, result)) =
+                        queue.lock().unwrap_or_else(PoisonError::into_inner).next()
+                    else {
+                        break;
+                    };
+                    let outcome = job();
+                    if outcome.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    *result = Some(outcome);
+                }
+            });
+        }
+    });
+    drop(queue);
+
+    // Jobs are taken in order, so those skipped after a failure come after it
+    results.into_iter().map_while(|result| result).collect()
+}
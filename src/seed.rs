@@ -0,0 +1,85 @@
+//! Preparing a session with copies of real files before it is first used.
+
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    os::unix::fs::{MetadataExt, lchown},
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::process::getuid;
+
+use crate::{
+    Backend, Error, IoErr, get_sessions_dir,
+    run::validate_permissions,
+    sessions::{mark_used, maybe_create_session, resolve_alias},
+    subtree,
+};
+
+/// Copies real files and directories into a session, recursively, so that
+/// they show up as modified and later changes to the real files no longer
+/// show through. The session is activated if necessary.
+///
+/// Relative paths are resolved against the current directory. Directories
+/// that are passed through to the real file system, like `/tmp`, can't be
+/// seeded.
+pub fn seed(session: impl AsRef<OsStr>, paths: &[PathBuf]) -> Result<(), Error> {
+    let uid = getuid();
+    validate_permissions(uid)?;
+
+    let mut session_dir = get_sessions_dir()?;
+    session_dir.push(resolve_alias(session.as_ref())?);
+    maybe_create_session(&mut session_dir, None, Backend::Auto)?;
+    mark_used(&mut session_dir)?;
+
+    let merged = session_dir.join("merged");
+    let device = fs::metadata(&merged)
+        .map_io_err_lazy(|| format!("Failed to stat {}", merged.display()))?
+        .dev();
+    let subtree = subtree::get(&mut session_dir)?;
+    let cwd = env::current_dir().map_io_err("Failed to get current directory")?;
+    for path in paths {
+        let path = cwd.join(path);
+        let mut file = merged.clone();
+        file.push(subtree::relative(&subtree, &path)?);
+
+        let metadata = fs::symlink_metadata(&file)
+            .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+        if metadata.dev() != device {
+            return Err(Error::InvalidArgument).attach_printable(format!(
+                "{} is passed through to the real file system and can't be seeded.",
+                path.display()
+            ));
+        }
+        copy_up(&file, &metadata, device)?;
+    }
+    Ok(())
+}
+
+/// Asks overlayfs to copy a file into the upper directory by changing its
+/// owner to the one it already has.
+fn copy_up(path: &Path, metadata: &fs::Metadata, device: u64) -> Result<(), Error> {
+    lchown(path, Some(metadata.uid()), Some(metadata.gid()))
+        .map_io_err_lazy(|| format!("Failed to copy {}", path.display()))?;
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(path)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let entry =
+            entry.map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?;
+        let path = entry.path();
+        let metadata = entry
+            .metadata()
+            .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+        // Mount points below the directory aren't part of the session
+        if metadata.dev() == device {
+            copy_up(&path, &metadata, device)?;
+        }
+    }
+    Ok(())
+}
@@ -1,21 +1,44 @@
 use std::{
-    ffi::CString,
-    fmt::Write as FmtWrite,
+    borrow::Cow,
+    ffi::{CString, OsStr, OsString},
     fs,
-    fs::DirEntry,
-    io,
+    fs::{DirEntry, File},
     io::{ErrorKind, Write},
-    os::unix::fs::DirEntryExt2,
-    path::{Path, PathBuf},
+    os::{
+        fd::OwnedFd,
+        unix::{
+            ffi::OsStrExt,
+            fs::{DirEntryExt2, MetadataExt},
+        },
+    },
+    path::{Component, Path, PathBuf},
 };
 
 use error_stack::{Result, ResultExt};
-use rustix::fs::{
-    AtFlags, CWD, MountFlags, MountPropagationFlags, StatxFlags, UnmountFlags, change_mount, mount,
-    recursive_bind_mount, statx, unmount,
+use rustix::{
+    fs::{
+        AtFlags, CWD, FileType, MountPropagationFlags, OFlags, RenameFlags, StatxFlags,
+        UnmountFlags, change_mount, recursive_bind_mount, renameat_with, statx, unlinkat, unmount,
+    },
+    io::Errno,
+    process::Pid,
 };
 
-use crate::{Error, IoErr, get_sessions_dir, path_undo::TmpPath};
+#[cfg(feature = "cli")]
+use crate::Style;
+use crate::{
+    Backend, EncryptionKey, Error, FaultPoint, IoErr, Usage, backend, clock, diff, encryption,
+    fault, get_sessions_dir, lower, meta,
+    mountinfo::{self, Mount},
+    output::v1,
+    overlay_options,
+    path_undo::TmpPath,
+    publish, scheduler, subtree,
+    system_dirs::{self, SystemDir},
+    usage, volatile, walk, wsl,
+};
+
+const LAST_USED: &str = "last-used";
 
 #[derive(Copy, Clone)]
 pub enum Op<'a, S> {
@@ -23,90 +46,423 @@ pub enum Op<'a, S> {
     List(&'a [S]),
 }
 
-pub fn list() -> Result<(), Error> {
-    let mut stdout = io::stdout().lock();
-    let mut is_first = true;
-    iter_all_sessions(|entry, session| {
-        let name = entry.file_name_ref().to_string_lossy();
-        let session_active = is_active_session(session, true)?;
+/// A session as found by [`iter`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub name: OsString,
+    /// The directory holding the session's overlay.
+    pub path: PathBuf,
+    /// Whether the session's overlay is currently mounted.
+    pub active: bool,
+    /// The space taken up by the session's changes, in bytes.
+    pub size: u64,
+    /// When the session was created, in seconds since the Unix epoch, if the
+    /// file system keeps track.
+    pub created: Option<u64>,
+    /// When the session's directory was last modified, in seconds since the
+    /// Unix epoch.
+    pub modified: u64,
+}
+
+/// Iterates over the sessions sorted by name, only inspecting each one once
+/// it is reached.
+pub fn iter() -> Result<impl Iterator<Item = Result<Entry, Error>>, Error> {
+    let sessions_dir = get_sessions_dir()?;
+    Ok(session_names()?.into_iter().map(move |name| {
+        let mut path = sessions_dir.join(&name);
+        let active = is_active_session(&mut path, true)?;
+        let stat = statx(
+            CWD,
+            &path,
+            AtFlags::empty(),
+            StatxFlags::BTIME | StatxFlags::MTIME,
+        )
+        .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+        Ok(Entry {
+            size: size(&path)?,
+            created: (stat.stx_mask & StatxFlags::BTIME.bits() != 0)
+                .then(|| stat.stx_btime.tv_sec.cast_unsigned()),
+            modified: stat.stx_mtime.tv_sec.cast_unsigned(),
+            name,
+            path,
+            active,
+        })
+    }))
+}
+
+/// Returns the sessions sorted by name along with the index that can be used
+/// to refer to them.
+pub fn listing() -> Result<v1::SessionList, Error> {
+    let sessions = iter()?
+        .enumerate()
+        .map(|(i, entry)| {
+            let Entry {
+                name,
+                active,
+                size,
+                created,
+                ..
+            } = entry?;
+            Ok(v1::Session {
+                name: name.to_string_lossy().into_owned(),
+                index: i + 1,
+                active,
+                created,
+                size,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(v1::SessionList::new(sessions))
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SessionStatus {
+    /// Whether the session's overlay is currently mounted.
+    pub active: bool,
+    /// The ID of the session's overlay mount while it is active, as listed in
+    /// `/proc/self/mountinfo`.
+    pub mount_id: Option<u64>,
+    /// The file systems mounted inside the active session, such as `/proc`
+    /// and passthroughs.
+    pub mounts: Vec<Mount>,
+    /// The space taken up by the session's changes, in bytes.
+    pub size: u64,
+    /// The number of inodes taken up by the session's changes, which file
+    /// systems with a fixed inode table can run out of before space.
+    pub inodes: u64,
+    /// The number of paths the session added, modified, or deleted, counting
+    /// deleted directories once.
+    pub changes: usize,
+}
+
+/// Inspects the current state of a session.
+pub fn status(session: impl AsRef<OsStr>) -> Result<SessionStatus, Error> {
+    let session = session.as_ref();
+    let changes = diff::changes(session)?.len();
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+
+    let active = is_active_session(&mut dir, true)?;
+    let (mount_id, mounts) = if active {
+        mountinfo::mounts_on(&dir.join("merged"))?.unzip()
+    } else {
+        (None, None)
+    };
+    let Usage { bytes, inodes } = usage_of(&dir)?;
+    Ok(SessionStatus {
+        active,
+        mount_id,
+        mounts: mounts.unwrap_or_default(),
+        size: bytes,
+        inodes,
+        changes,
+    })
+}
+
+/// Lists every mount belonging to an active session, its overlay first
+/// followed by what is mounted inside it, or nothing if the session is
+/// inactive.
+pub fn mounts(session: impl AsRef<OsStr>) -> Result<Vec<Mount>, Error> {
+    let session = session.as_ref();
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    if !dir.exists() {
+        return Err(Error::SessionNotFound)
+            .attach_printable(format!("Session {} does not exist", session.display()));
+    }
+    if !is_active_session(&mut dir, true)? {
+        return Ok(Vec::new());
+    }
+    mountinfo::mount_tree(&dir.join("merged"))
+}
+
+fn size(session: &Path) -> Result<u64, Error> {
+    usage_of(session).map(|usage| usage.bytes)
+}
+
+fn usage_of(session: &Path) -> Result<Usage, Error> {
+    let (diff, work) = (usage(&session.join("diff"))?, usage(&session.join("work"))?);
+    Ok(Usage {
+        bytes: diff.bytes + work.bytes,
+        inodes: diff.inodes + work.inodes,
+    })
+}
+
+/// Prints sessions sorted by name along with the index that can be used to
+/// refer to them.
+#[cfg(feature = "cli")]
+pub fn list(style: Style) -> Result<(), Error> {
+    let mut stdout = std::io::stdout().lock();
+    let colors = style.colors(&stdout);
+    for (i, session) in listing()?.sessions.iter().enumerate() {
+        let name = style.text(&session.name);
+        let index = session.index;
 
         let mut print = || {
-            if !is_first {
+            if i > 0 {
                 write!(stdout, ", ")?;
             }
-            if session_active {
-                write!(stdout, "[{name}]")
+            if session.active {
+                write!(
+                    stdout,
+                    "{index}:{}",
+                    Style::active(colors, format_args!("[{name}]"))
+                )
             } else {
-                write!(stdout, "{name}")
+                write!(stdout, "{index}:{name}")
             }
         };
 
         print().map_io_err("Failed to write to stdout")?;
-        is_first = false;
-
-        Ok(())
-    })
+    }
+    Ok(())
 }
 
-pub fn stop<S: AsRef<str>>(sessions: Op<S>) -> Result<(), Error> {
+pub fn stop<S: AsRef<OsStr>>(sessions: Op<S>) -> Result<(), Error> {
     iter_op(sessions, stop_session)
 }
 
-pub fn delete<S: AsRef<str>>(sessions: Op<S>) -> Result<(), Error> {
+/// Deletes sessions, optionally overwriting their contents first.
+///
+/// Shredding zeroes out every file before it is unlinked so that the data
+/// can't be recovered from the freed blocks. Note that this offers no
+/// guarantees on copy-on-write file systems or SSDs which remap writes.
+///
+/// Sessions are deleted in parallel at the maintenance IO priority from the
+/// config file.
+pub fn delete<S: AsRef<OsStr>>(sessions: Op<S>, shred: bool) -> Result<(), Error> {
+    let mut jobs = Vec::new();
     iter_op(sessions, |session| {
-        stop_session(session)?;
-        delete_session(session)
+        let mut session = session.clone();
+        jobs.push(move || {
+            stop_session(&mut session)?;
+            if shred {
+                shred_dir(&session)?;
+            }
+            delete_session(&session)
+        });
+        Ok(())
+    })?;
+    scheduler::run_all(jobs).map(drop)
+}
+
+/// Renames an inactive session.
+///
+/// Active sessions are refused since their overlay mount refers to the
+/// session's directories by their old path.
+pub fn rename(session: impl AsRef<OsStr>, name: impl AsRef<OsStr>) -> Result<(), Error> {
+    let (session, name) = (session.as_ref(), name.as_ref());
+    validate_name(name)?;
+    iter_op(Op::List(&[session]), |dir| {
+        if !dir.exists() {
+            return Err(Error::SessionNotFound)
+                .attach_printable(format!("Session {} does not exist", session.display()));
+        }
+        if is_active_session(dir, true)? {
+            return Err(Error::InvalidArgument).attach_printable(format!(
+                "Session {} is active, stop it before renaming it.",
+                session.display()
+            ));
+        }
+        let to = dir.with_file_name(name);
+        match renameat_with(CWD, &*dir, CWD, &to, RenameFlags::NOREPLACE) {
+            Err(Errno::EXIST) => Err(Error::Conflict)
+                .attach_printable(format!("Session {} already exists", name.display())),
+            r => r.map_io_err_lazy(|| {
+                format!(
+                    "Failed to rename session {} to {}",
+                    session.display(),
+                    name.display()
+                )
+            }),
+        }
     })
 }
 
-pub fn maybe_create_session(dir: &mut PathBuf) -> Result<(), Error> {
+/// Detaches the overlays of sessions whose `diff` directory was deleted while
+/// they were active, returning their mount points.
+///
+/// Such overlays can't be stopped normally and would otherwise pile up, so
+/// this is cheap enough to run on every invocation: it only reads the mount
+/// table and unmounts at most a few overlays at a time. Overlays that can't be
+/// unmounted, for example for lack of privileges, are left for later.
+pub fn unmount_abandoned() -> Result<Vec<PathBuf>, Error> {
+    const MAX_UNMOUNTS: usize = 8;
+
+    let sessions_dir = get_sessions_dir()?;
+    let mut unmounted = Vec::new();
+    for mount_point in mountinfo::overlays_under(&sessions_dir)? {
+        if unmounted.len() == MAX_UNMOUNTS {
+            break;
+        }
+        let mut components = mount_point.components();
+        let (Some(Component::Normal(name)), Some(Component::Normal(merged)), None) =
+            (components.next(), components.next(), components.next())
+        else {
+            continue;
+        };
+        if merged != "merged" {
+            continue;
+        }
+
+        let mut session = sessions_dir.join(name);
+        if TmpPath::new(&mut session, "diff").exists() {
+            continue;
+        }
+        session.push("merged");
+        if unmount_tree(&session).is_ok() {
+            unmounted.push(session);
+        }
+    }
+    Ok(unmounted)
+}
+
+fn unmount_tree(root: &Path) -> Result<(), Error> {
+    for target in mountinfo::mounts_under(root)?.iter().rev() {
+        unmount(target, UnmountFlags::DETACH)
+            .map_io_err_lazy(|| format!("Failed to unmount directory {}", target.display()))?;
+    }
+    unmount(root, UnmountFlags::DETACH)
+        .map_io_err_lazy(|| format!("Failed to unmount directory {}", root.display()))
+}
+
+/// Checks that a session name refers to a single visible directory in the
+/// sessions directory.
+pub fn validate_name(name: &OsStr) -> Result<(), Error> {
+    let mut components = Path::new(name).components();
+    if matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.as_bytes().starts_with(b".")
+    {
+        Ok(())
+    } else {
+        Err(Error::InvalidArgument)
+            .attach_printable(format!("Invalid session name {}", name.display()))
+    }
+}
+
+pub fn maybe_create_session(
+    dir: &mut PathBuf,
+    encryption_key: Option<&EncryptionKey>,
+    backend: Backend,
+) -> Result<(), Error> {
     if is_active_session(dir, false)? {
         return Ok(());
     }
 
+    let is_new = !TmpPath::new(dir, "diff").exists();
     for path in ["diff", "work", "merged"] {
         let dir = TmpPath::new(dir, path);
         fs::create_dir_all(&dir)
             .map_io_err_lazy(|| format!("Failed to create directory {dir:?}"))?;
     }
-    start_session(dir)
+
+    match encryption_key {
+        Some(key) if is_new => {
+            if let Err(e) = encryption::setup(dir, key) {
+                // Leave no half-initialized session behind so it can be retried
+                for path in ["diff", "work"] {
+                    drop(fs::remove_dir(TmpPath::new(dir, path)));
+                }
+                return Err(e);
+            }
+        }
+        Some(key) if encryption::is_encrypted(dir) => encryption::unlock(dir, key)?,
+        Some(_) => {
+            return Err(Error::InvalidArgument).attach_printable(
+                "Only new sessions can be encrypted, but this session already exists.",
+            );
+        }
+        None if encryption::is_encrypted(dir) => {
+            return Err(Error::InvalidArgument)
+                .attach_printable("This session is encrypted and requires its key file.");
+        }
+        None => {}
+    }
+    start_session(dir, backend)?;
+    meta::record_activation(dir, is_new)
 }
 
-fn start_session(dir: &mut PathBuf) -> Result<(), Error> {
+fn start_session(dir: &mut PathBuf, backend: Backend) -> Result<(), Error> {
+    wsl::check_session_location(dir)?;
+    let subtree = subtree::get(dir)?;
+    let unbound = system_dirs::get(dir)?;
+    let options = overlay_options::get(dir)?;
+    let mut storage = volatile::mount_storage(dir)?;
+    let mut lower = lower::mount_device(dir)?;
+    if subtree != Path::new("/") {
+        lower.push(subtree.strip_prefix("/").unwrap_or(&subtree));
+    }
     let command = {
-        let mut command = String::from("lowerdir=/,");
-        {
-            let diff = TmpPath::new(dir, "diff");
-            write!(command, "upperdir={},", diff.display()).unwrap();
-        }
-        {
-            let work = TmpPath::new(dir, "work");
-            write!(command, "workdir={}", work.display()).unwrap();
+        // Built from raw bytes as session paths need not be valid UTF-8
+        let mut command = b"lowerdir=".to_vec();
+        command.extend_from_slice(lower.as_os_str().as_bytes());
+        command.extend_from_slice(b",upperdir=");
+        command.extend_from_slice(TmpPath::new(&mut storage, "diff").as_os_str().as_bytes());
+        command.extend_from_slice(b",workdir=");
+        command.extend_from_slice(TmpPath::new(&mut storage, "work").as_os_str().as_bytes());
+        for option in options {
+            command.push(b',');
+            command.extend_from_slice(option.mount_option().as_bytes());
         }
 
-        CString::new(command.into_bytes())
+        CString::new(command)
+            .attach_printable("Invalid path bytes")
+            .change_context(Error::InvalidArgument)?
+    };
+
+    let source = {
+        let mut source = backend::SOURCE_PREFIX.to_vec();
+        source.extend_from_slice(dir.file_name().unwrap_or_default().as_bytes());
+        CString::new(source)
             .attach_printable("Invalid path bytes")
             .change_context(Error::InvalidArgument)?
     };
 
     let mut merged = TmpPath::new(dir, "merged");
-    mount(
-        c"overlay",
-        &*merged,
-        c"overlay",
-        MountFlags::empty(),
-        command.as_c_str(),
-    )
-    .map_io_err_lazy(|| format!("Failed to mount directory {merged:?}"))?;
-
-    for (source, target) in [
-        (c"/proc", "proc"),
-        (c"/dev", "dev"),
-        (c"/run", "run"),
-        (c"/tmp", "tmp"),
-    ] {
-        let target = TmpPath::new(&mut merged, target);
-        recursive_bind_mount(source, &*target)
+    if let Err(e) = backend::mount_overlay(backend, &command, &merged, &source) {
+        drop(merged);
+        drop(lower::unmount_device(dir));
+        return Err(e);
+    }
+
+    // A session is considered active as soon as its overlay is mounted, so a
+    // partially set up session must not be left behind. Sessions of a subtree
+    // see the real system directories wherever they are anyway.
+    let result = if subtree == Path::new("/") {
+        bind_system_dirs(&mut merged, &unbound)
+    } else {
+        fault::check(FaultPoint::AfterMount)
+    };
+    if result.is_err() {
+        for target in mountinfo::mounts_under(&merged)
+            .unwrap_or_default()
+            .iter()
+            .rev()
+        {
+            let _ = unmount(target, UnmountFlags::DETACH);
+        }
+        let _ = unmount(&*merged, UnmountFlags::DETACH);
+        drop(merged);
+        let _ = lower::unmount_device(dir);
+    }
+    result
+}
+
+fn bind_system_dirs(merged: &mut PathBuf, unbound: &[SystemDir]) -> Result<(), Error> {
+    fault::check(FaultPoint::AfterMount)?;
+    for dir in SystemDir::ALL {
+        if unbound.contains(&dir) {
+            continue;
+        }
+        let source = Path::new("/").join(dir.name());
+        // Not every system has all of them, WSL for one may lack /run
+        if !source.exists() {
+            continue;
+        }
+        let target = TmpPath::new(merged, dir.name());
+        recursive_bind_mount(&source, &*target)
             .map_io_err_lazy(|| format!("Failed to bind mount directory {target:?}"))?;
         change_mount(
             &*target,
@@ -118,39 +474,165 @@ fn start_session(dir: &mut PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
-fn stop_session(session: &mut PathBuf) -> Result<(), Error> {
+/// Returns the processes that see the given merged directory at `subtree`,
+/// which is their root directory unless the session only overlays part of the
+/// file system.
+///
+/// Sandboxed processes live in their own mount namespace, so the merged
+/// directory is recognized by its inode rather than by its path.
+pub fn session_processes(merged: &Path, subtree: &Path) -> Result<Vec<Pid>, Error> {
+    let merged =
+        fs::metadata(merged).map_io_err_lazy(|| format!("Failed to stat {}", merged.display()))?;
+
+    let mut pids = Vec::new();
+    for entry in fs::read_dir("/proc").map_io_err("Failed to open directory /proc")? {
+        let entry = entry.map_io_err("Failed to read directory /proc")?;
+        let Some(pid) = entry
+            .file_name_ref()
+            .to_str()
+            .and_then(|pid| pid.parse().ok())
+            .and_then(Pid::from_raw)
+        else {
+            continue;
+        };
+
+        // Processes may exit at any time, so errors just mean they're gone
+        let mut root = entry.path();
+        root.push("root");
+        if subtree != Path::new("/") {
+            root.push(subtree.strip_prefix("/").unwrap_or(subtree));
+        }
+        if fs::metadata(root)
+            .is_ok_and(|root| root.dev() == merged.dev() && root.ino() == merged.ino())
+        {
+            pids.push(pid);
+        }
+    }
+    Ok(pids)
+}
+
+pub fn stop_session(session: &mut PathBuf) -> Result<(), Error> {
     if !is_active_session(session, true)? {
         return Ok(());
     }
 
-    let mut merged = TmpPath::new(session, "merged");
+    // The overlay stays mounted in their namespaces even once it's unmounted
+    // here, so they'd carry on writing to a session that looks stopped
+    if !session_processes(&session.join("merged"), &subtree::get(session)?)?.is_empty() {
+        return Err(Error::InvalidArgument).attach_printable(format!(
+            "Session {} still has processes running in it.",
+            session.file_name().unwrap_or_default().display()
+        ));
+    }
+    publish::unpublish(session)?;
+    let merged = TmpPath::new(session, "merged");
 
-    for target in ["proc", "dev", "run", "tmp"] {
-        let target = TmpPath::new(&mut merged, target);
-        unmount(&*target, UnmountFlags::DETACH)
-            .map_io_err_lazy(|| format!("Failed to unmount directory {target:?}"))?;
+    for target in mountinfo::mounts_under(&merged)?.iter().rev() {
+        unmount(target, UnmountFlags::DETACH)
+            .map_io_err_lazy(|| format!("Failed to unmount directory {}", target.display()))?;
     }
 
+    fault::check(FaultPoint::BeforeUnmount)?;
     unmount(&*merged, UnmountFlags::empty())
-        .map_io_err_lazy(|| format!("Failed to unmount directory {merged:?}"))
+        .map_io_err_lazy(|| format!("Failed to unmount directory {merged:?}"))?;
+    drop(merged);
+    lower::unmount_device(session)?;
+
+    if encryption::is_encrypted(session) {
+        encryption::lock(session)?;
+    }
+    Ok(())
+}
+
+fn shred_dir(dir: &Path) -> Result<(), Error> {
+    let fd = walk::open_root(dir)
+        .map_io_err_lazy(|| format!("Failed to open directory {}", dir.display()))?;
+    shred_tree(&fd, &mut dir.to_path_buf())
+}
+
+fn shred_tree(dir: &OwnedFd, path: &mut PathBuf) -> Result<(), Error> {
+    static ZEROS: [u8; 1 << 16] = [0; 1 << 16];
+
+    for (name, file_type) in walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+        if file_type == FileType::Directory {
+            let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+                .map_io_err_lazy(|| format!("Failed to open directory {path:?}"))?;
+            shred_tree(&child, &mut path)?;
+            continue;
+        } else if file_type != FileType::RegularFile {
+            continue;
+        }
+
+        let mut file = File::from(
+            walk::open_beneath(dir, &name, OFlags::WRONLY)
+                .map_io_err_lazy(|| format!("Failed to open file {path:?}"))?,
+        );
+        let mut remaining = file
+            .metadata()
+            .map_io_err_lazy(|| format!("Failed to stat {path:?}"))?
+            .len();
+        while remaining > 0 {
+            #[allow(clippy::cast_possible_truncation)]
+            let chunk = remaining.min(ZEROS.len() as u64) as usize;
+            file.write_all(&ZEROS[..chunk])
+                .map_io_err_lazy(|| format!("Failed to overwrite file {path:?}"))?;
+            remaining -= chunk as u64;
+        }
+        file.sync_data()
+            .map_io_err_lazy(|| format!("Failed to sync file {path:?}"))?;
+    }
+    Ok(())
 }
 
-fn delete_session(session: &Path) -> Result<(), Error> {
-    fuc_engine::remove_dir_all(session)
-        .attach_printable_lazy(|| format!("Failed to delete directory {session:?}"))
-        .change_context(Error::Io)
+pub fn delete_session(session: &Path) -> Result<(), Error> {
+    volatile::unmount_storage(&mut session.to_path_buf())?;
+    let fd = walk::open_root(session)
+        .map_io_err_lazy(|| format!("Failed to open directory {}", session.display()))?;
+    remove_tree(&fd, &mut session.to_path_buf())?;
+    drop(fd);
+    unlinkat(CWD, session, AtFlags::REMOVEDIR)
+        .map_io_err_lazy(|| format!("Failed to delete directory {}", session.display()))
+}
+
+/// Empties a directory without ever following symlinks so entries planted by
+/// the sandboxed program can't redirect the deletion to host files.
+pub fn remove_tree(dir: &OwnedFd, path: &mut PathBuf) -> Result<(), Error> {
+    for (name, file_type) in walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?
+    {
+        let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+        let flags = if file_type == FileType::Directory {
+            let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+                .map_io_err_lazy(|| format!("Failed to open directory {path:?}"))?;
+            remove_tree(&child, &mut path)?;
+            AtFlags::REMOVEDIR
+        } else {
+            AtFlags::empty()
+        };
+        unlinkat(dir, &name, flags).map_io_err_lazy(|| format!("Failed to delete {path:?}"))?;
+    }
+    Ok(())
 }
 
 fn iter_all_sessions(
     mut f: impl FnMut(DirEntry, &mut PathBuf) -> Result<(), Error>,
 ) -> Result<(), Error> {
-    let mut sessions_dir = get_sessions_dir();
+    let mut sessions_dir = get_sessions_dir()?;
     for entry in match fs::read_dir(&sessions_dir) {
         Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
-        r => r.map_io_err_lazy(|| format!("Failed to open directory {sessions_dir:?}"))?,
+        r => {
+            r.map_io_err_lazy(|| format!("Failed to open directory {}", sessions_dir.display()))?
+        }
     } {
-        let entry =
-            entry.map_io_err_lazy(|| format!("Failed to read directory {sessions_dir:?}"))?;
+        let entry = entry
+            .map_io_err_lazy(|| format!("Failed to read directory {}", sessions_dir.display()))?;
+        // Sessions that are still being imported belong to their importer
+        if entry.file_name_ref().as_bytes().starts_with(b".") {
+            continue;
+        }
         let mut session = TmpPath::new(&mut sessions_dir, entry.file_name_ref());
 
         f(entry, &mut session)?;
@@ -158,25 +640,161 @@ fn iter_all_sessions(
     Ok(())
 }
 
+/// Opens a session's upper directory for walking.
+pub fn open_diff(session: &OsStr) -> Result<OwnedFd, Error> {
+    let mut diff = get_sessions_dir()?;
+    diff.push(resolve_session(session)?);
+    diff.push("diff");
+    match walk::open_root(&diff) {
+        Err(Errno::NOENT) => Err(Error::SessionNotFound)
+            .attach_printable(format!("Session {} does not exist", session.display())),
+        r => r.map_io_err_lazy(|| format!("Failed to open directory {}", diff.display())),
+    }
+}
+
+/// Records that a session was just used for the `@last` and `@prev` aliases.
+pub fn mark_used(session: &mut PathBuf) -> Result<(), Error> {
+    let marker = TmpPath::new(session, LAST_USED);
+    File::create(&marker)
+        .and_then(|file| file.set_modified(clock::now()))
+        .map_io_err_lazy(|| format!("Failed to write {marker:?}"))
+}
+
+/// Expands the `@last` and `@prev` aliases to the most and second most
+/// recently used sessions, leaving other references untouched.
+pub fn resolve_alias(reference: &OsStr) -> Result<Cow<'_, OsStr>, Error> {
+    let nth = match reference.as_bytes() {
+        b"@last" => 0,
+        b"@prev" => 1,
+        _ => return Ok(Cow::Borrowed(reference)),
+    };
+
+    let mut sessions_dir = get_sessions_dir()?;
+    let mut used = Vec::new();
+    for name in session_names()? {
+        let mut session = TmpPath::new(&mut sessions_dir, &name);
+        let marker = TmpPath::new(&mut session, LAST_USED);
+        match fs::metadata(&marker).and_then(|metadata| metadata.modified()) {
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            r => used.push((
+                r.map_io_err_lazy(|| format!("Failed to stat {marker:?}"))?,
+                name,
+            )),
+        }
+    }
+    used.sort_unstable_by(|(a, _), (b, _)| b.cmp(a));
+
+    used.into_iter()
+        .nth(nth)
+        .map(|(_, name)| Cow::Owned(name))
+        .ok_or(Error::SessionNotFound)
+        .attach_printable_lazy(|| {
+            format!(
+                "No session has been used enough to resolve {}",
+                reference.display()
+            )
+        })
+}
+
+/// Returns the names of all sessions in sorted order.
+pub fn session_names() -> Result<Vec<OsString>, Error> {
+    let sessions_dir = get_sessions_dir()?;
+    let mut names = Vec::new();
+    for entry in match fs::read_dir(&sessions_dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(names),
+        r => {
+            r.map_io_err_lazy(|| format!("Failed to open directory {}", sessions_dir.display()))?
+        }
+    } {
+        let entry = entry
+            .map_io_err_lazy(|| format!("Failed to read directory {}", sessions_dir.display()))?;
+        // Sessions that are still being imported
+        if !entry.file_name_ref().as_bytes().starts_with(b".") {
+            names.push(entry.file_name());
+        }
+    }
+    names.sort_unstable();
+    Ok(names)
+}
+
+/// Like [`resolve_alias`], but also accepts the index and name prefixes that
+/// [`resolve`] understands. Meant for references to existing sessions only, as
+/// a new session's name could otherwise be taken for an existing one's.
+pub fn resolve_session(reference: &OsStr) -> Result<Cow<'_, OsStr>, Error> {
+    let session = resolve_alias(reference)?;
+    if let Cow::Owned(_) = session {
+        return Ok(session);
+    }
+    let names = session_names()?;
+    let name = resolve(&get_sessions_dir()?, &names, reference)?;
+    Ok(if name == reference {
+        Cow::Borrowed(reference)
+    } else {
+        Cow::Owned(name.to_owned())
+    })
+}
+
+/// Finds the session a user-provided reference points to.
+///
+/// References are exact session names, the 1-based index printed by [`list`],
+/// or an unambiguous name prefix, in that order of precedence. Unknown
+/// references are returned as is so that callers report the missing session.
+fn resolve<'a>(
+    sessions_dir: &Path,
+    names: &'a [OsString],
+    reference: &'a OsStr,
+) -> Result<&'a OsStr, Error> {
+    if sessions_dir.join(reference).exists() {
+        return Ok(reference);
+    }
+    if let Some(index) = reference
+        .to_str()
+        .and_then(|index| index.parse::<usize>().ok())
+        && let Some(name) = index.checked_sub(1).and_then(|i| names.get(i))
+    {
+        return Ok(name);
+    }
+
+    let mut matches = names
+        .iter()
+        .filter(|name| name.as_bytes().starts_with(reference.as_bytes()));
+    match (matches.next(), matches.next()) {
+        (Some(name), None) => Ok(name),
+        (None, _) => Ok(reference),
+        (Some(first), Some(second)) => {
+            let candidates = [first, second]
+                .into_iter()
+                .chain(matches)
+                .map(|name| name.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(", ");
+            Err(Error::InvalidArgument).attach_printable(format!(
+                "Session prefix \"{}\" is ambiguous, candidates: {candidates}",
+                reference.display()
+            ))
+        }
+    }
+}
+
 #[allow(clippy::needless_pass_by_value)]
-fn iter_op<S: AsRef<str>>(
+fn iter_op<S: AsRef<OsStr>>(
     sessions: Op<S>,
     mut f: impl FnMut(&mut PathBuf) -> Result<(), Error>,
 ) -> Result<(), Error> {
     match sessions {
         Op::All => iter_all_sessions(|_, session| f(session)),
         Op::List(sessions) => {
-            let mut sessions_dir = get_sessions_dir();
+            let mut sessions_dir = get_sessions_dir()?;
             for session in sessions {
-                let mut session = TmpPath::new(&mut sessions_dir, session.as_ref());
-                f(&mut session)?;
+                let session = resolve_session(session.as_ref())?;
+                f(&mut TmpPath::new(&mut sessions_dir, &session))?;
             }
             Ok(())
         }
     }
 }
 
-fn is_active_session(session: &mut PathBuf, must_exist: bool) -> Result<bool, Error> {
+pub fn is_active_session(session: &mut PathBuf, must_exist: bool) -> Result<bool, Error> {
     let mount = {
         let merged = TmpPath::new(session, "merged");
         match statx(CWD, &*merged, AtFlags::empty(), StatxFlags::MNT_ID) {
@@ -191,8 +809,17 @@ fn is_active_session(session: &mut PathBuf, must_exist: bool) -> Result<bool, Er
     };
 
     let parent_mount = statx(CWD, &*session, AtFlags::empty(), StatxFlags::MNT_ID)
-        .map_io_err_lazy(|| format!("Failed to stat {session:?}"))?
+        .map_io_err_lazy(|| format!("Failed to stat {}", session.display()))?
         .stx_mnt_id;
 
     Ok(parent_mount != mount)
 }
+
+pub fn is_mount_point(path: &Path) -> Result<bool, Error> {
+    let mount_id = |path: &Path| {
+        statx(CWD, path, AtFlags::empty(), StatxFlags::MNT_ID)
+            .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))
+            .map(|stat| stat.stx_mnt_id)
+    };
+    Ok(mount_id(path)? != mount_id(&path.join(".."))?)
+}
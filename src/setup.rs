@@ -0,0 +1,125 @@
+use std::{
+    env, fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{XattrFlags, setxattr},
+    io::Errno,
+};
+
+use crate::{Error, IoErr, config::static_paths};
+
+/// The capabilities forkfs needs, as bit positions from `linux/capability.h`.
+///
+/// `cap_dac_override` onwards are required for `OverlayFS` to perform copy-ups
+/// on behalf of the sandboxed processes.
+const CAPABILITIES: [u32; 10] = [
+    0,  // cap_chown
+    18, // cap_sys_chroot
+    21, // cap_sys_admin
+    12, // cap_net_admin
+    1,  // cap_dac_override
+    3,  // cap_fowner
+    8,  // cap_setpcap
+    27, // cap_mknod
+    28, // cap_lease
+    31, // cap_setfcap
+];
+
+/// Grants the running forkfs binary the file capabilities it needs to work
+/// without root, equivalent to `setcap ...+ep`.
+///
+/// This must itself be run with enough privileges to set file capabilities,
+/// for example through `sudo`.
+pub fn setup() -> Result<PathBuf, Error> {
+    const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+    const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x1;
+
+    let exe = exe_path()
+        .ok_or(Error::Io)
+        .attach_printable("Failed to locate the forkfs binary")?;
+
+    let permitted = CAPABILITIES
+        .iter()
+        .fold(0u32, |caps, cap| caps | (1 << cap));
+    let mut data = Vec::with_capacity(20);
+    for word in [
+        VFS_CAP_REVISION_2 | VFS_CAP_FLAGS_EFFECTIVE,
+        permitted,
+        0,
+        0,
+        0,
+    ] {
+        data.extend_from_slice(&word.to_le_bytes());
+    }
+
+    match setxattr(&exe, "security.capability", &data, XattrFlags::empty()) {
+        Err(Errno::PERM) => Err(Error::NotRoot).attach_printable_lazy(|| {
+            format!(
+                "Granting capabilities requires root: $ sudo {} setup",
+                exe.display()
+            )
+        }),
+        r => r.map_io_err_lazy(|| format!("Failed to set capabilities on {}", exe.display())),
+    }?;
+    Ok(exe)
+}
+
+/// Locates the running forkfs binary.
+///
+/// `/proc/self/exe` is only readable while procfs is mounted, which minimal
+/// systems and containers don't always do, so the binary is otherwise looked
+/// up by the name it was started with like a shell would.
+#[must_use]
+pub fn exe_path() -> Option<PathBuf> {
+    if let Ok(exe) = fs::read_link("/proc/self/exe") {
+        return Some(exe);
+    }
+    let name = PathBuf::from(env::args_os().next()?);
+    if name.components().count() > 1 {
+        return fs::canonicalize(name).ok();
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(&name))
+        .find(|path| is_executable(path))
+        .and_then(|path| fs::canonicalize(path).ok())
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// The marker recording that forkfs has run with its capabilities before.
+fn marker_path() -> Option<PathBuf> {
+    if static_paths() {
+        return Some(PathBuf::from("/var/lib/forkfs/capabilities-granted"));
+    }
+    let mut path = dirs::state_dir()?;
+    path.push("forkfs");
+    path.push("capabilities-granted");
+    Some(path)
+}
+
+/// Remembers that forkfs successfully ran with its file capabilities so that
+/// losing them, typically by upgrading the binary, can be diagnosed later.
+pub fn remember_capabilities() {
+    let Some(path) = marker_path() else {
+        return;
+    };
+    if path.exists() {
+        return;
+    }
+    if let Some(dir) = path.parent() {
+        drop(fs::create_dir_all(dir));
+    }
+    drop(fs::File::create(path));
+}
+
+/// Returns whether forkfs ran with its file capabilities in the past.
+pub fn had_capabilities() -> bool {
+    marker_path().is_some_and(|path| path.exists())
+}
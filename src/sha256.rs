@@ -0,0 +1,19 @@
+//! SHA-256 digests, which name chunks in a [store](crate::store) by their
+//! contents and tell binaries apart in diffs.
+
+use std::fmt::Write;
+
+use sha2::{Digest, Sha256};
+
+/// Hashes `data` in one go.
+pub fn digest(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Formats a digest the way `sha256sum` prints it.
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
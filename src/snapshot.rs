@@ -0,0 +1,255 @@
+//! Checkpoints of a session's changes that it can be rolled back to.
+//!
+//! Each snapshot is a numbered directory in the session's `snapshots`
+//! directory holding a copy of the upper directory, made with reflinks where
+//! the file system supports them, along with a record describing it.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    process,
+    time::UNIX_EPOCH,
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{CWD, RenameFlags, renameat_with},
+    io::Errno,
+    process::getuid,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Error, IoErr, Retention, SessionOperand, clock,
+    clone::copy_upper,
+    encryption, get_sessions_dir,
+    path_undo::TmpPath,
+    run::validate_permissions,
+    sessions::{delete_session, open_diff, remove_tree, resolve_session},
+    stop_sessions, volatile, walk,
+};
+
+pub const SNAPSHOTS: &str = "snapshots";
+const RECORD: &str = "snapshot.toml";
+
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Snapshot {
+    /// The snapshot's number, counting up from 1 in the order snapshots were
+    /// taken.
+    #[serde(skip)]
+    pub number: u64,
+    /// The name the snapshot was given when it was taken.
+    pub tag: Option<String>,
+    /// When the snapshot was taken, in seconds since the Unix epoch.
+    pub created: u64,
+}
+
+/// Saves a copy of a session's current changes, optionally naming it `tag`.
+///
+/// The session may be active, but files being written to at the same time
+/// may be saved half written. Encrypted sessions can't be snapshotted since
+/// snapshots are stored unencrypted.
+pub fn create(session: impl AsRef<OsStr>, tag: Option<&str>) -> Result<Snapshot, Error> {
+    let session = session.as_ref();
+    validate_permissions(getuid())?;
+
+    let upper = open_diff(session)?;
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    if encryption::is_encrypted(&mut dir) {
+        return Err(Error::InvalidArgument)
+            .attach_printable("Encrypted sessions can't be snapshotted.");
+    }
+    let existing = snapshots(&mut dir)?;
+    if let Some(tag) = tag {
+        if tag.is_empty() || tag.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::InvalidArgument).attach_printable(format!(
+                "Invalid snapshot tag {tag:?}, numbers are reserved for referring to snapshots by \
+                 number."
+            ));
+        }
+        if existing
+            .iter()
+            .any(|snapshot| snapshot.tag.as_deref() == Some(tag))
+        {
+            return Err(Error::Conflict).attach_printable(format!(
+                "Session {} already has a snapshot tagged {tag}",
+                session.display()
+            ));
+        }
+    }
+
+    let snapshot = Snapshot {
+        number: existing.last().map_or(1, |last| last.number + 1),
+        tag: tag.map(str::to_string),
+        created: clock::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+    };
+    let snapshots_dir = TmpPath::new(&mut dir, SNAPSHOTS);
+    // Only renamed into place once complete so it never shows up half copied
+    let staging = snapshots_dir.join(format!(".{}-{}", snapshot.number, process::id()));
+    let diff = staging.join("diff");
+    fs::create_dir_all(&diff)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", diff.display()))?;
+    let result = copy_upper(&upper, &diff)
+        .and_then(|()| {
+            let record = staging.join(RECORD);
+            let contents = toml::to_string(&snapshot)
+                .change_context(Error::InvalidArgument)
+                .attach_printable("Failed to serialize the snapshot's record")?;
+            fs::write(&record, contents)
+                .map_io_err_lazy(|| format!("Failed to write {}", record.display()))
+        })
+        .and_then(|()| {
+            let to = snapshots_dir.join(snapshot.number.to_string());
+            match renameat_with(CWD, &staging, CWD, &to, RenameFlags::NOREPLACE) {
+                Err(Errno::EXIST) => Err(Error::Conflict)
+                    .attach_printable("Another snapshot was taken at the same time."),
+                r => r.map_io_err_lazy(|| format!("Failed to create snapshot {}", to.display())),
+            }
+        });
+    if result.is_err() {
+        drop(delete_session(&staging));
+    }
+    result.map(|()| snapshot)
+}
+
+/// Returns a session's snapshots, oldest first.
+pub fn list(session: impl AsRef<OsStr>) -> Result<Vec<Snapshot>, Error> {
+    let session = session.as_ref();
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    if !dir.exists() {
+        return Err(Error::SessionNotFound)
+            .attach_printable(format!("Session {} does not exist", session.display()));
+    }
+    snapshots(&mut dir)
+}
+
+/// Rolls a session back to one of its snapshots, referred to by number or
+/// tag and defaulting to the latest one, discarding the changes made since.
+///
+/// The session is stopped first. Its snapshots are kept, so it can be rolled
+/// back to the same snapshot again later.
+pub fn restore(session: impl AsRef<OsStr>, snapshot: Option<&str>) -> Result<Snapshot, Error> {
+    let session = session.as_ref();
+    validate_permissions(getuid())?;
+    stop_sessions(SessionOperand::List(&[session]))?;
+
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    let snapshots = snapshots(&mut dir)?;
+    let found = snapshot.map_or_else(
+        || snapshots.last(),
+        |reference| {
+            snapshots.iter().find(|snapshot| {
+                snapshot.tag.as_deref() == Some(reference)
+                    || snapshot.number.to_string() == reference
+            })
+        },
+    );
+    let Some(snapshot) = found else {
+        let session = session.display();
+        return Err(Error::InvalidArgument).attach_printable(snapshot.map_or_else(
+            || format!("Session {session} has no snapshots"),
+            |reference| format!("Session {session} has no snapshot {reference}"),
+        ));
+    };
+
+    let mut source = dir.join(SNAPSHOTS);
+    source.push(snapshot.number.to_string());
+    source.push("diff");
+    let upper = walk::open_root(&source)
+        .map_io_err_lazy(|| format!("Failed to open directory {}", source.display()))?;
+    if volatile::is_volatile(&mut dir) {
+        // A volatile session's upper directory is a mount point that can't be
+        // swapped out, so its contents are replaced in place instead.
+        let mut diff = dir.join("diff");
+        let fd = walk::open_root(&diff)
+            .map_io_err_lazy(|| format!("Failed to open directory {}", diff.display()))?;
+        remove_tree(&fd, &mut diff)?;
+        copy_upper(&upper, &diff)?;
+        return Ok(snapshot.clone());
+    }
+    let staging = dir.join(format!(".diff.restore-{}", process::id()));
+    fs::create_dir(&staging)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", staging.display()))?;
+    if let Err(e) = copy_upper(&upper, &staging) {
+        drop(delete_session(&staging));
+        return Err(e);
+    }
+
+    let diff = TmpPath::new(&mut dir, "diff");
+    renameat_with(CWD, &staging, CWD, &*diff, RenameFlags::EXCHANGE)
+        .map_io_err_lazy(|| format!("Failed to replace {}", diff.display()))?;
+    // The staging directory now holds the discarded changes
+    delete_session(&staging)?;
+    Ok(snapshot.clone())
+}
+
+/// Returns the untagged snapshots of a session that `retention` no longer
+/// keeps as of `now`, in seconds since the Unix epoch.
+pub fn expired(
+    session: &mut PathBuf,
+    retention: Retention,
+    now: u64,
+) -> Result<Vec<PathBuf>, Error> {
+    if retention.is_unlimited() {
+        return Ok(Vec::new());
+    }
+    let untagged = snapshots(session)?
+        .into_iter()
+        .filter(|snapshot| snapshot.tag.is_none())
+        .collect::<Vec<_>>();
+    let excess = retention
+        .snapshots
+        .map_or(0, |kept| untagged.len().saturating_sub(kept));
+    let oldest = retention
+        .snapshot_days
+        .map_or(0, |days| now.saturating_sub(days * 24 * 60 * 60));
+
+    let dir = TmpPath::new(session, SNAPSHOTS);
+    Ok(untagged
+        .iter()
+        .enumerate()
+        .filter(|&(i, snapshot)| i < excess || snapshot.created < oldest)
+        .map(|(_, snapshot)| dir.join(snapshot.number.to_string()))
+        .collect())
+}
+
+fn snapshots(session: &mut PathBuf) -> Result<Vec<Snapshot>, Error> {
+    let dir = TmpPath::new(session, SNAPSHOTS);
+    let mut snapshots = Vec::new();
+    for entry in match fs::read_dir(&dir) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(snapshots),
+        r => r.map_io_err_lazy(|| format!("Failed to open directory {}", dir.display()))?,
+    } {
+        let entry =
+            entry.map_io_err_lazy(|| format!("Failed to read directory {}", dir.display()))?;
+        // Snapshots being taken are hidden
+        let Some(number) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        let mut snapshot = load(&entry.path().join(RECORD))?;
+        snapshot.number = number;
+        snapshots.push(snapshot);
+    }
+    snapshots.sort_unstable_by_key(|snapshot| snapshot.number);
+    Ok(snapshots)
+}
+
+fn load(record: &Path) -> Result<Snapshot, Error> {
+    let contents = fs::read_to_string(record)
+        .map_io_err_lazy(|| format!("Failed to read {}", record.display()))?;
+    toml::from_str(&contents)
+        .change_context(Error::InvalidArgument)
+        .attach_printable_lazy(|| format!("Invalid snapshot record {}", record.display()))
+}
@@ -0,0 +1,334 @@
+//! Deduplicated storage for exported sessions.
+//!
+//! A store holds session archives cut into chunks at content-defined
+//! boundaries, the way restic and git-annex do: a boundary is wherever a
+//! rolling hash of the last bytes happens to match, so editing one file only
+//! changes the chunks around it instead of shifting every chunk after it.
+//! Chunks are named by their SHA-256 and shared by every archive in the store,
+//! which makes many sessions with small differences cost little more than one.
+//!
+//! A store is a directory laid out as:
+//!
+//! - `chunks/ab/ab…`: the chunks, grouped by the first byte of their hash.
+//! - `archives/<name>`: the hashes of the chunks making up each archive, one
+//!   per line after a header.
+//!
+//! Deleting an archive's file frees the chunks only it used the next time the
+//! store's garbage is [collected](crate::collect_garbage).
+
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::{self, Cursor, ErrorKind, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    process, vec,
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::fs::{FlockOperation, flock};
+
+use crate::{
+    Error, Garbage, GarbageKind, IoErr, Omission, export_session, import_session,
+    sessions::{resolve_session, validate_name},
+    sha256,
+};
+
+const HEADER: &str = "forkfs chunk index v1";
+const CHUNKS: &str = "chunks";
+const ARCHIVES: &str = "archives";
+const MIN_CHUNK: usize = 16 << 10;
+const MAX_CHUNK: usize = 1 << 20;
+/// How many of the rolling hash's top bits must be zero to end a chunk, making
+/// chunks 64 KiB larger than the minimum on average.
+const BOUNDARY_BITS: u32 = 16;
+
+/// Random values for each byte to feed the rolling hash with. They determine
+/// where chunks end and so must never change, or chunks from before the change
+/// would stop being reused.
+const GEAR: [u64; 256] = {
+    // splitmix64
+    let mut table = [0; 256];
+    let mut state = 0_u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// The outcome of adding a session to a store.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StoredArchive {
+    /// The name the archive is stored under, that of the session.
+    pub name: OsString,
+    /// The size of the archive in bytes.
+    pub size: u64,
+    /// How many of those bytes weren't already in the store.
+    pub added: u64,
+    /// The entries left out of the archive.
+    pub omissions: Vec<Omission>,
+}
+
+/// Adds a session's changes to the store at `store` under the session's name,
+/// replacing any archive stored under that name before. The store is created
+/// if it doesn't exist.
+///
+/// The archive is the same as the one [`export_session`] writes, just split
+/// into chunks that are only stored once across all archives. Chunks no
+/// archive uses anymore are left for
+/// [`collect_garbage`](crate::collect_garbage) to delete.
+pub fn export(
+    session: impl AsRef<OsStr>,
+    store: &Path,
+    preserve_special: bool,
+) -> Result<StoredArchive, Error> {
+    let session = session.as_ref();
+    let name = resolve_session(session)?.into_owned();
+    let chunks = store.join(CHUNKS);
+    let archives = store.join(ARCHIVES);
+    for dir in [&chunks, &archives] {
+        fs::create_dir_all(dir)
+            .map_io_err_lazy(|| format!("Failed to create directory {}", dir.display()))?;
+    }
+    let _lock = lock(store, FlockOperation::LockExclusive)?;
+
+    let mut chunker = Chunker {
+        dir: &chunks,
+        chunk: Vec::with_capacity(MAX_CHUNK),
+        hash: 0,
+        index: format!("{HEADER}\n"),
+        size: 0,
+        added: 0,
+    };
+    let omissions = export_session(session, &mut chunker, preserve_special)?;
+    chunker.end_chunk().map_io_err("Failed to write chunk")?;
+
+    let index = archives.join(&name);
+    let staging = archives.join(format!(".{}.tmp-{}", name.display(), process::id()));
+    fs::write(&staging, &chunker.index)
+        .and_then(|()| fs::rename(&staging, &index))
+        .map_io_err_lazy(|| format!("Failed to write {}", index.display()))?;
+    Ok(StoredArchive {
+        name,
+        size: chunker.size,
+        added: chunker.added,
+        omissions,
+    })
+}
+
+/// Creates the session `session` from the archive named `archive` in the
+/// store at `store`, like [`import_session`] does from a tar archive.
+pub fn import(
+    session: impl AsRef<OsStr>,
+    store: &Path,
+    archive: impl AsRef<OsStr>,
+) -> Result<(), Error> {
+    let archive = archive.as_ref();
+    validate_name(archive)?;
+    let _lock = lock(store, FlockOperation::LockShared)?;
+
+    let index = store.join(ARCHIVES).join(archive);
+    let contents = match fs::read_to_string(&index) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(Error::InvalidArgument).attach_printable(format!(
+                "No archive named {} in {}",
+                archive.display(),
+                store.display()
+            ));
+        }
+        r => r.map_io_err_lazy(|| format!("Failed to read {}", index.display()))?,
+    };
+    let mut lines = contents.lines();
+    if lines.next() != Some(HEADER) {
+        return Err(Error::InvalidArgument)
+            .attach_printable(format!("Unsupported chunk index {}", index.display()));
+    }
+    import_session(
+        session,
+        Chunks {
+            dir: store.join(CHUNKS),
+            hashes: lines.map(str::to_owned).collect::<Vec<_>>().into_iter(),
+            current: Cursor::default(),
+        },
+    )
+}
+
+/// Keeps stores consistent when several forkfs invocations use them at once:
+/// pruning, which takes an exclusive lock, must not delete the chunks of an
+/// archive that is still being stored or read.
+fn lock(store: &Path, operation: FlockOperation) -> Result<File, Error> {
+    let dir =
+        File::open(store).map_io_err_lazy(|| format!("Failed to open {}", store.display()))?;
+    flock(&dir, operation).map_io_err_lazy(|| format!("Failed to lock {}", store.display()))?;
+    Ok(dir)
+}
+
+/// Splits an archive into chunks as it is written, storing the new ones.
+struct Chunker<'a> {
+    dir: &'a Path,
+    chunk: Vec<u8>,
+    hash: u64,
+    index: String,
+    size: u64,
+    added: u64,
+}
+
+impl Chunker<'_> {
+    fn end_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let name = sha256::hex(&sha256::digest(&self.chunk));
+        let dir = self.dir.join(&name[..2]);
+        let path = dir.join(&name);
+        let len = self.chunk.len() as u64;
+        if !path.exists() {
+            let staging = dir.join(format!(".{name}.tmp-{}", process::id()));
+            fs::create_dir_all(&dir)
+                .and_then(|()| fs::write(&staging, &self.chunk))
+                .and_then(|()| fs::rename(&staging, &path))
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+            self.added += len;
+        }
+        self.size += len;
+        self.index.push_str(&name);
+        self.index.push('\n');
+        self.chunk.clear();
+        self.hash = 0;
+        Ok(())
+    }
+}
+
+impl Write for Chunker<'_> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let written = buf.len();
+        while !buf.is_empty() {
+            let start = self.chunk.len();
+            let available = &buf[..buf.len().min(MAX_CHUNK - start)];
+            // Each byte is shifted out of the hash 64 bytes later, so the bytes
+            // before those that can end the chunk don't need to be hashed
+            let skipped = (MIN_CHUNK - 64).saturating_sub(start).min(available.len());
+            let mut hash = self.hash;
+            let mut boundary = None;
+            for (i, &byte) in available.iter().enumerate().skip(skipped) {
+                hash = (hash << 1).wrapping_add(GEAR[usize::from(byte)]);
+                if start + i + 1 >= MIN_CHUNK && hash >> (64 - BOUNDARY_BITS) == 0 {
+                    boundary = Some(i + 1);
+                    break;
+                }
+            }
+            self.hash = hash;
+
+            let taken = boundary.unwrap_or(available.len());
+            self.chunk.extend_from_slice(&buf[..taken]);
+            buf = &buf[taken..];
+            if boundary.is_some() || self.chunk.len() == MAX_CHUNK {
+                self.end_chunk()?;
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads an archive back from its chunks, checking that none were corrupted.
+struct Chunks {
+    dir: PathBuf,
+    hashes: vec::IntoIter<String>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            let Some(name) = self.hashes.next() else {
+                return Ok(0);
+            };
+            let path = self.dir.join(name.get(..2).unwrap_or_default()).join(&name);
+            let chunk = fs::read(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+            if sha256::hex(&sha256::digest(&chunk)) != name {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Chunk {} is corrupt", path.display()),
+                ));
+            }
+            self.current = Cursor::new(chunk);
+        }
+    }
+}
+
+/// Deletes the chunks of the store at `store` that no archive uses, along
+/// with leftovers from interrupted writes, returning what was deleted. With
+/// `dry_run`, nothing is deleted.
+pub fn collect(store: &Path, dry_run: bool) -> Result<Vec<Garbage>, Error> {
+    let _lock = lock(store, FlockOperation::LockExclusive)?;
+    prune(&store.join(CHUNKS), &store.join(ARCHIVES), dry_run)
+}
+
+/// Deletes the chunks no archive uses, along with leftovers from interrupted
+/// writes.
+fn prune(chunks: &Path, archives: &Path, dry_run: bool) -> Result<Vec<Garbage>, Error> {
+    let read_dir = |dir: &Path| {
+        fs::read_dir(dir)
+            .and_then(Iterator::collect::<io::Result<Vec<_>>>)
+            .map_io_err_lazy(|| format!("Failed to read directory {}", dir.display()))
+    };
+    let mut unused = Vec::new();
+    let mut used = HashSet::new();
+    for entry in read_dir(archives)? {
+        let path = entry.path();
+        if entry.file_name().as_bytes().starts_with(b".") {
+            unused.push((path, GarbageKind::Leftover));
+            continue;
+        }
+        let index = fs::read_to_string(&path)
+            .map_io_err_lazy(|| format!("Failed to read {}", path.display()))?;
+        used.extend(index.lines().skip(1).map(str::to_owned));
+    }
+
+    for group in read_dir(chunks)? {
+        for chunk in read_dir(&group.path())? {
+            let name = chunk.file_name();
+            if name.to_str().is_some_and(|name| used.contains(name)) {
+                continue;
+            }
+            let kind = if name.as_bytes().starts_with(b".") {
+                GarbageKind::Leftover
+            } else {
+                GarbageKind::UnusedChunk
+            };
+            unused.push((chunk.path(), kind));
+        }
+    }
+
+    let mut garbage = Vec::with_capacity(unused.len());
+    for (path, kind) in unused {
+        let metadata = fs::symlink_metadata(&path)
+            .map_io_err_lazy(|| format!("Failed to stat {}", path.display()))?;
+        if !dry_run {
+            fs::remove_file(&path)
+                .map_io_err_lazy(|| format!("Failed to delete {}", path.display()))?;
+        }
+        garbage.push(Garbage {
+            path,
+            kind,
+            size: metadata.blocks() * 512,
+        });
+    }
+    Ok(garbage)
+}
@@ -0,0 +1,106 @@
+use std::{borrow::Cow, env, fmt::Display, io, io::IsTerminal};
+
+use error_stack::{
+    Report,
+    fmt::{Charset, ColorMode},
+};
+
+/// When to use colors in output.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ColorChoice {
+    /// Use colors when writing to a terminal unless `NO_COLOR` is set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// How human-readable output should be rendered.
+///
+/// All of forkfs' output goes through a single style so that it renders
+/// predictably in terminals, logs, and CI systems alike.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Style {
+    color: ColorChoice,
+    ascii: bool,
+}
+
+impl Style {
+    /// Creates a style, restricting output to ASCII characters if `ascii` is
+    /// set.
+    #[must_use]
+    pub const fn new(color: ColorChoice, ascii: bool) -> Self {
+        Self { color, ascii }
+    }
+
+    /// Applies this style to error reports printed to stderr.
+    pub fn install(self) {
+        Report::set_color_mode(if self.colors(&io::stderr()) {
+            ColorMode::Emphasis
+        } else {
+            ColorMode::None
+        });
+        Report::set_charset(if self.ascii {
+            Charset::Ascii
+        } else {
+            Charset::Utf8
+        });
+    }
+
+    /// Returns whether output written to `stream` should be colored.
+    pub fn colors(self, stream: &impl IsTerminal) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && stream.is_terminal()
+            }
+        }
+    }
+
+    /// Whether output is restricted to ASCII characters.
+    pub(crate) const fn ascii(self) -> bool {
+        self.ascii
+    }
+
+    /// Renders user-controlled text such as session names, escaping non-ASCII
+    /// characters in ASCII mode.
+    pub(crate) fn text(self, text: &str) -> Cow<'_, str> {
+        if self.ascii && !text.is_ascii() {
+            Cow::Owned(text.escape_default().to_string())
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    /// Highlights `text` in bold green if `colors` is set.
+    pub(crate) fn active(colors: bool, text: impl Display) -> impl Display {
+        Painted(colors.then_some("1;32"), text)
+    }
+
+    /// Colors `text` green if `colors` is set.
+    pub(crate) fn added(colors: bool, text: impl Display) -> impl Display {
+        Painted(colors.then_some("32"), text)
+    }
+
+    /// Colors `text` yellow if `colors` is set.
+    pub(crate) fn modified(colors: bool, text: impl Display) -> impl Display {
+        Painted(colors.then_some("33"), text)
+    }
+
+    /// Colors `text` red if `colors` is set.
+    pub(crate) fn deleted(colors: bool, text: impl Display) -> impl Display {
+        Painted(colors.then_some("31"), text)
+    }
+}
+
+struct Painted<T>(Option<&'static str>, T);
+
+impl<T: Display> Display for Painted<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(code) => write!(f, "\x1b[{code}m{}\x1b[0m", self.1),
+            None => self.1.fmt(f),
+        }
+    }
+}
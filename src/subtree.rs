@@ -0,0 +1,101 @@
+//! Sessions that only overlay one directory of the real file system.
+//!
+//! The directory is picked when the session is created and recorded in its
+//! `subtree` file. The session's upper directory then holds changes relative
+//! to it, which are reported at their real location by joining the two. While
+//! a command runs, the rest of the file system is the real one, only
+//! read-only.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    io::ErrorKind,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+
+use crate::{Error, IoErr, get_sessions_dir, path_undo::TmpPath, sessions::resolve_session};
+
+const RECORD: &str = "subtree";
+
+/// Records that a new session only overlays `subtree`, or checks that an
+/// existing session already does.
+pub fn assign(session: &mut PathBuf, subtree: &Path) -> Result<(), Error> {
+    let subtree = fs::canonicalize(subtree)
+        .map_io_err_lazy(|| format!("Failed to resolve path {}", subtree.display()))?;
+    if TmpPath::new(session, "diff").exists() {
+        let recorded = get(session)?;
+        return if recorded == subtree {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument).attach_printable(format!(
+                "This session overlays {}, not {}.",
+                recorded.display(),
+                subtree.display()
+            ))
+        };
+    }
+    if subtree == Path::new("/") {
+        return Ok(());
+    }
+
+    if !fs::metadata(&subtree)
+        .map_io_err_lazy(|| format!("Failed to stat {}", subtree.display()))?
+        .is_dir()
+    {
+        return Err(Error::InvalidArgument)
+            .attach_printable(format!("Root {} must be a directory.", subtree.display()));
+    }
+    fs::create_dir_all(&*session)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", session.display()))?;
+    record(session, &subtree)
+}
+
+/// Makes a copy of a session overlay the same directory as the original.
+pub fn inherit(source: &mut PathBuf, copy: &mut PathBuf) -> Result<(), Error> {
+    let subtree = get(source)?;
+    if subtree == Path::new("/") {
+        Ok(())
+    } else {
+        record(copy, &subtree)
+    }
+}
+
+fn record(session: &mut PathBuf, subtree: &Path) -> Result<(), Error> {
+    let record = TmpPath::new(session, RECORD);
+    fs::write(&record, subtree.as_os_str().as_bytes())
+        .map_io_err_lazy(|| format!("Failed to write {}", record.display()))
+}
+
+/// Returns the directory a session overlays, `/` unless it was created with a
+/// narrower one.
+pub fn get(session: &mut PathBuf) -> Result<PathBuf, Error> {
+    let record = TmpPath::new(session, RECORD);
+    match fs::read(&record) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(PathBuf::from("/")),
+        r => r
+            .map(|subtree| PathBuf::from(OsStr::from_bytes(&subtree)))
+            .map_io_err_lazy(|| format!("Failed to read {}", record.display())),
+    }
+}
+
+/// Returns the directory the named session overlays.
+pub fn of(session: &OsStr) -> Result<PathBuf, Error> {
+    let mut dir = get_sessions_dir()?;
+    dir.push(resolve_session(session)?);
+    get(&mut dir)
+}
+
+/// Returns where a real path is found in a session's upper directory, relative
+/// to it.
+pub fn relative<'a>(subtree: &Path, path: &'a Path) -> Result<&'a Path, Error> {
+    path.strip_prefix(subtree).or_else(|_| {
+        Err(Error::InvalidArgument).attach_printable(format!(
+            "{} is outside of the session's root {}.",
+            path.display(),
+            subtree.display()
+        ))
+    })
+}
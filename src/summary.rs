@@ -0,0 +1,60 @@
+//! Summaries of what a run did, for CI systems to publish as artifacts.
+
+use std::{ffi::OsStr, os::unix::process::ExitStatusExt, process::ExitStatus};
+
+use error_stack::Result;
+
+use crate::{ChangeKind, Concern, Error, apply::describe, diff, meta, output::v1, review};
+
+/// Sums up a command that ran in a session: how it exited, how the session
+/// changed while it ran, and which changes [`review_session`] flagged.
+///
+/// [`review_session`]: crate::review_session
+pub fn summarize(
+    session: impl AsRef<OsStr>,
+    command: &[impl AsRef<OsStr>],
+    status: ExitStatus,
+) -> Result<v1::RunSummary, Error> {
+    let session = session.as_ref();
+    let mut changes = v1::ChangeCounts::default();
+    for change in diff::changes(session)? {
+        *match change.kind {
+            ChangeKind::Added => &mut changes.added,
+            ChangeKind::Modified | ChangeKind::MetadataOnly => &mut changes.modified,
+            ChangeKind::Deleted => &mut changes.deleted,
+        } += 1;
+    }
+    // The run recorded the session's size as the command started
+    let (bytes_before, bytes_after) = meta::trend(session)?
+        .pop()
+        .map_or((0, 0), |run| (run.before.bytes, run.after.bytes));
+    let violations = review::review(session, &[])?
+        .into_iter()
+        .map(|finding| v1::Violation {
+            path: finding.path.to_string_lossy().into_owned(),
+            concern: match finding.concern {
+                Concern::SetId => "set-id",
+                Concern::Device => "device",
+                Concern::UnexpectedOwner { .. } => "unexpected-owner",
+                Concern::SensitivePath => "sensitive-path",
+            }
+            .to_string(),
+            description: describe(finding.concern).to_string(),
+        })
+        .collect();
+
+    Ok(v1::RunSummary {
+        version: v1::VERSION,
+        session: session.to_string_lossy().into_owned(),
+        command: command
+            .iter()
+            .map(|arg| arg.as_ref().to_string_lossy().into_owned())
+            .collect(),
+        exit_code: status.code(),
+        signal: status.signal(),
+        changes,
+        bytes_before,
+        bytes_after,
+        violations,
+    })
+}
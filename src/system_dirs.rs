@@ -0,0 +1,126 @@
+//! The real system directories sessions share with the host.
+//!
+//! `/proc`, `/dev`, `/run`, and `/tmp` are bound into a session's merged
+//! directory when it is activated, so that writes to them bypass the overlay.
+//! Sessions can be created without some of them, recorded in their `unbound`
+//! file, in which case those directories are part of the overlay like any
+//! other.
+
+use std::{fs, io::ErrorKind, path::PathBuf};
+
+use error_stack::{Result, ResultExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, IoErr, path_undo::TmpPath};
+
+const RECORD: &str = "unbound";
+
+/// A host directory shared with sessions rather than overlaid.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SystemDir {
+    Proc,
+    Dev,
+    Run,
+    Tmp,
+}
+
+impl SystemDir {
+    /// Every system directory, in the order they are bound.
+    pub const ALL: [Self; 4] = [Self::Proc, Self::Dev, Self::Run, Self::Tmp];
+
+    /// The directory's name in the root directory.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Proc => "proc",
+            Self::Dev => "dev",
+            Self::Run => "run",
+            Self::Tmp => "tmp",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|dir| dir.name() == name)
+    }
+}
+
+/// Records that a new session doesn't bind `unbound`, or checks that an
+/// existing session already doesn't.
+pub fn assign(session: &mut PathBuf, unbound: &[SystemDir]) -> Result<(), Error> {
+    let unbound = SystemDir::ALL
+        .into_iter()
+        .filter(|dir| unbound.contains(dir))
+        .collect::<Vec<_>>();
+    if TmpPath::new(session, "diff").exists() {
+        let recorded = get(session)?;
+        return if recorded == unbound {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument).attach_printable(format!(
+                "This session leaves {} unbound, not {}.",
+                describe(&recorded),
+                describe(&unbound)
+            ))
+        };
+    }
+    if unbound.is_empty() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(&*session)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", session.display()))?;
+    record(session, &unbound)
+}
+
+/// Makes a copy of a session bind the same system directories as the
+/// original.
+pub fn inherit(source: &mut PathBuf, copy: &mut PathBuf) -> Result<(), Error> {
+    let unbound = get(source)?;
+    if unbound.is_empty() {
+        Ok(())
+    } else {
+        record(copy, &unbound)
+    }
+}
+
+fn record(session: &mut PathBuf, unbound: &[SystemDir]) -> Result<(), Error> {
+    let record = TmpPath::new(session, RECORD);
+    let contents = unbound.iter().fold(String::new(), |mut contents, dir| {
+        contents.push_str(dir.name());
+        contents.push('\n');
+        contents
+    });
+    fs::write(&record, contents).map_io_err_lazy(|| format!("Failed to write {}", record.display()))
+}
+
+/// Returns the system directories a session doesn't bind, in the order they
+/// would otherwise be bound.
+pub fn get(session: &mut PathBuf) -> Result<Vec<SystemDir>, Error> {
+    let record = TmpPath::new(session, RECORD);
+    let contents = match fs::read_to_string(&record) {
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        r => r.map_io_err_lazy(|| format!("Failed to read {}", record.display()))?,
+    };
+    contents
+        .lines()
+        .map(|name| {
+            SystemDir::parse(name)
+                .ok_or(Error::InvalidArgument)
+                .attach_printable_lazy(|| {
+                    format!("Unknown system directory {name:?} in {}", record.display())
+                })
+        })
+        .collect()
+}
+
+fn describe(unbound: &[SystemDir]) -> String {
+    if unbound.is_empty() {
+        return "no system directory".to_string();
+    }
+    unbound
+        .iter()
+        .map(|dir| format!("/{}", dir.name()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
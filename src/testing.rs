@@ -0,0 +1,157 @@
+//! Throwaway sessions for testing forkfs and tools built on top of it.
+//!
+//! A [`Sandbox`] owns a uniquely named session that is deleted when it is
+//! dropped. Commands run in it are supervised rather than exec-ed so tests can
+//! then assert on the changes they left behind in the session.
+//!
+//! Sandboxes need the same privileges as `forkfs run`, so tests built on them
+//! should run as root or after `forkfs setup`.
+
+use std::{
+    ffi::OsStr,
+    fs,
+    os::{fd::OwnedFd, unix::ffi::OsStrExt},
+    path::{Path, PathBuf},
+    process::{self, ExitStatus},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::{
+    fs::{FileType, OFlags},
+    process::getuid,
+};
+
+use crate::{
+    Backend, Error, IoErr, Network, SessionOperand, delete_sessions, get_sessions_dir,
+    path_undo::TmpPath,
+    run::{Namespace, spawn_command, validate_permissions},
+    sessions::maybe_create_session,
+    walk,
+};
+
+/// An active session that is deleted on drop.
+pub struct Sandbox {
+    name: String,
+    dir: PathBuf,
+}
+
+impl Sandbox {
+    /// Creates and activates a new session that doesn't collide with any
+    /// other sandbox, in this process or another.
+    pub fn new() -> Result<Self, Error> {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+        validate_permissions(getuid())?;
+
+        let name = format!(
+            "forkfs-test-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut dir = get_sessions_dir()?;
+        dir.push(&name);
+        // Leftovers from a crashed process that happened to have the same pid
+        delete_sessions(SessionOperand::List(&[&name]), false).or_else(|e| {
+            match e.current_context() {
+                Error::SessionNotFound => Ok(()),
+                _ => Err(e),
+            }
+        })?;
+        maybe_create_session(&mut dir, None, Backend::Auto)?;
+        Ok(Self { name, dir })
+    }
+
+    /// The session's name, for use with the rest of the API.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Reactivates the session after it was stopped.
+    pub fn activate(&self) -> Result<(), Error> {
+        maybe_create_session(&mut self.dir.clone(), None, Backend::Auto)
+    }
+
+    /// Runs a command in the session and waits for it to exit.
+    pub fn run<T: AsRef<OsStr>>(&self, command: &[T]) -> Result<ExitStatus, Error> {
+        if command.is_empty() {
+            return Err(Error::InvalidArgument).attach_printable("No command to run.");
+        }
+        spawn_command(
+            command,
+            getuid(),
+            Namespace::new(
+                &self.dir.join("merged"),
+                Path::new("/"),
+                &[],
+                &[],
+                false,
+                Network::Host,
+                None,
+            )?,
+            None,
+        )?
+        .wait()
+        .map_io_err("Failed to wait for child")
+    }
+
+    /// Runs a shell script in the session and waits for it to exit.
+    pub fn run_script(&self, script: &str) -> Result<ExitStatus, Error> {
+        self.run(&["/bin/sh", "-c", script])
+    }
+
+    /// Returns the paths that were added, modified, or deleted in the session,
+    /// as seen from inside it and sorted.
+    ///
+    /// Directories only appear when they are empty since changes to their
+    /// contents are listed instead.
+    pub fn changes(&self) -> Result<Vec<PathBuf>, Error> {
+        let diff = self.dir.join("diff");
+        let fd = walk::open_root(&diff)
+            .map_io_err_lazy(|| format!("Failed to open directory {}", diff.display()))?;
+        let mut changes = Vec::new();
+        collect_changes(&fd, &mut PathBuf::from("/"), &mut changes)?;
+        changes.sort_unstable();
+        Ok(changes)
+    }
+
+    /// Reads a file as the session sees it.
+    pub fn read(&self, path: impl AsRef<Path>) -> Result<Vec<u8>, Error> {
+        let path = path.as_ref();
+        let merged = self
+            .dir
+            .join("merged")
+            .join(path.strip_prefix("/").unwrap_or(path));
+        fs::read(&merged).map_io_err_lazy(|| format!("Failed to read {}", path.display()))
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        drop(delete_sessions(SessionOperand::List(&[&self.name]), false));
+    }
+}
+
+fn collect_changes(
+    dir: &OwnedFd,
+    path: &mut PathBuf,
+    changes: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    let entries = walk::entries(dir)
+        .map_io_err_lazy(|| format!("Failed to read directory {}", path.display()))?;
+    if entries.is_empty() && path.as_os_str() != "/" {
+        changes.push(path.clone());
+    }
+    for (name, file_type) in entries {
+        let mut path = TmpPath::new(path, OsStr::from_bytes(name.as_bytes()));
+        if file_type == FileType::Directory {
+            let child = walk::open_beneath(dir, &name, OFlags::RDONLY | OFlags::DIRECTORY)
+                .map_io_err_lazy(|| format!("Failed to open directory {}", path.display()))?;
+            collect_changes(&child, &mut path, changes)?;
+        } else {
+            changes.push(path.to_path_buf());
+        }
+    }
+    Ok(())
+}
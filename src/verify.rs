@@ -0,0 +1,109 @@
+//! Comparing a session's changes with those of a known-good run.
+//!
+//! A manifest is what `forkfs sessions diff` prints: one change per line, an
+//! `A`, `M`, or `D` followed by the path as seen from inside the session.
+//! Recording it for a session an install script is known to have run
+//! correctly in lets later runs be checked for regressions. Blank lines,
+//! lines starting with `#`, and the indented details `--content` and
+//! `--structural` add are ignored.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+
+use crate::{ChangeKind, Error, IoErr, diff::changes};
+
+/// Appended to the paths `forkfs sessions diff` prints for files that were
+/// also changed outside the session.
+const DIVERGED_SUFFIX: &[u8] = b" (changed outside the session)";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeviationKind {
+    /// The session made a change the manifest doesn't list.
+    Unexpected,
+    /// The manifest lists a change the session didn't make.
+    Missing,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Deviation {
+    /// The changed path as seen from inside the session.
+    pub path: PathBuf,
+    pub change: ChangeKind,
+    pub kind: DeviationKind,
+}
+
+/// Lists the differences between a session's changes and those recorded in
+/// `manifest`, sorted by path.
+///
+/// A path the session changed differently than the manifest says, such as
+/// modifying a file it was expected to add, is reported both as a missing
+/// and an unexpected change.
+pub fn verify(session: impl AsRef<OsStr>, manifest: &Path) -> Result<Vec<Deviation>, Error> {
+    let expected = parse(manifest)?;
+    let actual = changes(session)?
+        .into_iter()
+        .map(|change| (change.path, change.kind))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut deviations = actual
+        .iter()
+        .filter(|&(path, change)| expected.get(path) != Some(change))
+        .map(|(path, &change)| (path, change, DeviationKind::Unexpected))
+        .chain(
+            expected
+                .iter()
+                .filter(|&(path, change)| actual.get(path) != Some(change))
+                .map(|(path, &change)| (path, change, DeviationKind::Missing)),
+        )
+        .map(|(path, change, kind)| Deviation {
+            path: path.clone(),
+            change,
+            kind,
+        })
+        .collect::<Vec<_>>();
+    deviations.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(deviations)
+}
+
+fn parse(manifest: &Path) -> Result<BTreeMap<PathBuf, ChangeKind>, Error> {
+    let contents = fs::read(manifest)
+        .map_io_err_lazy(|| format!("Failed to read manifest {}", manifest.display()))?;
+    let mut changes = BTreeMap::new();
+    for (i, line) in contents.split(|&b| b == b'\n').enumerate() {
+        if line.is_empty() || line[0] == b'#' || line[0].is_ascii_whitespace() {
+            continue;
+        }
+        let change = match line.get(..2) {
+            Some(b"A ") => ChangeKind::Added,
+            Some(b"M ") => ChangeKind::Modified,
+            Some(b"D ") => ChangeKind::Deleted,
+            _ => {
+                return Err(Error::InvalidArgument).attach_printable(format!(
+                    "Line {} of {} doesn't start with A, M, or D: {}",
+                    i + 1,
+                    manifest.display(),
+                    String::from_utf8_lossy(line)
+                ));
+            }
+        };
+        let path = &line[2..];
+        let path = path.strip_suffix(DIVERGED_SUFFIX).unwrap_or(path);
+        if !path.starts_with(b"/") {
+            return Err(Error::InvalidArgument).attach_printable(format!(
+                "Line {} of {} doesn't name an absolute path: {}",
+                i + 1,
+                manifest.display(),
+                String::from_utf8_lossy(line)
+            ));
+        }
+        changes.insert(PathBuf::from(OsStr::from_bytes(path)), change);
+    }
+    Ok(changes)
+}
@@ -0,0 +1,112 @@
+//! Sessions whose changes are kept in memory instead of on disk.
+//!
+//! Volatile storage is picked when the session is created and recorded by an
+//! empty `volatile` file. overlayfs needs its upper and work directories on
+//! the same mount, so both live in a tmpfs mounted at the session's `storage`
+//! directory and are bind mounted over `diff` and `work` for everything else
+//! that reads them. The tmpfs stays mounted until the session is deleted: the
+//! changes survive stopping the session, but not a reboot.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::mount::{MountFlags, UnmountFlags, mount, mount_bind, unmount};
+
+use crate::{Error, IoErr, path_undo::TmpPath, sessions::is_mount_point};
+
+const RECORD: &str = "volatile";
+const MOUNT_POINT: &str = "storage";
+const LAYERS: [&str; 2] = ["diff", "work"];
+
+/// Records that a new session keeps its changes in memory, or checks that an
+/// existing session already does.
+pub fn assign(session: &mut PathBuf) -> Result<(), Error> {
+    if TmpPath::new(session, "diff").exists() {
+        return if is_volatile(session) {
+            Ok(())
+        } else {
+            Err(Error::InvalidArgument).attach_printable(
+                "Only new sessions can use volatile storage, but this session already exists.",
+            )
+        };
+    }
+
+    fs::create_dir_all(&*session)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", session.display()))?;
+    let record = TmpPath::new(session, RECORD);
+    fs::write(&record, "").map_io_err_lazy(|| format!("Failed to write {}", record.display()))
+}
+
+/// Whether a session keeps its changes in memory.
+pub fn is_volatile(session: &mut PathBuf) -> bool {
+    TmpPath::new(session, RECORD).exists()
+}
+
+/// Returns the directory holding the upper and work directories overlays of
+/// a session should use, which is the session's own unless it is volatile.
+pub fn storage(session: &mut PathBuf) -> PathBuf {
+    if is_volatile(session) {
+        session.join(MOUNT_POINT)
+    } else {
+        session.clone()
+    }
+}
+
+/// Mounts a volatile session's storage unless it already is, returning
+/// [`storage()`].
+pub fn mount_storage(session: &mut PathBuf) -> Result<PathBuf, Error> {
+    if !is_volatile(session) {
+        return Ok(session.clone());
+    }
+    let storage = storage(session);
+    fs::create_dir_all(&storage)
+        .map_io_err_lazy(|| format!("Failed to create directory {}", storage.display()))?;
+    if is_mount_point(&storage)? {
+        return Ok(storage);
+    }
+
+    mount(
+        c"tmpfs",
+        &storage,
+        c"tmpfs",
+        MountFlags::NOSUID,
+        c"mode=0755",
+    )
+    .map_io_err_lazy(|| format!("Failed to mount tmpfs at {}", storage.display()))?;
+    let result = LAYERS.into_iter().try_for_each(|layer| {
+        let source = storage.join(layer);
+        fs::create_dir(&source)
+            .map_io_err_lazy(|| format!("Failed to create directory {}", source.display()))?;
+        let target = TmpPath::new(session, layer);
+        mount_bind(&source, &*target)
+            .map_io_err_lazy(|| format!("Failed to bind mount {}", target.display()))
+    });
+    if result.is_err() {
+        drop(unmount_storage(session));
+    }
+    result.map(|()| storage)
+}
+
+/// Unmounts a volatile session's storage if it is mounted, discarding its
+/// changes.
+pub fn unmount_storage(session: &mut PathBuf) -> Result<(), Error> {
+    let storage = session.join(MOUNT_POINT);
+    if !storage.exists() || !is_mount_point(&storage)? {
+        return Ok(());
+    }
+    for layer in LAYERS {
+        let target = TmpPath::new(session, layer);
+        if is_mount_point(&target)? {
+            unmount_dir(&target)?;
+        }
+    }
+    unmount_dir(&storage)
+}
+
+fn unmount_dir(dir: &Path) -> Result<(), Error> {
+    unmount(dir, UnmountFlags::DETACH)
+        .map_io_err_lazy(|| format!("Failed to unmount directory {}", dir.display()))
+}
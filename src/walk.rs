@@ -0,0 +1,85 @@
+//! Directory traversal relative to directory file descriptors.
+//!
+//! Walking a tree through absolute paths breaks once they exceed `PATH_MAX`
+//! and lets a sandboxed program that swaps a directory for a symlink mid-walk
+//! redirect us to arbitrary host files. Each entry is instead opened relative
+//! to its parent and never through a symlink.
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    ffi::CString,
+    os::fd::{AsFd, OwnedFd},
+    path::{Path, PathBuf},
+};
+
+use rustix::{
+    fs::{AtFlags, CWD, Dir, FileType, Mode, OFlags, ResolveFlags, Stat, openat, openat2, statat},
+    io,
+};
+
+/// Opens the root of a walk.
+pub fn open_root(dir: &Path) -> io::Result<OwnedFd> {
+    openat(
+        CWD,
+        dir,
+        OFlags::RDONLY | OFlags::DIRECTORY | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+    )
+}
+
+/// Opens the entry `name` of `dir` without following symlinks or leaving
+/// `dir`.
+pub fn open_beneath(dir: impl AsFd, name: &CString, flags: OFlags) -> io::Result<OwnedFd> {
+    openat2(
+        dir,
+        name.as_c_str(),
+        flags | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::empty(),
+        ResolveFlags::BENEATH | ResolveFlags::NO_SYMLINKS | ResolveFlags::NO_MAGICLINKS,
+    )
+}
+
+/// Lists the entries of `dir` other than `.` and `..`.
+pub fn entries(dir: impl AsFd) -> io::Result<Vec<(CString, FileType)>> {
+    let mut entries = Vec::new();
+    for entry in Dir::read_from(&dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name == c"." || name == c".." {
+            continue;
+        }
+        let file_type = match entry.file_type() {
+            // Not every file system fills in d_type
+            FileType::Unknown => {
+                FileType::from_raw_mode(statat(&dir, name, AtFlags::SYMLINK_NOFOLLOW)?.st_mode)
+            }
+            file_type => file_type,
+        };
+        entries.push((name.to_owned(), file_type));
+    }
+    Ok(entries)
+}
+
+/// The files with several hard links seen so far in a walk, so that copies of
+/// the tree can link to the first copy instead of duplicating the data.
+#[derive(Default)]
+pub struct Links {
+    copies: HashMap<(u64, u64), PathBuf>,
+}
+
+impl Links {
+    /// Returns the copy made of the file at `stat` earlier in the walk, or
+    /// remembers `copy` as the one to link to if there is none yet.
+    pub fn first_copy(&mut self, stat: &Stat, copy: &Path) -> Option<&Path> {
+        if stat.st_nlink < 2 {
+            return None;
+        }
+        match self.copies.entry((stat.st_dev, stat.st_ino)) {
+            Entry::Occupied(first) => Some(first.into_mut()),
+            Entry::Vacant(entry) => {
+                entry.insert(copy.to_path_buf());
+                None
+            }
+        }
+    }
+}
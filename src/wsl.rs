@@ -0,0 +1,68 @@
+//! Accommodations for the Windows Subsystem for Linux.
+//!
+//! WSL2 mounts Windows drives, such as `/mnt/c`, over 9p. Overlayfs can't store
+//! a session's changes on them and doesn't look through the mounts of its lower
+//! layer, so the drives would otherwise show up empty inside sessions.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use error_stack::{Result, ResultExt};
+use rustix::fs::{AtFlags, CWD, StatxFlags, statx};
+
+use crate::{Error, IoErr, mountinfo, mountinfo::Mount};
+
+/// Whether we're running under WSL, as reported by the kernel.
+pub fn is_wsl() -> bool {
+    static IS_WSL: OnceLock<bool> = OnceLock::new();
+    *IS_WSL.get_or_init(|| {
+        fs::read_to_string("/proc/sys/kernel/osrelease")
+            .is_ok_and(|release| release.to_ascii_lowercase().contains("microsoft"))
+    })
+}
+
+/// Returns where Windows drives are mounted, which is nowhere outside WSL.
+pub fn windows_drives() -> Result<Vec<PathBuf>, Error> {
+    if !is_wsl() {
+        return Ok(Vec::new());
+    }
+    Ok(mountinfo::mount_tree(Path::new("/"))?
+        .into_iter()
+        .filter(is_windows_drive)
+        .map(|mount| mount.path)
+        .collect())
+}
+
+/// Refuses to keep a session on a Windows drive, which overlayfs would only
+/// reject with an unhelpful error once mounting.
+pub fn check_session_location(session: &Path) -> Result<(), Error> {
+    if !is_wsl() {
+        return Ok(());
+    }
+    let id = statx(CWD, session, AtFlags::empty(), StatxFlags::MNT_ID)
+        .map_io_err_lazy(|| format!("Failed to stat {}", session.display()))?
+        .stx_mnt_id;
+    if mountinfo::mount_tree(Path::new("/"))?
+        .iter()
+        .any(|mount| mount.id == id && is_windows_drive(mount))
+    {
+        return Err(Error::InvalidArgument).attach_printable(format!(
+            "Session {} is on a Windows drive, which can't hold a session's changes. Use the \
+             `sessions-dir` setting to keep sessions in the Linux file system instead.",
+            session.display()
+        ));
+    }
+    Ok(())
+}
+
+fn is_windows_drive(mount: &Mount) -> bool {
+    mount.fs_type == "drvfs"
+        || mount.fs_type == "9p"
+            && mount
+                .fs_options
+                .split([',', ';'])
+                .any(|option| option == "aname=drvfs")
+}
@@ -0,0 +1,2495 @@
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::Write,
+    os::unix::fs::{FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    slice, thread,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use forkfs::{
+    Change, ChangeKind, ChangeStats, CommandRecord, Concern, Config, ConflictVersion, Deviation,
+    DeviationKind, DiffPage, DiffQuery, Drift, DriftKind, Error, ExportFilter, FakeClock,
+    FaultPoint, Finding, GarbageKind, Issue, Network, Omission, OmissionKind, OverlayOption,
+    Problem, Redaction, Resolution, Restart, RunOp, Session, SessionOperand, Viewer, abort_apply,
+    apply_resolved, apply_session, attach_process, check_session, clone_session, collect_garbage,
+    conflict_report, create_snapshot, current_session, delete_sessions, detached_processes,
+    diff_query, export_filtered_session, export_patch_series, export_redacted_session,
+    export_session, export_to_store, import_from_store, import_session, inject_fault, iter_changes,
+    iter_sessions, rename_session, restore_snapshot, resume_apply, revert_paths, review_session,
+    seed_session, session_conflicts, session_diff, session_drift, session_info, session_listing,
+    session_mounts, session_snapshots, session_status, session_trend, set_clock, stop_process,
+    stop_sessions, testing::Sandbox, unmount_abandoned_sessions, verify_session,
+};
+
+fn sandbox() -> Option<Sandbox> {
+    match Sandbox::new() {
+        Ok(sandbox) => Some(sandbox),
+        Err(e) if matches!(e.current_context(), Error::SetupRequired) => None,
+        Err(e) => panic!("{e:?}"),
+    }
+}
+
+#[test]
+fn writes_are_captured() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let file = format!("/root/{}", sandbox.name());
+
+    let status = sandbox.run_script(&format!("echo hello > {file}")).unwrap();
+
+    assert!(status.success());
+    assert_eq!(sandbox.changes().unwrap(), [PathBuf::from(&file)]);
+    assert_eq!(sandbox.read(&file).unwrap(), b"hello\n");
+    assert!(!PathBuf::from(file).exists());
+}
+
+#[test]
+fn diff_accepts_session_indices_and_prefixes() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let clone = format!("{}-diffed-by-prefix", sandbox.name());
+    let index = || {
+        session_listing()
+            .unwrap()
+            .sessions
+            .into_iter()
+            .find(|session| session.name == sandbox.name())
+            .unwrap()
+            .index
+    };
+
+    let status = sandbox.run_script("echo hi > /opt/indexed").unwrap();
+    clone_session(sandbox.name(), &clone).unwrap();
+    let by_prefix = session_diff(&clone[..clone.len() - 3]);
+    delete_sessions(SessionOperand::List(&[&clone]), false).unwrap();
+    // Sessions of tests running alongside shift the indices around
+    let by_index = (0..10)
+        .find_map(|_| {
+            let before = index();
+            let changes = session_diff(before.to_string());
+            (index() == before).then_some(changes)
+        })
+        .unwrap();
+
+    assert!(status.success());
+    let expected = session_diff(sandbox.name()).unwrap();
+    assert_eq!(by_prefix.unwrap(), expected);
+    assert_eq!(by_index.unwrap(), expected);
+}
+
+#[test]
+fn listing_reports_session_size() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let size = || {
+        session_listing()
+            .unwrap()
+            .sessions
+            .into_iter()
+            .find(|session| session.name == sandbox.name())
+            .unwrap()
+            .size
+    };
+    let before = size();
+
+    let status = sandbox
+        .run_script(&format!(
+            "head -c 65536 /dev/zero > /root/{}",
+            sandbox.name()
+        ))
+        .unwrap();
+
+    assert!(status.success());
+    assert!(size() >= before + 65536);
+}
+
+#[test]
+fn iterated_sessions_match_the_listing() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    sandbox.activate().unwrap();
+
+    let entry = iter_sessions()
+        .unwrap()
+        // Sessions of tests running alongside may vanish part way through
+        .filter_map(Result::ok)
+        .find(|entry| entry.name == sandbox.name())
+        .unwrap();
+    let listed = session_listing()
+        .unwrap()
+        .sessions
+        .into_iter()
+        .find(|session| session.name == sandbox.name())
+        .unwrap();
+
+    assert!(entry.active && listed.active);
+    assert_eq!(entry.size, listed.size);
+    assert_eq!(entry.created, listed.created);
+    assert!(entry.path.ends_with(sandbox.name()));
+    assert!(entry.path.join("merged").is_dir());
+    assert!(entry.modified > 0);
+}
+
+#[test]
+fn status_reports_mounts_and_changes() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = sandbox
+        .run_script(&format!("echo hello > /root/{}", sandbox.name()))
+        .unwrap();
+
+    assert!(status.success());
+    let status = session_status(sandbox.name()).unwrap();
+    assert!(status.active);
+    assert!(status.mount_id.is_some());
+    assert!(
+        status
+            .mounts
+            .iter()
+            .any(|mount| mount.path == Path::new("/proc"))
+    );
+    assert_eq!(status.changes, 1);
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+    let status = session_status(sandbox.name()).unwrap();
+    assert!(!status.active);
+    assert_eq!(status.mount_id, None);
+    assert!(status.mounts.is_empty());
+    assert_eq!(status.changes, 1);
+}
+
+#[test]
+fn status_counts_the_inodes_small_files_take_up() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let before = session_status(sandbox.name()).unwrap().inodes;
+
+    let status = sandbox
+        .run_script(&format!(
+            "mkdir /root/{0} && for i in $(seq 100); do : > /root/{0}/$i; done",
+            sandbox.name()
+        ))
+        .unwrap();
+
+    assert!(status.success());
+    let status = session_status(sandbox.name()).unwrap();
+    assert!(status.inodes >= before + 101, "{status:?}");
+    assert!(status.size < 101 * 4096, "{status:?}");
+}
+
+#[test]
+fn shells_know_their_session() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let mut child = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["shell", "--session", sandbox.name()])
+        .env("SHELL", "/bin/sh")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"echo \"$FORKFS_SESSION\"; exit 3\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    assert_eq!(output.status.code(), Some(3), "{output:?}");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\n", sandbox.name())
+    );
+}
+
+#[test]
+fn status_names_the_session_it_runs_in() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let rooted = format!("{}-rooted", sandbox.name());
+    let root = PathBuf::from(format!("/var/tmp/forkfs-status-{}", sandbox.name()));
+    fs::create_dir_all(&root).unwrap();
+    let status = |args: &[&str]| {
+        process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+            .args(args)
+            .args(["--", env!("CARGO_BIN_EXE_forkfs"), "status"])
+            .output()
+            .unwrap()
+    };
+
+    let inside = status(&["run", "--session", sandbox.name()]);
+    let subtree = status(&[
+        "run",
+        "--session",
+        &rooted,
+        "--root",
+        root.to_str().unwrap(),
+    ]);
+    let outside = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .arg("status")
+        .output()
+        .unwrap();
+    delete_sessions(SessionOperand::List(&[&rooted]), false).unwrap();
+    fs::remove_dir_all(&root).unwrap();
+
+    assert!(inside.status.success(), "{inside:?}");
+    assert_eq!(
+        String::from_utf8_lossy(&inside.stdout),
+        format!("{}\n", sandbox.name())
+    );
+    assert!(subtree.status.success(), "{subtree:?}");
+    assert_eq!(
+        String::from_utf8_lossy(&subtree.stdout),
+        format!("{rooted}\n")
+    );
+    assert!(!outside.status.success());
+    assert!(outside.stdout.is_empty());
+    assert_eq!(current_session().unwrap(), None);
+}
+
+#[test]
+fn session_handles_stop_sessions_on_drop() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let name = format!("{}-handle", sandbox.name());
+    let file = format!("/root/{name}");
+
+    let session = Session::open(&name).unwrap();
+    let active = session.activate().unwrap();
+    let activated = session_status(&session).map(|status| status.active);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            active.enter().unwrap();
+            fs::write(&file, "hello").unwrap();
+        });
+    });
+    let entered = session_status(&session).map(|status| (status.active, status.changes));
+    let dropped = session
+        .activate()
+        .map(drop)
+        .and_then(|()| session_status(&session));
+    delete_sessions(SessionOperand::List(&[&name]), false).unwrap();
+
+    assert!(activated.unwrap());
+    assert!(!Path::new(&file).exists());
+    assert_eq!(entered.unwrap(), (true, 1));
+    assert!(!dropped.unwrap().active);
+}
+
+#[test]
+fn binds_mount_host_paths_elsewhere() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let source = PathBuf::from(format!("/var/tmp/forkfs-bind-{}", sandbox.name()));
+    fs::create_dir_all(&source).unwrap();
+    fs::write(source.join("data"), "hello\n").unwrap();
+    let bind = |target: &str| format!("{}:/mnt/{}/{target}", source.display(), sandbox.name());
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["run", "--session", sandbox.name()])
+        .args(["--bind", &format!("{}:ro", bind("ro"))])
+        .args(["--bind", &bind("rw"), "--", "sh", "-c"])
+        .arg(format!(
+            "cd /mnt/{}; cat ro/data; echo ro > ro/data || echo bye > rw/data",
+            sandbox.name()
+        ))
+        .output()
+        .unwrap();
+    let data = fs::read_to_string(source.join("data"));
+    fs::remove_dir_all(&source).unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, b"hello\n");
+    assert_eq!(data.unwrap(), "bye\n");
+}
+
+#[test]
+fn unbound_system_dirs_are_overlaid() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-unbound", sandbox.name());
+    let file = format!("/tmp/{session}");
+    let run = |session: &str| {
+        process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+            .args(["run", "--session", session, "--no-bind", "tmp", "--"])
+            .args(["sh", "-c", &format!("echo hello > {file}")])
+            .output()
+            .unwrap()
+    };
+
+    let unbound = run(&session);
+    let changes = session_diff(&session);
+    delete_sessions(SessionOperand::List(&[&session]), false).unwrap();
+    let bound = run(sandbox.name());
+
+    assert!(unbound.status.success(), "{unbound:?}");
+    assert!(!Path::new(&file).exists());
+    assert!(
+        changes
+            .unwrap()
+            .iter()
+            .any(|change| change.path == Path::new(&file))
+    );
+    assert!(!bound.status.success());
+    assert!(
+        String::from_utf8_lossy(&bound.stderr).contains("leaves no system directory unbound"),
+        "{bound:?}"
+    );
+}
+
+#[test]
+fn overlay_options_are_passed_to_overlayfs() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-options", sandbox.name());
+    let run = |session: &str| {
+        RunOp::builder()
+            .session(session.as_ref())
+            .command(&["true"])
+            .overlay_options(&[OverlayOption::Metacopy, OverlayOption::Index])
+            .detach(true)
+            .build()
+            .run()
+    };
+
+    let status = run(&session);
+    attach_process(&session, None, &mut Vec::new()).unwrap();
+    let mounts = session_mounts(&session);
+    delete_sessions(SessionOperand::List(&[&session]), false).unwrap();
+    let existing = run(sandbox.name()).unwrap_err();
+
+    assert!(status.unwrap().success());
+    let options = &mounts.unwrap()[0].fs_options;
+    assert!(options.contains("metacopy=on"), "{options}");
+    assert!(options.contains("index=on"), "{options}");
+    assert!(matches!(existing.current_context(), Error::InvalidArgument));
+}
+
+#[test]
+fn volatile_sessions_keep_changes_in_memory() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-volatile", sandbox.name());
+    let file = format!("/root/{session}");
+    let run = |session: &str| {
+        process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+            .args(["run", "--session", session, "--volatile-storage", "--"])
+            .args(["sh", "-c", &format!("echo hello > {file}")])
+            .output()
+            .unwrap()
+    };
+
+    let volatile = run(&session);
+    stop_sessions(SessionOperand::List(&[&session])).unwrap();
+    let changes = session_diff(&session);
+    let mounts = fs::read_to_string("/proc/self/mountinfo").unwrap();
+    delete_sessions(SessionOperand::List(&[&session]), false).unwrap();
+    let remaining = fs::read_to_string("/proc/self/mountinfo").unwrap();
+    let existing = run(sandbox.name());
+
+    assert!(volatile.status.success(), "{volatile:?}");
+    assert!(!Path::new(&file).exists());
+    assert!(
+        changes
+            .unwrap()
+            .iter()
+            .any(|change| change.path == Path::new(&file))
+    );
+    let storage = format!("/{session}/storage ");
+    assert!(mounts.lines().any(|mount| mount.contains(&storage)));
+    assert!(!remaining.contains(&storage));
+    assert!(!existing.status.success());
+    assert!(
+        String::from_utf8_lossy(&existing.stderr).contains("this session already exists"),
+        "{existing:?}"
+    );
+}
+
+#[test]
+fn pipelines_finish_every_step_or_leave_nothing_behind() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-pipeline", sandbox.name());
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-pipeline-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+    let archive = dir.join("changes.tar");
+    let run = |name: &str, last: &str| {
+        let file = dir.join(format!("{name}.toml"));
+        fs::write(
+            &file,
+            format!(
+                r#"
+                session = "{session}"
+                template = "{}"
+
+                [[steps]]
+                op = "run"
+                command = ["sh", "-c", "echo hello > /opt/pipeline"]
+
+                [[steps]]
+                op = "export"
+                path = "{}"
+
+                [[steps]]
+                {last}
+                "#,
+                sandbox.name(),
+                archive.display()
+            ),
+        )
+        .unwrap();
+        process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+            .arg("pipeline")
+            .arg(file)
+            .output()
+            .unwrap()
+    };
+
+    let failed = run("failing", "op = \"run\"\ncommand = [\"false\"]");
+    let failed_leftovers = (archive.exists(), session_diff(&session).is_ok());
+    let finished = run("finishing", r#"op = "delete""#);
+    let exported = fs::read(&archive);
+    let finished_leftovers = session_diff(&session).is_ok();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(!failed.status.success());
+    assert!(
+        String::from_utf8_lossy(&failed.stderr).contains("[3/3] failed"),
+        "{failed:?}"
+    );
+    assert_eq!(failed_leftovers, (false, false));
+    assert!(finished.status.success(), "{finished:?}");
+    assert!(
+        exported
+            .unwrap()
+            .windows(b"opt/pipeline".len())
+            .any(|name| name == b"opt/pipeline")
+    );
+    assert!(!finished_leftovers);
+}
+
+#[test]
+fn ci_summaries_describe_the_run() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-ci", sandbox.name());
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-ci-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+    let summary = dir.join("summary.md");
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["run", "--session", &session, "--ci-summary"])
+        .arg(&summary)
+        .args([
+            "--",
+            "sh",
+            "-c",
+            "echo hello > /etc/profile.d/ci.sh; exit 3",
+        ])
+        .output()
+        .unwrap();
+    let markdown = fs::read_to_string(&summary);
+    let json = fs::read_to_string(summary.with_extension("json"));
+    delete_sessions(SessionOperand::List(&[&session]), false).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(output.status.code(), Some(3), "{output:?}");
+    let markdown = markdown.unwrap();
+    assert!(markdown.contains("exited with 3"), "{markdown}");
+    assert!(markdown.contains("/etc/profile.d/ci.sh"), "{markdown}");
+    let json = json.unwrap();
+    for field in [
+        r#""exit-code": 3"#,
+        r#""added": 1"#,
+        r#""concern": "sensitive-path""#,
+    ] {
+        assert!(json.contains(field), "{json}");
+    }
+}
+
+#[test]
+fn sessions_dir_can_be_overridden() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-sessions-{}", sandbox.name()));
+    let forkfs = |args: &[&str]| {
+        process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+            .env("FORKFS_DATA_DIR", &dir)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let ran = forkfs(&["run", "--session", "elsewhere", "--", "true"]);
+    let stored = dir.join("elsewhere/diff").is_dir();
+    let listed = forkfs(&["sessions", "list"]);
+    let deleted = forkfs(&[
+        "--sessions-dir",
+        "/nonexistent",
+        "sessions",
+        "delete",
+        "elsewhere",
+    ]);
+    let kept = dir.join("elsewhere").exists();
+    forkfs(&["sessions", "delete", "elsewhere"]);
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(ran.status.success(), "{ran:?}");
+    assert!(stored);
+    assert!(
+        String::from_utf8_lossy(&listed.stdout).contains("elsewhere"),
+        "{listed:?}"
+    );
+    assert!(!deleted.status.success());
+    assert!(kept);
+    assert!(
+        !session_listing()
+            .unwrap()
+            .sessions
+            .iter()
+            .any(|s| s.name == "elsewhere")
+    );
+}
+
+#[test]
+fn config_file_defaults_apply_to_runs() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-configured", sandbox.name());
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-config-{}", sandbox.name()));
+    fs::create_dir_all(dir.join("forkfs")).unwrap();
+    fs::write(
+        dir.join("forkfs/config.toml"),
+        format!("session = \"{session}\"\n\n[defaults]\nno-bind = [\"tmp\"]\n"),
+    )
+    .unwrap();
+    let file = format!("/tmp/{session}");
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .env("XDG_CONFIG_HOME", &dir)
+        .args(["run", "--", "sh", "-c", &format!("echo hello > {file}")])
+        .output()
+        .unwrap();
+    let changes = session_diff(&session);
+    drop(delete_sessions(SessionOperand::List(&[&session]), false));
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    assert!(!Path::new(&file).exists());
+    assert!(
+        changes
+            .unwrap()
+            .iter()
+            .any(|change| change.path == Path::new(&file))
+    );
+}
+
+#[test]
+fn sessions_are_only_discarded_after_successful_runs() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-discard", sandbox.name());
+    let run = |args: &[&str], command: &str| {
+        process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+            .arg("run")
+            .args(args)
+            .args(["--", "sh", "-c", command])
+            .output()
+            .unwrap()
+    };
+
+    let failed = run(&["--session", &session, "--discard-on-success"], "exit 2");
+    let kept = session_diff(&session).is_ok();
+    let succeeded = run(&["--session", &session, "--discard-on-success"], "true");
+    let discarded = session_diff(&session).is_err();
+    let ephemeral = run(&["--ephemeral", "--keep-on-failure"], "exit 3");
+    let stderr = String::from_utf8_lossy(&ephemeral.stderr);
+    let debugged = stderr
+        .split_once("Kept session ")
+        .and_then(|(_, rest)| rest.split_once(" for debugging"))
+        .map(|(name, _)| name.to_string());
+    let debuggable = debugged.as_deref().map(session_diff);
+    if let Some(name) = &debugged {
+        delete_sessions(SessionOperand::List(&[name]), false).unwrap();
+    }
+
+    assert_eq!(failed.status.code(), Some(2), "{failed:?}");
+    assert!(kept);
+    assert!(succeeded.status.success(), "{succeeded:?}");
+    assert!(discarded);
+    assert_eq!(ephemeral.status.code(), Some(3), "{ephemeral:?}");
+    assert!(debuggable.is_some_and(|diff| diff.is_ok()), "{ephemeral:?}");
+}
+
+#[test]
+fn mounts_include_the_overlay_and_binds() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let mounts = session_mounts(sandbox.name()).unwrap();
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+
+    assert_eq!(mounts[0].path, Path::new("/"));
+    assert_eq!(mounts[0].fs_type, "overlay");
+    assert!(mounts[0].fs_options.contains("lowerdir=/"));
+    let proc = mounts
+        .iter()
+        .find(|mount| mount.path == Path::new("/proc"))
+        .unwrap();
+    assert_eq!(proc.fs_type, "proc");
+    assert_eq!(proc.parent, mounts[0].id);
+    assert!(session_mounts(sandbox.name()).unwrap().is_empty());
+}
+
+#[test]
+fn only_inactive_sessions_can_be_renamed() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let renamed = format!("{}-renamed", sandbox.name());
+
+    let active = rename_session(sandbox.name(), &renamed).unwrap_err();
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+    rename_session(sandbox.name(), &renamed).unwrap();
+    let names = session_listing()
+        .unwrap()
+        .sessions
+        .into_iter()
+        .map(|session| session.name)
+        .collect::<Vec<_>>();
+    rename_session(&renamed, sandbox.name()).unwrap();
+
+    assert!(matches!(active.current_context(), Error::InvalidArgument));
+    assert!(names.contains(&renamed));
+    assert!(!names.iter().any(|name| name == sandbox.name()));
+}
+
+#[test]
+fn clones_start_with_the_same_changes() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let clone = format!("{}-clone", sandbox.name());
+
+    let status = sandbox
+        .run_script(&format!(
+            "echo hello > /root/{}; rm /etc/hostname; rm -r /etc/default; mkdir /etc/default",
+            sandbox.name()
+        ))
+        .unwrap();
+    assert!(status.success());
+    clone_session(sandbox.name(), &clone).unwrap();
+    let conflict = clone_session(sandbox.name(), &clone).unwrap_err();
+    let changes = session_diff(&clone);
+    delete_sessions(SessionOperand::List(&[&clone]), false).unwrap();
+
+    assert_eq!(changes.unwrap(), session_diff(sandbox.name()).unwrap());
+    assert!(matches!(conflict.current_context(), Error::Conflict));
+}
+
+#[test]
+fn many_sessions_are_deleted_at_once() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let clones = (0..4)
+        .map(|i| format!("{}-clone-{i}", sandbox.name()))
+        .collect::<Vec<_>>();
+
+    assert!(
+        sandbox
+            .run_script("head -c 100000 /dev/urandom > /opt/random")
+            .unwrap()
+            .success()
+    );
+    for clone in &clones {
+        clone_session(sandbox.name(), clone).unwrap();
+    }
+    delete_sessions(SessionOperand::List(&clones), true).unwrap();
+
+    let sessions = session_listing().unwrap().sessions;
+    assert!(
+        sessions
+            .iter()
+            .all(|session| !clones.contains(&session.name))
+    );
+    assert!(
+        sessions
+            .iter()
+            .any(|session| session.name == sandbox.name())
+    );
+}
+
+#[test]
+fn reverted_paths_show_the_real_files_again() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let file = PathBuf::from(format!("/root/{}", sandbox.name()));
+    let hidden = PathBuf::from(format!("/etc/default/{}", sandbox.name()));
+
+    let status = sandbox
+        .run_script(&format!(
+            "echo hello > {}; rm /etc/hostname; rm -r /etc/default; mkdir /etc/default; touch {}",
+            file.display(),
+            hidden.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    let reverted = revert_paths(
+        sandbox.name(),
+        &[file.clone(), PathBuf::from("/etc/hostname")],
+    )
+    .unwrap();
+    let refused = revert_paths(sandbox.name(), slice::from_ref(&hidden)).unwrap_err();
+
+    assert_eq!(reverted, [file, PathBuf::from("/etc/hostname")]);
+    assert!(matches!(refused.current_context(), Error::InvalidArgument));
+    assert!(
+        session_diff(sandbox.name())
+            .unwrap()
+            .iter()
+            .all(|change| change.path.starts_with("/etc/default"))
+    );
+}
+
+#[test]
+fn snapshots_roll_sessions_back() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let file = format!("/root/{}", sandbox.name());
+
+    assert!(
+        sandbox
+            .run_script(&format!("echo one > {file}"))
+            .unwrap()
+            .success()
+    );
+    create_snapshot(sandbox.name(), Some("one")).unwrap();
+    assert!(
+        sandbox
+            .run_script(&format!("echo two > {file}; rm /etc/hostname"))
+            .unwrap()
+            .success()
+    );
+    let latest = create_snapshot(sandbox.name(), None).unwrap();
+    let conflict = create_snapshot(sandbox.name(), Some("one")).unwrap_err();
+
+    restore_snapshot(sandbox.name(), Some("one")).unwrap();
+    sandbox.activate().unwrap();
+    assert_eq!(sandbox.read(&file).unwrap(), b"one\n");
+    assert_eq!(sandbox.changes().unwrap(), [PathBuf::from(&file)]);
+    restore_snapshot(sandbox.name(), None).unwrap();
+    sandbox.activate().unwrap();
+    assert_eq!(sandbox.read(&file).unwrap(), b"two\n");
+
+    assert_eq!(latest.number, 2);
+    assert!(matches!(conflict.current_context(), Error::Conflict));
+    let snapshots = session_snapshots(sandbox.name()).unwrap();
+    assert_eq!(
+        snapshots
+            .iter()
+            .map(|snapshot| snapshot.tag.as_deref())
+            .collect::<Vec<_>>(),
+        [Some("one"), None]
+    );
+}
+
+#[test]
+fn long_runs_are_checkpointed() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let file = format!("/root/{}", sandbox.name());
+
+    let status = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["run", "--session", sandbox.name()])
+        .args(["--checkpoint-every", "1s", "--", "sh", "-c"])
+        .arg(format!("echo hello > {file}; sleep 2"))
+        .status()
+        .unwrap();
+    let snapshots = session_snapshots(sandbox.name()).unwrap();
+    restore_snapshot(sandbox.name(), Some("1")).unwrap();
+    sandbox.activate().unwrap();
+
+    assert!(status.success());
+    assert!(!snapshots.is_empty());
+    assert!(snapshots.iter().all(|snapshot| snapshot.tag.is_none()));
+    assert_eq!(sandbox.read(&file).unwrap(), b"hello\n");
+}
+
+#[test]
+fn sessions_can_be_layered_on_another_root() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-lower", sandbox.name());
+    let run = |device: &str| {
+        RunOp::builder()
+            .session(session.as_ref())
+            .command(&["sh", "-c", "echo hello > /root/lower"])
+            .lower_device(Some(Path::new(device)))
+            .detach(true)
+            .build()
+            .run()
+    };
+
+    let status = run("/");
+    attach_process(&session, None, &mut Vec::new()).unwrap();
+    let mounts = session_mounts(&session);
+    let mismatch = run("/tmp").unwrap_err();
+    let changes = session_diff(&session);
+    delete_sessions(SessionOperand::List(&[&session]), false).unwrap();
+
+    assert!(status.unwrap().success());
+    assert!(mounts.unwrap()[0].fs_options.contains("/lower,"));
+    assert!(matches!(mismatch.current_context(), Error::InvalidArgument));
+    assert_eq!(changes.unwrap().len(), 1);
+}
+
+#[test]
+fn sessions_can_be_rooted_in_a_subtree() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-subtree", sandbox.name());
+    let root = env::temp_dir().join(&session);
+    fs::create_dir(&root).unwrap();
+    let script = format!(
+        "echo hello > {root}/file; touch /root/{session} 2> /dev/null || echo read-only",
+        root = root.display()
+    );
+
+    let status = RunOp::builder()
+        .session(session.as_ref())
+        .command(&["sh", "-c", &script])
+        .root(Some(&root))
+        .detach(true)
+        .build()
+        .run();
+    let mut output = Vec::new();
+    attach_process(&session, None, &mut output).unwrap();
+    let changes = session_diff(&session);
+    delete_sessions(SessionOperand::List(&[&session]), false).unwrap();
+    let written = root.join("file").exists();
+    fs::remove_dir(&root).unwrap();
+
+    assert!(status.unwrap().success());
+    assert_eq!(output, b"read-only\n");
+    assert_eq!(
+        changes.unwrap(),
+        [Change {
+            path: root.join("file"),
+            kind: ChangeKind::Added,
+            diverged: false,
+        }]
+    );
+    assert!(!written);
+}
+
+#[test]
+fn deletions_are_captured() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = sandbox.run(&["rm", "/etc/hostname"]).unwrap();
+
+    assert!(status.success());
+    assert_eq!(sandbox.changes().unwrap(), [PathBuf::from("/etc/hostname")]);
+    assert!(sandbox.read("/etc/hostname").is_err());
+    assert!(PathBuf::from("/etc/hostname").exists());
+}
+
+#[test]
+fn deleting_planted_symlinks_spares_their_targets() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let target = env::temp_dir().join(sandbox.name());
+    fs::create_dir_all(&target).unwrap();
+    fs::write(target.join("precious"), "").unwrap();
+
+    let status = sandbox
+        .run_script(&format!(
+            "ln -s {} /root/{}",
+            target.display(),
+            sandbox.name()
+        ))
+        .unwrap();
+    assert!(status.success());
+    drop(sandbox);
+
+    assert!(target.join("precious").exists());
+    fs::remove_dir_all(target).unwrap();
+}
+
+#[test]
+fn review_flags_privilege_escalation() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = sandbox
+        .run_script(
+            "touch /etc/ld.so.preload /usr/local/bin/shell && chmod u+s /usr/local/bin/shell && \
+             rm /etc/hostname",
+        )
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        review_session(sandbox.name(), &[]).unwrap(),
+        [
+            Finding {
+                path: PathBuf::from("/etc/ld.so.preload"),
+                concern: Concern::SensitivePath,
+            },
+            Finding {
+                path: PathBuf::from("/usr/local/bin/shell"),
+                concern: Concern::SetId,
+            },
+        ]
+    );
+    assert_eq!(
+        review_session(
+            sandbox.name(),
+            &[PathBuf::from("/etc"), PathBuf::from("/usr")]
+        )
+        .unwrap(),
+        []
+    );
+}
+
+#[test]
+fn diff_classifies_changes() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = sandbox
+        .run_script(
+            "mkdir /opt/new && touch /opt/new/file && echo >> /etc/hosts && rm /etc/hostname",
+        )
+        .unwrap();
+
+    assert!(status.success());
+    let change = |path: &str, kind| Change {
+        path: PathBuf::from(path),
+        kind,
+        diverged: false,
+    };
+    assert_eq!(
+        session_diff(sandbox.name()).unwrap(),
+        [
+            change("/etc/hostname", ChangeKind::Deleted),
+            change("/etc/hosts", ChangeKind::Modified),
+            change("/opt/new", ChangeKind::Added),
+            change("/opt/new/file", ChangeKind::Added),
+        ]
+    );
+}
+
+#[test]
+fn diff_queries_page_through_changes() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = sandbox
+        .run_script("mkdir /opt/new && touch /opt/new/a /opt/new/b /opt/new/c && rm /etc/hostname")
+        .unwrap();
+
+    assert!(status.success());
+    let query = |cursor| {
+        diff_query(
+            sandbox.name(),
+            DiffQuery {
+                prefix: Some(PathBuf::from("/opt/new")),
+                kinds: vec![ChangeKind::Added],
+                limit: Some(2),
+                cursor,
+                ..DiffQuery::default()
+            },
+        )
+        .unwrap()
+    };
+    let paths = |page: &DiffPage| {
+        page.changes
+            .iter()
+            .map(|change| change.path.clone())
+            .collect::<Vec<_>>()
+    };
+    let first = query(None);
+    assert_eq!(paths(&first), ["/opt/new", "/opt/new/a"].map(PathBuf::from));
+    let second = query(first.next);
+    assert_eq!(
+        paths(&second),
+        ["/opt/new/b", "/opt/new/c"].map(PathBuf::from)
+    );
+    assert_eq!(second.next, None);
+}
+
+#[test]
+fn change_iterators_tell_metadata_only_changes_apart() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = sandbox
+        .run_script(
+            "chmod 600 /etc/hosts && touch /etc/hostname && echo >> /etc/passwd && chmod 700 \
+             /usr/share && mkdir /opt/many && for i in $(seq 300); do touch /opt/many/$i; done",
+        )
+        .unwrap();
+    let changes = iter_changes(
+        sandbox.name(),
+        DiffQuery {
+            metadata_only: true,
+            ..DiffQuery::default()
+        },
+    )
+    .unwrap()
+    .collect::<Result<Vec<_>, _>>()
+    .unwrap();
+    let diff = session_diff(sandbox.name()).unwrap();
+    let modified = |changes: &[Change]| {
+        changes
+            .iter()
+            .filter(|change| change.kind != ChangeKind::Added)
+            .map(|change| (change.path.to_str().unwrap().to_string(), change.kind))
+            .collect::<Vec<_>>()
+    };
+
+    assert!(status.success());
+    assert_eq!(
+        modified(&changes),
+        [
+            ("/etc/hostname".to_string(), ChangeKind::MetadataOnly),
+            ("/etc/hosts".to_string(), ChangeKind::MetadataOnly),
+            ("/etc/passwd".to_string(), ChangeKind::Modified),
+            ("/usr/share".to_string(), ChangeKind::MetadataOnly),
+        ]
+    );
+    assert_eq!(changes.len(), diff.len() + 1);
+    assert_eq!(
+        modified(&diff),
+        [
+            ("/etc/hostname".to_string(), ChangeKind::Modified),
+            ("/etc/hosts".to_string(), ChangeKind::Modified),
+            ("/etc/passwd".to_string(), ChangeKind::Modified),
+        ]
+    );
+}
+
+#[test]
+fn diff_flags_files_changed_outside_the_session() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let path = PathBuf::from(format!("/var/tmp/forkfs-diverged-{}", sandbox.name()));
+    fs::write(&path, "before").unwrap();
+
+    let status = sandbox
+        .run_script(&format!("echo during >> {}", path.display()))
+        .unwrap();
+    assert!(status.success());
+    let diverged = || {
+        session_diff(sandbox.name())
+            .unwrap()
+            .iter()
+            .any(|change| change.path == path && change.diverged)
+    };
+    assert!(!diverged());
+
+    thread::sleep(Duration::from_millis(50));
+    fs::write(&path, "after").unwrap();
+    let result = diverged();
+    fs::remove_file(&path).unwrap();
+
+    assert!(result);
+}
+
+#[test]
+fn seeded_files_stop_following_the_real_ones() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from("/var/tmp").join(sandbox.name());
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("config"), "before").unwrap();
+
+    seed_session(sandbox.name(), slice::from_ref(&dir)).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    fs::write(dir.join("config"), "after").unwrap();
+    let contents = sandbox.read(dir.join("config"));
+    let changes = session_diff(sandbox.name()).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(contents.unwrap(), b"before");
+    assert_eq!(
+        changes,
+        [Change {
+            path: dir.join("config"),
+            kind: ChangeKind::Modified,
+            diverged: true,
+        }]
+    );
+}
+
+#[test]
+fn apply_replays_changes() {
+    replays_changes(false);
+}
+
+#[test]
+fn fast_applies_replay_the_same_changes() {
+    replays_changes(true);
+}
+
+fn replays_changes(fast: bool) {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-apply-{}", sandbox.name()));
+    fs::create_dir_all(dir.join("gone")).unwrap();
+    fs::write(dir.join("modified"), "before").unwrap();
+    fs::write(dir.join("gone/file"), "").unwrap();
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && echo after > modified && rm -r gone && mkdir new && echo > new/file",
+            dir.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    let applied = apply_session(sandbox.name(), &[], false, fast);
+    let modified = fs::read_to_string(dir.join("modified"));
+    let gone = dir.join("gone").exists();
+    let added = dir.join("new/file").exists();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(applied.unwrap().len(), 4);
+    assert_eq!(modified.unwrap(), "after\n");
+    assert!(!gone);
+    assert!(added);
+    assert_eq!(session_diff(sandbox.name()).unwrap(), []);
+}
+
+#[test]
+fn apply_keeps_hard_links() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-links-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && echo hi > a && mkdir sub && ln a sub/b",
+            dir.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    let applied = apply_session(sandbox.name(), &[], false, false);
+    let inodes = ["a", "sub/b"].map(|file| fs::metadata(dir.join(file)).map(|m| m.ino()));
+    fs::remove_dir_all(&dir).unwrap();
+
+    applied.unwrap();
+    let [a, b] = inodes;
+    assert_eq!(a.unwrap(), b.unwrap());
+}
+
+#[test]
+fn apply_carries_out_conflict_resolutions() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-resolve-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+    for file in ["kept", "merged"] {
+        fs::write(dir.join(file), "before").unwrap();
+    }
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && echo session > kept && echo session > merged",
+            dir.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    thread::sleep(Duration::from_millis(50));
+    for file in ["kept", "merged"] {
+        fs::write(dir.join(file), "live").unwrap();
+    }
+    let conflicts = session_conflicts(sandbox.name());
+    let unresolved = apply_session(sandbox.name(), &[], false, false);
+    let resolutions = BTreeMap::from([
+        (dir.join("kept"), Resolution::Live),
+        (dir.join("merged"), Resolution::Contents(b"merged".to_vec())),
+    ]);
+    let applied = apply_resolved(sandbox.name(), &[], &resolutions, false);
+    let contents = ["kept", "merged"].map(|file| fs::read_to_string(dir.join(file)));
+    fs::remove_dir_all(&dir).unwrap();
+
+    let conflicts = conflicts.unwrap();
+    assert_eq!(conflicts.len(), 2);
+    assert_eq!(conflicts[0].lower, ConflictVersion::Unknown);
+    assert_eq!(
+        conflicts[0].session,
+        ConflictVersion::File(b"session\n".to_vec())
+    );
+    assert_eq!(conflicts[0].live, ConflictVersion::File(b"live".to_vec()));
+    assert!(matches!(
+        unresolved.unwrap_err().current_context(),
+        Error::Conflict
+    ));
+    let applied = applied.unwrap();
+    assert_eq!(applied.len(), 1);
+    assert_eq!(applied[0].path, dir.join("merged"));
+    let [kept, merged] = contents;
+    assert_eq!(kept.unwrap(), "live");
+    assert_eq!(merged.unwrap(), "merged");
+    assert_eq!(session_diff(sandbox.name()).unwrap(), []);
+}
+
+#[test]
+fn apply_refuses_once_pinned_files_changed() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let session = format!("{}-pinned", sandbox.name());
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-pins-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("base"), "before").unwrap();
+    let pins = [dir.join("base")];
+    let script = format!("echo session >> {}", dir.join("snippet").display());
+    let run = || {
+        RunOp::builder()
+            .session(session.as_ref())
+            .command(&["sh", "-c", &script])
+            .pins(&pins)
+            .detach(true)
+            .build()
+            .run()?;
+        attach_process(&session, None, &mut Vec::new())
+    };
+
+    let first = run();
+    fs::write(dir.join("base"), "after").unwrap();
+    let refused = apply_session(&session, &[], false, false);
+    let forced = apply_session(&session, &[], true, false);
+    let second = run();
+    let repinned = apply_session(&session, &[], false, false);
+    let snippet = fs::read_to_string(dir.join("snippet"));
+    delete_sessions(SessionOperand::List(&[&session]), false).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    first.unwrap();
+    assert!(matches!(
+        refused.unwrap_err().current_context(),
+        Error::Conflict
+    ));
+    forced.unwrap();
+    second.unwrap();
+    repinned.unwrap();
+    assert_eq!(snippet.unwrap(), "session\nsession\n");
+}
+
+#[test]
+fn patch_series_have_a_patch_per_command() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-patches-{}", sandbox.name()));
+    let patches = dir.with_extension("patches");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("modified"), "a\nb\n").unwrap();
+    fs::write(dir.join("deleted"), "gone\n").unwrap();
+    let run = |script: &str| {
+        let script = format!("cd {} && {script}", dir.display());
+        let status = RunOp::builder()
+            .session(sandbox.name().as_ref())
+            .command(&["sh", "-c", &script])
+            .detach(true)
+            .build()
+            .run()
+            .unwrap();
+        attach_process(sandbox.name(), None, &mut Vec::new()).unwrap();
+        status
+    };
+
+    let first = run("echo new > added");
+    // Commands are told apart by the second they started in
+    thread::sleep(Duration::from_millis(1100));
+    let second = run("echo B > modified && rm deleted && printf '\\0' > binary");
+    let omissions = export_patch_series(sandbox.name(), &patches, &[], ExportFilter::default());
+    let mut series = fs::read_dir(&patches)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    series.sort();
+    let contents = series
+        .iter()
+        .map(|patch| fs::read_to_string(patch).unwrap())
+        .collect::<Vec<_>>();
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_dir_all(&patches).unwrap();
+
+    assert!(first.success());
+    assert!(second.success());
+    assert_eq!(
+        omissions.unwrap(),
+        [Omission {
+            path: dir.join("binary"),
+            kind: OmissionKind::Binary,
+        }]
+    );
+    assert_eq!(contents.len(), 2);
+    assert!(
+        series[0]
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("0001-sh-c-cd-")
+    );
+    let relative = dir.strip_prefix("/").unwrap().display();
+    assert!(contents[0].contains("Subject: [PATCH 1/2] sh -c cd "));
+    assert!(contents[0].contains(&format!(
+        "new file mode 100644\n--- /dev/null\n+++ b/{relative}/added\n@@ -0,0 +1 @@\n+new\n"
+    )));
+    assert!(!contents[0].contains("modified"));
+    assert!(contents[1].contains("Subject: [PATCH 2/2] sh -c cd "));
+    assert!(contents[1].contains(&format!(
+        "--- a/{relative}/modified\n+++ b/{relative}/modified\n@@ -1,2 +1 @@\n-a\n-b\n+B\n"
+    )));
+    assert!(contents[1].contains(&format!(
+        "deleted file mode 100644\n--- a/{relative}/deleted\n+++ /dev/null\n"
+    )));
+    assert!(!contents[1].contains(&format!("{relative}/binary")));
+}
+
+#[test]
+fn archives_are_applied_without_leaving_a_session_behind() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-apply-archive-{}", sandbox.name()));
+    let archive = dir.with_extension("tar");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("modified"), "before").unwrap();
+    fs::write(dir.join("deleted"), "").unwrap();
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && echo after > modified && rm deleted && echo > added",
+            dir.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    export_session(sandbox.name(), fs::File::create(&archive).unwrap(), false).unwrap();
+    let child = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["sessions", "apply-archive", "--no-input"])
+        .arg(&archive)
+        .spawn()
+        .unwrap();
+    let session = format!("apply-archive-{}", child.id());
+    let output = child.wait_with_output().unwrap();
+    let modified = fs::read_to_string(dir.join("modified"));
+    let mut entries = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    entries.sort();
+    let listing = session_listing().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    fs::remove_file(&archive).unwrap();
+
+    assert!(output.status.success());
+    assert_eq!(modified.unwrap(), "after\n");
+    assert_eq!(entries, ["added", "modified"]);
+    assert!(listing.sessions.iter().all(|listed| listed.name != session));
+    assert_eq!(sandbox.changes().unwrap().len(), 3);
+}
+
+#[test]
+fn conflict_reports_suggest_resolutions() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-report-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+    for file in ["deleted", "modified", "same"] {
+        fs::write(dir.join(file), "before").unwrap();
+    }
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && rm deleted && echo session > modified && echo same > same",
+            dir.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    thread::sleep(Duration::from_millis(50));
+    fs::write(dir.join("deleted"), "live").unwrap();
+    fs::write(dir.join("modified"), "live").unwrap();
+    fs::write(dir.join("same"), "same\n").unwrap();
+    let report = conflict_report(sandbox.name());
+    fs::remove_dir_all(&dir).unwrap();
+
+    let report = report.unwrap();
+    assert_eq!(report.session, sandbox.name());
+    let summary = report
+        .conflicts
+        .iter()
+        .map(|conflict| {
+            (
+                conflict.path.as_str(),
+                conflict.reason.as_str(),
+                conflict.session.kind.as_str(),
+                conflict.suggested_resolution.as_str(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let path = |file| format!("{}/{file}", dir.display());
+    assert_eq!(
+        summary,
+        [
+            (&*path("deleted"), "deleted-in-session", "missing", "manual"),
+            (&*path("modified"), "modified-in-both", "file", "manual"),
+            (&*path("same"), "same-change", "file", "live"),
+        ]
+    );
+    let [deleted, modified, same] = &*report.conflicts else {
+        unreachable!();
+    };
+    assert_eq!(deleted.session.sha256, None);
+    assert!(deleted.live.sha256.is_some());
+    assert_ne!(modified.session.sha256, modified.live.sha256);
+    assert_eq!(same.session.sha256, same.live.sha256);
+    assert_eq!(same.live.sha256.as_ref().map(String::len), Some(64));
+}
+
+#[test]
+fn special_files_are_applied_and_exported_faithfully() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-special-{}", sandbox.name()));
+    let imported = format!("{}-imported", sandbox.name());
+    fs::create_dir_all(&dir).unwrap();
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && mkfifo fifo && python3 -c 'import socket; \
+             socket.socket(socket.AF_UNIX).bind(\"sock\")'",
+            dir.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    let mut archive = Vec::new();
+    let omissions = export_session(sandbox.name(), &mut archive, false);
+    import_session(&imported, archive.as_slice()).unwrap();
+    let changes = session_diff(&imported);
+    delete_sessions(SessionOperand::List(&[&imported]), false).unwrap();
+    let applied = apply_session(sandbox.name(), &[], false, false);
+    let types = ["fifo", "sock"].map(|file| fs::symlink_metadata(dir.join(file)).unwrap());
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+        omissions.unwrap(),
+        [Omission {
+            path: dir.join("sock"),
+            kind: OmissionKind::Socket,
+        }]
+    );
+    assert_eq!(
+        changes
+            .unwrap()
+            .into_iter()
+            .map(|change| change.path)
+            .collect::<Vec<_>>(),
+        [dir.join("fifo")]
+    );
+    applied.unwrap();
+    let [fifo, sock] = types;
+    assert!(fifo.file_type().is_fifo());
+    assert!(sock.file_type().is_socket());
+}
+
+#[test]
+fn drift_tells_which_version_the_real_files_match() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-drift-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+    for file in ["untouched", "applied", "clobbered"] {
+        fs::write(dir.join(file), "before").unwrap();
+    }
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && for f in *; do echo after > $f; done",
+            dir.display()
+        ))
+        .unwrap();
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+    fs::write(dir.join("applied"), "after\n").unwrap();
+    fs::write(dir.join("clobbered"), "elsewhere").unwrap();
+    let drift = session_drift(sandbox.name());
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        drift.unwrap(),
+        [
+            (dir.join("applied"), DriftKind::Upper),
+            (dir.join("clobbered"), DriftKind::Neither),
+            (dir.join("untouched"), DriftKind::Lower),
+        ]
+        .map(|(path, kind)| Drift { path, kind })
+    );
+}
+
+#[test]
+fn manifests_catch_unexpected_and_missing_changes() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-verify-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("kept"), "before").unwrap();
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && echo after > kept && echo > extra",
+            dir.display()
+        ))
+        .unwrap();
+    let mut manifest = String::from("# known good\n");
+    for Change { path, kind, .. } in session_diff(sandbox.name()).unwrap() {
+        let letter = match kind {
+            ChangeKind::Added => 'A',
+            ChangeKind::Modified | ChangeKind::MetadataOnly => 'M',
+            ChangeKind::Deleted => 'D',
+        };
+        if path != dir.join("extra") {
+            manifest.push_str(&format!("{letter} {}\n", path.display()));
+        }
+    }
+    manifest.push_str(&format!(
+        "A {}\n    with details\n",
+        dir.join("expected").display()
+    ));
+    let file = dir.with_extension("manifest");
+    fs::write(&file, manifest).unwrap();
+    let deviations = verify_session(sandbox.name(), &file);
+    fs::write(&file, "? /etc/passwd\n").unwrap();
+    let malformed = verify_session(sandbox.name(), &file);
+    fs::remove_file(&file).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(status.success());
+    assert_eq!(
+        deviations.unwrap(),
+        [
+            (dir.join("expected"), DeviationKind::Missing),
+            (dir.join("extra"), DeviationKind::Unexpected),
+        ]
+        .map(|(path, kind)| Deviation {
+            path,
+            change: ChangeKind::Added,
+            kind,
+        })
+    );
+    assert!(matches!(
+        malformed.unwrap_err().current_context(),
+        Error::InvalidArgument
+    ));
+}
+
+#[test]
+fn interrupted_apply_leaves_real_files_untouched() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-apply-{}", sandbox.name()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("modified"), "before").unwrap();
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && echo after > modified && echo > added",
+            dir.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    inject_fault(Some(FaultPoint::MidCopy));
+    let result = apply_session(sandbox.name(), &[], false, false);
+    inject_fault(None);
+    let modified = fs::read_to_string(dir.join("modified"));
+    let entries = fs::read_dir(&dir).unwrap().count();
+    let changes = session_diff(sandbox.name());
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(result.is_err());
+    assert_eq!(modified.unwrap(), "before");
+    assert_eq!(entries, 1);
+    assert_eq!(changes.unwrap().len(), 2);
+}
+
+/// Applies a session that modifies, deletes, adds, and replaces a directory
+/// with a file in `dir`, failing after the deletions.
+fn interrupt_apply(sandbox: &Sandbox, dir: &Path) {
+    fs::create_dir_all(dir.join("tree")).unwrap();
+    fs::write(dir.join("tree/leaf"), "").unwrap();
+    fs::write(dir.join("modified"), "before").unwrap();
+    fs::write(dir.join("deleted"), "").unwrap();
+
+    let status = sandbox
+        .run_script(&format!(
+            "cd {} && echo after > modified && echo > added && rm -r deleted tree && echo > tree",
+            dir.display()
+        ))
+        .unwrap();
+    assert!(status.success());
+    inject_fault(Some(FaultPoint::MidCommit));
+    let result = apply_session(sandbox.name(), &[], false, false);
+    inject_fault(None);
+    assert!(result.is_err());
+}
+
+#[test]
+fn interrupted_applies_can_be_continued() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-continue-{}", sandbox.name()));
+    interrupt_apply(&sandbox, &dir);
+
+    let reapplied = apply_session(sandbox.name(), &[], false, false);
+    let resumed = resume_apply(sandbox.name(), &[], false, false);
+    let modified = fs::read_to_string(dir.join("modified"));
+    let mut entries = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    entries.sort();
+    let tree = fs::symlink_metadata(dir.join("tree"));
+    let changes = session_diff(sandbox.name());
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(matches!(
+        reapplied.unwrap_err().current_context(),
+        Error::Conflict
+    ));
+    resumed.unwrap();
+    assert_eq!(modified.unwrap(), "after\n");
+    assert_eq!(entries, ["added", "modified", "tree"]);
+    assert!(tree.unwrap().is_file());
+    assert_eq!(changes.unwrap(), []);
+}
+
+#[test]
+fn interrupted_applies_can_be_aborted() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/var/tmp/forkfs-abort-{}", sandbox.name()));
+    interrupt_apply(&sandbox, &dir);
+
+    let aborted = abort_apply(sandbox.name());
+    let modified = fs::read_to_string(dir.join("modified"));
+    let mut entries = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    entries.sort();
+    let leaf = dir.join("tree/leaf").exists();
+    let changes = session_diff(sandbox.name());
+    let aborted_again = abort_apply(sandbox.name());
+    fs::remove_dir_all(&dir).unwrap();
+
+    aborted.unwrap();
+    assert_eq!(modified.unwrap(), "before");
+    assert_eq!(entries, ["deleted", "modified", "tree"]);
+    assert!(leaf);
+    assert_eq!(changes.unwrap().len(), 4);
+    assert!(matches!(
+        aborted_again.unwrap_err().current_context(),
+        Error::InvalidArgument
+    ));
+}
+
+#[test]
+fn check_flags_hand_edits() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    assert_eq!(check_session(sandbox.name()).unwrap(), []);
+
+    let dir = Config::load().unwrap().sessions_dir().join(sandbox.name());
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+    fs::write(dir.join("diff/.wh.planted"), "").unwrap();
+    fs::write(dir.join("merged/planted"), "").unwrap();
+    let problems = check_session(sandbox.name()).unwrap();
+    fs::remove_file(dir.join("merged/planted")).unwrap();
+
+    assert_eq!(
+        problems,
+        [
+            Problem {
+                path: PathBuf::from("/.wh.planted"),
+                issue: Issue::ForeignWhiteout,
+            },
+            Problem {
+                path: dir.join("merged/planted"),
+                issue: Issue::HiddenByMount,
+            },
+        ]
+    );
+}
+
+#[test]
+fn export_encodes_whiteouts_portably() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let file = format!("/root/{}", sandbox.name());
+
+    let status = sandbox
+        .run_script(&format!(
+            "rm /etc/hostname && cp /bin/true {file} && chmod u+s {file}"
+        ))
+        .unwrap();
+    let mut archive = Vec::new();
+    export_session(sandbox.name(), &mut archive, false).unwrap();
+
+    assert!(status.success());
+    let headers = archive
+        .chunks(512)
+        .filter(|block| block[257..263] == *b"ustar\0")
+        .map(|block| {
+            let name = block[..100].split(|&c| c == 0).next().unwrap();
+            let mode = str::from_utf8(&block[100..107]).unwrap();
+            (
+                String::from_utf8_lossy(name).into_owned(),
+                u32::from_str_radix(mode, 8).unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    assert!(headers.iter().any(|(name, _)| name == "etc/.wh.hostname"));
+    let (_, mode) = headers.iter().find(|(name, _)| *name == file[1..]).unwrap();
+    assert_eq!(mode & 0o6000, 0);
+}
+
+#[test]
+fn redacted_exports_scrub_covered_files() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let imported = format!("{}-imported", sandbox.name());
+    let sessions = Config::load().unwrap().sessions_dir();
+    let redact = |paths: &[&str], pattern: &str, replacement: &str| Redaction {
+        paths: paths.iter().map(ToString::to_string).collect(),
+        pattern: pattern.to_string(),
+        replacement: replacement.to_string(),
+    };
+
+    let status = sandbox
+        .run_script(
+            "mkdir -p /opt/app && echo 'user=alice token=hunter2' | tee /opt/app/rc > /opt/log",
+        )
+        .unwrap();
+    let mut archive = Vec::new();
+    export_redacted_session(
+        sandbox.name(),
+        &mut archive,
+        false,
+        &[
+            redact(&["/opt/*/rc"], "token=\\w+", "token=[REDACTED]"),
+            redact(&[], "user=(\\w+)", "user=<$1>"),
+        ],
+    )
+    .unwrap();
+    import_session(&imported, archive.as_slice()).unwrap();
+    let diff = sessions.join(&imported).join("diff/opt");
+    let files = ["app/rc", "log"].map(|file| fs::read_to_string(diff.join(file)));
+    delete_sessions(SessionOperand::List(&[&imported]), false).unwrap();
+    let invalid = export_redacted_session(
+        sandbox.name(),
+        &mut Vec::new(),
+        false,
+        &[redact(&[], "(", "")],
+    );
+
+    assert!(status.success());
+    let [rc, log] = files.map(Result::unwrap);
+    assert_eq!(rc, "user=<alice> token=[REDACTED]\n");
+    assert_eq!(log, "user=<alice> token=hunter2\n");
+    assert!(matches!(
+        invalid.unwrap_err().current_context(),
+        Error::InvalidArgument
+    ));
+}
+
+#[test]
+fn filtered_exports_leave_out_large_and_binary_files() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let imported = format!("{}-imported", sandbox.name());
+
+    let status = sandbox
+        .run_script(
+            "echo hi > /opt/text && printf 'a\\0b' > /opt/binary && yes | head -c 4096 > \
+             /opt/large && ln /opt/large /opt/link",
+        )
+        .unwrap();
+    let mut archive = Vec::new();
+    let omissions = export_filtered_session(
+        sandbox.name(),
+        &mut archive,
+        false,
+        &[],
+        ExportFilter {
+            max_file_size: Some(1024),
+            exclude_binary: true,
+        },
+    );
+    import_session(&imported, archive.as_slice()).unwrap();
+    let changes = session_diff(&imported);
+    delete_sessions(SessionOperand::List(&[&imported]), false).unwrap();
+
+    assert!(status.success());
+    let mut omissions = omissions.unwrap();
+    omissions.sort_by(|a, b| a.path.cmp(&b.path));
+    assert_eq!(
+        omissions,
+        [
+            ("/opt/binary", OmissionKind::Binary),
+            ("/opt/large", OmissionKind::TooLarge),
+            ("/opt/link", OmissionKind::TooLarge),
+        ]
+        .map(|(path, kind)| Omission {
+            path: path.into(),
+            kind,
+        })
+    );
+    assert_eq!(
+        changes
+            .unwrap()
+            .into_iter()
+            .map(|change| change.path)
+            .collect::<Vec<_>>(),
+        [Path::new("/opt/text")]
+    );
+}
+
+#[test]
+fn copies_keep_hard_links() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let clone = format!("{}-clone", sandbox.name());
+    let imported = format!("{}-imported", sandbox.name());
+    let sessions = Config::load().unwrap().sessions_dir();
+
+    let status = sandbox
+        .run_script("echo hi > /opt/a && mkdir /opt/sub && ln /opt/a /opt/sub/b")
+        .unwrap();
+    clone_session(sandbox.name(), &clone).unwrap();
+    let mut archive = Vec::new();
+    export_session(sandbox.name(), &mut archive, false).unwrap();
+    import_session(&imported, archive.as_slice()).unwrap();
+    let inodes = [&clone, &imported].map(|session| {
+        let diff = sessions.join(session).join("diff/opt");
+        ["a", "sub/b"].map(|file| fs::metadata(diff.join(file)).map(|m| m.ino()).ok())
+    });
+    delete_sessions(SessionOperand::List(&[&clone, &imported]), false).unwrap();
+
+    assert!(status.success());
+    for [a, b] in inodes {
+        assert!(a.is_some());
+        assert_eq!(a, b);
+    }
+}
+
+#[test]
+fn imported_sessions_match_their_export() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let imported = format!("{}-imported", sandbox.name());
+
+    let status = sandbox
+        .run_script(
+            "rm -r /etc/hostname /usr/share && mkdir /usr/share && echo hi > /usr/share/new",
+        )
+        .unwrap();
+    let mut archive = Vec::new();
+    export_session(sandbox.name(), &mut archive, false).unwrap();
+    import_session(&imported, archive.as_slice()).unwrap();
+    let changes = session_diff(&imported);
+    let listing = session_listing().unwrap();
+    delete_sessions(SessionOperand::List(&[&imported]), false).unwrap();
+
+    assert!(status.success());
+    assert_eq!(changes.unwrap(), session_diff(sandbox.name()).unwrap());
+    assert!(
+        listing
+            .sessions
+            .iter()
+            .any(|session| session.name == imported && !session.active)
+    );
+}
+
+#[test]
+fn imported_hard_links_cant_follow_planted_symlinks() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let imported = format!("{}-imported", sandbox.name());
+    let victim = env::temp_dir().join(&imported);
+    fs::create_dir_all(&victim).unwrap();
+    fs::write(victim.join("secret"), "secret").unwrap();
+
+    let mut archive = Vec::new();
+    archive.extend(tar_header("sym", b'2', victim.to_str().unwrap()));
+    archive.extend(tar_header("hl", b'1', "sym/secret"));
+    archive.extend([0; 1024]);
+    let result = import_session(&imported, archive.as_slice());
+    let links = fs::metadata(victim.join("secret")).unwrap().nlink();
+    fs::remove_dir_all(&victim).unwrap();
+
+    assert!(matches!(
+        result.unwrap_err().current_context(),
+        Error::InvalidArgument
+    ));
+    assert_eq!(links, 1);
+    assert!(
+        !session_listing()
+            .unwrap()
+            .sessions
+            .iter()
+            .any(|session| session.name == imported)
+    );
+}
+
+#[test]
+fn stores_share_chunks_between_archives() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let store = PathBuf::from(format!("/var/tmp/forkfs-store-{}", sandbox.name()));
+    let copy = format!("{}-copy", sandbox.name());
+    let imported = format!("{}-imported", sandbox.name());
+
+    let status = sandbox
+        .run_script("head -c 300000 /dev/urandom > /opt/random && rm /etc/hostname")
+        .unwrap();
+    clone_session(sandbox.name(), &copy).unwrap();
+    let original = export_to_store(sandbox.name(), &store, false);
+    let cloned = export_to_store(&copy, &store, false);
+    import_from_store(&imported, &store, &copy).unwrap();
+    let changes = session_diff(&imported);
+    delete_sessions(SessionOperand::List(&[&copy, &imported]), false).unwrap();
+    fs::remove_dir_all(&store).unwrap();
+
+    assert!(status.success());
+    let original = original.unwrap();
+    assert!(original.size > 300_000);
+    assert_eq!(original.added, original.size);
+    assert_eq!(cloned.unwrap().added, 0);
+    assert_eq!(changes.unwrap(), session_diff(sandbox.name()).unwrap());
+}
+
+#[test]
+fn gc_deletes_what_interrupted_operations_left_behind() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let store = PathBuf::from(format!("/var/tmp/forkfs-gc-{}", sandbox.name()));
+    let mut exited = process::Command::new("true").spawn().unwrap();
+    exited.wait().unwrap();
+    let dead = exited.id();
+    let sessions_dir = Config::load().unwrap().sessions_dir();
+    let clone = sessions_dir.join(format!(".{}.clone-{dead}", sandbox.name()));
+    let snapshot = sessions_dir
+        .join(sandbox.name())
+        .join(format!("snapshots/.1-{dead}"));
+
+    assert!(
+        sandbox
+            .run_script("echo hello > /opt/file")
+            .unwrap()
+            .success()
+    );
+    export_to_store(sandbox.name(), &store, false).unwrap();
+    fs::remove_file(store.join("archives").join(sandbox.name())).unwrap();
+    for dir in [&clone, &snapshot] {
+        fs::create_dir_all(dir.join("diff")).unwrap();
+    }
+    let preview = collect_garbage(Some(&store), true).unwrap();
+    let existed = clone.exists() && snapshot.exists();
+    let collected = collect_garbage(Some(&store), false).unwrap();
+    let chunks = fs::read_dir(store.join("chunks"))
+        .unwrap()
+        .map(|group| fs::read_dir(group.unwrap().path()).unwrap().count())
+        .sum::<usize>();
+    fs::remove_dir_all(&store).unwrap();
+
+    assert!(existed);
+    assert_eq!(preview, collected);
+    for path in [&clone, &snapshot] {
+        assert!(!path.exists());
+        assert!(collected.iter().any(|garbage| garbage.path == *path
+            && garbage.kind == GarbageKind::Leftover
+            && garbage.size > 0));
+    }
+    assert!(
+        collected
+            .iter()
+            .any(|garbage| garbage.kind == GarbageKind::UnusedChunk)
+    );
+    assert_eq!(chunks, 0);
+}
+
+#[test]
+fn ephemeral_sessions_are_deleted_once_the_command_exits() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let file = format!("/root/{}", sandbox.name());
+
+    // Supervising the run takes over the process' stdio and signals
+    let output = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["run", "--ephemeral", "--", "sh", "-c"])
+        .arg(format!("echo hello > {file}; cat {file}; sleep 100 &"))
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(output.stdout, b"hello\n");
+    assert!(!Path::new(&file).exists());
+    assert!(
+        !session_listing()
+            .unwrap()
+            .sessions
+            .iter()
+            .any(|session| session.name.starts_with("ephemeral-"))
+    );
+}
+
+#[test]
+fn stopping_runs_unmount_the_session_once_unused() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let file = format!("/root/{}", sandbox.name());
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["run", "--stop", "--session", sandbox.name(), "--"])
+        .args(["sh", "-c"])
+        .arg(format!("(sleep 0.5; echo hello > {file}) & exit 3"))
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(3), "{output:?}");
+    assert!(!session_status(sandbox.name()).unwrap().active);
+    sandbox.activate().unwrap();
+    assert_eq!(sandbox.read(&file).unwrap(), b"hello\n");
+}
+
+#[test]
+fn detached_commands_outlive_the_run() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let detach = |command: &[&str]| {
+        RunOp::builder()
+            .session(sandbox.name().as_ref())
+            .command(command)
+            .detach(true)
+            .build()
+            .run()
+            .unwrap()
+    };
+
+    assert!(detach(&["sh", "-c", "echo started"]).success());
+    let mut output = Vec::new();
+    attach_process(sandbox.name(), None, &mut output).unwrap();
+    assert_eq!(output, b"started\n");
+
+    assert!(detach(&["sleep", "100"]).success());
+    let processes = detached_processes(sandbox.name()).unwrap();
+    assert_eq!(processes.len(), 1);
+    assert_eq!(processes[0].command, ["sleep", "100"]);
+    assert!(processes[0].is_running());
+    stop_process(sandbox.name(), Some(processes[0].pid), true).unwrap();
+    for _ in 0..50 {
+        if !detached_processes(sandbox.name()).unwrap()[0].is_running() {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("The detached process survived being killed");
+}
+
+#[test]
+fn private_pids_hide_the_host_processes() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = RunOp::builder()
+        .session(sandbox.name().as_ref())
+        .command(&[
+            "sh",
+            "-c",
+            &format!("echo $$; test -e /proc/{} || echo hidden", process::id()),
+        ])
+        .private_pids(true)
+        .detach(true)
+        .build()
+        .run()
+        .unwrap();
+    let mut output = Vec::new();
+    attach_process(sandbox.name(), None, &mut output).unwrap();
+
+    assert!(status.success());
+    // The namespace's init comes first
+    assert_eq!(output, b"2\nhidden\n");
+}
+
+#[test]
+fn isolated_sandboxes_have_no_network_interfaces() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = RunOp::builder()
+        .session(sandbox.name().as_ref())
+        .command(&["sed", "-n", r"s/^ *\([^:]*\):.*/\1/p", "/proc/net/dev"])
+        .network(Network::Isolated)
+        .detach(true)
+        .build()
+        .run()
+        .unwrap();
+    let mut output = Vec::new();
+    attach_process(sandbox.name(), None, &mut output).unwrap();
+
+    assert!(status.success());
+    // Every network namespace comes with a loopback interface, albeit down
+    assert_eq!(output, b"lo\n");
+}
+
+#[test]
+fn added_files_get_predictable_modes_and_owners() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = format!("/root/{}-owned", sandbox.name());
+    let nobody = Viewer::lookup("nobody").unwrap();
+
+    let run = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["run", "--session", sandbox.name(), "--umask", "077"])
+        .args(["--owner", "nobody", "--", "sh", "-c"])
+        .arg(format!("mkdir {dir} && touch {dir}/file"))
+        .output()
+        .unwrap();
+    let session = Session::open(sandbox.name()).unwrap();
+    let active = session.activate().unwrap();
+    let metadata = |path: &str| fs::symlink_metadata(active.root().join(&path[1..])).unwrap();
+    let (dir, file) = (metadata(&dir), metadata(&format!("{dir}/file")));
+    drop(active);
+
+    assert!(run.status.success(), "{run:?}");
+    assert_eq!(dir.mode() & 0o777, 0o700);
+    assert_eq!(file.mode() & 0o777, 0o600);
+    assert_eq!((dir.uid(), dir.gid()), (nobody.uid, nobody.gid));
+    assert_eq!((file.uid(), file.gid()), (nobody.uid, nobody.gid));
+}
+
+#[test]
+fn added_files_are_handed_over_from_the_sudo_user() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = format!("/var/tmp/{}-owned", sandbox.name());
+    let daemon = Viewer::lookup("daemon").unwrap();
+    let nobody = Viewer::lookup("nobody").unwrap();
+
+    let run = process::Command::new(env!("CARGO_BIN_EXE_forkfs"))
+        .args(["run", "--session", sandbox.name(), "--owner", "nobody"])
+        .args(["--", "sh", "-c"])
+        .arg(format!("mkdir {dir} && touch {dir}/file"))
+        .env("SUDO_UID", daemon.uid.to_string())
+        .output()
+        .unwrap();
+    let session = Session::open(sandbox.name()).unwrap();
+    let active = session.activate().unwrap();
+    let metadata = |path: &str| fs::symlink_metadata(active.root().join(&path[1..])).unwrap();
+    let (dir, file) = (metadata(&dir), metadata(&format!("{dir}/file")));
+    drop(active);
+
+    assert!(run.status.success(), "{run:?}");
+    assert_eq!((dir.uid(), dir.gid()), (nobody.uid, nobody.gid));
+    assert_eq!((file.uid(), file.gid()), (nobody.uid, nobody.gid));
+}
+
+#[test]
+fn waited_for_runs_leave_the_callers_stdio_alone() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let stdio = || ["0", "1"].map(|fd| fs::metadata(format!("/proc/self/fd/{fd}")).unwrap().ino());
+
+    let before = stdio();
+    let status = RunOp::builder()
+        .session(sandbox.name().as_ref())
+        .command(&["true"])
+        .wait(true)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(status.success());
+    assert_eq!(stdio(), before);
+}
+
+#[test]
+fn sessions_with_running_processes_arent_stopped() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    let status = RunOp::builder()
+        .session(sandbox.name().as_ref())
+        .command(&["sleep", "100"])
+        .detach(true)
+        .build()
+        .run()
+        .unwrap();
+    assert!(status.success());
+    let busy = stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap_err();
+    let pid = detached_processes(sandbox.name()).unwrap()[0].pid;
+    stop_process(sandbox.name(), Some(pid), true).unwrap();
+    for _ in 0..50 {
+        if !detached_processes(sandbox.name()).unwrap()[0].is_running() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert!(matches!(busy.current_context(), Error::InvalidArgument));
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+}
+
+#[test]
+fn failing_detached_commands_are_restarted() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let marker = format!("/root/{}", sandbox.name());
+    let script = format!("test -e {marker} || {{ touch {marker}; exit 1; }}");
+
+    let status = RunOp::builder()
+        .session(sandbox.name().as_ref())
+        .command(&["sh", "-c", &script])
+        .detach(true)
+        .restart(Restart::OnFailure)
+        .build()
+        .run()
+        .unwrap();
+
+    assert!(status.success());
+    for _ in 0..50 {
+        let process = detached_processes(sandbox.name()).unwrap().remove(0);
+        if !process.is_running() {
+            assert_eq!(process.restarts, 1);
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("The detached command wasn't restarted");
+}
+
+#[test]
+fn info_records_the_session_history() {
+    let clock = Rc::new(FakeClock::new(UNIX_EPOCH + Duration::from_secs(1_000_000)));
+    set_clock(Some(clock.clone()));
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    clock.advance(Duration::from_mins(1));
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+    sandbox.activate().unwrap();
+    clock.advance(Duration::from_mins(1));
+
+    let status = RunOp::builder()
+        .session(sandbox.name().as_ref())
+        .command(&["true"])
+        .detach(true)
+        .build()
+        .run()
+        .unwrap();
+    set_clock(None);
+
+    assert!(status.success());
+    let info = session_info(sandbox.name()).unwrap();
+    assert_eq!(info.created, Some(1_000_000));
+    assert_eq!(info.last_activated, Some(1_000_060));
+    assert_eq!(
+        info.commands,
+        [CommandRecord {
+            time: 1_000_120,
+            args: vec!["true".to_string()],
+            changes_before: Some(ChangeStats::default()),
+        }]
+    );
+}
+
+#[test]
+fn trend_measures_the_changes_of_each_run() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = PathBuf::from(format!("/opt/{}", sandbox.name()));
+    let script = format!(
+        "mkdir {dir} && head -c 8192 /dev/urandom > {dir}/file",
+        dir = dir.display()
+    );
+    let run = |command: &[&str]| {
+        let status = RunOp::builder()
+            .session(sandbox.name().as_ref())
+            .command(command)
+            .detach(true)
+            .build()
+            .run()
+            .unwrap();
+        attach_process(sandbox.name(), None, &mut Vec::new()).unwrap();
+        status
+    };
+
+    assert!(run(&["sh", "-c", &script]).success());
+    assert!(run(&["true"]).success());
+    let runs = session_trend(sandbox.name()).unwrap();
+
+    assert_eq!(runs.len(), 2);
+    assert_eq!(runs[0].before, ChangeStats::default());
+    assert_eq!(runs[0].after.files, 1);
+    assert_eq!(runs[0].after.top_dirs[0].path, dir);
+    assert!(runs[0].after.top_dirs[0].bytes >= 8192);
+    assert_eq!(runs[1].before, runs[0].after);
+    assert_eq!(runs[1].after, runs[1].before);
+}
+
+#[test]
+fn overlays_of_deleted_sessions_are_unmounted() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    let dir = Config::load().unwrap().sessions_dir().join(sandbox.name());
+    let hidden = dir.with_file_name(format!(".{}-diff", sandbox.name()));
+
+    let kept = unmount_abandoned_sessions().unwrap();
+    fs::rename(dir.join("diff"), &hidden).unwrap();
+    let unmounted = unmount_abandoned_sessions().unwrap();
+    fs::rename(&hidden, dir.join("diff")).unwrap();
+
+    assert!(!kept.contains(&dir.join("merged")));
+    assert_eq!(
+        unmounted
+            .iter()
+            .filter(|merged| **merged == dir.join("merged"))
+            .count(),
+        1
+    );
+    assert!(!is_active(&sandbox));
+}
+
+#[test]
+fn failed_activation_leaves_session_inactive() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+
+    inject_fault(Some(FaultPoint::AfterMount));
+    let result = sandbox.activate();
+    inject_fault(None);
+
+    assert!(result.is_err());
+    assert!(!is_active(&sandbox));
+    sandbox.activate().unwrap();
+    assert!(sandbox.run(&["true"]).unwrap().success());
+}
+
+#[test]
+fn interrupted_stop_can_be_retried() {
+    let Some(sandbox) = sandbox() else {
+        return;
+    };
+
+    inject_fault(Some(FaultPoint::BeforeUnmount));
+    let result = stop_sessions(SessionOperand::List(&[sandbox.name()]));
+    inject_fault(None);
+
+    assert!(result.is_err());
+    assert!(is_active(&sandbox));
+    stop_sessions(SessionOperand::List(&[sandbox.name()])).unwrap();
+    assert!(!is_active(&sandbox));
+}
+
+fn is_active(sandbox: &Sandbox) -> bool {
+    session_listing()
+        .unwrap()
+        .sessions
+        .iter()
+        .any(|session| session.name == sandbox.name() && session.active)
+}
+
+/// A ustar header for an entry without data.
+fn tar_header(path: &str, kind: u8, link: &str) -> [u8; 512] {
+    let mut header = [0; 512];
+    header[..path.len()].copy_from_slice(path.as_bytes());
+    header[100..107].copy_from_slice(b"0000777");
+    header[124..135].copy_from_slice(b"00000000000");
+    header[148..156].fill(b' ');
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    let checksum = header.iter().map(|&b| u32::from(b)).sum::<u32>();
+    header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+    header
+}